@@ -0,0 +1,324 @@
+// Copyright (c) 2016 DWANGO Co., Ltd. All Rights Reserved.
+// See the LICENSE file at the top-level directory of this distribution.
+
+//! Reusable load generators to compare executor configurations.
+//!
+//! # Examples
+//!
+//! ```
+//! # extern crate fibers;
+//! use fibers::InPlaceExecutor;
+//! use fibers::bench::{PingPong, Transport};
+//!
+//! let mut executor = InPlaceExecutor::new().unwrap();
+//! let report = PingPong::new(4, 100)
+//!     .transport(Transport::Channel)
+//!     .run(&mut executor)
+//!     .unwrap();
+//! assert_eq!(report.round_trips, 400);
+//! assert!(report.latency.p50 <= report.latency.p99);
+//! println!("{:.0} round trips/sec", report.throughput());
+//!
+//! let report = PingPong::new(2, 10)
+//!     .transport(Transport::Tcp)
+//!     .run(&mut executor)
+//!     .unwrap();
+//! assert_eq!(report.round_trips, 20);
+//! ```
+use futures::future::{self, Either, Loop};
+use futures::{Async, Future, Poll, Stream};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::time::{Duration, Instant};
+
+use crate::executor::Executor;
+use crate::fiber::Spawn;
+use crate::net::{TcpListener, TcpStream};
+use crate::sync::mpsc;
+use crate::sync::oneshot::{Monitor, MonitorError};
+
+const MESSAGE_SIZE: usize = 8;
+
+/// The transport used to exchange messages in `PingPong`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    /// `fibers::sync::mpsc` channels.
+    Channel,
+
+    /// TCP connections over the loopback interface.
+    Tcp,
+}
+
+/// A load generator which spawns pairs of fibers exchanging messages back and forth.
+///
+/// Each pair consists of a pinger and a ponger.
+/// The pinger sends a small message and waits for the echo from the ponger,
+/// and it repeats this for the specified number of round trips.
+#[derive(Debug, Clone)]
+pub struct PingPong {
+    pairs: usize,
+    round_trips: usize,
+    transport: Transport,
+}
+impl PingPong {
+    /// Makes a new `PingPong` instance which has `pairs` pairs of fibers,
+    /// each of which makes `round_trips` round trips.
+    ///
+    /// The transport is `Transport::Channel` by default.
+    pub fn new(pairs: usize, round_trips: usize) -> Self {
+        PingPong {
+            pairs,
+            round_trips,
+            transport: Transport::Channel,
+        }
+    }
+
+    /// Sets the transport used to exchange messages.
+    pub fn transport(mut self, transport: Transport) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    /// Runs the load on `executor` and reports the result.
+    pub fn run<E>(&self, executor: &mut E) -> io::Result<BenchReport>
+    where
+        E: Executor + Spawn,
+    {
+        let start = Instant::now();
+        let pingers = match self.transport {
+            Transport::Channel => self.spawn_channel_pairs(executor),
+            Transport::Tcp => self.spawn_tcp_pairs(executor)?,
+        };
+        let mut latencies = Vec::with_capacity(self.pairs * self.round_trips);
+        for pinger in pingers {
+            let mut pair_latencies = executor.run_fiber(pinger)?.map_err(into_io_error)?;
+            latencies.append(&mut pair_latencies);
+        }
+        let elapsed = start.elapsed();
+        Ok(BenchReport {
+            transport: self.transport,
+            round_trips: latencies.len(),
+            elapsed,
+            latency: LatencySummary::new(latencies),
+        })
+    }
+
+    fn spawn_channel_pairs<E: Spawn>(
+        &self,
+        executor: &E,
+    ) -> Vec<Monitor<Vec<Duration>, io::Error>> {
+        (0..self.pairs)
+            .map(|_| {
+                let (ping_tx, ping_rx) = mpsc::channel();
+                let (pong_tx, pong_rx) = mpsc::channel();
+                executor.spawn(ping_rx.for_each(move |t| {
+                    let _ = pong_tx.send(t);
+                    Ok(())
+                }));
+
+                let round_trips = self.round_trips;
+                let latencies = Vec::with_capacity(round_trips);
+                executor.spawn_monitor(future::loop_fn(
+                    (pong_rx, latencies),
+                    move |(rx, mut latencies)| {
+                        if latencies.len() == round_trips {
+                            return Either::A(future::ok(Loop::Break(latencies)));
+                        }
+                        let _ = ping_tx.send(Instant::now());
+                        Either::B(rx.into_future().then(|result| match result {
+                            Ok((Some(t), rx)) => {
+                                latencies.push(t.elapsed());
+                                Ok(Loop::Continue((rx, latencies)))
+                            }
+                            _ => Err(io::Error::new(
+                                io::ErrorKind::ConnectionAborted,
+                                "The ponger exited unexpectedly",
+                            )),
+                        }))
+                    },
+                ))
+            })
+            .collect()
+    }
+
+    fn spawn_tcp_pairs<E>(
+        &self,
+        executor: &mut E,
+    ) -> io::Result<Vec<Monitor<Vec<Duration>, io::Error>>>
+    where
+        E: Executor + Spawn,
+    {
+        let bind = executor.spawn_monitor(TcpListener::bind(([127, 0, 0, 1], 0).into()));
+        let listener = executor.run_fiber(bind)?.map_err(into_io_error)?;
+        let addr = listener.local_addr()?;
+        let handle = executor.handle();
+        let pongers =
+            listener
+                .incoming()
+                .take(self.pairs as u64)
+                .for_each(move |(connected, _)| {
+                    let echo = connected.and_then(|stream| {
+                        future::loop_fn(stream, |stream| {
+                            Exchange::echo(stream).map(|stream| match stream {
+                                None => Loop::Break(()),
+                                Some(stream) => Loop::Continue(stream),
+                            })
+                        })
+                    });
+                    handle.spawn(echo.map_err(|_| ()));
+                    Ok(())
+                });
+        executor.spawn(pongers.map_err(|_| ()));
+
+        let round_trips = self.round_trips;
+        Ok((0..self.pairs)
+            .map(|_| {
+                let pinger = TcpStream::connect(addr).and_then(move |stream| {
+                    let latencies = Vec::with_capacity(round_trips);
+                    future::loop_fn((stream, latencies), move |(stream, mut latencies)| {
+                        if latencies.len() == round_trips {
+                            return Either::A(future::ok(Loop::Break(latencies)));
+                        }
+                        let start = Instant::now();
+                        Either::B(Exchange::ping(stream).and_then(move |stream| {
+                            latencies.push(start.elapsed());
+                            Ok(Loop::Continue((stream, latencies)))
+                        }))
+                    })
+                });
+                executor.spawn_monitor(pinger)
+            })
+            .collect())
+    }
+}
+
+/// The result of a benchmark.
+#[derive(Debug, Clone)]
+pub struct BenchReport {
+    /// The transport used in the benchmark.
+    pub transport: Transport,
+
+    /// The total number of the completed round trips.
+    pub round_trips: usize,
+
+    /// The elapsed time of the whole benchmark.
+    pub elapsed: Duration,
+
+    /// The summary of the round trip latencies.
+    pub latency: LatencySummary,
+}
+impl BenchReport {
+    /// Returns the number of the round trips per second.
+    pub fn throughput(&self) -> f64 {
+        let secs = self.elapsed.as_secs() as f64 + f64::from(self.elapsed.subsec_nanos()) / 1e9;
+        if secs == 0.0 {
+            0.0
+        } else {
+            self.round_trips as f64 / secs
+        }
+    }
+}
+
+/// A summary of latency samples.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct LatencySummary {
+    /// The minimum latency.
+    pub min: Duration,
+
+    /// The median latency.
+    pub p50: Duration,
+
+    /// The 90th percentile latency.
+    pub p90: Duration,
+
+    /// The 99th percentile latency.
+    pub p99: Duration,
+
+    /// The maximum latency.
+    pub max: Duration,
+}
+impl LatencySummary {
+    /// Makes a summary of `samples`.
+    ///
+    /// If `samples` is empty, all the fields will be zero.
+    pub fn new(mut samples: Vec<Duration>) -> Self {
+        if samples.is_empty() {
+            return Self::default();
+        }
+        samples.sort();
+        let percentile = |p: usize| samples[(samples.len() - 1) * p / 100];
+        LatencySummary {
+            min: samples[0],
+            p50: percentile(50),
+            p90: percentile(90),
+            p99: percentile(99),
+            max: samples[samples.len() - 1],
+        }
+    }
+}
+
+fn into_io_error(e: MonitorError<io::Error>) -> io::Error {
+    e.unwrap_or_else(|| io::Error::other("The fiber was aborted"))
+}
+
+/// A future which exchanges a message over a TCP stream.
+enum Exchange {
+    Writing(TcpStream, [u8; MESSAGE_SIZE], usize, bool),
+    Reading(TcpStream, [u8; MESSAGE_SIZE], usize, bool),
+    Polled,
+}
+impl Exchange {
+    /// Sends a message and receives the echo.
+    fn ping(stream: TcpStream) -> impl Future<Item = TcpStream, Error = io::Error> {
+        Exchange::Writing(stream, [0; MESSAGE_SIZE], 0, true).map(|s| assert_some!(s))
+    }
+
+    /// Receives a message and sends it back.
+    ///
+    /// If the peer has closed the connection, this will result in `None`.
+    fn echo(stream: TcpStream) -> Self {
+        Exchange::Reading(stream, [0; MESSAGE_SIZE], 0, false)
+    }
+}
+impl Future for Exchange {
+    type Item = Option<TcpStream>;
+    type Error = io::Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            match mem::replace(self, Exchange::Polled) {
+                Exchange::Writing(mut stream, buf, offset, is_pinger) => {
+                    match stream.write(&buf[offset..]) {
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                            *self = Exchange::Writing(stream, buf, offset, is_pinger);
+                            return Ok(Async::NotReady);
+                        }
+                        Err(e) => return Err(e),
+                        Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+                        Ok(n) if offset + n < MESSAGE_SIZE => {
+                            *self = Exchange::Writing(stream, buf, offset + n, is_pinger);
+                        }
+                        Ok(_) if is_pinger => *self = Exchange::Reading(stream, buf, 0, true),
+                        Ok(_) => return Ok(Async::Ready(Some(stream))),
+                    }
+                }
+                Exchange::Reading(mut stream, mut buf, offset, is_pinger) => {
+                    match stream.read(&mut buf[offset..]) {
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                            *self = Exchange::Reading(stream, buf, offset, is_pinger);
+                            return Ok(Async::NotReady);
+                        }
+                        Err(e) => return Err(e),
+                        Ok(0) if offset == 0 && !is_pinger => return Ok(Async::Ready(None)),
+                        Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+                        Ok(n) if offset + n < MESSAGE_SIZE => {
+                            *self = Exchange::Reading(stream, buf, offset + n, is_pinger);
+                        }
+                        Ok(_) if is_pinger => return Ok(Async::Ready(Some(stream))),
+                        Ok(_) => *self = Exchange::Writing(stream, buf, 0, false),
+                    }
+                }
+                Exchange::Polled => panic!("Cannot poll Exchange twice"),
+            }
+        }
+    }
+}
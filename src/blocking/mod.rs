@@ -0,0 +1,395 @@
+// Copyright (c) 2016 DWANGO Co., Ltd. All Rights Reserved.
+// See the LICENSE file at the top-level directory of this distribution.
+
+//! A thread pool for blocking operations and CPU-bound computations.
+//!
+//! Fibers must not block their scheduler threads.
+//! Instead, blocking operations (e.g., reading files or heavy computations)
+//! can be executed on the blocking pool and their results can be awaited by fibers.
+//!
+//! The pool is shared by the whole process and is created lazily when first used.
+//! It is elastic: threads are spawned on demand up to the maximum
+//! (see `set_thread_limits`), and the threads idle longer than the idle timeout
+//! (see `set_idle_timeout`) exit until the minimum number of threads remain.
+//! So bursty blocking work does not pin many threads permanently.
+//!
+//! # Examples
+//!
+//! ```
+//! # extern crate fibers;
+//! # extern crate futures;
+//! use fibers::{blocking, Executor, InPlaceExecutor};
+//!
+//! let mut executor = InPlaceExecutor::new().unwrap();
+//! let monitor = blocking::spawn(|| Ok::<_, ()>((1..=10).sum::<u32>()));
+//! assert_eq!(executor.run_fiber(monitor).unwrap(), Ok(55));
+//! ```
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, OnceLock};
+use std::thread;
+use std::time::Duration;
+
+pub use self::scope::{scope, Scope, ScopedJoinHandle};
+
+use crate::sync::oneshot::{self, Monitor};
+
+mod scope;
+
+/// The default maximum number of the threads in the blocking pool.
+pub const DEFAULT_MAX_THREADS: usize = 64;
+
+/// The default idle timeout of the threads in the blocking pool.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Executes `f` on the blocking pool and returns a future to monitor its result.
+///
+/// If `f` panics, the monitor results in `MonitorError::Aborted`.
+pub fn spawn<F, T, E>(f: F) -> Monitor<T, E>
+where
+    F: FnOnce() -> Result<T, E> + Send + 'static,
+    T: Send + 'static,
+    E: Send + 'static,
+{
+    let (monitored, monitor) = oneshot::monitor();
+    execute(Box::new(move || monitored.exit(f())));
+    monitor
+}
+
+/// Sets the minimum and maximum numbers of the threads in the blocking pool.
+///
+/// The minimum number of threads are kept alive even if they are idle.
+/// If a job is queued while all of the threads are busy,
+/// a new thread is spawned unless the number of the threads reaches `max_threads`.
+///
+/// The defaults are `0` and `DEFAULT_MAX_THREADS`.
+///
+/// # Panics
+///
+/// If `max_threads` is `0` or `min_threads` is greater than `max_threads`,
+/// this function will panic.
+///
+/// # Examples
+///
+/// ```
+/// use fibers::blocking;
+///
+/// blocking::set_thread_limits(2, 8);
+/// assert!(blocking::stats().threads >= 2);
+/// ```
+pub fn set_thread_limits(min_threads: usize, max_threads: usize) {
+    assert!(max_threads > 0);
+    assert!(min_threads <= max_threads);
+    let pool = pool();
+    let mut inner = pool.lock();
+    inner.min_threads = min_threads;
+    inner.max_threads = max_threads;
+    for _ in inner.threads..min_threads {
+        pool.spawn_thread(&mut inner);
+    }
+
+    // The surplus threads exit when they become idle.
+    pool.cv.notify_all();
+}
+
+/// Sets the duration after which an idle thread exits
+/// (as long as more than the minimum number of threads remain).
+///
+/// The default value is `DEFAULT_IDLE_TIMEOUT`.
+pub fn set_idle_timeout(timeout: Duration) {
+    pool().lock().idle_timeout = timeout;
+}
+
+/// Sets the callback invoked when the blocking pool becomes saturated.
+///
+/// The pool is saturated when a job is queued while all of the threads are busy and
+/// no more threads can be spawned.
+/// The callback is invoked with the depth of the queue
+/// each time the pool changes from unsaturated to saturated
+/// (the pool is unsaturated again when the queue becomes empty).
+///
+/// Note that the callback is invoked on the thread queueing the job,
+/// so it should not block.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate fibers;
+/// # extern crate futures;
+/// use std::sync::mpsc;
+/// use fibers::{blocking, Executor, InPlaceExecutor};
+/// use fibers::sync::oneshot::Monitor;
+///
+/// blocking::set_thread_limits(0, 1);
+/// let (tx, rx) = mpsc::channel();
+/// blocking::on_saturation(move |depth| tx.send(depth).unwrap());
+///
+/// let (release_tx, release_rx) = mpsc::channel::<()>();
+/// let busy = blocking::spawn(move || release_rx.recv().map_err(|_| ()));
+/// let queued = blocking::spawn(|| Ok::<_, ()>(()));
+/// assert!(rx.recv().unwrap() >= 1);
+///
+/// release_tx.send(()).unwrap();
+/// let mut executor = InPlaceExecutor::new().unwrap();
+/// executor.run_future(Monitor::join_all(vec![busy, queued])).unwrap().unwrap();
+/// assert_eq!(blocking::stats().saturations, 1);
+/// ```
+pub fn on_saturation<F>(f: F)
+where
+    F: Fn(usize) + Send + Sync + 'static,
+{
+    pool().lock().saturation_callback = Some(Arc::new(f));
+}
+
+/// Returns the statistics of the blocking pool.
+pub fn stats() -> BlockingPoolStats {
+    let inner = pool().lock();
+    BlockingPoolStats {
+        threads: inner.threads,
+        idle_threads: inner.idle_threads,
+        queue_depth: inner.queue.len(),
+        completed_jobs: inner.completed_jobs,
+        saturations: inner.saturations,
+    }
+}
+
+/// Statistics of the blocking pool.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct BlockingPoolStats {
+    /// The number of the alive threads.
+    pub threads: usize,
+
+    /// The number of the threads waiting for jobs.
+    pub idle_threads: usize,
+
+    /// The number of the jobs waiting to be executed.
+    pub queue_depth: usize,
+
+    /// The number of the jobs which have been executed.
+    pub completed_jobs: u64,
+
+    /// The number of the times the pool has become saturated (see `on_saturation`).
+    pub saturations: u64,
+}
+
+/// Executes `job` on the blocking pool.
+pub(crate) fn execute(job: Job) {
+    pool().execute(job);
+}
+
+/// Runs a job queued in the blocking pool on the current thread (if any).
+///
+/// This is used by the threads waiting for other jobs to avoid deadlocks.
+/// Returns `true` if a job was run, otherwise `false`.
+fn run_queued_job() -> bool {
+    let pool = pool();
+    let job = pool.lock().pop_job();
+    if let Some(job) = job {
+        let _ = panic::catch_unwind(AssertUnwindSafe(job));
+        pool.lock().completed_jobs += 1;
+        true
+    } else {
+        false
+    }
+}
+
+pub(crate) type Job = Box<dyn FnOnce() + Send + 'static>;
+
+static POOL: OnceLock<Arc<Pool>> = OnceLock::new();
+
+fn pool() -> &'static Arc<Pool> {
+    POOL.get_or_init(|| Arc::new(Pool::new()))
+}
+
+struct Pool {
+    inner: Mutex<PoolInner>,
+    cv: Condvar,
+}
+impl Pool {
+    fn new() -> Self {
+        Pool {
+            inner: Mutex::new(PoolInner {
+                queue: VecDeque::new(),
+                threads: 0,
+                idle_threads: 0,
+                next_thread_id: 0,
+                min_threads: 0,
+                max_threads: DEFAULT_MAX_THREADS,
+                idle_timeout: DEFAULT_IDLE_TIMEOUT,
+                saturated: false,
+                saturations: 0,
+                completed_jobs: 0,
+                saturation_callback: None,
+            }),
+            cv: Condvar::new(),
+        }
+    }
+
+    fn execute(self: &Arc<Self>, job: Job) {
+        let mut inner = self.lock();
+        inner.queue.push_back(job);
+        if inner.idle_threads >= inner.queue.len() {
+            self.cv.notify_one();
+        } else if inner.threads < inner.max_threads {
+            self.spawn_thread(&mut inner);
+        } else if !inner.saturated {
+            inner.saturated = true;
+            inner.saturations += 1;
+            let depth = inner.queue.len();
+            let callback = inner.saturation_callback.clone();
+            drop(inner);
+            if let Some(callback) = callback {
+                callback(depth);
+            }
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, PoolInner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn spawn_thread(self: &Arc<Self>, inner: &mut PoolInner) {
+        let pool = Arc::clone(self);
+        thread::Builder::new()
+            .name(format!("fibers-blocking-{}", inner.next_thread_id))
+            .spawn(move || pool.run_worker())
+            .expect("Cannot spawn a blocking pool thread");
+        inner.threads += 1;
+        inner.next_thread_id += 1;
+    }
+
+    fn run_worker(&self) {
+        let mut inner = self.lock();
+        loop {
+            if let Some(job) = inner.pop_job() {
+                drop(inner);
+
+                // The panic is notified to the monitor by dropping its `Monitored`.
+                let _ = panic::catch_unwind(AssertUnwindSafe(job));
+                inner = self.lock();
+                inner.completed_jobs += 1;
+                continue;
+            }
+            if inner.threads > inner.max_threads {
+                inner.threads -= 1;
+                return;
+            }
+
+            inner.idle_threads += 1;
+            let timeout = inner.idle_timeout;
+            let (guard, result) = self
+                .cv
+                .wait_timeout(inner, timeout)
+                .unwrap_or_else(|e| e.into_inner());
+            inner = guard;
+            inner.idle_threads -= 1;
+            if result.timed_out() && inner.queue.is_empty() && inner.threads > inner.min_threads {
+                inner.threads -= 1;
+                return;
+            }
+        }
+    }
+}
+
+struct PoolInner {
+    queue: VecDeque<Job>,
+    threads: usize,
+    idle_threads: usize,
+    next_thread_id: usize,
+    min_threads: usize,
+    max_threads: usize,
+    idle_timeout: Duration,
+    saturated: bool,
+    saturations: u64,
+    completed_jobs: u64,
+    saturation_callback: Option<Arc<dyn Fn(usize) + Send + Sync>>,
+}
+impl PoolInner {
+    fn pop_job(&mut self) -> Option<Job> {
+        let job = self.queue.pop_front();
+        if self.queue.is_empty() {
+            self.saturated = false;
+        }
+        job
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{mpsc, Barrier};
+    use std::time::Instant;
+
+    use super::*;
+
+    // NOTE: The global pool is shared by the other tests, so each test uses its own pool.
+    fn new_pool(max_threads: usize, idle_timeout: Duration) -> Arc<Pool> {
+        let pool = Arc::new(Pool::new());
+        pool.lock().max_threads = max_threads;
+        pool.lock().idle_timeout = idle_timeout;
+        pool
+    }
+
+    fn wait_until<F: Fn() -> bool>(f: F) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !f() {
+            assert!(Instant::now() < deadline, "Timeout expired");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn idle_threads_exit_until_min_threads_remain() {
+        let pool = new_pool(4, Duration::from_millis(20));
+        pool.lock().min_threads = 1;
+
+        let barrier = Arc::new(Barrier::new(4));
+        for _ in 0..3 {
+            let barrier = Arc::clone(&barrier);
+            pool.execute(Box::new(move || {
+                barrier.wait();
+            }));
+        }
+        barrier.wait();
+        assert_eq!(pool.lock().threads, 3);
+
+        wait_until(|| pool.lock().threads == 1);
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(pool.lock().threads, 1);
+        assert_eq!(pool.lock().completed_jobs, 3);
+    }
+
+    #[test]
+    fn saturation_callback_is_invoked_again_after_queue_drains() {
+        let pool = new_pool(1, Duration::from_millis(20));
+        let (depth_tx, depth_rx) = mpsc::channel();
+        let depth_tx = Mutex::new(depth_tx);
+        pool.lock().saturation_callback = Some(Arc::new(move |depth| {
+            let _ = depth_tx.lock().unwrap().send(depth);
+        }));
+
+        for round in 1..=2 {
+            let (started_tx, started_rx) = mpsc::channel();
+            let (release_tx, release_rx) = mpsc::channel::<()>();
+            pool.execute(Box::new(move || {
+                let _ = started_tx.send(());
+                let _ = release_rx.recv();
+            }));
+            started_rx.recv().unwrap();
+
+            // The pool saturates only once until the queue drains.
+            pool.execute(Box::new(|| {}));
+            pool.execute(Box::new(|| {}));
+            assert_eq!(depth_rx.try_recv(), Ok(1));
+            assert!(depth_rx.try_recv().is_err());
+            assert_eq!(pool.lock().saturations, round);
+
+            release_tx.send(()).unwrap();
+            wait_until(|| {
+                let inner = pool.lock();
+                inner.completed_jobs == round * 3 && inner.idle_threads == inner.threads
+            });
+            assert!(!pool.lock().saturated);
+        }
+    }
+}
@@ -0,0 +1,261 @@
+// Copyright (c) 2016 DWANGO Co., Ltd. All Rights Reserved.
+// See the LICENSE file at the top-level directory of this distribution.
+
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+use super::{execute, run_queued_job, Job};
+use crate::sync::oneshot::{self, Monitor};
+
+/// The interval to check the queue of the blocking pool while waiting for scoped tasks.
+const HELP_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Executes `f` on the blocking pool with a scope
+/// in which tasks borrowing `data` can be spawned.
+///
+/// The tasks spawned via `Scope::spawn` are executed on the blocking pool,
+/// and they are joined before the scope exits.
+/// Thus, the tasks can borrow `data` (and the values derived from it in `f`)
+/// without wrapping them in `Arc`.
+///
+/// The returned monitor results in `data` and the result of `f`,
+/// so the fiber can get the ownership of `data` back.
+/// If `f` or any un-joined task panics, the monitor results in `MonitorError::Aborted`.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate fibers;
+/// # extern crate futures;
+/// use fibers::{blocking, Executor, InPlaceExecutor};
+///
+/// let mut executor = InPlaceExecutor::new().unwrap();
+/// let buf = (0..1000u64).collect::<Vec<_>>();
+/// let monitor = blocking::scope(buf, |s, buf| {
+///     let (front, back) = buf.split_at_mut(500);
+///     let front = s.spawn(move || {
+///         front.iter_mut().for_each(|x| *x *= 2);
+///         front.iter().sum::<u64>()
+///     });
+///     let back = s.spawn(move || back.iter().sum::<u64>());
+///     Ok::<_, ()>(front.join().unwrap() + back.join().unwrap())
+/// });
+/// let (buf, sum) = executor.run_fiber(monitor).unwrap().unwrap();
+/// assert_eq!(sum, 2 * (0..500).sum::<u64>() + (500..1000).sum::<u64>());
+/// assert_eq!(buf[1], 2);
+/// ```
+pub fn scope<D, F, T, E>(data: D, f: F) -> Monitor<(D, T), E>
+where
+    D: Send + 'static,
+    F: for<'scope> FnOnce(&'scope Scope<'scope>, &'scope mut D) -> Result<T, E> + Send + 'static,
+    T: Send + 'static,
+    E: Send + 'static,
+{
+    let (monitored, monitor) = oneshot::monitor();
+    execute(Box::new(move || {
+        let mut data = data;
+        let scope = Scope {
+            state: Arc::new(ScopeState::default()),
+            _lifetime: PhantomData,
+        };
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope, &mut data)));
+
+        // NOTE: The scoped tasks must be completed before `data` is released,
+        // regardless of whether `f` has panicked or not.
+        scope.wait_all();
+        if let Ok(result) = result {
+            if scope.state.lock().unjoined_panics == 0 {
+                monitored.exit(result.map(|t| (data, t)));
+            }
+        }
+    }));
+    monitor
+}
+
+/// A scope to spawn tasks which can borrow data.
+///
+/// See the `scope` function for more details.
+pub struct Scope<'scope> {
+    state: Arc<ScopeState>,
+    _lifetime: PhantomData<&'scope mut &'scope ()>,
+}
+impl<'scope> Scope<'scope> {
+    /// Spawns a task on the blocking pool within this scope.
+    ///
+    /// The task is joined before the scope exits,
+    /// even if the returned handle is dropped.
+    pub fn spawn<F, T>(&'scope self, f: F) -> ScopedJoinHandle<'scope, T>
+    where
+        F: FnOnce() -> T + Send + 'scope,
+        T: Send + 'scope,
+    {
+        self.state.lock().pending += 1;
+        let packet = Arc::new(Packet {
+            scope: Arc::clone(&self.state),
+            result: Mutex::new(None),
+            cv: Condvar::new(),
+        });
+        let job: Box<dyn FnOnce() + Send + 'scope> = {
+            let packet = Arc::clone(&packet);
+            Box::new(move || {
+                let result = panic::catch_unwind(AssertUnwindSafe(f));
+                *packet.lock() = Some(result);
+                packet.cv.notify_all();
+            })
+        };
+
+        // SAFETY: `pending` is decremented in `Packet::drop`, i.e., after both of the job
+        // (including `f`) and the handle (including the result) have been dropped.
+        // The scope waits for it in `ScopeState::wait_all` before the borrowed data is released,
+        // so no value bound by `'scope` outlives the scope.
+        let job = unsafe { mem::transmute::<Box<dyn FnOnce() + Send + 'scope>, Job>(job) };
+        execute(job);
+        ScopedJoinHandle {
+            packet,
+            _lifetime: PhantomData,
+        }
+    }
+
+    fn wait_all(&self) {
+        wait(|| self.state.lock().pending == 0, &self.state.cv);
+    }
+}
+impl<'scope> fmt::Debug for Scope<'scope> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Scope {{ pending: {}, .. }}", self.state.lock().pending)
+    }
+}
+
+/// A handle to join a task spawned by `Scope::spawn` method.
+pub struct ScopedJoinHandle<'scope, T> {
+    packet: Arc<Packet<T>>,
+    _lifetime: PhantomData<&'scope ()>,
+}
+impl<'scope, T> ScopedJoinHandle<'scope, T> {
+    /// Waits for the task to complete and returns its result.
+    ///
+    /// If the task panicked, this returns `Err` with the panic payload.
+    pub fn join(self) -> thread::Result<T> {
+        wait(|| self.packet.lock().is_some(), &self.packet.cv);
+        // NOTE: Because the result is taken here, a panic is not counted as an un-joined one.
+        self.packet.lock().take().expect("Never fails")
+    }
+
+    /// Returns `true` if the task has completed, otherwise `false`.
+    pub fn is_finished(&self) -> bool {
+        self.packet.lock().is_some()
+    }
+}
+impl<'scope, T> fmt::Debug for ScopedJoinHandle<'scope, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ScopedJoinHandle {{ .. }}")
+    }
+}
+
+#[derive(Default)]
+struct ScopeState {
+    inner: Mutex<ScopeStateInner>,
+    cv: Condvar,
+}
+impl ScopeState {
+    fn lock(&self) -> MutexGuard<'_, ScopeStateInner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[derive(Default)]
+struct ScopeStateInner {
+    pending: usize,
+    unjoined_panics: usize,
+}
+
+struct Packet<T> {
+    scope: Arc<ScopeState>,
+    result: Mutex<Option<thread::Result<T>>>,
+    cv: Condvar,
+}
+impl<T> Packet<T> {
+    fn lock(&self) -> MutexGuard<'_, Option<thread::Result<T>>> {
+        self.result.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+impl<T> Drop for Packet<T> {
+    fn drop(&mut self) {
+        // The result (which may borrow the scoped data) must be dropped before `pending` is decremented.
+        let unjoined_panic = matches!(self.lock().take(), Some(Err(_)));
+        let mut state = self.scope.lock();
+        if unjoined_panic {
+            state.unjoined_panics += 1;
+        }
+        state.pending -= 1;
+        self.scope.cv.notify_all();
+    }
+}
+
+/// Waits until `is_done` returns `true`.
+///
+/// While waiting, the current thread executes the jobs queued in the blocking pool,
+/// so the pool never deadlocks even if all of its threads are waiting for scoped tasks.
+fn wait<F>(is_done: F, cv: &Condvar)
+where
+    F: Fn() -> bool,
+{
+    let dummy = Mutex::new(());
+    while !is_done() {
+        if !run_queued_job() {
+            let guard = dummy.lock().unwrap_or_else(|e| e.into_inner());
+            let _ = cv.wait_timeout(guard, HELP_INTERVAL);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::executor::{Executor, InPlaceExecutor};
+    use crate::sync::oneshot::MonitorError;
+
+    struct CheckOnDrop<'a>(&'a AtomicBool);
+    impl<'a> Drop for CheckOnDrop<'a> {
+        fn drop(&mut self) {
+            thread::sleep(Duration::from_millis(10));
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn unjoined_results_are_dropped_within_scope() {
+        let mut executor = InPlaceExecutor::new().unwrap();
+        let monitor = scope(AtomicBool::new(false), |s, dropped| {
+            let dropped = &*dropped;
+            let _ = s.spawn(move || CheckOnDrop(dropped));
+            Ok::<_, ()>(())
+        });
+        let (dropped, ()) = executor.run_fiber(monitor).unwrap().unwrap();
+        assert!(dropped.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn joining_a_panicked_task_does_not_hide_other_panics() {
+        let mut executor = InPlaceExecutor::new().unwrap();
+        let monitor = scope((), |s, _| {
+            let _ = s.spawn(|| panic!("un-joined"));
+            let joined = s.spawn(|| panic!("joined"));
+            assert!(joined.join().is_err());
+            Ok::<_, ()>(())
+        });
+        assert_eq!(
+            executor.run_fiber(monitor).unwrap().err(),
+            Some(MonitorError::Aborted)
+        );
+    }
+}
@@ -0,0 +1,382 @@
+// Copyright (c) 2016 DWANGO Co., Ltd. All Rights Reserved.
+// See the LICENSE file at the top-level directory of this distribution.
+
+//! Typed publish/subscribe event bus.
+//!
+//! An `EventBus` has a topic for each type of values.
+//! Every value published to a topic is delivered to all of the subscribers of the topic,
+//! which is useful for decoupled intra-process events such as "peer disconnected" or
+//! "configuration changed".
+//!
+//! Each subscriber has its own buffer whose capacity and overflow behavior
+//! are specified by `BufferPolicy`, so slow subscribers never block publishers.
+//!
+//! # Examples
+//!
+//! ```
+//! # extern crate fibers;
+//! # extern crate futures;
+//! use fibers::{Executor, InPlaceExecutor, Spawn};
+//! use fibers::bus::EventBus;
+//! use futures::Stream;
+//!
+//! #[derive(Debug, Clone, PartialEq)]
+//! struct PeerDisconnected(u32);
+//!
+//! let mut executor = InPlaceExecutor::new().unwrap();
+//! let bus = EventBus::new();
+//! let events = bus.subscribe::<PeerDisconnected>();
+//! let monitor = executor.spawn_monitor(events.take(2).collect());
+//!
+//! assert_eq!(bus.publish(PeerDisconnected(1)), 1);
+//! assert_eq!(bus.publish("other topic"), 0);
+//! assert_eq!(bus.publish(PeerDisconnected(2)), 1);
+//!
+//! let events = executor.run_fiber(monitor).unwrap().unwrap();
+//! assert_eq!(events, vec![PeerDisconnected(1), PeerDisconnected(2)]);
+//! ```
+//!
+//! # Note
+//!
+//! Like the channels in the `sync` module,
+//! the structures in this module can be used on both inside and outside of a fiber.
+use futures::{Async, Poll, Stream};
+use std::any::{Any, TypeId};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::metrics::{WakeCounters, WakeStats};
+use crate::sync::Notifier;
+
+/// A publish/subscribe event bus whose topics are keyed by the types of the values.
+///
+/// The bus is cheaply cloneable and can be shared by many fibers (and threads).
+/// When all of the clones are dropped, the streams of the subscribers are terminated.
+#[derive(Clone)]
+pub struct EventBus {
+    inner: Arc<BusInner>,
+}
+impl EventBus {
+    /// Makes a new `EventBus` instance which has no topics.
+    pub fn new() -> Self {
+        EventBus {
+            inner: Arc::new(BusInner {
+                topics: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    /// Subscribes to the topic of the type `T` with an unbounded buffer.
+    ///
+    /// This is equivalent to `self.subscribe_with(BufferPolicy::Unbounded)`.
+    pub fn subscribe<T>(&self) -> Subscriber<T>
+    where
+        T: Clone + Send + 'static,
+    {
+        self.subscribe_with(BufferPolicy::Unbounded)
+    }
+
+    /// Subscribes to the topic of the type `T` with the given buffering policy.
+    ///
+    /// The subscriber receives only the values published after this call.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use fibers::bus::{BufferPolicy, EventBus};
+    /// use futures::{Async, Stream};
+    ///
+    /// let bus = EventBus::new();
+    /// let mut latest = bus.subscribe_with::<u32>(BufferPolicy::DropOldest(1));
+    /// for i in 0..3u32 {
+    ///     bus.publish(i);
+    /// }
+    /// assert_eq!(latest.poll(), Ok(Async::Ready(Some(2))));
+    /// assert_eq!(latest.dropped(), 2);
+    /// ```
+    pub fn subscribe_with<T>(&self, policy: BufferPolicy) -> Subscriber<T>
+    where
+        T: Clone + Send + 'static,
+    {
+        let notifier = Notifier::new();
+        let slot = Arc::new(Slot {
+            state: Mutex::new(SlotState {
+                queue: VecDeque::new(),
+                dropped: 0,
+                closed: false,
+            }),
+            policy,
+            notifier: notifier.clone(),
+        });
+        self.inner
+            .lock_topics()
+            .entry(TypeId::of::<T>())
+            .or_insert_with(|| Box::new(Topic::<T> { slots: Vec::new() }))
+            .as_any_mut()
+            .downcast_mut::<Topic<T>>()
+            .expect("Never fails")
+            .slots
+            .push(Arc::clone(&slot));
+        Subscriber {
+            slot,
+            notifier,
+            waiting: false,
+            wake: WakeCounters::default(),
+        }
+    }
+
+    /// Publishes `value` to all of the subscribers of the topic of the type `T`.
+    ///
+    /// Returns the number of the subscribers which have buffered the value
+    /// (i.e., the subscribers which dropped it due to their buffering policies are excluded).
+    pub fn publish<T>(&self, value: T) -> usize
+    where
+        T: Clone + Send + 'static,
+    {
+        let mut topics = self.inner.lock_topics();
+        let topic = match topics.get_mut(&TypeId::of::<T>()) {
+            None => return 0,
+            Some(topic) => topic
+                .as_any_mut()
+                .downcast_mut::<Topic<T>>()
+                .expect("Never fails"),
+        };
+
+        // The slots whose subscribers have been dropped are removed here.
+        topic.slots.retain(|slot| Arc::strong_count(slot) > 1);
+        let mut delivered = 0;
+        for slot in &topic.slots {
+            if slot.push(value.clone()) {
+                delivered += 1;
+            }
+        }
+        delivered
+    }
+
+    /// Returns the number of the subscribers of the topic of the type `T`.
+    pub fn subscribers<T>(&self) -> usize
+    where
+        T: Clone + Send + 'static,
+    {
+        self.inner
+            .lock_topics()
+            .get_mut(&TypeId::of::<T>())
+            .and_then(|topic| topic.as_any_mut().downcast_mut::<Topic<T>>())
+            .map_or(0, |topic| {
+                topic.slots.retain(|slot| Arc::strong_count(slot) > 1);
+                topic.slots.len()
+            })
+    }
+}
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl fmt::Debug for EventBus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "EventBus {{ .. }}")
+    }
+}
+
+/// The policy of the buffer of a subscriber.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BufferPolicy {
+    /// The buffer has no limit.
+    Unbounded,
+
+    /// The buffer holds at most the given number of values,
+    /// and the oldest value is dropped when a new value arrives at the full buffer.
+    DropOldest(usize),
+
+    /// The buffer holds at most the given number of values,
+    /// and new values are dropped while the buffer is full.
+    DropNewest(usize),
+}
+
+/// A subscriber of a topic of an `EventBus`.
+///
+/// This is a stream which yields the values published to the topic.
+/// The stream terminates when all of the clones of the bus are dropped
+/// and the buffered values are consumed.
+///
+/// This stream will never fail.
+pub struct Subscriber<T> {
+    slot: Arc<Slot<T>>,
+    notifier: Notifier,
+    waiting: bool,
+    wake: WakeCounters,
+}
+impl<T> Subscriber<T> {
+    /// Returns the buffering policy of this subscriber.
+    pub fn policy(&self) -> BufferPolicy {
+        self.slot.policy
+    }
+
+    /// Returns the number of the values dropped by the buffering policy so far.
+    pub fn dropped(&self) -> u64 {
+        self.slot.lock().dropped
+    }
+
+    /// Returns the number of the values buffered in this subscriber.
+    pub fn len(&self) -> usize {
+        self.slot.lock().queue.len()
+    }
+
+    /// Returns `true` if no values are buffered in this subscriber, otherwise `false`.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the statistics of the wakeups of the fiber waiting on this subscriber.
+    ///
+    /// See `sync::mpsc::Receiver::wake_stats` for the definition of the wakeups.
+    pub fn wake_stats(&self) -> WakeStats {
+        self.wake.snapshot()
+    }
+
+    fn try_recv(&self) -> Option<Option<T>> {
+        let mut state = self.slot.lock();
+        match state.queue.pop_front() {
+            Some(value) => Some(Some(value)),
+            None if state.closed => Some(None),
+            None => None,
+        }
+    }
+}
+impl<T> Stream for Subscriber<T> {
+    type Item = T;
+    type Error = ();
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let mut result = self.try_recv();
+        if result.is_none() {
+            self.notifier.await_notification();
+            result = self.try_recv();
+        }
+        if self.waiting {
+            self.wake.record(result.is_some());
+        }
+        self.waiting = result.is_none();
+        Ok(result.map_or(Async::NotReady, Async::Ready))
+    }
+}
+impl<T> fmt::Debug for Subscriber<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Subscriber {{ policy: {:?}, .. }}", self.slot.policy)
+    }
+}
+
+struct BusInner {
+    topics: Mutex<HashMap<TypeId, Box<dyn AnyTopic>>>,
+}
+impl BusInner {
+    fn lock_topics(&self) -> MutexGuard<'_, HashMap<TypeId, Box<dyn AnyTopic>>> {
+        self.topics.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+impl Drop for BusInner {
+    fn drop(&mut self) {
+        for topic in self.lock_topics().values_mut() {
+            topic.close();
+        }
+    }
+}
+
+trait AnyTopic: Send {
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+    fn close(&mut self);
+}
+
+struct Topic<T> {
+    slots: Vec<Arc<Slot<T>>>,
+}
+impl<T: Send + 'static> AnyTopic for Topic<T> {
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+    fn close(&mut self) {
+        for slot in &self.slots {
+            slot.lock().closed = true;
+            slot.notifier.notify();
+        }
+    }
+}
+
+struct Slot<T> {
+    state: Mutex<SlotState<T>>,
+    policy: BufferPolicy,
+    notifier: Notifier,
+}
+impl<T> Slot<T> {
+    fn lock(&self) -> MutexGuard<'_, SlotState<T>> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn push(&self, value: T) -> bool {
+        {
+            let mut state = self.lock();
+            match self.policy {
+                BufferPolicy::Unbounded => {}
+                BufferPolicy::DropOldest(capacity) => {
+                    if capacity == 0 {
+                        state.dropped += 1;
+                        return false;
+                    }
+                    while state.queue.len() >= capacity {
+                        state.queue.pop_front();
+                        state.dropped += 1;
+                    }
+                }
+                BufferPolicy::DropNewest(capacity) => {
+                    if state.queue.len() >= capacity {
+                        state.dropped += 1;
+                        return false;
+                    }
+                }
+            }
+            state.queue.push_back(value);
+        }
+        self.notifier.notify();
+        true
+    }
+}
+
+struct SlotState<T> {
+    queue: VecDeque<T>,
+    dropped: u64,
+    closed: bool,
+}
+
+#[cfg(test)]
+mod test {
+    use futures::{Async, Stream};
+
+    use super::*;
+
+    #[test]
+    fn buffer_policies_work() {
+        let bus = EventBus::new();
+        let mut oldest = bus.subscribe_with::<usize>(BufferPolicy::DropOldest(2));
+        let mut newest = bus.subscribe_with::<usize>(BufferPolicy::DropNewest(2));
+        assert_eq!(bus.subscribers::<usize>(), 2);
+
+        assert_eq!(bus.publish(0usize), 2);
+        assert_eq!(bus.publish(1usize), 2);
+        assert_eq!(bus.publish(2usize), 1);
+        assert_eq!(oldest.dropped(), 1);
+        assert_eq!(newest.dropped(), 1);
+
+        assert_eq!(oldest.poll(), Ok(Async::Ready(Some(1))));
+        assert_eq!(oldest.poll(), Ok(Async::Ready(Some(2))));
+        assert_eq!(newest.poll(), Ok(Async::Ready(Some(0))));
+        assert_eq!(newest.poll(), Ok(Async::Ready(Some(1))));
+        assert_eq!(newest.poll(), Ok(Async::NotReady));
+
+        std::mem::drop(oldest);
+        assert_eq!(bus.subscribers::<usize>(), 1);
+
+        std::mem::drop(bus);
+        assert_eq!(newest.poll(), Ok(Async::Ready(None)));
+    }
+}
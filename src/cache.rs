@@ -0,0 +1,310 @@
+// Copyright (c) 2016 DWANGO Co., Ltd. All Rights Reserved.
+// See the LICENSE file at the top-level directory of this distribution.
+
+//! Caches backed by the timers of the runtime.
+//!
+//! # Examples
+//!
+//! ```
+//! # extern crate fibers;
+//! # extern crate futures;
+//! use std::time::Duration;
+//! use fibers::{Executor, InPlaceExecutor, Spawn};
+//! use fibers::cache::TtlCache;
+//! use futures::Stream;
+//!
+//! let mut executor = InPlaceExecutor::new().unwrap();
+//! let cache = TtlCache::new(Duration::from_millis(10));
+//! let evictions = executor.spawn_monitor(cache.evictions().take(1).collect());
+//!
+//! cache.insert("foo", 1);
+//! assert_eq!(cache.get(&"foo"), Some(1));
+//!
+//! // The entry is evicted after the TTL.
+//! let evicted = executor.run_fiber(evictions).unwrap().unwrap();
+//! assert_eq!(evicted, vec![("foo", 1)]);
+//! assert_eq!(cache.get(&"foo"), None);
+//! ```
+use futures::{Async, Poll, Stream};
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::collections::HeapMap;
+use crate::sync::mpsc;
+use crate::time::timer::ExpiryTimer;
+
+/// A cache whose entries expire after their time-to-live (TTL).
+///
+/// The cache is cheaply cloneable and can be shared by many fibers (and threads).
+///
+/// Expired entries are never returned.
+/// They are removed either lazily (when the cache is accessed) or actively
+/// by the timer of an eviction stream (see `TtlCache::evictions`).
+pub struct TtlCache<K, V> {
+    inner: Arc<Mutex<CacheInner<K, V>>>,
+}
+impl<K, V> TtlCache<K, V>
+where
+    K: Hash + Eq + Clone,
+{
+    /// Makes a new `TtlCache` instance whose entries expire after `ttl` by default.
+    pub fn new(ttl: Duration) -> Self {
+        let inner = CacheInner {
+            ttl,
+            entries: HashMap::new(),
+            expiry_queue: HeapMap::new(),
+            next_seqno: 0,
+            event_tx: None,
+        };
+        TtlCache {
+            inner: Arc::new(Mutex::new(inner)),
+        }
+    }
+
+    /// Returns the default TTL of the entries.
+    pub fn ttl(&self) -> Duration {
+        self.lock().ttl
+    }
+
+    /// Inserts an entry which expires after the default TTL.
+    ///
+    /// If the cache already has an entry for `key`, its value and TTL are replaced and
+    /// the old value is returned.
+    pub fn insert(&self, key: K, value: V) -> Option<V> {
+        let mut inner = self.lock();
+        let ttl = inner.ttl;
+        inner.insert(key, value, ttl, Instant::now())
+    }
+
+    /// Inserts an entry which expires after `ttl`.
+    ///
+    /// If the expiry time is not representable (e.g., `ttl` is `Duration::MAX`),
+    /// the entry never expires.
+    pub fn insert_with_ttl(&self, key: K, value: V, ttl: Duration) -> Option<V> {
+        self.lock().insert(key, value, ttl, Instant::now())
+    }
+
+    /// Returns a clone of the value associated with `key`.
+    pub fn get(&self, key: &K) -> Option<V>
+    where
+        V: Clone,
+    {
+        let mut inner = self.lock();
+        inner.evict_expired_entries(Instant::now());
+        inner.entries.get(key).map(|e| e.value.clone())
+    }
+
+    /// Removes the entry associated with `key` and returns its value.
+    ///
+    /// The removal is not reported as an eviction.
+    pub fn remove(&self, key: &K) -> Option<V> {
+        let mut inner = self.lock();
+        inner.evict_expired_entries(Instant::now());
+        let entry = inner.entries.remove(key)?;
+        if let Some(expiry) = entry.expiry {
+            inner.expiry_queue.remove(&expiry);
+        }
+        Some(entry.value)
+    }
+
+    /// Returns the number of the live entries in this cache.
+    pub fn len(&self) -> usize {
+        let mut inner = self.lock();
+        inner.evict_expired_entries(Instant::now());
+        inner.entries.len()
+    }
+
+    /// Returns `true` if this cache has no live entries, otherwise `false`.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns a stream of the entries evicted due to expiration.
+    ///
+    /// While the stream is polled inside a fiber, it evicts the entries as soon as they expire.
+    /// The entries evicted lazily by other operations are also reported.
+    ///
+    /// If this method is called multiple times, only the latest stream receives evictions.
+    pub fn evictions(&self) -> Evictions<K, V> {
+        let (tx, rx) = mpsc::channel();
+        self.lock().event_tx = Some(tx);
+        Evictions {
+            cache: self.clone(),
+            event_rx: rx,
+            timer: ExpiryTimer::default(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, CacheInner<K, V>> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+impl<K, V> Clone for TtlCache<K, V> {
+    fn clone(&self) -> Self {
+        TtlCache {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+impl<K, V> fmt::Debug for TtlCache<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TtlCache {{ .. }}")
+    }
+}
+
+/// A stream of the entries evicted from a `TtlCache` due to expiration.
+///
+/// This is created by calling `TtlCache::evictions` method.
+///
+/// The stream never ends and never fails.
+pub struct Evictions<K, V> {
+    cache: TtlCache<K, V>,
+    event_rx: mpsc::Receiver<Event<K, V>>,
+    timer: ExpiryTimer,
+}
+impl<K, V> Evictions<K, V>
+where
+    K: Hash + Eq + Clone,
+{
+    fn poll_timer(&mut self) {
+        let cache = &self.cache;
+        self.timer.poll(|now| {
+            let mut inner = cache.lock();
+            inner.evict_expired_entries(now);
+            inner.expiry_queue.peek().map(|(k, _)| k.0)
+        });
+    }
+}
+impl<K, V> Stream for Evictions<K, V>
+where
+    K: Hash + Eq + Clone,
+{
+    type Item = (K, V);
+    type Error = ();
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            while let Async::Ready(Some(event)) = self.event_rx.poll()? {
+                if let Event::Evicted(key, value) = event {
+                    return Ok(Async::Ready(Some((key, value))));
+                }
+            }
+            self.poll_timer();
+            match self.event_rx.poll()? {
+                Async::Ready(Some(Event::Evicted(key, value))) => {
+                    return Ok(Async::Ready(Some((key, value))));
+                }
+                Async::Ready(Some(Event::Rearm)) => {}
+                Async::Ready(None) | Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+    }
+}
+impl<K, V> fmt::Debug for Evictions<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Evictions {{ .. }}")
+    }
+}
+
+enum Event<K, V> {
+    Evicted(K, V),
+
+    /// An entry which expires earlier than the others has been inserted.
+    Rearm,
+}
+
+struct CacheInner<K, V> {
+    ttl: Duration,
+    entries: HashMap<K, Entry<V>>,
+    expiry_queue: HeapMap<(Instant, u64), K>,
+    next_seqno: u64,
+    event_tx: Option<mpsc::Sender<Event<K, V>>>,
+}
+impl<K, V> CacheInner<K, V>
+where
+    K: Hash + Eq + Clone,
+{
+    fn insert(&mut self, key: K, value: V, ttl: Duration, now: Instant) -> Option<V> {
+        self.evict_expired_entries(now);
+
+        let expiry = now
+            .checked_add(ttl)
+            .map(|expiry_time| (expiry_time, self.next_seqno));
+        self.next_seqno += 1;
+        if let Some(expiry) = expiry {
+            let is_earliest = self.expiry_queue.peek().is_none_or(|(k, _)| expiry < *k);
+            self.expiry_queue.push_if_absent(expiry, key.clone());
+            if is_earliest {
+                if let Some(ref tx) = self.event_tx {
+                    let _ = tx.send(Event::Rearm);
+                }
+            }
+        }
+
+        let old = self.entries.insert(key, Entry { value, expiry })?;
+        if let Some(old_expiry) = old.expiry {
+            self.expiry_queue.remove(&old_expiry);
+        }
+        Some(old.value)
+    }
+
+    fn evict_expired_entries(&mut self, now: Instant) {
+        while let Some((_, key)) = self.expiry_queue.pop_if(|k, _| k.0 <= now) {
+            let entry = self.entries.remove(&key).expect("Never fails");
+            if let Some(ref tx) = self.event_tx {
+                let _ = tx.send(Event::Evicted(key, entry.value));
+            }
+        }
+    }
+}
+
+struct Entry<V> {
+    value: V,
+
+    /// `None` means that the entry never expires.
+    expiry: Option<(Instant, u64)>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn entries_expire() {
+        let cache = TtlCache::new(Duration::from_secs(10));
+        let mut inner = cache.lock();
+        let now = Instant::now();
+
+        assert_eq!(inner.insert(0, "a", Duration::from_secs(1), now), None);
+        assert_eq!(inner.insert(1, "b", Duration::from_secs(2), now), None);
+        assert_eq!(inner.insert(0, "c", Duration::from_secs(3), now), Some("a"));
+        assert_eq!(inner.expiry_queue.len(), 2);
+
+        inner.evict_expired_entries(now + Duration::from_secs(2));
+        assert_eq!(inner.entries.len(), 1);
+        assert!(inner.entries.contains_key(&0));
+        inner.evict_expired_entries(now + Duration::from_secs(3));
+        assert_eq!(inner.entries.len(), 0);
+        assert_eq!(inner.expiry_queue.len(), 0);
+    }
+
+    #[test]
+    fn huge_ttl_means_no_expiration() {
+        let cache = TtlCache::new(Duration::MAX);
+        assert_eq!(cache.insert(0, "a"), None);
+        assert_eq!(cache.insert_with_ttl(1, "b", Duration::MAX), None);
+        assert_eq!(
+            cache.insert_with_ttl(0, "c", Duration::from_secs(1)),
+            Some("a")
+        );
+        assert_eq!(cache.len(), 2);
+
+        let mut inner = cache.lock();
+        assert_eq!(inner.expiry_queue.len(), 1);
+        inner.evict_expired_entries(Instant::now() + Duration::from_secs(2));
+        assert_eq!(inner.entries.len(), 1);
+        assert!(inner.entries.contains_key(&1));
+    }
+}
@@ -42,7 +42,7 @@ where
     where
         F: FnOnce(&K, &V) -> bool,
     {
-        if self.inner.smallest().map_or(false, |(k, v)| f(k, v)) {
+        if self.inner.smallest().is_some_and(|(k, v)| f(k, v)) {
             self.inner.take_smallest()
         } else {
             None
@@ -56,6 +56,21 @@ where
         self.inner.smallest()
     }
 
+    /// Returns the number of the smallest entries which satisfy the predicate `f`,
+    /// counting at most `limit` entries.
+    ///
+    /// The entries are checked in ascending order of their keys until `f` returns `false`.
+    pub fn count_while<F>(&self, limit: usize, mut f: F) -> usize
+    where
+        F: FnMut(&K) -> bool,
+    {
+        self.inner
+            .iter()
+            .take(limit)
+            .take_while(|&(k, _)| f(k))
+            .count()
+    }
+
     /// Removes the entry which has `key` from the heap.
     ///
     /// If such entry exists, this will return `true`, otherwise `false`.
@@ -0,0 +1,364 @@
+// Copyright (c) 2016 DWANGO Co., Ltd. All Rights Reserved.
+// See the LICENSE file at the top-level directory of this distribution.
+
+//! Helpers for CPU-bound computations in fibers.
+//!
+//! A fiber which computes for a long time without suspending
+//! delays the other fibers on the same scheduler (e.g., fibers handling I/O).
+//! The helpers in this module interleave such computations with automatic yields.
+//!
+//! # Examples
+//!
+//! ```
+//! # extern crate fibers;
+//! # extern crate futures;
+//! use fibers::{cpu, Executor, InPlaceExecutor, Spawn};
+//! use futures::Stream;
+//!
+//! let mut executor = InPlaceExecutor::new().unwrap();
+//!
+//! // Yields the fiber after every 100 items.
+//! let squares = cpu::run_chunked::<_, ()>((0..1000u64).map(|i| i * i), 100);
+//! let monitor = executor.spawn_monitor(squares.fold(0, |sum, i| Ok::<_, ()>(sum + i)));
+//! assert_eq!(executor.run_fiber(monitor).unwrap(), Ok(332_833_500));
+//! ```
+use futures::{Async, Future, Poll, Stream};
+use std::error;
+use std::fmt;
+use std::io;
+use std::marker::PhantomData;
+use std::time::{Duration, Instant};
+
+use crate::blocking;
+use crate::fiber;
+use crate::sync::oneshot::Monitor;
+
+/// Makes a stream which yields the items of `iter`,
+/// yielding the current fiber after every `chunk_size` items.
+///
+/// The stream never fails unless the computation is moved to the blocking pool
+/// (see `RunChunked::offload_after`).
+///
+/// # Panics
+///
+/// If `chunk_size` is `0`, this function will panic.
+///
+/// The stream must be polled inside a fiber, otherwise it may never be woken up.
+pub fn run_chunked<I, E>(iter: I, chunk_size: usize) -> RunChunked<I::IntoIter, E>
+where
+    I: IntoIterator,
+{
+    assert!(chunk_size > 0);
+    RunChunked {
+        state: State::Inline(iter.into_iter()),
+        yield_every: yield_every(1),
+        chunk: Vec::new().into_iter(),
+        chunk_size,
+        elapsed: Duration::from_secs(0),
+        offload: None,
+        _error: PhantomData,
+    }
+}
+
+/// Makes a `YieldEvery` instance which yields the current fiber after every `n` ticks.
+///
+/// # Panics
+///
+/// If `n` is `0`, this function will panic.
+pub fn yield_every(n: usize) -> YieldEvery {
+    assert!(n > 0);
+    YieldEvery { n, count: 0 }
+}
+
+/// A counter which yields the current fiber periodically.
+///
+/// This is created by calling `yield_every` function and
+/// is intended to be used in hand-written `Future::poll` (or `Stream::poll`) methods.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate fibers;
+/// # extern crate futures;
+/// use fibers::{cpu, Executor, InPlaceExecutor, Spawn};
+/// use fibers::cpu::YieldEvery;
+/// use futures::{Async, Future, Poll};
+///
+/// struct Count {
+///     n: usize,
+///     yielder: YieldEvery,
+/// }
+/// impl Future for Count {
+///     type Item = usize;
+///     type Error = ();
+///     fn poll(&mut self) -> Poll<usize, ()> {
+///         while self.n < 1000 {
+///             futures::try_ready!(self.yielder.tick());
+///             self.n += 1;
+///         }
+///         Ok(Async::Ready(self.n))
+///     }
+/// }
+///
+/// let mut executor = InPlaceExecutor::new().unwrap();
+/// let monitor = executor.spawn_monitor(Count { n: 0, yielder: cpu::yield_every(10) });
+/// assert_eq!(executor.run_fiber(monitor).unwrap(), Ok(1000));
+/// ```
+#[derive(Debug, Clone)]
+pub struct YieldEvery {
+    n: usize,
+    count: usize,
+}
+impl YieldEvery {
+    /// Counts a unit of work.
+    ///
+    /// Once `n` units have been counted, the next call yields the current fiber
+    /// and returns `Ok(Async::NotReady)` instead of counting.
+    /// In that case, the caller should return `Ok(Async::NotReady)` immediately,
+    /// and the fiber will be rescheduled (and the unit will be counted by the retried call).
+    /// Otherwise, this returns `Ok(Async::Ready(()))`.
+    pub fn tick<E>(&mut self) -> Poll<(), E> {
+        if self.count >= self.n {
+            self.count = 0;
+            fiber::yield_poll()
+        } else {
+            self.count += 1;
+            Ok(Async::Ready(()))
+        }
+    }
+}
+
+/// A stream which yields the items of an iterator, yielding the current fiber periodically.
+///
+/// This is created by calling `run_chunked` function.
+pub struct RunChunked<I: Iterator, E> {
+    state: State<I>,
+    yield_every: YieldEvery,
+    chunk: std::vec::IntoIter<I::Item>,
+    chunk_size: usize,
+    elapsed: Duration,
+    offload: Option<OffloadPolicy<I, E>>,
+    _error: PhantomData<E>,
+}
+impl<I: Iterator, E> RunChunked<I, E> {
+    /// Moves the rest of the computation to the blocking pool (see the [blocking module])
+    /// if the time spent in iterating exceeds `threshold`.
+    ///
+    /// After the computation is moved,
+    /// the items are computed on the blocking pool in chunks of `chunk_size` items.
+    /// The next chunk is computed only after the previous one is received by the fiber,
+    /// so at most one chunk is computed ahead of the consumer
+    /// (i.e., an infinite iterator or a slow consumer does not exhaust memory).
+    ///
+    /// If the iterator panics on the blocking pool,
+    /// the stream fails with `E::from(IteratorPanicked)`.
+    ///
+    /// [blocking module]: ../blocking/index.html
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate fibers;
+    /// # extern crate futures;
+    /// use std::time::Duration;
+    /// use fibers::{cpu, Executor, InPlaceExecutor, Spawn};
+    /// use futures::Stream;
+    ///
+    /// let mut executor = InPlaceExecutor::new().unwrap();
+    /// let items = cpu::run_chunked::<_, cpu::IteratorPanicked>(0..1000, 10)
+    ///     .offload_after(Duration::from_secs(0));
+    /// let monitor = executor.spawn_monitor(items.collect());
+    /// assert_eq!(executor.run_fiber(monitor).unwrap(), Ok((0..1000).collect()));
+    /// ```
+    pub fn offload_after(mut self, threshold: Duration) -> Self
+    where
+        I: Send + 'static,
+        I::Item: Send + 'static,
+        E: From<IteratorPanicked>,
+    {
+        self.offload = Some((threshold, offload::<I>, E::from));
+        self
+    }
+}
+impl<I: Iterator, E> Stream for RunChunked<I, E> {
+    type Item = I::Item;
+    type Error = E;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            if let Some(item) = self.chunk.next() {
+                return Ok(Async::Ready(Some(item)));
+            }
+            match self.state {
+                State::Inline(ref mut iter) => {
+                    if let Some((threshold, offload, _)) = self.offload {
+                        if self.elapsed >= threshold {
+                            let iter = match std::mem::replace(&mut self.state, State::Done) {
+                                State::Inline(iter) => iter,
+                                _ => unreachable!(),
+                            };
+                            self.state = State::Offloaded(offload(iter, self.chunk_size));
+                            continue;
+                        }
+                    }
+                    futures::try_ready!(self.yield_every.tick());
+                    let start = Instant::now();
+                    let chunk = iter.take(self.chunk_size).collect::<Vec<_>>();
+                    self.elapsed += start.elapsed();
+                    if chunk.is_empty() {
+                        self.state = State::Done;
+                    }
+                    self.chunk = chunk.into_iter();
+                }
+                State::Offloaded(ref mut monitor) => {
+                    let (_, offload, from_panic) = self.offload.expect("Never fails");
+                    match monitor.poll() {
+                        Ok(Async::NotReady) => return Ok(Async::NotReady),
+                        Ok(Async::Ready((iter, chunk))) => {
+                            if chunk.is_empty() {
+                                self.state = State::Done;
+                            } else {
+                                self.state = State::Offloaded(offload(iter, self.chunk_size));
+                                self.chunk = chunk.into_iter();
+                            }
+                        }
+                        Err(_) => {
+                            self.state = State::Done;
+                            return Err(from_panic(IteratorPanicked));
+                        }
+                    }
+                }
+                State::Done => return Ok(Async::Ready(None)),
+            }
+        }
+    }
+}
+impl<I: Iterator, E> fmt::Debug for RunChunked<I, E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "RunChunked {{ chunk_size: {}, elapsed: {:?}, .. }}",
+            self.chunk_size, self.elapsed
+        )
+    }
+}
+
+/// The error which indicates that the iterator moved to the blocking pool has panicked.
+///
+/// See `RunChunked::offload_after`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IteratorPanicked;
+impl fmt::Display for IteratorPanicked {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "The offloaded iterator panicked")
+    }
+}
+impl error::Error for IteratorPanicked {}
+impl From<IteratorPanicked> for io::Error {
+    fn from(e: IteratorPanicked) -> Self {
+        io::Error::other(e)
+    }
+}
+
+type ChunkMonitor<I> = Monitor<(I, Vec<<I as Iterator>::Item>), ()>;
+type Offload<I> = fn(I, usize) -> ChunkMonitor<I>;
+
+/// The threshold, the spawner of the chunks and the converter of the panic error.
+type OffloadPolicy<I, E> = (Duration, Offload<I>, fn(IteratorPanicked) -> E);
+
+enum State<I: Iterator> {
+    Inline(I),
+    Offloaded(ChunkMonitor<I>),
+    Done,
+}
+
+/// Computes the next chunk of `iter` on the blocking pool.
+///
+/// The iterator is returned together with the chunk, so that the pool thread is released
+/// between chunks and no chunk is computed until the previous one is received.
+fn offload<I>(mut iter: I, chunk_size: usize) -> ChunkMonitor<I>
+where
+    I: Iterator + Send + 'static,
+    I::Item: Send + 'static,
+{
+    blocking::spawn(move || {
+        let chunk = iter.by_ref().take(chunk_size).collect::<Vec<_>>();
+        Ok((iter, chunk))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use futures::Stream;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::*;
+    use crate::executor::{Executor, InPlaceExecutor};
+    use crate::fiber::Spawn;
+    use crate::sync::oneshot::MonitorError;
+
+    #[test]
+    fn yield_every_works() {
+        let mut yielder = yield_every(3);
+        let mut units = 0;
+        let mut yields = 0;
+        while units < 10 {
+            match yielder.tick::<()>().unwrap() {
+                Async::Ready(()) => units += 1,
+                Async::NotReady => yields += 1,
+            }
+        }
+        assert_eq!(yields, 3);
+    }
+
+    #[test]
+    fn run_chunked_yields_after_every_chunk() {
+        let mut stream = run_chunked::<_, ()>(0..10, 2);
+        let mut items = Vec::new();
+        let mut yields = 0;
+        loop {
+            match stream.poll().unwrap() {
+                Async::Ready(Some(item)) => items.push(item),
+                Async::Ready(None) => break,
+                Async::NotReady => yields += 1,
+            }
+        }
+        assert_eq!(items, (0..10).collect::<Vec<_>>());
+        assert_eq!(yields, 5);
+    }
+
+    #[test]
+    fn offloaded_iterator_panic_is_reported() {
+        let mut executor = InPlaceExecutor::new().unwrap();
+        let iter = (0..100).map(|i| if i == 50 { panic!("oops") } else { i });
+        let items =
+            run_chunked::<_, IteratorPanicked>(iter, 10).offload_after(Duration::from_secs(0));
+        let monitor = executor.spawn_monitor(items.collect());
+        assert_eq!(
+            executor.run_fiber(monitor).unwrap(),
+            Err(MonitorError::Failed(IteratorPanicked))
+        );
+    }
+
+    #[test]
+    fn offloaded_iterator_is_not_run_ahead() {
+        let mut executor = InPlaceExecutor::new().unwrap();
+        let produced = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&produced);
+        let iter = (0..).inspect(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        let items =
+            run_chunked::<_, IteratorPanicked>(iter, 10).offload_after(Duration::from_secs(0));
+        let monitor = executor.spawn_monitor(items.take(15).collect());
+        assert_eq!(
+            executor.run_fiber(monitor).unwrap(),
+            Ok((0..15).collect::<Vec<_>>())
+        );
+
+        // At most one chunk is computed ahead of the consumed ones.
+        thread::sleep(Duration::from_millis(50));
+        assert!(produced.load(Ordering::SeqCst) <= 30);
+    }
+}
@@ -0,0 +1,151 @@
+// Copyright (c) 2016 DWANGO Co., Ltd. All Rights Reserved.
+// See the LICENSE file at the top-level directory of this distribution.
+
+use std::sync::atomic::{self, AtomicUsize};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The length of a duty cycle of the throttled threads.
+const PERIOD: Duration = Duration::from_millis(100);
+
+/// The CPU quota of the scheduler threads of an executor (in millicores).
+///
+/// `0` means that there is no quota.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct CpuQuota(Arc<AtomicUsize>);
+impl CpuQuota {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self) -> Option<f64> {
+        match self.0.load(atomic::Ordering::SeqCst) {
+            0 => None,
+            millicores => Some(millicores as f64 / 1000.0),
+        }
+    }
+
+    pub fn set(&self, cores: Option<f64>) {
+        let millicores = cores.map_or(0, |c| {
+            assert!(c > 0.0, "CPU quota must be positive");
+            ((c * 1000.0) as usize).max(1)
+        });
+        self.0.store(millicores, atomic::Ordering::SeqCst);
+    }
+}
+
+/// The duty cycling state of a scheduler thread.
+///
+/// Each thread is permitted to use `quota / thread_count` of the CPU time in each period.
+/// If a thread exhausts the budget, it sleeps until the end of the period.
+#[derive(Debug)]
+pub(crate) struct Throttle {
+    quota: CpuQuota,
+    thread_count: usize,
+    period_start: Instant,
+    cpu_time_at_start: Duration,
+}
+impl Throttle {
+    pub fn new(quota: CpuQuota, thread_count: usize) -> Self {
+        Throttle {
+            quota,
+            thread_count,
+            period_start: Instant::now(),
+            cpu_time_at_start: thread_cpu_time(),
+        }
+    }
+
+    /// Sleeps the current thread if it has exhausted the budget of the current period.
+    pub fn throttle(&mut self) {
+        let cores = if let Some(cores) = self.quota.get() {
+            cores
+        } else {
+            return;
+        };
+        let now = Instant::now();
+        let period_end = self.period_start + PERIOD;
+        if now < period_end {
+            let budget = budget(cores, self.thread_count);
+            let used = thread_cpu_time().saturating_sub(self.cpu_time_at_start);
+            if used < budget {
+                return;
+            }
+            thread::sleep(period_end - now);
+        }
+        self.period_start = Instant::now();
+        self.cpu_time_at_start = thread_cpu_time();
+    }
+}
+
+/// Returns the CPU time which each of `thread_count` threads can use in a period.
+fn budget(cores: f64, thread_count: usize) -> Duration {
+    PERIOD.mul_f64(cores / thread_count as f64)
+}
+
+#[cfg(unix)]
+fn thread_cpu_time() -> Duration {
+    let mut t = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut t);
+    }
+    Duration::new(t.tv_sec as u64, t.tv_nsec as u32)
+}
+
+#[cfg(not(unix))]
+fn thread_cpu_time() -> Duration {
+    // The CPU time of threads is unavailable, so the quota has no effect.
+    Duration::from_secs(0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn quota_is_stored_in_millicores() {
+        let quota = CpuQuota::new();
+        assert_eq!(quota.get(), None);
+
+        quota.set(Some(1.5));
+        assert_eq!(quota.get(), Some(1.5));
+
+        // Too small quotas are rounded up to one millicore.
+        quota.set(Some(0.0001));
+        assert_eq!(quota.get(), Some(0.001));
+
+        quota.set(None);
+        assert_eq!(quota.get(), None);
+    }
+
+    #[test]
+    fn budget_is_divided_among_threads() {
+        assert_eq!(budget(1.0, 4), Duration::from_millis(25));
+        assert_eq!(budget(0.5, 1), Duration::from_millis(50));
+
+        // A budget longer than the period never throttles the threads.
+        assert_eq!(budget(4.0, 2), Duration::from_millis(200));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn throttle_sleeps_until_end_of_period_if_budget_is_exhausted() {
+        let quota = CpuQuota::new();
+        let mut throttle = Throttle::new(quota.clone(), 1);
+        let period_start = throttle.period_start;
+
+        // Without a quota, the thread never sleeps.
+        throttle.throttle();
+        assert_eq!(throttle.period_start, period_start);
+
+        quota.set(Some(0.001));
+        let start = thread_cpu_time();
+        while thread_cpu_time() - start < budget(0.001, 1) {}
+        throttle.throttle();
+        assert!(Instant::now() >= period_start + PERIOD);
+        assert!(throttle.period_start >= period_start + PERIOD);
+    }
+}
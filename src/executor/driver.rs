@@ -0,0 +1,136 @@
+// Copyright (c) 2016 DWANGO Co., Ltd. All Rights Reserved.
+// See the LICENSE file at the top-level directory of this distribution.
+
+use std::fmt;
+
+/// A driver of an external resource (e.g., a completion queue of a device or
+/// a connection pool of a database client).
+///
+/// A driver registered to an executor is called once per executor turn
+/// (i.e., `Executor::run_once`) on the thread running the executor,
+/// so external resource libraries can integrate with fibers without owning a thread.
+/// Typically, a driver completes the operations of the resource and
+/// notifies the waiting fibers (e.g., via `fibers::sync::oneshot` channels).
+///
+/// Any `FnMut(usize) -> usize` closure is a driver.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate fibers;
+/// # extern crate futures;
+/// use std::collections::VecDeque;
+/// use std::sync::{Arc, Mutex};
+/// use fibers::{Executor, InPlaceExecutor, Spawn};
+/// use fibers::sync::oneshot;
+///
+/// // A queue of the pending requests of a (pseudo) device.
+/// let queue = Arc::new(Mutex::new(VecDeque::<(u32, oneshot::Sender<u32>)>::new()));
+///
+/// let mut executor = InPlaceExecutor::new().unwrap();
+/// let device = Arc::clone(&queue);
+/// executor.add_driver(16, move |budget: usize| {
+///     let mut queue = device.lock().unwrap();
+///     let mut completed = 0;
+///     while completed < budget {
+///         match queue.pop_front() {
+///             None => break,
+///             Some((request, reply)) => {
+///                 let _ = reply.send(request * 2);
+///                 completed += 1;
+///             }
+///         }
+///     }
+///     completed
+/// });
+///
+/// let (tx, rx) = oneshot::channel();
+/// queue.lock().unwrap().push_back((21, tx));
+/// let monitor = executor.spawn_monitor(rx);
+/// assert_eq!(executor.run_fiber(monitor).unwrap().ok(), Some(42));
+/// ```
+pub trait Driver: Send + 'static {
+    /// Drives the resource, processing at most `budget` units of work.
+    ///
+    /// Returns the number of the units actually processed.
+    /// If this returns a positive number, the executor assumes more work may be pending
+    /// and does not block in the subsequent I/O polling.
+    fn drive(&mut self, budget: usize) -> usize;
+}
+impl<F> Driver for F
+where
+    F: FnMut(usize) -> usize + Send + 'static,
+{
+    fn drive(&mut self, budget: usize) -> usize {
+        self(budget)
+    }
+}
+
+/// The drivers registered to an executor.
+#[derive(Default)]
+pub(super) struct Drivers {
+    drivers: Vec<(usize, Box<dyn Driver>)>,
+}
+impl Drivers {
+    pub fn add<D: Driver>(&mut self, budget: usize, driver: D) {
+        assert!(budget > 0);
+        self.drivers.push((budget, Box::new(driver)));
+    }
+
+    /// Calls all of the drivers once, and returns `true` if any of them did some work.
+    pub fn drive(&mut self) -> bool {
+        let mut did_something = false;
+        for &mut (budget, ref mut driver) in &mut self.drivers {
+            if driver.drive(budget) > 0 {
+                did_something = true;
+            }
+        }
+        did_something
+    }
+}
+impl fmt::Debug for Drivers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Drivers {{ len: {} }}", self.drivers.len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    #[test]
+    fn drive_works() {
+        let budgets = Arc::new(Mutex::new(Vec::new()));
+        let mut drivers = Drivers::default();
+        assert!(!drivers.drive());
+
+        for &(budget, done) in &[(1, 0), (8, 3), (4, 0)] {
+            let budgets = Arc::clone(&budgets);
+            drivers.add(budget, move |b| {
+                budgets.lock().unwrap().push(b);
+                done
+            });
+        }
+
+        // All the drivers are called with their own budgets.
+        assert!(drivers.drive());
+        assert_eq!(*budgets.lock().unwrap(), vec![1, 8, 4]);
+        assert_eq!(format!("{:?}", drivers), "Drivers { len: 3 }");
+    }
+
+    #[test]
+    fn idle_drivers_do_nothing() {
+        let mut drivers = Drivers::default();
+        drivers.add(1, |_| 0);
+        drivers.add(2, |_| 0);
+        assert!(!drivers.drive());
+    }
+
+    #[test]
+    #[should_panic]
+    fn zero_budget_is_rejected() {
+        Drivers::default().add(0, |_| 0);
+    }
+}
@@ -3,11 +3,16 @@
 
 use futures::Future;
 use std::io;
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::time;
 
-use super::Executor;
+use super::driver::Drivers;
+use super::poll_timeout::PollTimeoutState;
+use super::{Driver, Executor, PollTimeout};
 use crate::fiber::{self, Spawn};
 use crate::io::poll;
+use crate::trace::TraceRing;
 
 /// An executor that executes spawned fibers and I/O event polling on current thread.
 ///
@@ -46,6 +51,8 @@ use crate::io::poll;
 pub struct InPlaceExecutor {
     scheduler: fiber::Scheduler,
     poller: poll::Poller,
+    poll_timeout: PollTimeoutState,
+    drivers: Drivers,
 }
 impl InPlaceExecutor {
     /// Creates a new instance of `InPlaceExecutor`.
@@ -54,8 +61,254 @@ impl InPlaceExecutor {
         Ok(InPlaceExecutor {
             scheduler: fiber::Scheduler::new(poller.handle()),
             poller,
+            poll_timeout: PollTimeoutState::new(PollTimeout::default()),
+            drivers: Drivers::default(),
         })
     }
+
+    /// Sets the policy to determine the timeout of the I/O polling in `Executor::run_once`.
+    ///
+    /// The default value is `PollTimeout::default()`.
+    pub fn set_poll_timeout(&mut self, policy: PollTimeout) {
+        self.poll_timeout = PollTimeoutState::new(policy);
+    }
+
+    /// Returns the policy to determine the timeout of the I/O polling.
+    pub fn poll_timeout(&self) -> PollTimeout {
+        self.poll_timeout.policy()
+    }
+
+    /// Registers a driver of an external resource which is called once per `run_once`
+    /// with `budget`.
+    ///
+    /// See the documentation of `Driver` for more details.
+    ///
+    /// # Panics
+    ///
+    /// If `budget` is `0`, this method will panic.
+    pub fn add_driver<D: Driver>(&mut self, budget: usize, driver: D) {
+        self.drivers.add(budget, driver);
+    }
+
+    /// Returns a snapshot of the state of the scheduler of this executor.
+    pub fn scheduler_snapshot(&self) -> fiber::SchedulerSnapshot {
+        self.scheduler.snapshot()
+    }
+
+    /// Sets the threshold of the spawn-to-first-poll latency of the fibers in this executor.
+    ///
+    /// See `Scheduler::set_first_poll_threshold` for more details.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate fibers;
+    /// # extern crate futures;
+    /// use std::sync::mpsc;
+    /// use std::thread;
+    /// use std::time::Duration;
+    /// use fibers::{Executor, InPlaceExecutor, Spawn};
+    ///
+    /// let mut executor = InPlaceExecutor::new().unwrap();
+    /// executor.set_first_poll_threshold(Some(Duration::from_millis(10)));
+    /// let (tx, rx) = mpsc::channel();
+    /// executor.on_slow_first_poll(move |fiber, latency| {
+    ///     tx.send((fiber.name.clone(), latency)).unwrap();
+    /// });
+    ///
+    /// let monitor = executor.build_fiber().name("late").spawn_monitor(futures::finished::<(), ()>(()));
+    /// thread::sleep(Duration::from_millis(20));
+    /// executor.run_fiber(monitor).unwrap().unwrap();
+    ///
+    /// let (name, latency) = rx.recv().unwrap();
+    /// assert_eq!(name.as_ref().map(|s| s.as_str()), Some("late"));
+    /// assert!(latency >= Duration::from_millis(20));
+    ///
+    /// let stats = executor.scheduler_snapshot().first_poll;
+    /// assert_eq!(stats.fibers, 1);
+    /// assert_eq!(stats.slow_fibers, 1);
+    /// ```
+    pub fn set_first_poll_threshold(&mut self, threshold: Option<time::Duration>) {
+        self.scheduler.set_first_poll_threshold(threshold);
+    }
+
+    /// Sets the callback invoked when the spawn-to-first-poll latency of a fiber exceeds
+    /// the threshold.
+    ///
+    /// See `Scheduler::on_slow_first_poll` for more details.
+    pub fn on_slow_first_poll<F>(&mut self, f: F)
+    where
+        F: Fn(&fiber::FiberSnapshot, time::Duration) + Send + Sync + 'static,
+    {
+        self.scheduler.on_slow_first_poll(f);
+    }
+
+    /// Enables the tracing of the scheduling events with a ring buffer of `capacity` events.
+    ///
+    /// See the [trace module](../trace/index.html) for more details.
+    pub fn enable_tracing(&mut self, capacity: usize) -> TraceRing {
+        let ring = TraceRing::new(capacity);
+        self.scheduler.set_trace_ring(Some(ring.clone()));
+        self.poller.set_trace_ring(Some(ring.clone()));
+        ring
+    }
+
+    /// Returns the statistics of the I/O poller of this executor.
+    pub fn poller_stats(&self) -> poll::PollerStats {
+        self.poller.stats()
+    }
+
+    /// Sets the maximum number of the timers fired in a polling of the poller of this executor.
+    ///
+    /// See `Poller::set_timer_budget` for more details.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate fibers;
+    /// # extern crate futures;
+    /// use std::sync::Arc;
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    /// use std::time::Duration;
+    /// use fibers::{Executor, InPlaceExecutor, Spawn};
+    /// use fibers::sync::oneshot::Monitor;
+    /// use fibers::time::timer;
+    ///
+    /// let mut executor = InPlaceExecutor::new().unwrap();
+    /// executor.set_timer_budget(Some(1));
+    /// let warnings = Arc::new(AtomicUsize::new(0));
+    /// let counter = Arc::clone(&warnings);
+    /// executor.on_timer_saturation(move |_backlog| {
+    ///     counter.fetch_add(1, Ordering::SeqCst);
+    /// });
+    ///
+    /// let monitors = (0..3)
+    ///     .map(|_| executor.spawn_monitor(timer::timeout(Duration::from_millis(50))))
+    ///     .collect::<Vec<_>>();
+    /// executor.run_future(Monitor::join_all(monitors)).unwrap().unwrap();
+    ///
+    /// // The three timers expired at once, but only one timer was fired in each polling.
+    /// assert_eq!(executor.poller_stats().timer_saturations, 1);
+    /// assert_eq!(executor.poller_stats().timer_backlog, 0);
+    /// assert_eq!(warnings.load(Ordering::SeqCst), 1);
+    /// ```
+    pub fn set_timer_budget(&mut self, budget: Option<usize>) {
+        self.poller.set_timer_budget(budget);
+    }
+
+    /// Sets the callback invoked when the poller of this executor becomes saturated with timers.
+    ///
+    /// See `Poller::on_timer_saturation` for more details.
+    pub fn on_timer_saturation<F>(&mut self, f: F)
+    where
+        F: Fn(usize) + Send + Sync + 'static,
+    {
+        self.poller.on_timer_saturation(f);
+    }
+
+    /// Sets the artificial delay inserted before I/O readiness is delivered to the fibers.
+    ///
+    /// This is intended for testing or staging environments only.
+    /// See `Poller::set_readiness_delay` for more details.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate fibers;
+    /// # extern crate futures;
+    /// use std::time::{Duration, Instant};
+    /// use fibers::{Executor, InPlaceExecutor, Spawn};
+    /// use fibers::net::{TcpListener, TcpStream};
+    /// use futures::{Future, Stream};
+    ///
+    /// let mut executor = InPlaceExecutor::new().unwrap();
+    /// let bind = executor.spawn_monitor(TcpListener::bind("127.0.0.1:0".parse().unwrap()));
+    /// let listener = executor.run_fiber(bind).unwrap().unwrap();
+    /// let addr = listener.local_addr().unwrap();
+    ///
+    /// executor.set_readiness_delay(Some(Duration::from_millis(50)));
+    /// let start = Instant::now();
+    /// let server = executor.spawn_monitor(
+    ///     listener.incoming().into_future().map(|_| ()).map_err(|(e, _)| e),
+    /// );
+    /// executor.spawn(TcpStream::connect(addr).map(|_| ()).map_err(|e| panic!("{}", e)));
+    /// executor.run_fiber(server).unwrap().unwrap();
+    /// assert!(start.elapsed() >= Duration::from_millis(50));
+    /// ```
+    pub fn set_readiness_delay(&mut self, delay: Option<time::Duration>) {
+        self.poller.set_readiness_delay(delay);
+    }
+
+    /// Enables or disables the registry of the sockets registered in the poller of this executor.
+    ///
+    /// See `Poller::set_socket_registry` for more details.
+    pub fn set_socket_registry(&self, enabled: bool) {
+        self.poller.set_socket_registry(enabled);
+    }
+
+    /// Returns the snapshots of the live sockets recorded in the socket registry.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate fibers;
+    /// # extern crate futures;
+    /// use fibers::{Executor, InPlaceExecutor, Spawn};
+    /// use fibers::io::poll::SocketKind;
+    /// use fibers::net::TcpListener;
+    ///
+    /// let mut executor = InPlaceExecutor::new().unwrap();
+    /// executor.set_socket_registry(true);
+    ///
+    /// let bind = executor.spawn_monitor(TcpListener::bind("127.0.0.1:0".parse().unwrap()));
+    /// let listener = executor.run_fiber(bind).unwrap().unwrap();
+    ///
+    /// let sockets = executor.sockets();
+    /// assert_eq!(sockets.len(), 1);
+    /// assert_eq!(sockets[0].kind, SocketKind::TcpListener);
+    /// assert_eq!(sockets[0].local_addr, listener.local_addr().ok());
+    /// assert!(sockets[0].owner.is_some());
+    ///
+    /// drop(listener);
+    /// executor.run_once().unwrap();
+    /// assert!(executor.sockets().is_empty());
+    /// ```
+    pub fn sockets(&self) -> Vec<poll::SocketSnapshot> {
+        self.poller.sockets()
+    }
+
+    /// Runs one unit of works, waiting for I/O events at most `timeout`.
+    ///
+    /// Unlike `Executor::run_once` method, this ignores the poll timeout policy of the executor.
+    pub fn run_once_with_timeout(&mut self, timeout: Option<time::Duration>) -> io::Result<()> {
+        self.scheduler.run_once(false);
+        self.drivers.drive();
+        self.poller.poll(timeout)?;
+        Ok(())
+    }
+
+    /// Makes the descriptor of this executor (see `AsRawFd`) readable
+    /// also when fibers are woken up from other threads or timers expire (the latter only on Linux).
+    ///
+    /// Only the handles created after this call are affected.
+    #[cfg(feature = "ffi")]
+    pub(crate) fn enable_external_wakeups(&mut self) -> io::Result<()> {
+        self.scheduler.set_wakes_poller(true);
+        #[cfg(target_os = "linux")]
+        self.poller.enable_timer_fd()?;
+        Ok(())
+    }
+
+    /// Makes the descriptor of this executor readable if there are runnable fibers.
+    ///
+    /// The pending requests to the scheduler (e.g., wakeups) are handled beforehand.
+    #[cfg(feature = "ffi")]
+    pub(crate) fn notify_pending_work(&mut self) {
+        self.scheduler.handle_pending_requests();
+        if self.scheduler.run_queue_len() > 0 {
+            self.poller.handle().wake();
+        }
+    }
 }
 impl Executor for InPlaceExecutor {
     type Handle = InPlaceExecutorHandle;
@@ -66,10 +319,22 @@ impl Executor for InPlaceExecutor {
     }
     fn run_once(&mut self) -> io::Result<()> {
         self.scheduler.run_once(false);
-        self.poller.poll(Some(time::Duration::from_millis(1)))?;
+        let driven = self.drivers.drive();
+        let runnable = driven || self.scheduler.run_queue_len() > 0;
+        let timeout = self.poll_timeout.next(runnable, self.poller.was_active());
+        self.poller.poll(timeout)?;
         Ok(())
     }
 }
+#[cfg(unix)]
+impl AsRawFd for InPlaceExecutor {
+    /// Returns the file descriptor of the underlying I/O poller.
+    ///
+    /// See the documentation of `Poller::as_raw_fd` for the meaning of the descriptor.
+    fn as_raw_fd(&self) -> RawFd {
+        self.poller.as_raw_fd()
+    }
+}
 impl Spawn for InPlaceExecutor {
     fn spawn_boxed(&self, fiber: Box<dyn Future<Item = (), Error = ()> + Send>) {
         self.handle().spawn_boxed(fiber)
@@ -5,13 +5,20 @@
 use futures::{Async, Future};
 use std::io;
 
+pub use self::driver::Driver;
 pub use self::in_place::{InPlaceExecutor, InPlaceExecutorHandle};
+pub use self::panic_policy::{PanicPolicy, WorkerPanic};
+pub use self::poll_timeout::PollTimeout;
 pub use self::thread_pool::{ThreadPoolExecutor, ThreadPoolExecutorHandle};
 
 use crate::fiber::Spawn;
 use crate::sync::oneshot::{Monitor, MonitorError};
 
+mod cpu_quota;
+mod driver;
 mod in_place;
+mod panic_policy;
+mod poll_timeout;
 mod thread_pool;
 
 /// The `Executor` trait allows for spawning and executing fibers.
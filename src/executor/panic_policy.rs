@@ -0,0 +1,105 @@
+// Copyright (c) 2016 DWANGO Co., Ltd. All Rights Reserved.
+// See the LICENSE file at the top-level directory of this distribution.
+
+use std::any::Any;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::sync::mpsc;
+
+/// The policy to handle the panics of the scheduler threads of an executor.
+///
+/// The policy is applied to the panics occurred outside of the polls of fibers
+/// (i.e., in the internals of the executor).
+/// The panics of fibers are isolated: a panicking fiber is dropped
+/// and its monitors result in `MonitorError::Aborted`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PanicPolicy {
+    /// Aborts the process.
+    Abort,
+
+    /// Restarts the scheduler on a new thread.
+    ///
+    /// The fibers of the scheduler are re-homed to the new thread.
+    ///
+    /// Note that the worker local values (see the [worker module](../worker/index.html))
+    /// of the scheduler are reset.
+    Restart,
+
+    /// Terminates the scheduler thread.
+    ///
+    /// The fibers of the scheduler are dropped, and
+    /// `Executor::run_once` method of the executor will return an error after that.
+    Terminate,
+}
+impl Default for PanicPolicy {
+    /// Returns `PanicPolicy::Terminate`.
+    fn default() -> Self {
+        PanicPolicy::Terminate
+    }
+}
+
+/// A panic occurred on a scheduler thread of an executor.
+#[derive(Debug, Clone)]
+pub struct WorkerPanic {
+    /// The index of the scheduler in the executor.
+    pub index: usize,
+
+    /// The message of the panic.
+    pub message: String,
+
+    /// The policy applied to the panic.
+    pub policy: PanicPolicy,
+}
+
+/// The panic handling state shared by the scheduler threads of an executor.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PanicHandler(Arc<Mutex<PanicHandlerInner>>);
+impl PanicHandler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn policy(&self) -> PanicPolicy {
+        self.lock().policy
+    }
+
+    pub fn set_policy(&self, policy: PanicPolicy) {
+        self.lock().policy = policy;
+    }
+
+    pub fn panics(&self) -> mpsc::Receiver<WorkerPanic> {
+        let (tx, rx) = mpsc::channel();
+        self.lock().panic_tx = Some(tx);
+        rx
+    }
+
+    /// Reports the panic of the `index`-th scheduler and returns the policy to apply.
+    pub fn handle(&self, index: usize, payload: &(dyn Any + Send)) -> PanicPolicy {
+        let inner = self.lock();
+        if let Some(ref tx) = inner.panic_tx {
+            let message = if let Some(s) = payload.downcast_ref::<&str>() {
+                (*s).to_owned()
+            } else if let Some(s) = payload.downcast_ref::<String>() {
+                s.clone()
+            } else {
+                "Box<dyn Any>".to_owned()
+            };
+            let _ = tx.send(WorkerPanic {
+                index,
+                message,
+                policy: inner.policy,
+            });
+        }
+        inner.policy
+    }
+
+    fn lock(&self) -> MutexGuard<'_, PanicHandlerInner> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[derive(Debug, Default)]
+struct PanicHandlerInner {
+    policy: PanicPolicy,
+    panic_tx: Option<mpsc::Sender<WorkerPanic>>,
+}
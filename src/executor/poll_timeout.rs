@@ -0,0 +1,130 @@
+// Copyright (c) 2016 DWANGO Co., Ltd. All Rights Reserved.
+// See the LICENSE file at the top-level directory of this distribution.
+
+use std::cmp;
+use std::time::Duration;
+
+/// The policy to determine the timeout of each I/O polling of an executor.
+///
+/// Regardless of the policy, the timeout is shortened to the nearest timer deadline
+/// (taking the slack of the timer into account).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PollTimeout {
+    /// Always uses the specified timeout.
+    Fixed(Duration),
+
+    /// Adapts the timeout to the recent load.
+    ///
+    /// If there are runnable fibers, the poller does not block.
+    /// While the executor is busy (i.e., events have happened recently), `min` is used.
+    /// Each time an idle polling ends without any events, the timeout is doubled up to `max`.
+    ///
+    /// Note that the wakeups of fibers from other threads are not notified to the poller,
+    /// so `max` is also the upper bound of the latency of such wakeups.
+    Adaptive {
+        /// The timeout used while the executor is busy.
+        min: Duration,
+
+        /// The upper bound of the timeout used while the executor is idle.
+        max: Duration,
+    },
+
+    /// Blocks until the nearest timer deadline, or until any I/O events or
+    /// requests to the poller (e.g., registrations and timer settings) happen.
+    ///
+    /// If there are runnable fibers, the poller does not block.
+    /// This eliminates the idle wakeups, but the wakeups of fibers from other threads are
+    /// not notified to the poller (see `Adaptive`).
+    /// Thus, this is suitable for pollers running on dedicated threads,
+    /// and is the default of `ThreadPoolExecutor`.
+    UntilNextTimer,
+}
+impl Default for PollTimeout {
+    /// Returns `PollTimeout::Adaptive { min: 0ms, max: 1ms }`.
+    fn default() -> Self {
+        PollTimeout::Adaptive {
+            min: Duration::from_millis(0),
+            max: Duration::from_millis(1),
+        }
+    }
+}
+
+/// The state of the poll timeout computation.
+#[derive(Debug)]
+pub(crate) struct PollTimeoutState {
+    policy: PollTimeout,
+    current: Duration,
+}
+impl PollTimeoutState {
+    pub fn new(policy: PollTimeout) -> Self {
+        let current = match policy {
+            PollTimeout::Fixed(timeout) => timeout,
+            PollTimeout::Adaptive { min, .. } => min,
+            PollTimeout::UntilNextTimer => Duration::from_millis(0),
+        };
+        PollTimeoutState { policy, current }
+    }
+
+    pub fn policy(&self) -> PollTimeout {
+        self.policy
+    }
+
+    /// Returns the timeout of the next polling.
+    ///
+    /// `runnable` indicates whether there are runnable fibers, and
+    /// `active` indicates whether any events happened in the last polling.
+    /// `None` means that the polling blocks until the nearest timer deadline.
+    pub fn next(&mut self, runnable: bool, active: bool) -> Option<Duration> {
+        match self.policy {
+            PollTimeout::Fixed(timeout) => Some(timeout),
+            PollTimeout::Adaptive { min, max } => {
+                if runnable {
+                    self.current = min;
+                    return Some(Duration::from_millis(0));
+                }
+                if active {
+                    self.current = min;
+                } else {
+                    let doubled = cmp::max(self.current * 2, Duration::from_micros(50));
+                    self.current = cmp::min(doubled, max);
+                }
+                Some(self.current)
+            }
+            PollTimeout::UntilNextTimer => {
+                if runnable {
+                    Some(Duration::from_millis(0))
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn adaptive_timeout_works() {
+        let min = Duration::from_millis(0);
+        let max = Duration::from_millis(1);
+        let mut state = PollTimeoutState::new(PollTimeout::Adaptive { min, max });
+
+        assert_eq!(state.next(true, false), Some(Duration::from_millis(0)));
+        assert_eq!(state.next(false, false), Some(Duration::from_micros(50)));
+        assert_eq!(state.next(false, false), Some(Duration::from_micros(100)));
+        for _ in 0..10 {
+            state.next(false, false);
+        }
+        assert_eq!(state.next(false, false), Some(max));
+        assert_eq!(state.next(false, true), Some(min));
+
+        let mut state = PollTimeoutState::new(PollTimeout::Fixed(max));
+        assert_eq!(state.next(true, true), Some(max));
+
+        let mut state = PollTimeoutState::new(PollTimeout::UntilNextTimer);
+        assert_eq!(state.next(true, false), Some(Duration::from_millis(0)));
+        assert_eq!(state.next(false, true), None);
+    }
+}
@@ -4,15 +4,24 @@
 use futures::{Async, Future};
 use nbchan::mpsc as nb_mpsc;
 use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::process;
 use std::sync::mpsc::TryRecvError;
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time;
 
-use super::Executor;
+use super::cpu_quota::{CpuQuota, Throttle};
+use super::driver::Drivers;
+use super::panic_policy::PanicHandler;
+use super::poll_timeout::PollTimeoutState;
+use super::{Driver, Executor, PanicPolicy, PollTimeout, WorkerPanic};
 use crate::fiber::Task;
 use crate::fiber::{self, Spawn};
 use crate::io::poll;
+use crate::sync::mpsc;
 use crate::sync::oneshot::{self, Link};
+use crate::trace::TraceRing;
 
 /// An executor that executes spawned fibers on pooled threads.
 ///
@@ -49,6 +58,8 @@ pub struct ThreadPoolExecutor {
     spawn_tx: nb_mpsc::Sender<Task>,
     round: usize,
     steps: usize,
+    config: SchedulerThreadConfig,
+    drivers: Drivers,
 }
 impl ThreadPoolExecutor {
     /// Creates a new instance of `ThreadPoolExecutor`.
@@ -77,7 +88,12 @@ impl ThreadPoolExecutor {
     pub fn with_thread_count(count: usize) -> io::Result<Self> {
         assert!(count > 0);
         let pollers = PollerPool::new(count)?;
-        let schedulers = SchedulerPool::new(&pollers);
+        let config = SchedulerThreadConfig {
+            thread_count: count,
+            panic_handler: PanicHandler::new(),
+            cpu_quota: CpuQuota::new(),
+        };
+        let schedulers = SchedulerPool::new(&pollers, &config);
         let (tx, rx) = nb_mpsc::channel();
         Ok(ThreadPoolExecutor {
             pool: schedulers,
@@ -86,8 +102,203 @@ impl ThreadPoolExecutor {
             spawn_rx: rx,
             round: 0,
             steps: 0,
+            config,
+            drivers: Drivers::default(),
         })
     }
+
+    /// Registers a driver of an external resource which is called once per `run_once`
+    /// with `budget`.
+    ///
+    /// The drivers are called on the thread running this executor
+    /// (not on the scheduler threads).
+    /// See the documentation of `Driver` for more details.
+    ///
+    /// # Panics
+    ///
+    /// If `budget` is `0`, this method will panic.
+    pub fn add_driver<D: Driver>(&mut self, budget: usize, driver: D) {
+        self.drivers.add(budget, driver);
+    }
+
+    /// Sets the policy to handle the panics of the scheduler threads.
+    ///
+    /// The default value is `PanicPolicy::Terminate`.
+    pub fn set_panic_policy(&mut self, policy: PanicPolicy) {
+        self.config.panic_handler.set_policy(policy);
+    }
+
+    /// Returns the policy to handle the panics of the scheduler threads.
+    pub fn panic_policy(&self) -> PanicPolicy {
+        self.config.panic_handler.policy()
+    }
+
+    /// Returns a stream of the panics occurred on the scheduler threads.
+    ///
+    /// The panics of fibers are not reported (see `PanicPolicy`).
+    /// Each panic is reported regardless of the policy
+    /// (but the report is lost if the policy is `PanicPolicy::Abort`).
+    /// If this method is called multiple times, only the latest stream receives panics.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate fibers;
+    /// # extern crate futures;
+    /// use std::sync::atomic::{AtomicBool, Ordering};
+    /// use std::time::Duration;
+    /// use fibers::{Executor, Spawn, ThreadPoolExecutor};
+    /// use fibers::executor::PanicPolicy;
+    /// use futures::{Future, Stream};
+    ///
+    /// let mut executor = ThreadPoolExecutor::with_thread_count(1).unwrap();
+    /// executor.set_panic_policy(PanicPolicy::Restart);
+    /// let panics = executor.panics();
+    ///
+    /// // Panics in a callback invoked by the scheduler (i.e., outside of the polls of fibers).
+    /// let panicked = AtomicBool::new(false);
+    /// executor.set_first_poll_threshold(Some(Duration::from_secs(0)));
+    /// executor.on_slow_first_poll(move |_, _| {
+    ///     if !panicked.swap(true, Ordering::SeqCst) {
+    ///         panic!("oops");
+    ///     }
+    /// });
+    /// executor.spawn(futures::finished(()));
+    /// let panic = executor.run_future(panics.into_future()).unwrap().ok().unwrap().0.unwrap();
+    /// assert_eq!(panic.message, "oops");
+    ///
+    /// // The scheduler has been restarted.
+    /// let monitor = executor.spawn_monitor(futures::finished::<_, ()>(1));
+    /// assert_eq!(executor.run_fiber(monitor).unwrap(), Ok(1));
+    /// ```
+    pub fn panics(&self) -> mpsc::Receiver<WorkerPanic> {
+        self.config.panic_handler.panics()
+    }
+
+    /// Caps the aggregate CPU usage of the scheduler threads to `cores` (e.g., `0.5`).
+    ///
+    /// The quota is divided equally among the scheduler threads.
+    /// Each thread measures its CPU time in each period of 100 milliseconds,
+    /// and sleeps until the end of the period if it has exhausted its share (i.e., duty cycling).
+    ///
+    /// `None` means no quota (the default).
+    /// Note that the CPU time of the poller threads is not counted, and
+    /// the quota has no effect on non-Unix platforms.
+    ///
+    /// # Panics
+    ///
+    /// If `cores` is not positive, this method will panic.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use fibers::ThreadPoolExecutor;
+    ///
+    /// let executor = ThreadPoolExecutor::with_thread_count(2).unwrap();
+    /// executor.set_cpu_quota(Some(0.5));
+    /// assert_eq!(executor.cpu_quota(), Some(0.5));
+    /// ```
+    pub fn set_cpu_quota(&self, cores: Option<f64>) {
+        self.config.cpu_quota.set(cores);
+    }
+
+    /// Returns the CPU quota of the scheduler threads.
+    pub fn cpu_quota(&self) -> Option<f64> {
+        self.config.cpu_quota.get()
+    }
+
+    /// Returns the handles of the schedulers in the thread pool.
+    ///
+    /// For example, `SchedulerHandle::snapshot` method can be used to inspect the schedulers.
+    pub fn scheduler_handles(&self) -> &[fiber::SchedulerHandle] {
+        &self.pool.schedulers
+    }
+
+    /// Sets the threshold of the spawn-to-first-poll latency of the fibers in this executor.
+    ///
+    /// The threshold is applied to all the schedulers in the thread pool.
+    /// Note that the latency includes the time a fiber has waited to be dispatched
+    /// to a scheduler by `run_once`.
+    /// See `Scheduler::set_first_poll_threshold` for more details.
+    pub fn set_first_poll_threshold(&self, threshold: Option<time::Duration>) {
+        for scheduler in &self.pool.schedulers {
+            scheduler.set_first_poll_threshold(threshold);
+        }
+    }
+
+    /// Sets the callback invoked when the spawn-to-first-poll latency of a fiber exceeds
+    /// the threshold.
+    ///
+    /// The callback is shared by all the schedulers in the thread pool,
+    /// and is invoked on the scheduler threads.
+    /// See `Scheduler::on_slow_first_poll` for more details.
+    pub fn on_slow_first_poll<F>(&self, f: F)
+    where
+        F: Fn(&fiber::FiberSnapshot, time::Duration) + Send + Sync + 'static,
+    {
+        let f = Arc::new(f);
+        for scheduler in &self.pool.schedulers {
+            let f = Arc::clone(&f);
+            scheduler.on_slow_first_poll(move |fiber, latency| f(fiber, latency));
+        }
+    }
+
+    /// Enables the tracing of the scheduling events with a ring buffer of `capacity` events.
+    ///
+    /// The ring is shared by all the schedulers and pollers in the thread pool.
+    /// See the [trace module](../trace/index.html) for more details.
+    pub fn enable_tracing(&self, capacity: usize) -> TraceRing {
+        let ring = TraceRing::new(capacity);
+        for scheduler in &self.pool.schedulers {
+            scheduler.set_trace_ring(Some(ring.clone()));
+        }
+        for poller in &self.pollers.pollers {
+            poller.set_trace_ring(Some(ring.clone()));
+        }
+        ring
+    }
+
+    /// Sets the artificial delay inserted before I/O readiness is delivered to the fibers.
+    ///
+    /// The delay is applied to all the pollers in the thread pool.
+    /// This is intended for testing or staging environments only.
+    /// See `Poller::set_readiness_delay` for more details.
+    pub fn set_readiness_delay(&self, delay: Option<time::Duration>) {
+        for poller in &self.pollers.pollers {
+            poller.set_readiness_delay(delay);
+        }
+    }
+
+    /// Sets the policy to determine the timeout of each I/O polling of the poller threads.
+    ///
+    /// The default value is `PollTimeout::UntilNextTimer`,
+    /// i.e., an idle poller thread sleeps until the nearest timer deadline.
+    pub fn set_poll_timeout(&self, policy: PollTimeout) {
+        *self
+            .pollers
+            .poll_timeout
+            .lock()
+            .unwrap_or_else(|e| e.into_inner()) = policy;
+        for poller in &self.pollers.pollers {
+            poller.wake();
+        }
+    }
+
+    /// Returns the policy to determine the timeout of each I/O polling of the poller threads.
+    pub fn poll_timeout(&self) -> PollTimeout {
+        *self
+            .pollers
+            .poll_timeout
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Returns the handles of the I/O pollers in the thread pool.
+    ///
+    /// For example, `PollerHandle::stats` method can be used to inspect the pollers.
+    pub fn poller_handles(&self) -> &[poll::PollerHandle] {
+        &self.pollers.pollers
+    }
 }
 impl Executor for ThreadPoolExecutor {
     type Handle = ThreadPoolExecutorHandle;
@@ -97,24 +308,32 @@ impl Executor for ThreadPoolExecutor {
         }
     }
     fn run_once(&mut self) -> io::Result<()> {
+        let driven = self.drivers.drive();
         match self.spawn_rx.try_recv() {
             Err(TryRecvError::Empty) => {
-                thread::sleep(time::Duration::from_millis(1));
+                if !driven {
+                    thread::sleep(time::Duration::from_millis(1));
+                }
             }
             Err(TryRecvError::Disconnected) => unreachable!(),
             Ok(task) => {
                 let i = self.round % self.pool.schedulers.len();
-                self.pool.schedulers[i].spawn_boxed(task.0);
+                self.pool.schedulers[i].spawn_task(task);
                 self.round = self.round.wrapping_add(1);
             }
         }
         self.steps = self.steps.wrapping_add(1);
         let i = self.steps % self.pool.schedulers.len();
         if self.pool.links[i].poll().is_err() {
-            Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("The {}-th scheduler thread is aborted", i),
-            ))
+            Err(io::Error::other(format!(
+                "The {}-th scheduler thread is aborted",
+                i
+            )))
+        } else if self.pollers.links[i].poll().is_err() {
+            Err(io::Error::other(format!(
+                "The {}-th poller thread is aborted",
+                i
+            )))
         } else {
             Ok(())
         }
@@ -133,7 +352,7 @@ pub struct ThreadPoolExecutorHandle {
 }
 impl Spawn for ThreadPoolExecutorHandle {
     fn spawn_boxed(&self, fiber: Box<dyn Future<Item = (), Error = ()> + Send>) {
-        let _ = self.spawn_tx.send(Task(fiber));
+        let _ = self.spawn_tx.send(Task::new(fiber));
     }
 }
 
@@ -141,27 +360,49 @@ impl Spawn for ThreadPoolExecutorHandle {
 struct PollerPool {
     pollers: Vec<poll::PollerHandle>,
     links: Vec<Link<(), io::Error>>,
+    poll_timeout: Arc<Mutex<PollTimeout>>,
 }
 impl PollerPool {
     pub fn new(pool_size: usize) -> io::Result<Self> {
         let mut pollers = Vec::new();
         let mut links = Vec::new();
+        let poll_timeout = Arc::new(Mutex::new(PollTimeout::UntilNextTimer));
         for _ in 0..pool_size {
             let (link0, mut link1) = oneshot::link();
             let mut poller = poll::Poller::new()?;
             links.push(link0);
             pollers.push(poller.handle());
+            let policy = Arc::clone(&poll_timeout);
             thread::spawn(move || {
+                let mut poll_timeout = PollTimeoutState::new(PollTimeout::UntilNextTimer);
                 while let Ok(Async::NotReady) = link1.poll() {
-                    let timeout = time::Duration::from_millis(1);
-                    if let Err(e) = poller.poll(Some(timeout)) {
+                    let policy = *policy.lock().unwrap_or_else(|e| e.into_inner());
+                    if policy != poll_timeout.policy() {
+                        poll_timeout = PollTimeoutState::new(policy);
+                    }
+                    let timeout = poll_timeout.next(false, poller.was_active());
+                    if let Err(e) = poller.poll(timeout) {
                         link1.exit(Err(e));
                         return;
                     }
                 }
             });
         }
-        Ok(PollerPool { pollers, links })
+        Ok(PollerPool {
+            pollers,
+            links,
+            poll_timeout,
+        })
+    }
+}
+impl Drop for PollerPool {
+    fn drop(&mut self) {
+        // The poller threads may be blocking without any timeouts,
+        // so they are woken up to notice that the links have been dropped.
+        self.links.clear();
+        for poller in &self.pollers {
+            poller.wake();
+        }
     }
 }
 
@@ -171,20 +412,137 @@ struct SchedulerPool {
     links: Vec<Link<(), ()>>,
 }
 impl SchedulerPool {
-    pub fn new(poller_pool: &PollerPool) -> Self {
+    pub fn new(poller_pool: &PollerPool, config: &SchedulerThreadConfig) -> Self {
         let mut schedulers = Vec::new();
         let mut links = Vec::new();
-        for poller in &poller_pool.pollers {
-            let (link0, mut link1) = oneshot::link();
+        for (index, poller) in poller_pool.pollers.iter().enumerate() {
+            let (link0, link1) = oneshot::link();
             let mut scheduler = fiber::Scheduler::new(poller.clone());
+            scheduler.set_catches_fiber_panics(true);
             links.push(link0);
             schedulers.push(scheduler.handle());
-            thread::spawn(move || {
-                while let Ok(Async::NotReady) = link1.poll() {
-                    scheduler.run_once(true);
-                }
-            });
+            spawn_scheduler_thread(index, scheduler, link1, config.clone());
         }
         SchedulerPool { schedulers, links }
     }
 }
+
+/// The settings shared by the scheduler threads of an executor.
+#[derive(Debug, Clone)]
+struct SchedulerThreadConfig {
+    thread_count: usize,
+    panic_handler: PanicHandler,
+    cpu_quota: CpuQuota,
+}
+
+fn spawn_scheduler_thread(
+    index: usize,
+    mut scheduler: fiber::Scheduler,
+    mut link: Link<(), ()>,
+    config: SchedulerThreadConfig,
+) {
+    thread::spawn(move || {
+        let mut throttle = Throttle::new(config.cpu_quota.clone(), config.thread_count);
+        while let Ok(Async::NotReady) = link.poll() {
+            let result = panic::catch_unwind(AssertUnwindSafe(|| scheduler.run_once(true)));
+            if let Err(payload) = result {
+                match config.panic_handler.handle(index, &*payload) {
+                    PanicPolicy::Abort => process::abort(),
+                    PanicPolicy::Restart => {
+                        scheduler.recover_from_panic();
+                        spawn_scheduler_thread(index, scheduler, link, config);
+                        return;
+                    }
+                    PanicPolicy::Terminate => panic::resume_unwind(payload),
+                }
+            }
+            throttle.throttle();
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use futures::{Future, Stream};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::sync::oneshot::MonitorError;
+
+    #[test]
+    fn idle_pollers_do_not_wake_up_periodically() {
+        let executor = ThreadPoolExecutor::with_thread_count(1).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        let polls = || executor.poller_handles()[0].stats().polls;
+
+        let before = polls();
+        thread::sleep(Duration::from_millis(100));
+        assert!(polls() - before < 10, "{} pollings", polls() - before);
+
+        executor.set_poll_timeout(PollTimeout::Fixed(Duration::from_millis(1)));
+        thread::sleep(Duration::from_millis(10));
+        let before = polls();
+        thread::sleep(Duration::from_millis(100));
+        assert!(polls() - before >= 10, "{} pollings", polls() - before);
+    }
+
+    #[test]
+    fn fiber_panics_do_not_trigger_panic_policy() {
+        let mut executor = ThreadPoolExecutor::with_thread_count(1).unwrap();
+        let monitor =
+            executor.spawn_monitor(futures::lazy(|| -> Result<(), ()> { panic!("oops") }));
+        assert_eq!(
+            executor.run_fiber(monitor).unwrap(),
+            Err(MonitorError::Aborted)
+        );
+
+        // The scheduler thread is still alive.
+        let monitor = executor.spawn_monitor(futures::finished::<_, ()>(1));
+        assert_eq!(executor.run_fiber(monitor).unwrap(), Ok(1));
+    }
+
+    #[test]
+    fn restart_rehomes_fibers() {
+        let mut executor = ThreadPoolExecutor::with_thread_count(1).unwrap();
+        executor.set_panic_policy(PanicPolicy::Restart);
+        let panics = executor.panics();
+
+        let (tx, rx) = oneshot::channel();
+        let monitor = executor.spawn_monitor(rx.map_err(|_| ()));
+        let scheduler = executor.scheduler_handles()[0].clone();
+        while executor
+            .run_future(scheduler.snapshot())
+            .unwrap()
+            .unwrap()
+            .fibers
+            .is_empty()
+        {}
+
+        scheduler.abort();
+        let (panic, _) = executor
+            .run_future(panics.into_future())
+            .unwrap()
+            .ok()
+            .unwrap();
+        assert_eq!(panic.unwrap().policy, PanicPolicy::Restart);
+
+        // The waiting fiber has been moved to the new scheduler thread.
+        tx.send(1).unwrap();
+        assert_eq!(executor.run_fiber(monitor).unwrap(), Ok(1));
+    }
+
+    #[test]
+    fn run_once_fails_if_poller_thread_is_aborted() {
+        let mut executor = ThreadPoolExecutor::with_thread_count(1).unwrap();
+        assert!(executor.run_once().is_ok());
+
+        // Simulates the failure of the poller thread (i.e., `Poller::poll` returns an error).
+        let (link0, link1) = oneshot::link();
+        executor.pollers.links[0] = link0;
+        link1.exit(Err(io::Error::other("poll failed")));
+
+        let e = executor.run_once().err().unwrap();
+        assert_eq!(e.to_string(), "The 0-th poller thread is aborted");
+    }
+}
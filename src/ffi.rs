@@ -0,0 +1,356 @@
+// Copyright (c) 2016 DWANGO Co., Ltd. All Rights Reserved.
+// See the LICENSE file at the top-level directory of this distribution.
+
+//! C-callable functions to embed the fibers runtime in a foreign host application.
+//!
+//! This module is available only if the `ffi` feature is enabled.
+//!
+//! The functions drive an `InPlaceExecutor` instance on the host's thread.
+//! A typical embedding looks like the following:
+//!
+//! ```c
+//! FibersExecutor *executor = fibers_executor_new();
+//! fibers_executor_spawn(executor, my_poll_fn, my_data, my_free_fn);
+//!
+//! int fd = fibers_executor_wakeup_fd(executor);
+//! for (;;) {
+//!     // Waits on `fd` together with the host's own descriptors.
+//!     wait_readable(fd, -1 /* no timeout */);
+//!     if (fibers_executor_run_once(executor, 0) != 0) break;
+//! }
+//! fibers_executor_free(executor);
+//! ```
+//!
+//! The descriptor becomes readable when the executor has works to do, i.e.,
+//! when I/O events happen, fibers are woken up (e.g., by `fibers_waker_wake` or
+//! from other threads), or timers expire.
+//! Note that the timer expirations are notified only on Linux.
+//! On the other platforms, the host should wait on the descriptor with a short timeout.
+use futures::{Async, Future, Poll};
+use std::os::raw::{c_int, c_void};
+use std::ptr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::executor::InPlaceExecutor;
+use crate::fiber::{self, Spawn, Unpark};
+
+/// The type of the callback which is called each time the spawned fiber is polled.
+///
+/// It should return `0` if the task is still in progress,
+/// a positive value if the task has completed successfully,
+/// and a negative value if the task has failed.
+///
+/// `waker` is valid only during the call.
+/// A callback returning `0` should retain it by `fibers_waker_clone` and
+/// call `fibers_waker_wake` when the task can make progress.
+/// Otherwise (i.e., if no clone of `waker` is alive), the fiber is polled again
+/// at the next turn of the executor.
+pub type FibersPollFn = extern "C" fn(data: *mut c_void, waker: *const FibersWaker) -> c_int;
+
+/// The type of the callback which releases the data passed to `fibers_executor_spawn`.
+pub type FibersFreeFn = extern "C" fn(data: *mut c_void);
+
+/// An opaque executor object.
+#[derive(Debug)]
+pub struct FibersExecutor(InPlaceExecutor);
+
+/// An opaque object to wake up a fiber spawned by `fibers_executor_spawn`.
+///
+/// It can be sent to other threads.
+#[derive(Debug)]
+pub struct FibersWaker(Mutex<Option<Unpark>>);
+
+/// Creates a new executor.
+///
+/// If it fails, this will return a null pointer.
+#[no_mangle]
+pub extern "C" fn fibers_executor_new() -> *mut FibersExecutor {
+    let executor = InPlaceExecutor::new().and_then(|mut executor| {
+        executor.enable_external_wakeups()?;
+        Ok(executor)
+    });
+    match executor {
+        Ok(executor) => Box::into_raw(Box::new(FibersExecutor(executor))),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Releases the executor.
+///
+/// # Safety
+///
+/// `executor` must be a pointer returned by `fibers_executor_new` (or null),
+/// and must not be used after calling this function.
+#[no_mangle]
+pub unsafe extern "C" fn fibers_executor_free(executor: *mut FibersExecutor) {
+    if !executor.is_null() {
+        drop(Box::from_raw(executor));
+    }
+}
+
+/// Returns the file descriptor which becomes readable when the executor has works to do.
+///
+/// On the platforms where such a descriptor is not available, this will return `-1`.
+///
+/// # Safety
+///
+/// `executor` must be a valid pointer returned by `fibers_executor_new`.
+#[no_mangle]
+pub unsafe extern "C" fn fibers_executor_wakeup_fd(executor: *const FibersExecutor) -> c_int {
+    wakeup_fd(&(*executor).0)
+}
+
+#[cfg(unix)]
+fn wakeup_fd(executor: &InPlaceExecutor) -> c_int {
+    use std::os::unix::io::AsRawFd;
+    executor.as_raw_fd()
+}
+
+#[cfg(not(unix))]
+fn wakeup_fd(_executor: &InPlaceExecutor) -> c_int {
+    -1
+}
+
+/// Spawns a fiber which calls `poll` with `data` until it returns a non-zero value.
+///
+/// When the fiber exits, `free` (if not null) is called with `data`.
+///
+/// # Safety
+///
+/// `executor` must be a valid pointer returned by `fibers_executor_new`.
+/// `data` must be valid until `free` is called (or the fiber exits, if `free` is null).
+#[no_mangle]
+pub unsafe extern "C" fn fibers_executor_spawn(
+    executor: *mut FibersExecutor,
+    poll: FibersPollFn,
+    data: *mut c_void,
+    free: Option<FibersFreeFn>,
+) {
+    (*executor).0.spawn(CallbackFuture { poll, data, free });
+}
+
+/// Runs one unit of works, waiting for I/O events at most `timeout_ms` milliseconds.
+///
+/// If there are works left after this call, the descriptor returned by
+/// `fibers_executor_wakeup_fd` is kept readable.
+///
+/// This returns `0` on success, otherwise `-1`.
+///
+/// # Safety
+///
+/// `executor` must be a valid pointer returned by `fibers_executor_new`.
+#[no_mangle]
+pub unsafe extern "C" fn fibers_executor_run_once(
+    executor: *mut FibersExecutor,
+    timeout_ms: u32,
+) -> c_int {
+    let executor = &mut (*executor).0;
+    let timeout = Duration::from_millis(u64::from(timeout_ms));
+    let result = executor.run_once_with_timeout(Some(timeout));
+    executor.notify_pending_work();
+    match result {
+        Ok(()) => 0,
+        Err(_) => -1,
+    }
+}
+
+/// Makes a new reference to the waker.
+///
+/// The returned pointer must be released by `fibers_waker_wake` or `fibers_waker_free`.
+///
+/// # Safety
+///
+/// `waker` must be a valid pointer passed to a `FibersPollFn` callback (during the call)
+/// or returned by this function.
+#[no_mangle]
+pub unsafe extern "C" fn fibers_waker_clone(waker: *const FibersWaker) -> *mut FibersWaker {
+    Arc::increment_strong_count(waker);
+    waker as *mut FibersWaker
+}
+
+/// Wakes up the fiber associated with the waker, and releases the waker.
+///
+/// This can be called from any thread.
+///
+/// # Safety
+///
+/// `waker` must be a pointer returned by `fibers_waker_clone`,
+/// and must not be used after calling this function.
+#[no_mangle]
+pub unsafe extern "C" fn fibers_waker_wake(waker: *mut FibersWaker) {
+    let waker = Arc::from_raw(waker);
+    let unpark = waker.0.lock().unwrap_or_else(|e| e.into_inner()).take();
+    drop(unpark);
+}
+
+/// Releases the waker without waking up the fiber.
+///
+/// Note that the fiber is woken up when all the references to the waker are released.
+///
+/// # Safety
+///
+/// `waker` must be a pointer returned by `fibers_waker_clone`,
+/// and must not be used after calling this function.
+#[no_mangle]
+pub unsafe extern "C" fn fibers_waker_free(waker: *mut FibersWaker) {
+    drop(Arc::from_raw(waker));
+}
+
+struct CallbackFuture {
+    poll: FibersPollFn,
+    data: *mut c_void,
+    free: Option<FibersFreeFn>,
+}
+unsafe impl Send for CallbackFuture {}
+impl Future for CallbackFuture {
+    type Item = ();
+    type Error = ();
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let unpark = fiber::with_current_context(|mut c| c.park());
+        let waker = Arc::new(FibersWaker(Mutex::new(unpark)));
+
+        // NOTE: The reference passed to the callback is released when `waker` is dropped,
+        // so the fiber is woken up immediately if the callback has not cloned it.
+        match (self.poll)(self.data, &*waker) {
+            0 => Ok(Async::NotReady),
+            n if n > 0 => Ok(Async::Ready(())),
+            _ => Err(()),
+        }
+    }
+}
+impl Drop for CallbackFuture {
+    fn drop(&mut self) {
+        if let Some(free) = self.free {
+            free(self.data);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+    use std::thread;
+
+    extern "C" fn count_up(data: *mut c_void, _waker: *const FibersWaker) -> c_int {
+        let counter = unsafe { &*(data as *const AtomicUsize) };
+        if counter.fetch_add(1, Ordering::SeqCst) + 1 < 3 {
+            0
+        } else {
+            1
+        }
+    }
+
+    #[test]
+    fn it_works() {
+        let counter = AtomicUsize::new(0);
+        unsafe {
+            let executor = fibers_executor_new();
+            assert!(!executor.is_null());
+            fibers_executor_spawn(
+                executor,
+                count_up,
+                &counter as *const _ as *mut c_void,
+                None,
+            );
+            while counter.load(Ordering::SeqCst) < 3 {
+                assert_eq!(fibers_executor_run_once(executor, 0), 0);
+            }
+            fibers_executor_free(executor);
+        }
+        assert_eq!(counter.load(Ordering::SeqCst), 3);
+    }
+
+    struct WakerSlot {
+        waker: AtomicPtr<FibersWaker>,
+        woken: AtomicBool,
+    }
+
+    extern "C" fn wait_for_waker(data: *mut c_void, waker: *const FibersWaker) -> c_int {
+        let slot = unsafe { &*(data as *const WakerSlot) };
+        if slot.woken.load(Ordering::SeqCst) {
+            return 1;
+        }
+        let waker = unsafe { fibers_waker_clone(waker) };
+        slot.waker.store(waker, Ordering::SeqCst);
+        0
+    }
+
+    #[cfg(target_os = "linux")]
+    fn is_readable(fd: c_int) -> bool {
+        let mut pollfd = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        unsafe { libc::poll(&mut pollfd, 1, 0) == 1 }
+    }
+
+    #[test]
+    fn waker_works() {
+        let slot = WakerSlot {
+            waker: AtomicPtr::new(ptr::null_mut()),
+            woken: AtomicBool::new(false),
+        };
+        unsafe {
+            let executor = fibers_executor_new();
+            assert!(!executor.is_null());
+            fibers_executor_spawn(
+                executor,
+                wait_for_waker,
+                &slot as *const _ as *mut c_void,
+                None,
+            );
+            while slot.waker.load(Ordering::SeqCst).is_null() {
+                assert_eq!(fibers_executor_run_once(executor, 0), 0);
+            }
+
+            // The fiber is parked until the waker is woken up
+            assert_eq!(fibers_executor_run_once(executor, 0), 0);
+            assert_eq!((*executor).0.scheduler_snapshot().run_queue_len, 0);
+            #[cfg(target_os = "linux")]
+            assert!(!is_readable(fibers_executor_wakeup_fd(executor)));
+
+            slot.woken.store(true, Ordering::SeqCst);
+            let waker = slot.waker.swap(ptr::null_mut(), Ordering::SeqCst) as usize;
+            thread::spawn(move || fibers_waker_wake(waker as *mut FibersWaker))
+                .join()
+                .unwrap();
+            #[cfg(target_os = "linux")]
+            assert!(is_readable(fibers_executor_wakeup_fd(executor)));
+
+            for _ in 0..10 {
+                assert_eq!(fibers_executor_run_once(executor, 0), 0);
+            }
+            assert!((*executor).0.scheduler_snapshot().fibers.is_empty());
+            fibers_executor_free(executor);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn timers_make_the_descriptor_readable() {
+        use crate::time::timer;
+
+        unsafe {
+            let executor = fibers_executor_new();
+            assert!(!executor.is_null());
+            (*executor)
+                .0
+                .spawn(timer::timeout(Duration::from_millis(20)).map_err(|_| ()));
+            for _ in 0..10 {
+                assert_eq!(fibers_executor_run_once(executor, 0), 0);
+            }
+            let fd = fibers_executor_wakeup_fd(executor);
+            assert!(!is_readable(fd));
+
+            thread::sleep(Duration::from_millis(30));
+            assert!(is_readable(fd));
+            while !(*executor).0.scheduler_snapshot().fibers.is_empty() {
+                assert_eq!(fibers_executor_run_once(executor, 0), 0);
+            }
+            fibers_executor_free(executor);
+        }
+    }
+}
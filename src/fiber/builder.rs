@@ -0,0 +1,121 @@
+// Copyright (c) 2016 DWANGO Co., Ltd. All Rights Reserved.
+// See the LICENSE file at the top-level directory of this distribution.
+
+use futures::Future;
+use std::fmt;
+use std::panic::Location;
+use std::time;
+
+use super::{Spawn, SpawnSite, WithDeadline};
+use crate::sync::oneshot::{self, Monitor};
+use crate::time::timer;
+
+/// A builder to spawn a fiber with options.
+///
+/// This is created by calling `Spawn::build_fiber` method.
+///
+/// To pin a fiber to a particular scheduler thread, call `build_fiber` on its `SchedulerHandle`
+/// (e.g., one of `ThreadPoolExecutor::scheduler_handles`).
+///
+/// # Examples
+///
+/// ```
+/// # extern crate fibers;
+/// # extern crate futures;
+/// use std::time::{Duration, Instant};
+/// use fibers::{Executor, InPlaceExecutor, Spawn};
+/// use fibers::sync::oneshot::MonitorError;
+///
+/// let mut executor = InPlaceExecutor::new().unwrap();
+/// let monitor = executor
+///     .build_fiber()
+///     .name("slow-query")
+///     .deadline(Instant::now() + Duration::from_millis(10))
+///     .spawn_monitor(futures::empty::<(), ()>());
+/// assert_eq!(executor.run_fiber(monitor).unwrap(), Err(MonitorError::DeadlineExceeded));
+/// ```
+pub struct FiberBuilder<'a, S: ?Sized + 'a> {
+    spawner: &'a S,
+    site: &'static Location<'static>,
+    name: Option<String>,
+    deadline: Option<time::Instant>,
+}
+impl<'a, S: Spawn + ?Sized + 'a> FiberBuilder<'a, S> {
+    pub(super) fn new(spawner: &'a S, site: &'static Location<'static>) -> Self {
+        FiberBuilder {
+            spawner,
+            site,
+            name: None,
+            deadline: None,
+        }
+    }
+
+    /// Sets the name of the fiber.
+    ///
+    /// The name can be retrieved via `Context::name` method and `FiberSnapshot::name` field.
+    pub fn name<N: Into<String>>(mut self, name: N) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Sets the deadline of the fiber.
+    ///
+    /// See `Spawn::spawn_monitor_with_deadline` for the behavior on the expiration.
+    pub fn deadline(mut self, deadline: time::Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Spawns a fiber which will execute `f` with the options.
+    pub fn spawn<F>(self, f: F)
+    where
+        F: Future<Item = (), Error = ()> + Send + 'static,
+    {
+        if self.deadline.is_some() {
+            let _ = self.spawn_monitor(f);
+        } else {
+            let spawner = self.spawner;
+            spawner.spawn(self.wrap(f));
+        }
+    }
+
+    /// Spawns a fiber which will execute `f` with the options,
+    /// and returns a future to monitor its execution result.
+    pub fn spawn_monitor<F, T, E>(self, f: F) -> Monitor<T, E>
+    where
+        F: Future<Item = T, Error = E> + Send + 'static,
+        T: Send + 'static,
+        E: Send + 'static,
+    {
+        let spawner = self.spawner;
+        let deadline = self.deadline;
+        let f = self.wrap(f);
+        if let Some(deadline) = deadline {
+            let (monitored, monitor) = oneshot::monitor();
+            let delay = deadline.saturating_duration_since(time::Instant::now());
+            spawner.spawn(WithDeadline {
+                inner: f,
+                timeout: timer::timeout(delay),
+                monitored: Some(monitored),
+            });
+            monitor
+        } else {
+            spawner.spawn_monitor(f)
+        }
+    }
+
+    fn wrap<F>(self, f: F) -> SpawnSite<F> {
+        let mut f = SpawnSite::new(self.site, f);
+        f.name = self.name;
+        f
+    }
+}
+impl<'a, S: ?Sized + 'a> fmt::Debug for FiberBuilder<'a, S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "FiberBuilder {{ name: {:?}, deadline: {:?}, .. }}",
+            self.name, self.deadline
+        )
+    }
+}
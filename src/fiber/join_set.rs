@@ -0,0 +1,166 @@
+// Copyright (c) 2016 DWANGO Co., Ltd. All Rights Reserved.
+// See the LICENSE file at the top-level directory of this distribution.
+
+use futures::{Async, Future, Poll, Stream};
+use std::fmt;
+
+use super::Spawn;
+use crate::sync::oneshot::{Monitor, MonitorError};
+
+/// A set of spawned child fibers.
+///
+/// `JoinSet` is a stream which yields the results of the children in the order of completion.
+/// The stream ends when the set becomes empty.
+/// Note that a failed child is yielded as an error of the stream,
+/// and the stream can be polled continuously after that.
+///
+/// Unlike polling a `Vec<Monitor>` from the head,
+/// the polling of the children is fair:
+/// each poll starts at the child next to the one which completed last,
+/// so children near the head cannot shadow the others.
+///
+/// If the set is dropped, all of the remaining children are aborted.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate fibers;
+/// # extern crate futures;
+/// use fibers::{Executor, InPlaceExecutor, Spawn};
+/// use fibers::fiber::JoinSet;
+/// use futures::{Future, Stream};
+///
+/// let mut executor = InPlaceExecutor::new().unwrap();
+/// let handle = executor.handle();
+/// let parent = executor.spawn_monitor(futures::lazy(move || {
+///     let mut children = JoinSet::new();
+///     for i in 0..3 {
+///         children.spawn(&handle, futures::finished::<_, ()>(i));
+///     }
+///     children.spawn(&handle, futures::failed(()));
+///     children
+///         .then(|r| Ok::<_, ()>(r.ok()))
+///         .filter_map(|r| r)
+///         .collect()
+/// }));
+/// let mut results = executor.run_fiber(parent).unwrap().unwrap();
+/// results.sort();
+/// assert_eq!(results, vec![0, 1, 2]);
+/// ```
+pub struct JoinSet<T, E> {
+    children: Vec<Monitor<T, E>>,
+    next: usize,
+}
+impl<T, E> JoinSet<T, E>
+where
+    T: Send + 'static,
+    E: Send + 'static,
+{
+    /// Makes a new empty `JoinSet` instance.
+    pub fn new() -> Self {
+        JoinSet {
+            children: Vec::new(),
+            next: 0,
+        }
+    }
+
+    /// Spawns `f` as a child fiber of this set by using `spawner`.
+    pub fn spawn<S, F>(&mut self, spawner: &S, f: F)
+    where
+        S: Spawn + ?Sized,
+        F: Future<Item = T, Error = E> + Send + 'static,
+    {
+        self.children.push(spawner.spawn_abortable(f));
+    }
+
+    /// Aborts all of the children in this set.
+    ///
+    /// Each child is dropped at the next time it is resumed,
+    /// and the stream yields `MonitorError::Aborted` for it
+    /// (unless it has already completed).
+    pub fn abort_all(&mut self) {
+        for child in &mut self.children {
+            child.abort();
+        }
+    }
+
+    /// Returns the number of the children which have not been yielded yet.
+    pub fn len(&self) -> usize {
+        self.children.len()
+    }
+
+    /// Returns `true` if this set has no children, otherwise `false`.
+    pub fn is_empty(&self) -> bool {
+        self.children.is_empty()
+    }
+}
+impl<T, E> Default for JoinSet<T, E>
+where
+    T: Send + 'static,
+    E: Send + 'static,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl<T, E> Stream for JoinSet<T, E> {
+    type Item = T;
+    type Error = MonitorError<E>;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let len = self.children.len();
+        if len == 0 {
+            return Ok(Async::Ready(None));
+        }
+        for i in 0..len {
+            let index = (self.next + i) % len;
+            let result = match self.children[index].poll() {
+                Ok(Async::NotReady) => continue,
+                Ok(Async::Ready(v)) => Ok(Async::Ready(Some(v))),
+                Err(e) => Err(e),
+            };
+            self.children.remove(index);
+            self.next = index;
+            return result;
+        }
+        Ok(Async::NotReady)
+    }
+}
+impl<T, E> fmt::Debug for JoinSet<T, E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "JoinSet {{ len: {}, .. }}", self.children.len())
+    }
+}
+
+impl<T, E> Drop for JoinSet<T, E> {
+    fn drop(&mut self) {
+        for child in &mut self.children {
+            child.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::empty;
+
+    use super::*;
+    use crate::executor::{Executor, InPlaceExecutor};
+
+    #[test]
+    fn abort_all_works() {
+        let mut executor = InPlaceExecutor::new().unwrap();
+        let handle = executor.handle();
+        let parent = executor.spawn_monitor(futures::lazy(move || {
+            let mut children = JoinSet::<(), ()>::new();
+            children.spawn(&handle, empty());
+            children.spawn(&handle, empty());
+            children.abort_all();
+            children.then(Ok::<_, ()>).collect()
+        }));
+        let results = executor.run_fiber(parent).unwrap().unwrap();
+        assert_eq!(
+            results,
+            vec![Err(MonitorError::Aborted), Err(MonitorError::Aborted)]
+        );
+    }
+}
@@ -8,14 +8,23 @@
 use futures::future::Either;
 use futures::{self, Async, Future, IntoFuture, Poll};
 use std::fmt;
+use std::panic::Location;
 use std::sync::atomic::{self, AtomicUsize};
 use std::sync::Arc;
+use std::time;
 
+pub use self::builder::FiberBuilder;
+pub use self::join_set::JoinSet;
 pub use self::schedule::{with_current_context, yield_poll, Context};
-pub use self::schedule::{Scheduler, SchedulerHandle, SchedulerId};
+pub use self::schedule::{
+    FirstPollStats, Scheduler, SchedulerHandle, SchedulerId, SchedulerSnapshot,
+};
 
 use crate::sync::oneshot::{self, Link, Monitor};
+use crate::time::timer;
 
+mod builder;
+mod join_set;
 mod schedule;
 
 /// The identifier of a fiber.
@@ -40,13 +49,48 @@ pub trait Spawn {
     }
 
     /// Equivalent to `self.spawn(futures::lazy(|| f()))`.
+    ///
+    /// In addition, the location of the caller is recorded as the spawn site of the fiber
+    /// (see `Context::spawn_site`).
+    #[track_caller]
     fn spawn_fn<F, T>(&self, f: F)
     where
         F: FnOnce() -> T + Send + 'static,
         T: IntoFuture<Item = (), Error = ()> + Send + 'static,
         T::Future: Send,
     {
-        self.spawn(futures::lazy(f))
+        self.spawn(SpawnSite::new(Location::caller(), futures::lazy(f)))
+    }
+
+    /// Equivalent to `self.spawn_monitor(futures::lazy(|| f()))`.
+    ///
+    /// In addition, the location of the caller is recorded as the spawn site of the fiber
+    /// (see `Context::spawn_site`).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate fibers;
+    /// # extern crate futures;
+    /// use fibers::{fiber, Executor, InPlaceExecutor, Spawn};
+    ///
+    /// let mut executor = InPlaceExecutor::new().unwrap();
+    /// let monitor = executor.spawn_monitor_fn(|| {
+    ///     let site = fiber::with_current_context(|c| c.spawn_site()).unwrap().unwrap();
+    ///     Ok::<_, ()>(site.line())
+    /// });
+    /// assert_eq!(executor.run_fiber(monitor).unwrap(), Ok(line!() - 4));
+    /// ```
+    #[track_caller]
+    fn spawn_monitor_fn<F, T>(&self, f: F) -> Monitor<T::Item, T::Error>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: IntoFuture + Send + 'static,
+        T::Future: Send,
+        T::Item: Send + 'static,
+        T::Error: Send + 'static,
+    {
+        self.spawn_monitor(SpawnSite::new(Location::caller(), futures::lazy(f)))
     }
 
     /// Spawns a fiber and returns a future to monitor its execution result.
@@ -64,6 +108,78 @@ pub trait Spawn {
         monitor
     }
 
+    /// Spawns a fiber which can be aborted via the returning monitor (see `Monitor::abort`).
+    ///
+    /// This is the same as `spawn_monitor` except that an abort request channel is
+    /// allocated for the fiber and checked at every resumption of it.
+    /// Thus, use this only if the fiber may be aborted
+    /// (e.g., it is a child passed to `oneshot::join_all`).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate fibers;
+    /// # extern crate futures;
+    /// use fibers::{Executor, InPlaceExecutor, Spawn};
+    /// use fibers::sync::oneshot::MonitorError;
+    ///
+    /// let mut executor = InPlaceExecutor::new().unwrap();
+    /// let mut monitor = executor.spawn_abortable(futures::empty::<(), ()>());
+    /// monitor.abort();
+    /// assert_eq!(executor.run_fiber(monitor).unwrap(), Err(MonitorError::Aborted));
+    /// ```
+    fn spawn_abortable<F, T, E>(&self, f: F) -> Monitor<T, E>
+    where
+        F: Future<Item = T, Error = E> + Send + 'static,
+        T: Send + 'static,
+        E: Send + 'static,
+    {
+        let (monitored, mut monitor) = oneshot::monitor();
+        let fiber = f.then(move |r| {
+            monitored.exit(r);
+            Ok(())
+        });
+        self.spawn(Abortable::new(fiber, &mut monitor));
+        monitor
+    }
+
+    /// Spawns a fiber which will be cancelled if it does not complete by `deadline`.
+    ///
+    /// If the deadline expires, the future `f` is dropped without being polled
+    /// at the next time the fiber is resumed (i.e., at its suspension point) and
+    /// the returning monitor results in `MonitorError::DeadlineExceeded`.
+    /// Thus, supervisors can distinguish timeouts from crashes (`MonitorError::Aborted`).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate fibers;
+    /// # extern crate futures;
+    /// use std::time::{Duration, Instant};
+    /// use fibers::{Executor, InPlaceExecutor, Spawn};
+    /// use fibers::sync::oneshot::MonitorError;
+    ///
+    /// let mut executor = InPlaceExecutor::new().unwrap();
+    /// let deadline = Instant::now() + Duration::from_millis(10);
+    /// let monitor = executor.spawn_monitor_with_deadline(futures::empty::<(), ()>(), deadline);
+    /// assert_eq!(executor.run_fiber(monitor).unwrap(), Err(MonitorError::DeadlineExceeded));
+    /// ```
+    fn spawn_monitor_with_deadline<F, T, E>(&self, f: F, deadline: time::Instant) -> Monitor<T, E>
+    where
+        F: Future<Item = T, Error = E> + Send + 'static,
+        T: Send + 'static,
+        E: Send + 'static,
+    {
+        let (monitored, monitor) = oneshot::monitor();
+        let delay = deadline.saturating_duration_since(time::Instant::now());
+        self.spawn(WithDeadline {
+            inner: f,
+            timeout: timer::timeout(delay),
+            monitored: Some(monitored),
+        });
+        monitor
+    }
+
     /// Spawns a linked fiber.
     ///
     /// If the returning `Link` is dropped, the spawned fiber will terminate.
@@ -120,6 +236,30 @@ pub trait Spawn {
     {
         BoxSpawn(Box::new(move |fiber| self.spawn_boxed(fiber)))
     }
+
+    /// Makes a builder to spawn a fiber with options (e.g., a name and a deadline).
+    ///
+    /// The location of the caller is recorded as the spawn site of the fiber
+    /// (see `Context::spawn_site`).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate fibers;
+    /// # extern crate futures;
+    /// use fibers::{fiber, Executor, InPlaceExecutor, Spawn};
+    ///
+    /// let mut executor = InPlaceExecutor::new().unwrap();
+    /// let monitor = executor.build_fiber().name("conn-123").spawn_monitor(futures::lazy(|| {
+    ///     let name = fiber::with_current_context(|c| c.name().map(|n| n.to_owned()));
+    ///     Ok::<_, ()>(name.unwrap())
+    /// }));
+    /// assert_eq!(executor.run_fiber(monitor).unwrap(), Ok(Some("conn-123".to_owned())));
+    /// ```
+    #[track_caller]
+    fn build_fiber(&self) -> FiberBuilder<'_, Self> {
+        FiberBuilder::new(self, Location::caller())
+    }
 }
 
 type BoxFn = Box<dyn Fn(Box<dyn Future<Item = (), Error = ()> + Send>) + Send + 'static>;
@@ -143,21 +283,240 @@ impl fmt::Debug for BoxSpawn {
     }
 }
 
+/// The kind of the resource on which a fiber is suspended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum WaitKind {
+    /// Waiting for an I/O object to become readable.
+    Read,
+
+    /// Waiting for an I/O object to become writable.
+    Write,
+
+    /// Waiting for a timer to expire.
+    Timer,
+
+    /// Waiting for a message on a channel (including monitors and links).
+    Channel,
+
+    /// Parked explicitly by calling `Context::park` method.
+    Other,
+}
+impl WaitKind {
+    pub(crate) const ALL: [WaitKind; 5] = [
+        WaitKind::Read,
+        WaitKind::Write,
+        WaitKind::Timer,
+        WaitKind::Channel,
+        WaitKind::Other,
+    ];
+
+    fn from_index(index: usize) -> Option<Self> {
+        Self::ALL.get(index).cloned()
+    }
+
+    pub(crate) fn label(self) -> &'static str {
+        match self {
+            WaitKind::Read => "read",
+            WaitKind::Write => "write",
+            WaitKind::Timer => "timer",
+            WaitKind::Channel => "channel",
+            WaitKind::Other => "other",
+        }
+    }
+}
+
+/// The breakdown of the time a fiber has spent suspended, categorized by `WaitKind`.
+///
+/// Each suspension is attributed to the kind of the resource which woke the fiber up.
+///
+/// This is recorded only for the fibers which have called `Context::enable_wait_stats` method.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate fibers;
+/// # extern crate futures;
+/// use std::time::Duration;
+/// use fibers::{fiber, Executor, InPlaceExecutor, Spawn};
+/// use fibers::fiber::WaitKind;
+/// use fibers::time::timer;
+/// use futures::{self, Future};
+///
+/// let mut executor = InPlaceExecutor::new().unwrap();
+/// let monitor = executor.spawn_monitor(futures::lazy(|| {
+///     fiber::with_current_context(|mut c| c.enable_wait_stats());
+///     timer::timeout(Duration::from_millis(10))
+/// }).map(|()| {
+///     assert_some_stats();
+/// }));
+/// executor.run_fiber(monitor).unwrap().unwrap();
+///
+/// fn assert_some_stats() {
+///     let stats = fiber::with_current_context(|c| c.wait_stats().cloned()).unwrap().unwrap();
+///     assert!(stats.wait_time(WaitKind::Timer) >= Duration::from_millis(10));
+///     assert_eq!(stats.wait_time(WaitKind::Read), Duration::from_secs(0));
+/// }
+/// ```
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct WaitStats {
+    wait_times: [time::Duration; 5],
+    wait_counts: [u64; 5],
+}
+impl WaitStats {
+    /// Returns the total time spent waiting on the resources of the specified kind.
+    pub fn wait_time(&self, kind: WaitKind) -> time::Duration {
+        self.wait_times[kind as usize]
+    }
+
+    /// Returns the number of suspensions which were resumed by the resources of the specified kind.
+    pub fn wait_count(&self, kind: WaitKind) -> u64 {
+        self.wait_counts[kind as usize]
+    }
+
+    /// Returns the total time spent suspended.
+    pub fn total_wait_time(&self) -> time::Duration {
+        self.wait_times.iter().sum()
+    }
+
+    fn record(&mut self, kind: WaitKind, elapsed: time::Duration) {
+        self.wait_times[kind as usize] += elapsed;
+        self.wait_counts[kind as usize] += 1;
+    }
+
+    fn merge(&mut self, other: &WaitStats) {
+        for i in 0..self.wait_times.len() {
+            self.wait_times[i] += other.wait_times[i];
+            self.wait_counts[i] += other.wait_counts[i];
+        }
+    }
+}
+
+/// A charge of memory usage to a fiber.
+///
+/// This is created by calling `Context::charge_memory` method,
+/// and the charged bytes are attributed to the fiber until this object is dropped.
+/// The object may be moved to other fibers (e.g., together with the charged buffer),
+/// but the charge remains attributed to the original fiber.
+///
+/// It is intended to be used by buffers and buffer pools to account the memory they hold,
+/// so that the fibers hoarding memory can be found via `SchedulerSnapshot`.
+///
+/// Note that the accounting is manual only: nothing is charged unless a charge is made
+/// explicitly by `Context::charge_memory`.
+/// In particular, the buffers of this crate (e.g., `io::AdaptiveReadBuf`,
+/// `net::CoalescingWriter`, `net::Reassembler` and the receive buffer of
+/// `net::rudp::RudpSocket`) do not charge the memory they hold.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate fibers;
+/// # extern crate futures;
+/// use fibers::{fiber, Executor, InPlaceExecutor, Spawn};
+/// use futures::{self, Future};
+///
+/// let mut executor = InPlaceExecutor::new().unwrap();
+/// let monitor = executor.spawn_monitor(futures::lazy(|| {
+///     let buf = vec![0u8; 1024];
+///     let mut charge = fiber::with_current_context(|mut c| c.charge_memory(buf.len())).unwrap();
+///     assert_eq!(fiber::with_current_context(|c| c.memory_usage()), Some(1024));
+///
+///     charge.resize(4096);
+///     assert_eq!(fiber::with_current_context(|c| c.memory_usage()), Some(4096));
+///
+///     drop(charge);
+///     assert_eq!(fiber::with_current_context(|c| c.memory_usage()), Some(0));
+///     Ok(()) as Result<(), ()>
+/// }));
+/// executor.run_fiber(monitor).unwrap().unwrap();
+/// ```
+#[derive(Debug)]
+pub struct MemoryCharge {
+    bytes: usize,
+    usage: Arc<AtomicUsize>,
+}
+impl MemoryCharge {
+    /// Returns the number of the bytes charged by this object.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Changes the number of the bytes charged by this object.
+    pub fn resize(&mut self, bytes: usize) {
+        if bytes > self.bytes {
+            self.usage
+                .fetch_add(bytes - self.bytes, atomic::Ordering::SeqCst);
+        } else {
+            self.usage
+                .fetch_sub(self.bytes - bytes, atomic::Ordering::SeqCst);
+        }
+        self.bytes = bytes;
+    }
+}
+impl Drop for MemoryCharge {
+    fn drop(&mut self) {
+        self.usage.fetch_sub(self.bytes, atomic::Ordering::SeqCst);
+    }
+}
+
+/// A snapshot of the state of a fiber.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct FiberSnapshot {
+    /// The identifier of the fiber.
+    pub fiber_id: FiberId,
+
+    /// The number of the bytes currently charged to the fiber via `Context::charge_memory`.
+    pub memory_usage: usize,
+
+    /// The wait statistics of the fiber (if enabled).
+    pub wait_stats: Option<WaitStats>,
+
+    /// The location where the fiber was spawned (if recorded).
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "crate::metrics::serialize_spawn_site")
+    )]
+    pub spawn_site: Option<&'static Location<'static>>,
+
+    /// The name of the fiber (if given via `FiberBuilder::name`).
+    pub name: Option<String>,
+}
+
+const NOT_WOKEN: usize = usize::MAX;
+
 #[derive(Debug)]
 struct FiberState {
     pub fiber_id: FiberId,
     task: Task,
     parks: usize,
     unparks: Arc<AtomicUsize>,
+    woken_by: Arc<AtomicUsize>,
+    suspended_at: Option<time::Instant>,
+    wait_stats: Option<WaitStats>,
+    memory_usage: Arc<AtomicUsize>,
+    spawn_site: Option<&'static Location<'static>>,
+    name: Option<String>,
+    spawned_at: Option<time::Instant>,
     pub in_run_queue: bool,
 }
 impl FiberState {
     pub fn new(fiber_id: FiberId, task: Task) -> Self {
+        let spawned_at = Some(task.1);
         FiberState {
             fiber_id,
             task,
             parks: 0,
             unparks: Arc::new(AtomicUsize::new(0)),
+            woken_by: Arc::new(AtomicUsize::new(NOT_WOKEN)),
+            suspended_at: None,
+            wait_stats: None,
+            memory_usage: Arc::new(AtomicUsize::new(0)),
+            spawn_site: None,
+            name: None,
+            spawned_at,
             in_run_queue: false,
         }
     }
@@ -166,7 +525,16 @@ impl FiberState {
             self.parks -= 1;
             self.unparks.fetch_sub(1, atomic::Ordering::SeqCst);
         }
-        !matches!(self.task.0.poll(), Ok(Async::NotReady))
+        self.record_resumption();
+        let finished = !matches!(self.task.0.poll(), Ok(Async::NotReady));
+        if !finished && self.wait_stats.is_some() && !self.is_runnable() {
+            self.suspended_at = Some(time::Instant::now());
+        }
+        finished
+    }
+    /// Returns the time elapsed since the fiber was spawned if it has never been polled.
+    pub fn take_first_poll_latency(&mut self) -> Option<time::Duration> {
+        self.spawned_at.take().map(|t| t.elapsed())
     }
     pub fn is_runnable(&self) -> bool {
         self.parks == 0 || self.unparks.load(atomic::Ordering::SeqCst) > 0
@@ -175,15 +543,46 @@ impl FiberState {
         &mut self,
         scheduler_id: schedule::SchedulerId,
         scheduler: schedule::SchedulerHandle,
+        kind: WaitKind,
     ) -> Unpark {
         self.parks += 1;
         Unpark {
             fiber_id: self.fiber_id,
+            kind,
             unparks: Arc::clone(&self.unparks),
+            woken_by: Arc::clone(&self.woken_by),
             scheduler_id,
             scheduler,
         }
     }
+    pub fn charge_memory(&self, bytes: usize) -> MemoryCharge {
+        self.memory_usage.fetch_add(bytes, atomic::Ordering::SeqCst);
+        MemoryCharge {
+            bytes,
+            usage: Arc::clone(&self.memory_usage),
+        }
+    }
+    pub fn memory_usage(&self) -> usize {
+        self.memory_usage.load(atomic::Ordering::SeqCst)
+    }
+    pub fn snapshot(&self) -> FiberSnapshot {
+        FiberSnapshot {
+            fiber_id: self.fiber_id,
+            memory_usage: self.memory_usage(),
+            wait_stats: self.wait_stats.clone(),
+            spawn_site: self.spawn_site,
+            name: self.name.clone(),
+        }
+    }
+    fn record_resumption(&mut self) {
+        let woken_by = self.woken_by.swap(NOT_WOKEN, atomic::Ordering::SeqCst);
+        if let Some(suspended_at) = self.suspended_at.take() {
+            let kind = WaitKind::from_index(woken_by).unwrap_or(WaitKind::Other);
+            if let Some(stats) = self.wait_stats.as_mut() {
+                stats.record(kind, suspended_at.elapsed());
+            }
+        }
+    }
     pub fn yield_once(&mut self) {
         self.parks += 1;
         self.unparks.fetch_add(1, atomic::Ordering::SeqCst);
@@ -198,7 +597,9 @@ impl FiberState {
 #[derive(Debug)]
 pub struct Unpark {
     fiber_id: FiberId,
+    kind: WaitKind,
     unparks: Arc<AtomicUsize>,
+    woken_by: Arc<AtomicUsize>,
     scheduler_id: schedule::SchedulerId,
     scheduler: schedule::SchedulerHandle,
 }
@@ -207,11 +608,22 @@ impl Unpark {
     pub fn context_id(&self) -> ContextId {
         (self.scheduler_id, self.fiber_id)
     }
+
+    /// Returns the kind of the resource on which the fiber is waiting.
+    pub fn kind(&self) -> WaitKind {
+        self.kind
+    }
 }
 impl Drop for Unpark {
     fn drop(&mut self) {
         let old = self.unparks.fetch_add(1, atomic::Ordering::SeqCst);
         if old == 0 {
+            let _ = self.woken_by.compare_exchange(
+                NOT_WOKEN,
+                self.kind as usize,
+                atomic::Ordering::SeqCst,
+                atomic::Ordering::SeqCst,
+            );
             self.scheduler.wakeup(self.fiber_id);
         }
     }
@@ -219,13 +631,120 @@ impl Drop for Unpark {
 
 pub(crate) type FiberFuture = Box<dyn Future<Item = (), Error = ()> + Send>;
 
-pub(crate) struct Task(pub FiberFuture);
+/// A fiber to be spawned and the time when it was spawned.
+pub(crate) struct Task(pub FiberFuture, pub time::Instant);
+impl Task {
+    pub fn new(future: FiberFuture) -> Self {
+        Task(future, time::Instant::now())
+    }
+}
 impl fmt::Debug for Task {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "Task(_)")
     }
 }
 
+/// A future which records the spawn site (and the name) of the running fiber
+/// when it is polled first.
+struct SpawnSite<F> {
+    site: Option<&'static Location<'static>>,
+    name: Option<String>,
+    inner: F,
+}
+impl<F> SpawnSite<F> {
+    fn new(site: &'static Location<'static>, inner: F) -> Self {
+        SpawnSite {
+            site: Some(site),
+            name: None,
+            inner,
+        }
+    }
+}
+impl<F: Future> Future for SpawnSite<F> {
+    type Item = F::Item;
+    type Error = F::Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if let Some(site) = self.site.take() {
+            let name = self.name.take();
+            with_current_context(|mut c| {
+                c.set_spawn_site(site);
+                if let Some(name) = name {
+                    c.set_name(name);
+                }
+            });
+        }
+        self.inner.poll()
+    }
+}
+
+/// A future which executes `inner` until the deadline and notifies the result to the monitor.
+struct WithDeadline<F: Future> {
+    inner: F,
+    timeout: timer::Timeout,
+    monitored: Option<oneshot::Monitored<F::Item, F::Error>>,
+}
+impl<F: Future> Future for WithDeadline<F> {
+    type Item = ();
+    type Error = ();
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        // NOTE: The deadline is checked first, so that `inner` is never resumed after the expiration.
+        if !matches!(self.timeout.poll(), Ok(Async::NotReady)) {
+            let monitored = self
+                .monitored
+                .take()
+                .expect("Cannot poll WithDeadline twice");
+            monitored.exit_deadline_exceeded();
+            return Ok(Async::Ready(()));
+        }
+        let result = match self.inner.poll() {
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Ok(Async::Ready(v)) => Ok(v),
+            Err(e) => Err(e),
+        };
+        let monitored = self
+            .monitored
+            .take()
+            .expect("Cannot poll WithDeadline twice");
+        monitored.exit(result);
+        Ok(Async::Ready(()))
+    }
+}
+
+/// A fiber which is dropped when the abort is requested via its monitor (see `Monitor::abort`).
+pub(crate) struct Abortable<F> {
+    inner: F,
+    abort_rx: Option<oneshot::Receiver<()>>,
+}
+impl<F> Abortable<F> {
+    pub fn new<T, E>(inner: F, monitor: &mut Monitor<T, E>) -> Self {
+        let (abort_tx, abort_rx) = oneshot::channel();
+        monitor.set_abort_handle(abort_tx);
+        Abortable {
+            inner,
+            abort_rx: Some(abort_rx),
+        }
+    }
+}
+impl<F: Future<Item = (), Error = ()>> Future for Abortable<F> {
+    type Item = ();
+    type Error = ();
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match self.abort_rx.as_mut().map(Future::poll) {
+            Some(Ok(Async::Ready(()))) => {
+                // Dropping `inner` (which owns the `Monitored`) makes the monitor
+                // result in `MonitorError::Aborted`.
+                return Ok(Async::Ready(()));
+            }
+            Some(Err(_)) => {
+                // The monitor has been dropped, so the fiber can no longer be aborted.
+                self.abort_rx = None;
+            }
+            _ => {}
+        }
+        self.inner.poll()
+    }
+}
+
 struct SelectEither<A, B>(Option<(A, B)>);
 impl<A: Future, B: Future> SelectEither<A, B> {
     fn new(a: A, b: B) -> Self {
@@ -251,3 +770,49 @@ impl<A: Future, B: Future> Future for SelectEither<A, B> {
         Ok(Async::NotReady)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::executor::{Executor, InPlaceExecutor};
+    use crate::sync::oneshot::MonitorError;
+
+    #[test]
+    fn expired_fiber_is_not_polled() {
+        let mut executor = InPlaceExecutor::new().unwrap();
+        let polls = Arc::new(AtomicUsize::new(0));
+        let future = {
+            let polls = Arc::clone(&polls);
+            futures::future::poll_fn(move || {
+                polls.fetch_add(1, Ordering::SeqCst);
+                Ok::<_, ()>(Async::NotReady::<()>)
+            })
+        };
+        let deadline = Instant::now() - Duration::from_millis(1);
+        let monitor = executor.spawn_monitor_with_deadline(future, deadline);
+        assert_eq!(
+            executor.run_fiber(monitor).unwrap(),
+            Err(MonitorError::DeadlineExceeded)
+        );
+        assert_eq!(polls.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn wait_stats_of_exited_fibers_are_retained() {
+        let mut executor = InPlaceExecutor::new().unwrap();
+        let monitor = executor.spawn_monitor(futures::lazy(|| {
+            with_current_context(|mut c| c.enable_wait_stats());
+            timer::timeout(Duration::from_millis(10)).map_err(|_| ())
+        }));
+        executor.run_fiber(monitor).unwrap().unwrap();
+
+        let snapshot = executor.scheduler_snapshot();
+        assert!(snapshot.fibers.is_empty());
+        assert!(snapshot.wait_stats.total_wait_time() >= Duration::from_millis(10));
+        assert_eq!(snapshot.wait_stats.wait_count(WaitKind::Timer), 1);
+    }
+}
@@ -3,13 +3,20 @@
 
 use futures::{Async, Future, Poll};
 use std::cell::RefCell;
+use std::cmp;
 use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe, Location};
 use std::sync::atomic;
 use std::sync::mpsc as std_mpsc;
+use std::sync::Arc;
+use std::time;
 
-use super::{FiberState, Spawn};
+use super::{FiberState, Spawn, WaitKind, WaitStats};
 use crate::fiber::{self, Task};
 use crate::io::poll;
+use crate::sync::oneshot;
+use crate::trace::{TraceEventKind, TraceRing};
 
 static NEXT_SCHEDULER_ID: atomic::AtomicUsize = atomic::AtomicUsize::new(0);
 
@@ -34,7 +41,7 @@ pub type SchedulerId = usize;
 /// will be popped and executed (i.e., `Future::poll` method is called).
 /// If the future of a fiber moves to readied state,
 /// it will be removed from the scheduler.
-
+///
 /// For efficiency reasons, it is recommended to run a scheduler on a dedicated thread.
 #[derive(Debug)]
 pub struct Scheduler {
@@ -45,6 +52,16 @@ pub struct Scheduler {
     request_tx: RequestSender,
     request_rx: RequestReceiver,
     poller: poll::PollerHandle,
+    wakes_poller: bool,
+    #[cfg(any(test, feature = "testing"))]
+    dropping_wakeups: usize,
+    trace: Option<TraceRing>,
+    running_fiber: Option<fiber::FiberId>,
+    catches_fiber_panics: bool,
+    first_poll: FirstPollStats,
+    exited_wait_stats: fiber::WaitStats,
+    first_poll_threshold: Option<time::Duration>,
+    slow_first_poll_callback: Option<SlowFirstPollCallback>,
 }
 impl Scheduler {
     /// Creates a new scheduler instance.
@@ -58,6 +75,16 @@ impl Scheduler {
             request_tx,
             request_rx,
             poller,
+            wakes_poller: false,
+            #[cfg(any(test, feature = "testing"))]
+            dropping_wakeups: 0,
+            trace: None,
+            running_fiber: None,
+            catches_fiber_panics: false,
+            first_poll: FirstPollStats::default(),
+            exited_wait_stats: fiber::WaitStats::default(),
+            first_poll_threshold: None,
+            slow_first_poll_callback: None,
         }
     }
 
@@ -76,10 +103,97 @@ impl Scheduler {
         self.fibers.len()
     }
 
+    /// Returns a snapshot of the state of this scheduler and its fibers.
+    pub fn snapshot(&self) -> SchedulerSnapshot {
+        let mut fibers = self
+            .fibers
+            .values()
+            .map(|f| f.snapshot())
+            .collect::<Vec<_>>();
+        fibers.sort_by_key(|f| f.fiber_id);
+        let mut wait_stats = self.exited_wait_stats.clone();
+        for stats in fibers.iter().filter_map(|f| f.wait_stats.as_ref()) {
+            wait_stats.merge(stats);
+        }
+        SchedulerSnapshot {
+            scheduler_id: self.scheduler_id,
+            run_queue_len: self.run_queue.len(),
+            fibers,
+            first_poll: self.first_poll.clone(),
+            wait_stats,
+        }
+    }
+
     /// Returns a handle of this scheduler.
     pub fn handle(&self) -> SchedulerHandle {
         SchedulerHandle {
             request_tx: self.request_tx.clone(),
+            poller: if self.wakes_poller {
+                Some(self.poller.clone())
+            } else {
+                None
+            },
+        }
+    }
+
+    /// Sets the ring buffer to record the scheduling events of this scheduler.
+    ///
+    /// `None` disables the tracing.
+    pub fn set_trace_ring(&mut self, ring: Option<TraceRing>) {
+        self.trace = ring;
+    }
+
+    /// Sets the threshold of the spawn-to-first-poll latency of the fibers.
+    ///
+    /// The latency is the time elapsed from the spawn of a fiber
+    /// (i.e., the call of `Spawn::spawn_boxed`) to the first poll of it.
+    /// It includes the time the fiber has waited in the queues of the executor and this scheduler,
+    /// and thus steadily high latencies indicate that this scheduler is overloaded.
+    ///
+    /// If the latency of a fiber exceeds the threshold, `FirstPollStats::slow_fibers` is incremented
+    /// and the callback set by `on_slow_first_poll` (if any) is invoked.
+    ///
+    /// `None` means that there is no threshold (the default).
+    pub fn set_first_poll_threshold(&mut self, threshold: Option<time::Duration>) {
+        self.first_poll_threshold = threshold;
+    }
+
+    /// Sets the callback invoked when the spawn-to-first-poll latency of a fiber exceeds
+    /// the threshold (see `set_first_poll_threshold`).
+    ///
+    /// The callback is invoked with the snapshot of the fiber and its latency,
+    /// on the thread running this scheduler just after the first poll of the fiber,
+    /// so it should not block.
+    pub fn on_slow_first_poll<F>(&mut self, f: F)
+    where
+        F: Fn(&fiber::FiberSnapshot, time::Duration) + Send + Sync + 'static,
+    {
+        self.slow_first_poll_callback = Some(SlowFirstPollCallback(Arc::new(f)));
+    }
+
+    /// Makes the requests to this scheduler (e.g., wakeups of fibers) wake up the poller.
+    ///
+    /// This is needed if the thread running this scheduler blocks in the poller
+    /// (see `fibers::ffi`). Only the handles created after this call are affected.
+    #[cfg(feature = "ffi")]
+    pub(crate) fn set_wakes_poller(&mut self, enabled: bool) {
+        self.wakes_poller = enabled;
+    }
+
+    /// Makes this scheduler catch the panics of the fibers.
+    ///
+    /// If a fiber panics while being polled, the fiber is dropped
+    /// (i.e., its monitors result in `MonitorError::Aborted`) and the panic is not propagated
+    /// to the caller of `run_once`.
+    pub(crate) fn set_catches_fiber_panics(&mut self, enabled: bool) {
+        self.catches_fiber_panics = enabled;
+    }
+
+    /// Handles all the requests which have been sent to this scheduler.
+    #[cfg(feature = "ffi")]
+    pub(crate) fn handle_pending_requests(&mut self) {
+        while let Ok(request) = self.request_rx.try_recv() {
+            self.handle_request(request);
         }
     }
 
@@ -116,20 +230,48 @@ impl Scheduler {
     fn handle_request(&mut self, request: Request) {
         match request {
             Request::Spawn(task) => self.spawn_fiber(task),
+            #[cfg(any(test, feature = "testing"))]
+            Request::WakeUp(_) if self.dropping_wakeups > 0 => {
+                self.dropping_wakeups -= 1;
+            }
             Request::WakeUp(fiber_id) => {
+                self.trace(TraceEventKind::WakeUp { fiber_id });
                 if self.fibers.contains_key(&fiber_id) {
                     self.schedule(fiber_id);
                 }
             }
+            Request::Snapshot(reply) => {
+                let _ = reply.send(self.snapshot());
+            }
+            Request::SetTraceRing(ring) => {
+                self.trace = ring;
+            }
+            Request::SetFirstPollThreshold(threshold) => {
+                self.first_poll_threshold = threshold;
+            }
+            Request::SetSlowFirstPollCallback(callback) => {
+                self.slow_first_poll_callback = Some(callback);
+            }
+            #[cfg(any(test, feature = "testing"))]
+            Request::DropWakeups(count) => {
+                self.dropping_wakeups += count;
+            }
+            #[cfg(any(test, feature = "testing"))]
+            Request::Abort => {
+                panic!("The scheduler {} is aborted on purpose", self.scheduler_id);
+            }
         }
     }
     fn spawn_fiber(&mut self, task: Task) {
         let fiber_id = self.next_fiber_id();
         self.fibers
             .insert(fiber_id, fiber::FiberState::new(fiber_id, task));
+        self.trace(TraceEventKind::Spawn { fiber_id });
         self.schedule(fiber_id);
     }
     fn run_fiber(&mut self, fiber_id: fiber::FiberId) {
+        let first_poll_latency =
+            assert_some!(self.fibers.get_mut(&fiber_id)).take_first_poll_latency();
         let finished;
         let is_runnable = {
             CURRENT_CONTEXT.with(|context| {
@@ -137,7 +279,7 @@ impl Scheduler {
                 if context
                     .scheduler
                     .as_ref()
-                    .map_or(true, |s| s.id != self.scheduler_id)
+                    .is_none_or(|s| s.id != self.scheduler_id)
                 {
                     context.switch(self);
                 }
@@ -153,19 +295,66 @@ impl Scheduler {
                 let fiber = assert_some!(self.fibers.get_mut(&fiber_id));
                 context.fiber = Some(fiber as _);
             });
+            self.trace(TraceEventKind::PollStart { fiber_id });
+            let fiber = assert_some!(self.fibers.get_mut(&fiber_id));
+            self.running_fiber = Some(fiber_id);
+            finished = if self.catches_fiber_panics {
+                // The panicking fiber is regarded as finished (i.e., it is dropped).
+                panic::catch_unwind(AssertUnwindSafe(|| fiber.run_once())).unwrap_or(true)
+            } else {
+                fiber.run_once()
+            };
+            self.trace(TraceEventKind::PollEnd { fiber_id, finished });
+            if let Some(latency) = first_poll_latency {
+                self.record_first_poll(fiber_id, latency);
+            }
+            self.running_fiber = None;
             let fiber = assert_some!(self.fibers.get_mut(&fiber_id));
-            finished = fiber.run_once();
             CURRENT_CONTEXT.with(|context| {
                 context.borrow_mut().fiber = None;
             });
             fiber.is_runnable()
         };
         if finished {
-            self.fibers.remove(&fiber_id);
+            self.remove_fiber(fiber_id);
         } else if is_runnable {
             self.schedule(fiber_id);
         }
     }
+    /// Recovers this scheduler from a panic which has occurred during `run_once`,
+    /// so that it can be run on another thread.
+    ///
+    /// If the panic occurred while running a fiber (e.g., in the callback of `on_slow_first_poll`),
+    /// the fiber is dropped.
+    pub(crate) fn recover_from_panic(&mut self) {
+        if let Some(fiber_id) = self.running_fiber.take() {
+            self.remove_fiber(fiber_id);
+        }
+    }
+    fn remove_fiber(&mut self, fiber_id: fiber::FiberId) {
+        let fiber = assert_some!(self.fibers.remove(&fiber_id));
+        if let Some(ref stats) = fiber.wait_stats {
+            self.exited_wait_stats.merge(stats);
+        }
+    }
+    fn record_first_poll(&mut self, fiber_id: fiber::FiberId, latency: time::Duration) {
+        let fiber = assert_some!(self.fibers.get(&fiber_id));
+        let stats = &mut self.first_poll;
+        stats.fibers += 1;
+        stats.total_latency += latency;
+        stats.max_latency = cmp::max(stats.max_latency, latency);
+        if self.first_poll_threshold.is_some_and(|t| latency > t) {
+            stats.slow_fibers += 1;
+            if let Some(ref callback) = self.slow_first_poll_callback {
+                (callback.0)(&fiber.snapshot(), latency);
+            }
+        }
+    }
+    fn trace(&self, event: TraceEventKind) {
+        if let Some(ref trace) = self.trace {
+            trace.record(Some(self.scheduler_id), event);
+        }
+    }
     fn next_fiber_id(&mut self) -> fiber::FiberId {
         loop {
             let id = self.next_fiber_id;
@@ -197,18 +386,120 @@ impl Scheduler {
 #[derive(Debug, Clone)]
 pub struct SchedulerHandle {
     request_tx: RequestSender,
+    poller: Option<poll::PollerHandle>,
 }
 impl SchedulerHandle {
+    fn send(&self, request: Request) {
+        let _ = self.request_tx.send(request);
+        if let Some(ref poller) = self.poller {
+            poller.wake();
+        }
+    }
+
     /// Wakes up a specified fiber in the scheduler.
     ///
     /// This forces the fiber to be pushed to the run queue of the scheduler.
     pub fn wakeup(&self, fiber_id: fiber::FiberId) {
-        let _ = self.request_tx.send(Request::WakeUp(fiber_id));
+        self.send(Request::WakeUp(fiber_id));
+    }
+
+    /// Makes a future which will take a snapshot of the state of the scheduler.
+    ///
+    /// If the scheduler has been dropped, the future will result in an error.
+    pub fn snapshot(&self) -> oneshot::Receiver<SchedulerSnapshot> {
+        let (tx, rx) = oneshot::channel();
+        self.send(Request::Snapshot(tx));
+        rx
+    }
+
+    /// Sets the ring buffer to record the scheduling events of the scheduler.
+    ///
+    /// `None` disables the tracing.
+    pub fn set_trace_ring(&self, ring: Option<TraceRing>) {
+        self.send(Request::SetTraceRing(ring));
     }
+
+    /// Sets the threshold of the spawn-to-first-poll latency of the fibers in the scheduler.
+    ///
+    /// See `Scheduler::set_first_poll_threshold` for more details.
+    pub fn set_first_poll_threshold(&self, threshold: Option<time::Duration>) {
+        self.send(Request::SetFirstPollThreshold(threshold));
+    }
+
+    /// Sets the callback invoked when the spawn-to-first-poll latency of a fiber exceeds
+    /// the threshold.
+    ///
+    /// See `Scheduler::on_slow_first_poll` for more details.
+    pub fn on_slow_first_poll<F>(&self, f: F)
+    where
+        F: Fn(&fiber::FiberSnapshot, time::Duration) + Send + Sync + 'static,
+    {
+        let callback = SlowFirstPollCallback(Arc::new(f));
+        self.send(Request::SetSlowFirstPollCallback(callback));
+    }
+
+    /// Sends a task to the scheduler, preserving its spawn time.
+    pub(crate) fn spawn_task(&self, task: Task) {
+        self.send(Request::Spawn(task));
+    }
+
+    /// Makes the scheduler ignore the next `count` wakeup requests (for fault injection).
+    #[cfg(any(test, feature = "testing"))]
+    pub(crate) fn drop_wakeups(&self, count: usize) {
+        self.send(Request::DropWakeups(count));
+    }
+
+    /// Makes the scheduler panic (for fault injection).
+    #[cfg(any(test, feature = "testing"))]
+    pub(crate) fn abort(&self) {
+        self.send(Request::Abort);
+    }
+}
+
+/// A snapshot of the state of a scheduler.
+///
+/// This is created by calling `Scheduler::snapshot` or `SchedulerHandle::snapshot` method.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct SchedulerSnapshot {
+    /// The identifier of the scheduler.
+    pub scheduler_id: SchedulerId,
+
+    /// The length of the run queue of the scheduler.
+    pub run_queue_len: usize,
+
+    /// The snapshots of the alive fibers in the scheduler (ordered by their identifiers).
+    pub fibers: Vec<fiber::FiberSnapshot>,
+
+    /// The statistics of the spawn-to-first-poll latencies of the fibers.
+    pub first_poll: FirstPollStats,
+
+    /// The wait statistics accumulated over all the fibers (including the exited ones)
+    /// which have enabled their wait statistics (see `Context::enable_wait_stats`).
+    pub wait_stats: fiber::WaitStats,
+}
+
+/// Statistics of the spawn-to-first-poll latencies of the fibers in a scheduler.
+///
+/// See `Scheduler::set_first_poll_threshold` for more details.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct FirstPollStats {
+    /// The number of the fibers which have been polled at least once.
+    pub fibers: u64,
+
+    /// The sum of the latencies of the fibers.
+    pub total_latency: time::Duration,
+
+    /// The maximum latency of the fibers.
+    pub max_latency: time::Duration,
+
+    /// The number of the fibers whose latency exceeded the threshold.
+    pub slow_fibers: u64,
 }
 impl Spawn for SchedulerHandle {
     fn spawn_boxed(&self, fiber: Box<dyn Future<Item = (), Error = ()> + Send>) {
-        let _ = self.request_tx.send(Request::Spawn(Task(fiber)));
+        self.spawn_task(Task::new(fiber));
     }
 }
 
@@ -243,8 +534,68 @@ impl<'a> Context<'a> {
 
     /// Parks the current fiber.
     pub fn park(&mut self) -> super::Unpark {
+        self.park_for(WaitKind::Other)
+    }
+
+    /// Parks the current fiber to wait for a resource of the specified kind.
+    pub fn park_for(&mut self, kind: WaitKind) -> super::Unpark {
         self.fiber
-            .park(self.scheduler.id, self.scheduler.handle.clone())
+            .park(self.scheduler.id, self.scheduler.handle.clone(), kind)
+    }
+
+    /// Starts recording the wait statistics of the current fiber.
+    ///
+    /// If the recording has already been enabled, this method does nothing.
+    pub fn enable_wait_stats(&mut self) {
+        if self.fiber.wait_stats.is_none() {
+            self.fiber.wait_stats = Some(WaitStats::default());
+        }
+    }
+
+    /// Charges `bytes` of memory usage to the current fiber.
+    ///
+    /// The charge is released when the returned object is dropped.
+    ///
+    /// The memory usage of a fiber only reflects the charges made by this method
+    /// (see `MemoryCharge` for more details).
+    pub fn charge_memory(&mut self, bytes: usize) -> super::MemoryCharge {
+        self.fiber.charge_memory(bytes)
+    }
+
+    /// Returns the number of the bytes currently charged to the current fiber.
+    pub fn memory_usage(&self) -> usize {
+        self.fiber.memory_usage()
+    }
+
+    /// Returns the wait statistics of the current fiber.
+    ///
+    /// If `enable_wait_stats` has not been called in the fiber, this will return `None`.
+    pub fn wait_stats(&self) -> Option<&WaitStats> {
+        self.fiber.wait_stats.as_ref()
+    }
+
+    /// Returns the location where the current fiber was spawned.
+    ///
+    /// The location is recorded only for the fibers spawned by
+    /// `Spawn::spawn_fn` or `Spawn::spawn_monitor_fn` method, or via the `FiberBuilder`
+    /// made by `Spawn::build_fiber` method (the location where `build_fiber` is called).
+    pub fn spawn_site(&self) -> Option<&'static Location<'static>> {
+        self.fiber.spawn_site
+    }
+
+    pub(crate) fn set_spawn_site(&mut self, site: &'static Location<'static>) {
+        self.fiber.spawn_site = Some(site);
+    }
+
+    /// Returns the name of the current fiber.
+    ///
+    /// The name is recorded only for the fibers spawned with a name via `FiberBuilder`.
+    pub fn name(&self) -> Option<&str> {
+        self.fiber.name.as_deref()
+    }
+
+    pub(crate) fn set_name(&mut self, name: String) {
+        self.fiber.name = Some(name);
     }
 
     /// Returns the I/O event poller for this context.
@@ -321,7 +672,7 @@ impl InnerContext {
             poller: scheduler.poller.clone(),
         })
     }
-    pub fn as_context(&mut self) -> Option<Context> {
+    pub fn as_context(&mut self) -> Option<Context<'_>> {
         if let Some(scheduler) = self.scheduler.as_mut() {
             if let Some(fiber) = self.fiber {
                 let fiber = unsafe { &mut *fiber };
@@ -336,4 +687,21 @@ impl InnerContext {
 enum Request {
     Spawn(Task),
     WakeUp(fiber::FiberId),
+    Snapshot(oneshot::Sender<SchedulerSnapshot>),
+    SetTraceRing(Option<TraceRing>),
+    SetFirstPollThreshold(Option<time::Duration>),
+    SetSlowFirstPollCallback(SlowFirstPollCallback),
+    #[cfg(any(test, feature = "testing"))]
+    DropWakeups(usize),
+    #[cfg(any(test, feature = "testing"))]
+    Abort,
+}
+
+type SlowFirstPollFn = dyn Fn(&fiber::FiberSnapshot, time::Duration) + Send + Sync;
+
+struct SlowFirstPollCallback(Arc<SlowFirstPollFn>);
+impl fmt::Debug for SlowFirstPollCallback {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SlowFirstPollCallback(_)")
+    }
 }
@@ -2,7 +2,9 @@
 // See the LICENSE file at the top-level directory of this distribution.
 
 //! I/O related functionalities.
+pub use self::read_buf::AdaptiveReadBuf;
 pub use self::stdio::{stdin, Stdin};
 
 pub mod poll;
+mod read_buf;
 mod stdio;
@@ -13,8 +13,10 @@ use std::io;
 use std::ops;
 use std::sync::Arc;
 
-pub use self::poller::{EventedHandle, Poller, PollerHandle};
+pub(crate) use self::poller::SocketInfo;
+pub use self::poller::{EventedHandle, Poller, PollerHandle, PollerStats};
 pub use self::poller::{Register, DEFAULT_EVENTS_CAPACITY};
+pub use self::poller::{SocketKind, SocketSnapshot};
 
 use crate::sync_atomic::{AtomicBorrowMut, AtomicCell};
 
@@ -29,7 +31,7 @@ where
     pub fn new(inner: T) -> Self {
         SharableEvented(Arc::new(AtomicCell::new(inner)))
     }
-    pub fn lock(&self) -> EventedLock<T> {
+    pub fn lock(&self) -> EventedLock<'_, T> {
         loop {
             // NOTE: We assume conflicts are very rare.
             // (But should be refined in future releases)
@@ -76,12 +78,12 @@ pub struct EventedLock<'a, T: 'a>(AtomicBorrowMut<'a, T>);
 impl<'a, T: 'a> ops::Deref for EventedLock<'a, T> {
     type Target = T;
     fn deref(&self) -> &T {
-        &*self.0
+        &self.0
     }
 }
 impl<'a, T: 'a> ops::DerefMut for EventedLock<'a, T> {
     fn deref_mut(&mut self) -> &mut T {
-        &mut *self.0
+        &mut self.0
     }
 }
 
@@ -3,24 +3,150 @@
 
 use futures::{self, Future};
 use nbchan::mpsc as nb_mpsc;
-use std::collections::HashMap;
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
 use std::fmt;
 use std::io;
-use std::sync::atomic::{self, AtomicUsize};
-use std::sync::mpsc::{RecvError, TryRecvError};
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::panic::Location;
+use std::sync::atomic::{self, AtomicBool, AtomicU64, AtomicUsize};
+use std::sync::mpsc::{RecvError, SendError, TryRecvError};
 use std::sync::Arc;
+#[cfg(any(test, feature = "testing"))]
+use std::thread;
 use std::time;
 
 use super::{EventedLock, Interest, SharableEvented};
 use crate::collections::HeapMap;
+use crate::fiber::{ContextId, WaitKind};
+use crate::metrics::{WakeCounters, WakeStats};
 use crate::sync::oneshot;
+use crate::trace::{TraceEventKind, TraceRing};
 
-type RequestSender = nb_mpsc::Sender<Request>;
 type RequestReceiver = nb_mpsc::Receiver<Request>;
 
 /// The default capacity of the event buffer of a poller.
 pub const DEFAULT_EVENTS_CAPACITY: usize = 128;
 
+/// The token reserved for the waker of a poller (`usize::MAX` is reserved by mio).
+const WAKER_TOKEN: mio::Token = mio::Token(usize::MAX - 1);
+
+/// The token reserved for the timer descriptor of a poller (see `Poller::enable_timer_fd`).
+#[cfg(target_os = "linux")]
+const TIMER_TOKEN: mio::Token = mio::Token(usize::MAX - 2);
+
+/// The maximum number of the expired timers counted as the timer backlog in a polling.
+const MAX_TIMER_BACKLOG: usize = 4096;
+
+/// The tokens greater than or equal to this are not assigned to registrants.
+const MIN_RESERVED_TOKEN: usize = usize::MAX - 2;
+
+/// The sender of the requests to a poller, which wakes up the poller on each sending.
+#[derive(Debug, Clone)]
+struct RequestSender {
+    tx: nb_mpsc::Sender<Request>,
+    waker: Arc<Waker>,
+}
+impl RequestSender {
+    fn send(&self, request: Request) -> Result<(), SendError<Request>> {
+        self.tx.send(request)?;
+        self.waker.wake();
+        Ok(())
+    }
+}
+
+/// A waker which makes a blocking polling return.
+///
+/// On Linux, an `eventfd` is used so that the wakeups also make the descriptor of the poller
+/// readable while the poller is not polling (e.g., while it is waited by an external event loop).
+#[derive(Debug)]
+struct Waker {
+    #[cfg(target_os = "linux")]
+    event_fd: RawFd,
+    #[cfg(not(target_os = "linux"))]
+    set_readiness: mio::SetReadiness,
+    notified: AtomicBool,
+}
+impl Waker {
+    fn wake(&self) {
+        // NOTE: The readiness is set only once until the poller handles the wakeup,
+        // so that bursts of requests do not issue a system call for each.
+        if !self.notified.swap(true, atomic::Ordering::SeqCst) {
+            self.set_readable(true);
+        }
+    }
+    fn reset(&self) {
+        self.set_readable(false);
+        self.notified.store(false, atomic::Ordering::SeqCst);
+    }
+
+    #[cfg(target_os = "linux")]
+    fn new(poll: &mio::Poll) -> io::Result<(Self, ())> {
+        let event_fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+        if event_fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let waker = Waker {
+            event_fd,
+            notified: AtomicBool::new(false),
+        };
+        poll.register(
+            &mio::unix::EventedFd(&event_fd),
+            WAKER_TOKEN,
+            mio::Ready::readable(),
+            mio::PollOpt::level(),
+        )?;
+        Ok((waker, ()))
+    }
+    #[cfg(target_os = "linux")]
+    fn set_readable(&self, readable: bool) {
+        let mut value = 1u64;
+        let buf = &mut value as *mut u64 as *mut libc::c_void;
+        unsafe {
+            if readable {
+                libc::write(self.event_fd, buf, 8);
+            } else {
+                libc::read(self.event_fd, buf, 8);
+            }
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn new(poll: &mio::Poll) -> io::Result<(Self, mio::Registration)> {
+        let (registration, set_readiness) = mio::Registration::new2();
+        poll.register(
+            &registration,
+            WAKER_TOKEN,
+            mio::Ready::readable(),
+            mio::PollOpt::edge(),
+        )?;
+        let waker = Waker {
+            set_readiness,
+            notified: AtomicBool::new(false),
+        };
+        Ok((waker, registration))
+    }
+    #[cfg(not(target_os = "linux"))]
+    fn set_readable(&self, readable: bool) {
+        let readiness = if readable {
+            mio::Ready::readable()
+        } else {
+            mio::Ready::empty()
+        };
+        let _ = self.set_readiness.set_readiness(readiness);
+    }
+}
+#[cfg(target_os = "linux")]
+impl Drop for Waker {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.event_fd);
+        }
+    }
+}
+
 struct MioEvents(mio::Events);
 impl fmt::Debug for MioEvents {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -32,16 +158,20 @@ impl fmt::Debug for MioEvents {
 struct Registrant {
     is_first: bool,
     evented: BoxEvented,
-    read_waitings: Vec<oneshot::Monitored<(), io::Error>>,
-    write_waitings: Vec<oneshot::Monitored<(), io::Error>>,
+    read_waitings: Vec<Waiter>,
+    write_waitings: Vec<Waiter>,
+    waiting_since: Option<time::Instant>,
+    info: Option<Box<SocketInfo>>,
 }
 impl Registrant {
-    pub fn new(evented: BoxEvented) -> Self {
+    pub fn new(evented: BoxEvented, info: Option<Box<SocketInfo>>) -> Self {
         Registrant {
             is_first: true,
             evented,
             read_waitings: Vec::new(),
             write_waitings: Vec::new(),
+            waiting_since: None,
+            info,
         }
     }
     pub fn mio_interest(&self) -> mio::Ready {
@@ -62,12 +192,28 @@ impl Registrant {
 pub struct Poller {
     poll: mio::Poll,
     events: MioEvents,
+    #[cfg(not(target_os = "linux"))]
+    _waker_registration: mio::Registration,
     request_tx: RequestSender,
     request_rx: RequestReceiver,
     next_token: usize,
     next_timeout_id: Arc<AtomicUsize>,
     registrants: HashMap<mio::Token, Registrant>,
-    timeout_queue: HeapMap<(time::Instant, usize), oneshot::Sender<()>>,
+    timeout_queue: HeapMap<(time::Instant, usize), TimerEntry>,
+    timeout_horizon: HeapMap<(time::Instant, usize), ()>,
+    counters: Arc<PollerCounters>,
+    #[cfg(any(test, feature = "testing"))]
+    poll_delay: Option<time::Duration>,
+    readiness_delay: Option<time::Duration>,
+    delayed_waiters: VecDeque<(time::Instant, Waiter)>,
+    trace: Option<TraceRing>,
+    was_active: bool,
+    socket_registry: Arc<AtomicBool>,
+    waits: HeapMap<(time::Instant, usize), ()>,
+    timer_budget: Option<usize>,
+    timer_saturation_callback: Option<TimerSaturationCallback>,
+    #[cfg(target_os = "linux")]
+    timer_fd: Option<TimerFd>,
 }
 impl Poller {
     /// Creates a new poller.
@@ -84,16 +230,35 @@ impl Poller {
     /// (https://docs.rs/mio/0.6.1/mio/struct.Events.html#method.with_capacity).
     pub fn with_capacity(capacity: usize) -> io::Result<Self> {
         let poll = mio::Poll::new()?;
+        #[cfg_attr(target_os = "linux", allow(unused_variables))]
+        let (waker, registration) = Waker::new(&poll)?;
+        let waker = Arc::new(waker);
         let (tx, rx) = nb_mpsc::channel();
         Ok(Poller {
             poll,
             events: MioEvents(mio::Events::with_capacity(capacity)),
-            request_tx: tx,
+            #[cfg(not(target_os = "linux"))]
+            _waker_registration: registration,
+            request_tx: RequestSender { tx, waker },
             request_rx: rx,
             next_token: 0,
             next_timeout_id: Arc::new(AtomicUsize::new(0)),
             registrants: HashMap::new(),
             timeout_queue: HeapMap::new(),
+            timeout_horizon: HeapMap::new(),
+            counters: Arc::new(PollerCounters::default()),
+            #[cfg(any(test, feature = "testing"))]
+            poll_delay: None,
+            readiness_delay: None,
+            delayed_waiters: VecDeque::new(),
+            trace: None,
+            was_active: false,
+            socket_registry: Arc::new(AtomicBool::new(false)),
+            waits: HeapMap::new(),
+            timer_budget: None,
+            timer_saturation_callback: None,
+            #[cfg(target_os = "linux")]
+            timer_fd: None,
         })
     }
 
@@ -108,32 +273,80 @@ impl Poller {
     /// Blocks the current thread and wait until any events happen or `timeout` expires.
     ///
     /// On the former case, the poller notifies the fibers waiting on those events.
+    ///
+    /// The blocking is also interrupted by the requests from `PollerHandle`s
+    /// (e.g., registrations and timer settings), and is shortened to the nearest timer deadline.
+    /// Thus, `None` can be passed as `timeout` if the poller runs on a dedicated thread.
     pub fn poll(&mut self, timeout: Option<time::Duration>) -> io::Result<()> {
         let mut did_something = false;
 
         // Request
-        match self.request_rx.try_recv() {
-            Err(TryRecvError::Empty) => {}
-            Err(TryRecvError::Disconnected) => unreachable!(),
-            Ok(r) => {
-                did_something = true;
-                self.handle_request(r)?;
+        loop {
+            match self.request_rx.try_recv() {
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => unreachable!(),
+                Ok(r) => {
+                    did_something = true;
+                    self.handle_request(r)?;
+                }
             }
         }
+        #[cfg(any(test, feature = "testing"))]
+        if let Some(delay) = self.poll_delay {
+            thread::sleep(delay);
+        }
 
         // Timeout
+        //
+        // NOTE: All the timers whose deadline has passed are fired here (up to the budget),
+        // even if their slack allows to delay the firing (i.e., those are coalesced).
         let now = time::Instant::now();
-        while let Some((_, notifier)) = self.timeout_queue.pop_if(|k, _| k.0 <= now) {
-            let _ = notifier.send(());
+        let mut budget = self.timer_budget.unwrap_or(usize::MAX);
+        while let Some(((_, timeout_id), entry)) =
+            self.timeout_queue.pop_if(|k, _| budget > 0 && k.0 <= now)
+        {
+            budget -= 1;
+            self.timeout_horizon.remove(&(entry.latest, timeout_id));
+            self.counters
+                .timers_fired
+                .fetch_add(1, atomic::Ordering::SeqCst);
+            if let Some(ref trace) = self.trace {
+                trace.record(None, TraceEventKind::TimerFired { timeout_id });
+            }
+            if now < entry.latest {
+                self.counters
+                    .timers_coalesced
+                    .fetch_add(1, atomic::Ordering::SeqCst);
+            }
+            let _ = entry.notifier.send(());
+            did_something = true;
+        }
+        self.update_timer_backlog(now, budget == 0);
+
+        // Delayed readiness
+        while self
+            .delayed_waiters
+            .front()
+            .is_some_and(|&(deadline, _)| deadline <= now)
+        {
+            let (_, waiter) = self.delayed_waiters.pop_front().expect("Never fails");
+            waiter.notify();
+            did_something = true;
         }
 
         // I/O event
+        let next_deadline = match (
+            self.timeout_horizon.peek().map(|(k, _)| k.0),
+            self.delayed_waiters.front().map(|&(deadline, _)| deadline),
+        ) {
+            (Some(a), Some(b)) => Some(cmp::min(a, b)),
+            (a, b) => a.or(b),
+        };
         let timeout = if did_something {
             Some(time::Duration::from_millis(0))
-        } else if let Some((k, _)) = self.timeout_queue.peek() {
-            let duration_until_next_expiry_time = k.0 - now;
+        } else if let Some(deadline) = next_deadline {
+            let duration_until_next_expiry_time = deadline - now;
             if let Some(timeout) = timeout {
-                use std::cmp;
                 Some(cmp::min(timeout, duration_until_next_expiry_time))
             } else {
                 Some(duration_until_next_expiry_time)
@@ -142,17 +355,90 @@ impl Poller {
             timeout
         };
         let _ = self.poll.poll(&mut self.events.0, timeout)?;
-        for e in self.events.0.iter() {
-            let r = assert_some!(self.registrants.get_mut(&e.token()));
-            if e.readiness().is_readable() {
-                for _ in r.read_waitings.drain(..).map(|tx| tx.exit(Ok(()))) {}
+        self.counters.polls.fetch_add(1, atomic::Ordering::SeqCst);
+        self.was_active = did_something || !self.events.0.is_empty();
+
+        let now = time::Instant::now();
+        let delayed_until = self.readiness_delay.map(|d| now + d);
+        loop {
+            for e in self.events.0.iter() {
+                if e.token() == WAKER_TOKEN {
+                    self.request_tx.waker.reset();
+                    continue;
+                }
+                #[cfg(target_os = "linux")]
+                {
+                    if e.token() == TIMER_TOKEN {
+                        if let Some(ref mut timer_fd) = self.timer_fd {
+                            timer_fd.clear();
+                        }
+                        continue;
+                    }
+                }
+                let r = assert_some!(self.registrants.get_mut(&e.token()));
+                let mut waiters = Vec::new();
+                if e.readiness().is_readable() {
+                    waiters.append(&mut r.read_waitings);
+                }
+                if e.readiness().is_writable() {
+                    waiters.append(&mut r.write_waitings);
+                }
+                if let Some(deadline) = delayed_until {
+                    self.delayed_waiters
+                        .extend(waiters.into_iter().map(|w| (deadline, w)));
+                } else {
+                    for _ in waiters.into_iter().map(Waiter::notify) {}
+                }
+                Self::track_wait(&mut self.waits, e.token(), r, now, true);
+                Self::mio_register(&self.poll, e.token(), r)?;
             }
-            if e.readiness().is_writable() {
-                for _ in r.write_waitings.drain(..).map(|tx| tx.exit(Ok(()))) {}
+
+            // NOTE: If the event buffer is full, some ready events may be left in the kernel.
+            // They are fetched immediately (instead of at the next polling), so that the registrations
+            // which do not fit in the buffer are not delayed (or starved) under sustained load.
+            // Because the registrations are oneshot, each of them is reported at most once
+            // until it is re-armed, so this loop terminates.
+            if self.events.0.iter().count() < self.events.0.capacity() {
+                break;
             }
-            Self::mio_register(&self.poll, e.token(), r)?;
+            let _ = self
+                .poll
+                .poll(&mut self.events.0, Some(time::Duration::from_millis(0)))?;
         }
+        let longest_wait = self
+            .waits
+            .peek()
+            .map_or(0, |(&(since, _), _)| (now - since).as_micros() as u64);
+        self.counters
+            .longest_wait_micros
+            .store(longest_wait, atomic::Ordering::SeqCst);
 
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(ref mut timer_fd) = self.timer_fd {
+                timer_fd.arm(next_deadline)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes the descriptor of this poller (see `AsRawFd`) readable also when timers expire.
+    ///
+    /// This is needed only if the poller is embedded in an external event loop
+    /// which waits for the descriptor (see `fibers::ffi`).
+    /// Internally, a `timerfd` is armed with the nearest timer deadline at the end of each polling.
+    #[cfg(all(target_os = "linux", feature = "ffi"))]
+    pub(crate) fn enable_timer_fd(&mut self) -> io::Result<()> {
+        if self.timer_fd.is_none() {
+            let timer_fd = TimerFd::new()?;
+            self.poll.register(
+                &mio::unix::EventedFd(&timer_fd.fd),
+                TIMER_TOKEN,
+                mio::Ready::readable(),
+                mio::PollOpt::level(),
+            )?;
+            self.timer_fd = Some(timer_fd);
+        }
         Ok(())
     }
 
@@ -161,19 +447,134 @@ impl Poller {
         PollerHandle {
             request_tx: self.request_tx.clone(),
             next_timeout_id: Arc::clone(&self.next_timeout_id),
+            counters: Arc::clone(&self.counters),
+            socket_registry: Arc::clone(&self.socket_registry),
             is_alive: true,
         }
     }
 
+    /// Enables or disables the registry of the sockets registered in this poller.
+    ///
+    /// While the registry is enabled, the sockets created by `fibers::net` record
+    /// their descriptions (e.g., addresses and the fiber which created them)
+    /// and those can be retrieved via `Poller::sockets` method.
+    /// This is useful to attribute descriptor leaks.
+    ///
+    /// Note that the sockets registered while the registry is disabled are not recorded.
+    pub fn set_socket_registry(&self, enabled: bool) {
+        self.socket_registry
+            .store(enabled, atomic::Ordering::SeqCst);
+    }
+
+    /// Returns the snapshots of the recorded sockets which are alive.
+    ///
+    /// See `Poller::set_socket_registry` for more details.
+    pub fn sockets(&self) -> Vec<SocketSnapshot> {
+        let mut sockets = self
+            .registrants
+            .iter()
+            .filter_map(|(token, r)| r.info.as_ref().map(|info| (token.0, info.snapshot(r))))
+            .collect::<Vec<_>>();
+        sockets.sort_by_key(|s| s.0);
+        sockets.into_iter().map(|s| s.1).collect()
+    }
+
+    /// Returns the statistics of the poller.
+    pub fn stats(&self) -> PollerStats {
+        self.counters.snapshot()
+    }
+
+    /// Sets the maximum number of the timers fired in a polling.
+    ///
+    /// If more timers expire at once (i.e., the poller is saturated with timers),
+    /// the rest are left as a backlog and fired in batches in the subsequent pollings
+    /// in the order of their deadlines.
+    /// Thus, the timers may fire later than their deadlines,
+    /// but the latency of each polling (including I/O events handling) is bounded.
+    ///
+    /// `None` means that there is no limit (the default).
+    ///
+    /// # Panics
+    ///
+    /// If `budget` is `Some(0)`, this method will panic.
+    pub fn set_timer_budget(&mut self, budget: Option<usize>) {
+        assert_ne!(budget, Some(0));
+        self.timer_budget = budget;
+    }
+
+    /// Sets the callback invoked when this poller becomes saturated with timers.
+    ///
+    /// The callback is invoked with the size of the backlog (see `PollerStats::timer_backlog`)
+    /// each time the backlog changes from empty to non-empty.
+    pub fn on_timer_saturation<F>(&mut self, f: F)
+    where
+        F: Fn(usize) + Send + Sync + 'static,
+    {
+        self.timer_saturation_callback = Some(TimerSaturationCallback(Arc::new(f)));
+    }
+
+    /// Sets the artificial delay inserted before I/O readiness is delivered to the fibers.
+    ///
+    /// This emulates the round-trip latency of a remote network on the local host,
+    /// and is intended for testing or staging environments only.
+    /// Unlike sleeping in the poller, the delay does not stall the other events and timers.
+    ///
+    /// `None` disables the delay (the default).
+    /// The readiness which has already been delayed is delivered at the original deadline.
+    pub fn set_readiness_delay(&mut self, delay: Option<time::Duration>) {
+        self.readiness_delay = delay;
+    }
+
+    fn update_timer_backlog(&mut self, now: time::Instant, budget_exhausted: bool) {
+        let was_saturated = self.counters.timer_backlog.load(atomic::Ordering::SeqCst) > 0;
+
+        // NOTE: If the budget remains, all the expired timers have been fired.
+        // Otherwise, the expired timers are counted up to `MAX_TIMER_BACKLOG`,
+        // so that a huge backlog does not make each polling even slower.
+        let backlog = if budget_exhausted {
+            self.timeout_queue
+                .count_while(MAX_TIMER_BACKLOG, |k| k.0 <= now)
+        } else {
+            0
+        };
+        self.counters
+            .timer_backlog
+            .store(backlog as u64, atomic::Ordering::SeqCst);
+        if backlog > 0 && !was_saturated {
+            self.counters
+                .timer_saturations
+                .fetch_add(1, atomic::Ordering::SeqCst);
+            if let Some(ref callback) = self.timer_saturation_callback {
+                (callback.0)(backlog);
+            }
+        }
+    }
+
+    /// Returns `true` if any requests, timers or I/O events were handled in the last polling.
+    pub(crate) fn was_active(&self) -> bool {
+        self.was_active
+    }
+
+    /// Sets the ring buffer to record the timer events of this poller.
+    ///
+    /// `None` disables the tracing.
+    pub fn set_trace_ring(&mut self, ring: Option<TraceRing>) {
+        self.trace = ring;
+    }
+
     fn handle_request(&mut self, request: Request) -> io::Result<()> {
         match request {
-            Request::Register(evented, mut reply) => {
+            Request::Register(evented, info, mut reply) => {
                 let token = self.next_token();
-                self.registrants.insert(token, Registrant::new(evented));
+                self.registrants
+                    .insert(token, Registrant::new(evented, info));
                 (reply.0)(token);
             }
             Request::Deregister(token) => {
-                let r = assert_some!(self.registrants.remove(&token));
+                let mut r = assert_some!(self.registrants.remove(&token));
+                r.read_waitings.clear();
+                r.write_waitings.clear();
+                Self::track_wait(&mut self.waits, token, &mut r, time::Instant::now(), false);
                 if !r.is_first {
                     self.poll.deregister(&*r.evented.0)?;
                 }
@@ -184,21 +585,72 @@ impl Poller {
                     Interest::Read => r.read_waitings.push(notifier),
                     Interest::Write => r.write_waitings.push(notifier),
                 }
+                Self::track_wait(&mut self.waits, token, r, time::Instant::now(), false);
                 if r.read_waitings.len() == 1 || r.write_waitings.len() == 1 {
                     Self::mio_register(&self.poll, token, r)?;
                 }
             }
-            Request::SetTimeout(timeout_id, expiry_time, reply) => {
+            Request::SetTimeout(timeout_id, expiry_time, slack, notifier) => {
+                let latest = latest_expiry_time(expiry_time, slack);
+                let entry = TimerEntry { latest, notifier };
                 assert!(self
                     .timeout_queue
-                    .push_if_absent((expiry_time, timeout_id), reply,));
+                    .push_if_absent((expiry_time, timeout_id), entry));
+                assert!(self
+                    .timeout_horizon
+                    .push_if_absent((latest, timeout_id), ()));
+            }
+            Request::SetTraceRing(ring) => {
+                self.trace = ring;
+            }
+            Request::Sockets(reply) => {
+                let _ = reply.send(self.sockets());
+            }
+            Request::SetTimerBudget(budget) => {
+                self.timer_budget = budget;
             }
-            Request::CancelTimeout(timeout_id, expiry_time) => {
-                self.timeout_queue.remove(&(expiry_time, timeout_id));
+            Request::SetTimerSaturationCallback(callback) => {
+                self.timer_saturation_callback = Some(callback);
+            }
+            #[cfg(any(test, feature = "testing"))]
+            Request::SetPollDelay(delay) => {
+                self.poll_delay = delay;
+            }
+            Request::SetReadinessDelay(delay) => {
+                self.readiness_delay = delay;
+            }
+            Request::CancelTimeout(timeout_id, expiry_time, slack) => {
+                if self.timeout_queue.remove(&(expiry_time, timeout_id)) {
+                    self.timeout_horizon
+                        .remove(&(latest_expiry_time(expiry_time, slack), timeout_id));
+                }
             }
         }
         Ok(())
     }
+    /// Updates the index of the registrations on which some fibers are waiting.
+    ///
+    /// If `restart` is `true`, the current wait of `r` (if any) is regarded as completed.
+    fn track_wait(
+        waits: &mut HeapMap<(time::Instant, usize), ()>,
+        token: mio::Token,
+        r: &mut Registrant,
+        now: time::Instant,
+        restart: bool,
+    ) {
+        let is_waiting = r.mio_interest() != mio::Ready::empty();
+        if let Some(since) = r.waiting_since {
+            if is_waiting && !restart {
+                return;
+            }
+            waits.remove(&(since, token.0));
+            r.waiting_since = None;
+        }
+        if is_waiting {
+            waits.push_if_absent((now, token.0), ());
+            r.waiting_since = Some(now);
+        }
+    }
     fn mio_register(poll: &mio::Poll, token: mio::Token, r: &mut Registrant) -> io::Result<()> {
         let interest = r.mio_interest();
         if interest != mio::Ready::empty() {
@@ -216,7 +668,7 @@ impl Poller {
         loop {
             let token = self.next_token;
             self.next_token = token.wrapping_add(1);
-            if self.registrants.contains_key(&mio::Token(token)) {
+            if token >= MIN_RESERVED_TOKEN || self.registrants.contains_key(&mio::Token(token)) {
                 continue;
             }
             return mio::Token(token);
@@ -224,11 +676,28 @@ impl Poller {
     }
 }
 
+#[cfg(unix)]
+impl AsRawFd for Poller {
+    /// Returns the file descriptor of the underlying `epoll` or `kqueue` instance.
+    ///
+    /// The descriptor becomes readable when there are I/O events to be handled,
+    /// so it can be used to embed the poller in an external event loop.
+    ///
+    /// The requests from `PollerHandle`s also make it readable,
+    /// but timer expirations are not notified via it (except for the executors of `fibers::ffi`
+    /// on Linux). Thus `Poller::poll` method should also be called periodically.
+    fn as_raw_fd(&self) -> RawFd {
+        self.poll.as_raw_fd()
+    }
+}
+
 /// A handle of a poller.
 #[derive(Debug, Clone)]
 pub struct PollerHandle {
     request_tx: RequestSender,
     next_timeout_id: Arc<AtomicUsize>,
+    counters: Arc<PollerCounters>,
+    socket_registry: Arc<AtomicBool>,
     is_alive: bool,
 }
 impl PollerHandle {
@@ -237,17 +706,119 @@ impl PollerHandle {
         self.is_alive
     }
 
+    /// Returns the statistics of the original poller.
+    pub fn stats(&self) -> PollerStats {
+        self.counters.snapshot()
+    }
+
+    /// Sets the ring buffer to record the timer events of the original poller.
+    ///
+    /// `None` disables the tracing.
+    pub fn set_trace_ring(&self, ring: Option<TraceRing>) {
+        let _ = self.request_tx.send(Request::SetTraceRing(ring));
+    }
+
+    /// Sets the maximum number of the timers fired in a polling of the original poller.
+    ///
+    /// See `Poller::set_timer_budget` for more details.
+    ///
+    /// # Panics
+    ///
+    /// If `budget` is `Some(0)`, this method will panic.
+    pub fn set_timer_budget(&self, budget: Option<usize>) {
+        assert_ne!(budget, Some(0));
+        let _ = self.request_tx.send(Request::SetTimerBudget(budget));
+    }
+
+    /// Sets the callback invoked when the original poller becomes saturated with timers.
+    ///
+    /// See `Poller::on_timer_saturation` for more details.
+    pub fn on_timer_saturation<F>(&self, f: F)
+    where
+        F: Fn(usize) + Send + Sync + 'static,
+    {
+        let callback = TimerSaturationCallback(Arc::new(f));
+        let _ = self
+            .request_tx
+            .send(Request::SetTimerSaturationCallback(callback));
+    }
+
+    /// Wakes up the original poller if it is blocking.
+    pub(crate) fn wake(&self) {
+        self.request_tx.waker.wake();
+    }
+
+    /// Makes the original poller sleep `delay` before each polling (for fault injection).
+    ///
+    /// `None` cancels the delay.
+    #[cfg(any(test, feature = "testing"))]
+    pub(crate) fn set_poll_delay(&self, delay: Option<time::Duration>) {
+        let _ = self.request_tx.send(Request::SetPollDelay(delay));
+    }
+
+    /// Sets the artificial delay inserted before I/O readiness is delivered by the original poller.
+    ///
+    /// See `Poller::set_readiness_delay` for more details.
+    pub fn set_readiness_delay(&self, delay: Option<time::Duration>) {
+        let _ = self.request_tx.send(Request::SetReadinessDelay(delay));
+    }
+
+    /// Enables or disables the registry of the sockets registered in the original poller.
+    ///
+    /// See `Poller::set_socket_registry` for more details.
+    pub fn set_socket_registry(&self, enabled: bool) {
+        self.socket_registry
+            .store(enabled, atomic::Ordering::SeqCst);
+    }
+
+    /// Makes a future which will take the snapshots of the recorded sockets
+    /// of the original poller.
+    ///
+    /// See `Poller::sockets` for more details.
+    pub fn sockets(&self) -> oneshot::Receiver<Vec<SocketSnapshot>> {
+        let (tx, rx) = oneshot::channel();
+        let _ = self.request_tx.send(Request::Sockets(tx));
+        rx
+    }
+
     /// Makes a future to register new evented object to the poller.
     pub fn register<E>(&mut self, evented: E) -> Register<E>
+    where
+        E: mio::Evented + Send + 'static,
+    {
+        self.register_with_info(evented, None)
+    }
+
+    /// Makes a future to register a socket to the poller.
+    ///
+    /// If the socket registry is enabled, the description of the socket made by `describe`
+    /// is recorded.
+    pub(crate) fn register_socket<E, F>(&mut self, evented: E, describe: F) -> Register<E>
+    where
+        E: mio::Evented + Send + 'static,
+        F: FnOnce(&E) -> SocketInfo,
+    {
+        let info = if self.socket_registry.load(atomic::Ordering::SeqCst) {
+            Some(Box::new(describe(&evented)))
+        } else {
+            None
+        };
+        self.register_with_info(evented, info)
+    }
+
+    fn register_with_info<E>(&mut self, evented: E, info: Option<Box<SocketInfo>>) -> Register<E>
     where
         E: mio::Evented + Send + 'static,
     {
         let evented = SharableEvented::new(evented);
         let box_evented = BoxEvented(Box::new(evented.clone()));
         let request_tx = self.request_tx.clone();
+        let wake = info
+            .as_ref()
+            .map_or_else(Default::default, |info| Arc::clone(&info.wake));
         let (tx, rx) = oneshot::channel();
         let mut reply = Some(move |token| {
-            let handle = EventedHandle::new(evented, request_tx, token);
+            let handle = EventedHandle::new(evented, request_tx, token, wake);
             let _ = tx.send(handle);
         });
         let reply = RegisterReplyFn(Box::new(move |token| {
@@ -256,7 +827,7 @@ impl PollerHandle {
         }));
         if self
             .request_tx
-            .send(Request::Register(box_evented, reply))
+            .send(Request::Register(box_evented, info, reply))
             .is_err()
         {
             self.is_alive = false;
@@ -264,16 +835,17 @@ impl PollerHandle {
         Register { rx }
     }
 
-    fn set_timeout(&self, delay_from_now: time::Duration) -> Timeout {
-        let (tx, rx) = oneshot::channel();
+    fn set_timeout(&self, delay_from_now: time::Duration, slack: time::Duration) -> Timeout {
+        let (tx, rx) = oneshot::channel_for(WaitKind::Timer);
         let expiry_time = time::Instant::now() + delay_from_now;
         let timeout_id = self.next_timeout_id.fetch_add(1, atomic::Ordering::SeqCst);
-        let request = Request::SetTimeout(timeout_id, expiry_time, tx);
+        let request = Request::SetTimeout(timeout_id, expiry_time, slack, tx);
         let _ = self.request_tx.send(request);
         Timeout {
             cancel: Some(CancelTimeout {
                 timeout_id,
                 expiry_time,
+                slack,
                 request_tx: self.request_tx.clone(),
             }),
             rx,
@@ -281,21 +853,241 @@ impl PollerHandle {
     }
 }
 
-pub fn set_timeout(poller: &PollerHandle, delay_from_now: time::Duration) -> Timeout {
-    poller.set_timeout(delay_from_now)
+/// Returns the end of the slack of a timer.
+///
+/// If it is not representable (e.g., `slack` is `Duration::MAX`), `expiry_time` is returned,
+/// i.e., the timer fires without being coalesced.
+fn latest_expiry_time(expiry_time: time::Instant, slack: time::Duration) -> time::Instant {
+    expiry_time.checked_add(slack).unwrap_or(expiry_time)
+}
+
+pub fn set_timeout(
+    poller: &PollerHandle,
+    delay_from_now: time::Duration,
+    slack: time::Duration,
+) -> Timeout {
+    poller.set_timeout(delay_from_now, slack)
+}
+
+/// Statistics of a poller.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct PollerStats {
+    /// The number of the pollings (i.e., the wakeups of the poller).
+    pub polls: u64,
+
+    /// The number of the timers which have fired.
+    pub timers_fired: u64,
+
+    /// The number of the timers which have fired earlier than the end of their slack,
+    /// together with other events (i.e., without dedicated wakeups of the poller).
+    pub timers_coalesced: u64,
+
+    /// The age of the longest-waiting registration,
+    /// i.e., the time elapsed since the oldest wait for the readiness of an I/O object started.
+    ///
+    /// This is updated at each polling.
+    /// Note that an idle I/O object (e.g., a connection waiting for the next request) also
+    /// makes this value grow, so it indicates starvation only if the object is known to be ready.
+    pub longest_wait: time::Duration,
+
+    /// The number of the expired timers which have not been fired yet
+    /// due to the timer budget (see `Poller::set_timer_budget`).
+    ///
+    /// This is updated at each polling, and is capped at 4096.
+    pub timer_backlog: u64,
+
+    /// The number of the times the poller has become saturated with timers
+    /// (i.e., the timer backlog has become non-empty).
+    pub timer_saturations: u64,
+}
+
+/// The kind of a socket recorded in the socket registry of a poller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum SocketKind {
+    /// A TCP listener.
+    TcpListener,
+
+    /// A TCP stream.
+    TcpStream,
+
+    /// A UDP socket.
+    UdpSocket,
+}
+
+/// A snapshot of a socket recorded in the socket registry of a poller.
+///
+/// This is created by calling `Poller::sockets` or `PollerHandle::sockets` method.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct SocketSnapshot {
+    /// The kind of the socket.
+    pub kind: SocketKind,
+
+    /// The local address of the socket (at the time of the registration).
+    pub local_addr: Option<SocketAddr>,
+
+    /// The address of the peer of the socket (at the time of the registration).
+    pub peer_addr: Option<SocketAddr>,
+
+    /// Whether some fibers are waiting for the socket to become readable.
+    pub read_interest: bool,
+
+    /// Whether some fibers are waiting for the socket to become writable.
+    pub write_interest: bool,
+
+    /// The execution context (i.e., fiber) which registered the socket.
+    pub owner: Option<ContextId>,
+
+    /// The location where the owner fiber was spawned (if recorded).
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "crate::metrics::serialize_spawn_site")
+    )]
+    pub spawn_site: Option<&'static Location<'static>>,
+
+    /// The statistics of the wakeups of the fibers waiting for the socket.
+    pub wake_stats: WakeStats,
+}
+
+/// The description of a socket recorded in the socket registry.
+#[derive(Debug)]
+pub(crate) struct SocketInfo {
+    pub kind: SocketKind,
+    pub local_addr: Option<SocketAddr>,
+    pub peer_addr: Option<SocketAddr>,
+    pub owner: Option<ContextId>,
+    pub spawn_site: Option<&'static Location<'static>>,
+    pub wake: Arc<WakeCounters>,
+}
+impl SocketInfo {
+    fn snapshot(&self, r: &Registrant) -> SocketSnapshot {
+        SocketSnapshot {
+            kind: self.kind,
+            local_addr: self.local_addr,
+            peer_addr: self.peer_addr,
+            read_interest: !r.read_waitings.is_empty(),
+            write_interest: !r.write_waitings.is_empty(),
+            owner: self.owner,
+            spawn_site: self.spawn_site,
+            wake_stats: self.wake.snapshot(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct PollerCounters {
+    polls: AtomicU64,
+    timers_fired: AtomicU64,
+    timers_coalesced: AtomicU64,
+    longest_wait_micros: AtomicU64,
+    timer_backlog: AtomicU64,
+    timer_saturations: AtomicU64,
+}
+impl PollerCounters {
+    fn snapshot(&self) -> PollerStats {
+        PollerStats {
+            polls: self.polls.load(atomic::Ordering::SeqCst),
+            timers_fired: self.timers_fired.load(atomic::Ordering::SeqCst),
+            timers_coalesced: self.timers_coalesced.load(atomic::Ordering::SeqCst),
+            longest_wait: time::Duration::from_micros(
+                self.longest_wait_micros.load(atomic::Ordering::SeqCst),
+            ),
+            timer_backlog: self.timer_backlog.load(atomic::Ordering::SeqCst),
+            timer_saturations: self.timer_saturations.load(atomic::Ordering::SeqCst),
+        }
+    }
+}
+
+/// A `timerfd` which expires at the nearest timer deadline of a poller.
+#[cfg(target_os = "linux")]
+#[derive(Debug)]
+struct TimerFd {
+    fd: RawFd,
+    deadline: Option<time::Instant>,
+}
+#[cfg(target_os = "linux")]
+impl TimerFd {
+    #[cfg(feature = "ffi")]
+    fn new() -> io::Result<Self> {
+        let fd = unsafe {
+            libc::timerfd_create(
+                libc::CLOCK_MONOTONIC,
+                libc::TFD_NONBLOCK | libc::TFD_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(TimerFd { fd, deadline: None })
+    }
+
+    fn arm(&mut self, deadline: Option<time::Instant>) -> io::Result<()> {
+        if self.deadline == deadline {
+            return Ok(());
+        }
+        let mut spec: libc::itimerspec = unsafe { std::mem::zeroed() };
+        if let Some(deadline) = deadline {
+            // NOTE: A zero value disarms the timer, so the expired deadlines are rounded up to 1ns.
+            let delay = cmp::max(
+                deadline.saturating_duration_since(time::Instant::now()),
+                time::Duration::from_nanos(1),
+            );
+            spec.it_value.tv_sec = delay.as_secs() as libc::time_t;
+            spec.it_value.tv_nsec = libc::c_long::from(delay.subsec_nanos() as i32);
+        }
+        if unsafe { libc::timerfd_settime(self.fd, 0, &spec, std::ptr::null_mut()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.deadline = deadline;
+        Ok(())
+    }
+
+    fn clear(&mut self) {
+        let mut buf = [0u8; 8];
+        unsafe {
+            libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len());
+        }
+        self.deadline = None;
+    }
+}
+#[cfg(target_os = "linux")]
+impl Drop for TimerFd {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+struct TimerSaturationCallback(Arc<dyn Fn(usize) + Send + Sync>);
+impl fmt::Debug for TimerSaturationCallback {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TimerSaturationCallback(_)")
+    }
+}
+
+#[derive(Debug)]
+struct TimerEntry {
+    latest: time::Instant,
+    notifier: oneshot::Sender<()>,
 }
 
 #[derive(Debug)]
 struct CancelTimeout {
     timeout_id: usize,
     expiry_time: time::Instant,
+    slack: time::Duration,
     request_tx: RequestSender,
 }
 impl CancelTimeout {
     pub fn cancel(self) {
-        let _ = self
-            .request_tx
-            .send(Request::CancelTimeout(self.timeout_id, self.expiry_time));
+        let _ = self.request_tx.send(Request::CancelTimeout(
+            self.timeout_id,
+            self.expiry_time,
+            self.slack,
+        ));
     }
 }
 
@@ -345,34 +1137,83 @@ impl<T> Future for Register<T> {
 ///
 /// When all references of this handle are dropped,
 /// the corresponding entry in the poller is deregistered.
+///
+/// The evented object is shared by this handle and the poller,
+/// and it is dropped (e.g., its file descriptor is closed) only after the poller has
+/// processed the deregistration.
+/// Thus, the poller never fires events of a stale registration for a reused descriptor.
 #[derive(Debug)]
 pub struct EventedHandle<T> {
     token: mio::Token,
     request_tx: RequestSender,
     inner: SharableEvented<T>,
+    wake: Arc<WakeCounters>,
 }
 impl<T: mio::Evented> EventedHandle<T> {
-    fn new(inner: SharableEvented<T>, request_tx: RequestSender, token: mio::Token) -> Arc<Self> {
+    fn new(
+        inner: SharableEvented<T>,
+        request_tx: RequestSender,
+        token: mio::Token,
+        wake: Arc<WakeCounters>,
+    ) -> Arc<Self> {
         Arc::new(EventedHandle {
             token,
             request_tx,
             inner,
+            wake,
         })
     }
 
     /// Monitors occurrence of an event specified by `interest`.
     pub fn monitor(&self, interest: Interest) -> oneshot::Monitor<(), io::Error> {
-        let (monitored, monitor) = oneshot::monitor();
-        let _ = self
-            .request_tx
-            .send(Request::Monitor(self.token, interest, monitored));
+        let kind = match interest {
+            Interest::Read => WaitKind::Read,
+            Interest::Write => WaitKind::Write,
+        };
+        let (monitored, monitor) = oneshot::monitor_for(kind);
+        let _ = self.request_tx.send(Request::Monitor(
+            self.token,
+            interest,
+            Waiter::Monitor(monitored),
+        ));
         monitor
     }
 
+    /// Calls `f` when an event specified by `interest` occurs.
+    ///
+    /// Unlike `monitor`, no fiber needs to wait for the event.
+    /// Note that `f` is called on the thread running the poller,
+    /// so it should not block (e.g., it is typically used to spawn a fiber).
+    ///
+    /// If the poller is dropped before the event occurs, `f` is dropped without being called.
+    pub fn on_ready<F>(&self, interest: Interest, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let _ = self.request_tx.send(Request::Monitor(
+            self.token,
+            interest,
+            Waiter::Callback(Box::new(f)),
+        ));
+    }
+
     /// Returns the locked reference to the inner evented object.
-    pub fn inner(&self) -> EventedLock<T> {
+    pub fn inner(&self) -> EventedLock<'_, T> {
         self.inner.lock()
     }
+
+    /// Records a wakeup of a fiber which has waited for an event via `monitor` method.
+    ///
+    /// `progressed` should be `false` if the operation retried after the wakeup
+    /// made no progress (e.g., it failed with `WouldBlock` again).
+    pub fn record_wakeup(&self, progressed: bool) {
+        self.wake.record(progressed);
+    }
+
+    /// Returns the statistics of the wakeups recorded via `record_wakeup` method.
+    pub fn wake_stats(&self) -> WakeStats {
+        self.wake.snapshot()
+    }
 }
 impl<T> Drop for EventedHandle<T> {
     fn drop(&mut self) {
@@ -380,6 +1221,27 @@ impl<T> Drop for EventedHandle<T> {
     }
 }
 
+enum Waiter {
+    Monitor(oneshot::Monitored<(), io::Error>),
+    Callback(Box<dyn FnOnce() + Send + 'static>),
+}
+impl Waiter {
+    fn notify(self) {
+        match self {
+            Waiter::Monitor(monitored) => monitored.exit(Ok(())),
+            Waiter::Callback(f) => f(),
+        }
+    }
+}
+impl fmt::Debug for Waiter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Waiter::Monitor(ref monitored) => write!(f, "Waiter::Monitor({:?})", monitored),
+            Waiter::Callback(_) => write!(f, "Waiter::Callback(_)"),
+        }
+    }
+}
+
 struct BoxEvented(Box<dyn mio::Evented + Send + 'static>);
 impl fmt::Debug for BoxEvented {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -396,9 +1258,109 @@ impl fmt::Debug for RegisterReplyFn {
 
 #[derive(Debug)]
 enum Request {
-    Register(BoxEvented, RegisterReplyFn),
+    Register(BoxEvented, Option<Box<SocketInfo>>, RegisterReplyFn),
     Deregister(mio::Token),
-    Monitor(mio::Token, Interest, oneshot::Monitored<(), io::Error>),
-    SetTimeout(usize, time::Instant, oneshot::Sender<()>),
-    CancelTimeout(usize, time::Instant),
+    Monitor(mio::Token, Interest, Waiter),
+    SetTimeout(usize, time::Instant, time::Duration, oneshot::Sender<()>),
+    CancelTimeout(usize, time::Instant, time::Duration),
+    Sockets(oneshot::Sender<Vec<SocketSnapshot>>),
+    SetTimerBudget(Option<usize>),
+    SetTimerSaturationCallback(TimerSaturationCallback),
+    #[cfg(any(test, feature = "testing"))]
+    SetPollDelay(Option<time::Duration>),
+    SetReadinessDelay(Option<time::Duration>),
+    SetTraceRing(Option<TraceRing>),
+}
+
+#[cfg(test)]
+mod test {
+    use futures::{Async, Future};
+
+    use super::*;
+
+    fn udp_socket() -> mio::net::UdpSocket {
+        mio::net::UdpSocket::bind(&"127.0.0.1:0".parse().unwrap()).unwrap()
+    }
+
+    fn register(
+        poller: &mut Poller,
+        socket: mio::net::UdpSocket,
+    ) -> Arc<EventedHandle<mio::net::UdpSocket>> {
+        let mut register = poller.register(socket);
+        poller.poll(Some(time::Duration::from_millis(0))).unwrap();
+        match register.poll().unwrap() {
+            Async::Ready(handle) => handle,
+            Async::NotReady => panic!(),
+        }
+    }
+
+    #[test]
+    fn events_beyond_the_buffer_capacity_are_dispatched_at_once() {
+        let mut poller = Poller::with_capacity(2).unwrap();
+        let handles = (0..5)
+            .map(|_| register(&mut poller, udp_socket()))
+            .collect::<Vec<_>>();
+        let mut monitors = handles
+            .iter()
+            .map(|h| h.monitor(Interest::Write))
+            .collect::<Vec<_>>();
+
+        poller.poll(Some(time::Duration::from_millis(0))).unwrap();
+        for monitor in &mut monitors {
+            assert_eq!(monitor.poll().unwrap(), Async::Ready(()));
+        }
+    }
+
+    #[test]
+    fn longest_wait_works() {
+        let mut poller = Poller::new().unwrap();
+        let handle = register(&mut poller, udp_socket());
+        assert_eq!(poller.stats().longest_wait, time::Duration::from_millis(0));
+
+        let _monitor = handle.monitor(Interest::Read);
+        poller.poll(Some(time::Duration::from_millis(0))).unwrap();
+        thread::sleep(time::Duration::from_millis(20));
+        poller.poll(Some(time::Duration::from_millis(0))).unwrap();
+        assert!(poller.stats().longest_wait >= time::Duration::from_millis(20));
+
+        drop(handle);
+        poller.poll(Some(time::Duration::from_millis(0))).unwrap();
+        assert_eq!(poller.stats().longest_wait, time::Duration::from_millis(0));
+    }
+
+    #[test]
+    fn timer_backlog_works() {
+        let mut poller = Poller::new().unwrap();
+        poller.set_timer_budget(Some(2));
+        let handle = poller.handle();
+        let zero = time::Duration::from_millis(0);
+        let _timeouts = (0..MAX_TIMER_BACKLOG + 5)
+            .map(|_| set_timeout(&handle, zero, zero))
+            .collect::<Vec<_>>();
+
+        poller.poll(Some(zero)).unwrap();
+        assert_eq!(poller.stats().timer_backlog, MAX_TIMER_BACKLOG as u64);
+        assert_eq!(poller.stats().timer_saturations, 1);
+
+        poller.set_timer_budget(Some(MAX_TIMER_BACKLOG));
+        poller.poll(Some(zero)).unwrap();
+        assert_eq!(poller.stats().timer_backlog, 3);
+
+        poller.poll(Some(zero)).unwrap();
+        assert_eq!(poller.stats().timer_backlog, 0);
+        assert_eq!(poller.stats().timers_fired, MAX_TIMER_BACKLOG as u64 + 5);
+    }
+
+    #[test]
+    fn huge_slack_does_not_overflow() {
+        let mut poller = Poller::new().unwrap();
+        let handle = poller.handle();
+        let zero = time::Duration::from_millis(0);
+        let mut timeout = set_timeout(&handle, zero, time::Duration::MAX);
+        drop(set_timeout(&handle, zero, time::Duration::MAX));
+
+        poller.poll(Some(zero)).unwrap();
+        assert_eq!(timeout.poll(), Ok(Async::Ready(())));
+        assert_eq!(poller.stats().timers_fired, 1);
+    }
 }
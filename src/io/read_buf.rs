@@ -0,0 +1,213 @@
+// Copyright (c) 2016 DWANGO Co., Ltd. All Rights Reserved.
+// See the LICENSE file at the top-level directory of this distribution.
+
+use std::io::{self, Read};
+
+/// A read buffer whose size adapts to the observed read sizes.
+///
+/// The size of the buffer is doubled when a read fills the whole buffer,
+/// and is halved (and the memory is released) when two consecutive reads
+/// would have fit in the half of the buffer.
+/// The size is kept within the limits given by `with_limits`.
+///
+/// So connections which exchange only small messages (or are idle) keep small buffers,
+/// while bulk transfers quickly get large buffers.
+///
+/// # Examples
+///
+/// ```
+/// use fibers::io::AdaptiveReadBuf;
+///
+/// let mut buf = AdaptiveReadBuf::with_limits(16, 64);
+/// assert_eq!(buf.size(), 16);
+///
+/// // The reads fill the buffer: it grows
+/// let mut input = &[0; 100][..];
+/// assert_eq!(buf.read_from(&mut input).unwrap().len(), 16);
+/// assert_eq!(buf.read_from(&mut input).unwrap().len(), 32);
+/// assert_eq!(buf.size(), 64);
+///
+/// // The reads are small: it shrinks
+/// buf.read_from(&mut &[0; 10][..]).unwrap();
+/// assert_eq!(buf.size(), 64);
+/// buf.read_from(&mut &[0; 10][..]).unwrap();
+/// assert_eq!(buf.size(), 32);
+/// ```
+#[derive(Debug)]
+pub struct AdaptiveReadBuf {
+    buf: Vec<u8>,
+    min_size: usize,
+    max_size: usize,
+    shrink_pending: bool,
+}
+impl AdaptiveReadBuf {
+    /// The default minimum size of the buffer.
+    pub const DEFAULT_MIN_SIZE: usize = 256;
+
+    /// The default maximum size of the buffer.
+    pub const DEFAULT_MAX_SIZE: usize = 64 * 1024;
+
+    /// Makes a new `AdaptiveReadBuf` instance with the default limits.
+    ///
+    /// This is equivalent to `AdaptiveReadBuf::with_limits(DEFAULT_MIN_SIZE, DEFAULT_MAX_SIZE)`.
+    pub fn new() -> Self {
+        Self::with_limits(Self::DEFAULT_MIN_SIZE, Self::DEFAULT_MAX_SIZE)
+    }
+
+    /// Makes a new `AdaptiveReadBuf` instance whose size is kept within `min_size..=max_size`.
+    ///
+    /// The initial size of the buffer is `min_size`.
+    ///
+    /// # Panics
+    ///
+    /// If `min_size` is `0` or greater than `max_size`, this function will panic.
+    pub fn with_limits(min_size: usize, max_size: usize) -> Self {
+        assert!(min_size > 0);
+        assert!(min_size <= max_size);
+        AdaptiveReadBuf {
+            buf: vec![0; min_size],
+            min_size,
+            max_size,
+            shrink_pending: false,
+        }
+    }
+
+    /// Makes a new `AdaptiveReadBuf` instance whose size is fixed to `size`.
+    ///
+    /// # Panics
+    ///
+    /// If `size` is `0`, this function will panic.
+    pub fn fixed(size: usize) -> Self {
+        Self::with_limits(size, size)
+    }
+
+    /// Returns the current size of the buffer.
+    pub fn size(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Returns the minimum size of the buffer.
+    pub fn min_size(&self) -> usize {
+        self.min_size
+    }
+
+    /// Returns the maximum size of the buffer.
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    /// Reads some bytes from `reader` into the buffer and returns them.
+    ///
+    /// After the read, the size of the buffer is adjusted for the next read.
+    /// An empty slice means that `reader` has reached the end of the stream.
+    pub fn read_from<R: Read>(&mut self, reader: &mut R) -> io::Result<&[u8]> {
+        let size = self.buf.len();
+        let read_size = reader.read(&mut self.buf)?;
+        let next_size = self.next_size(size, read_size);
+
+        // The bytes read are kept intact, because they always fit in the resized buffer.
+        if next_size > size {
+            self.buf.resize(next_size, 0);
+        } else if next_size < size {
+            self.buf.truncate(next_size);
+            self.buf.shrink_to_fit();
+        }
+        Ok(&self.buf[..read_size])
+    }
+
+    fn next_size(&mut self, size: usize, read_size: usize) -> usize {
+        if read_size == size {
+            self.shrink_pending = false;
+            return size.saturating_mul(2).min(self.max_size);
+        }
+        let half = (size / 2).max(self.min_size);
+        if read_size > 0 && read_size <= half && half < size {
+            if self.shrink_pending {
+                self.shrink_pending = false;
+                return half;
+            }
+            self.shrink_pending = true;
+        } else {
+            self.shrink_pending = false;
+        }
+        size
+    }
+}
+impl Default for AdaptiveReadBuf {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct WouldBlockReader;
+    impl Read for WouldBlockReader {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+
+    #[test]
+    fn size_is_kept_within_limits() {
+        let mut buf = AdaptiveReadBuf::with_limits(16, 48);
+        let mut input = &[1; 100][..];
+        assert_eq!(buf.read_from(&mut input).unwrap(), &[1; 16][..]);
+        assert_eq!(buf.read_from(&mut input).unwrap(), &[1; 32][..]);
+        assert_eq!(buf.size(), 48);
+        assert_eq!(buf.read_from(&mut input).unwrap().len(), 48);
+        assert_eq!(buf.size(), 48);
+
+        // 48 -> 24 -> 16 (not 12)
+        for _ in 0..4 {
+            buf.read_from(&mut &[0; 1][..]).unwrap();
+        }
+        assert_eq!(buf.size(), 16);
+        for _ in 0..2 {
+            buf.read_from(&mut &[0; 1][..]).unwrap();
+        }
+        assert_eq!(buf.size(), 16);
+    }
+
+    #[test]
+    fn shrink_requires_consecutive_small_reads() {
+        let mut buf = AdaptiveReadBuf::with_limits(8, 64);
+        buf.read_from(&mut &[0; 100][..]).unwrap();
+        buf.read_from(&mut &[0; 100][..]).unwrap();
+        assert_eq!(buf.size(), 32);
+
+        // A large read resets the pending shrink.
+        buf.read_from(&mut &[0; 4][..]).unwrap();
+        buf.read_from(&mut &[0; 20][..]).unwrap();
+        buf.read_from(&mut &[0; 4][..]).unwrap();
+        assert_eq!(buf.size(), 32);
+
+        // So does the end of the stream.
+        buf.read_from(&mut &[][..]).unwrap();
+        buf.read_from(&mut &[0; 4][..]).unwrap();
+        assert_eq!(buf.size(), 32);
+        buf.read_from(&mut &[0; 4][..]).unwrap();
+        assert_eq!(buf.size(), 16);
+    }
+
+    #[test]
+    fn failed_reads_do_not_resize() {
+        let mut buf = AdaptiveReadBuf::with_limits(8, 64);
+        buf.read_from(&mut &[0; 4][..]).unwrap();
+        let e = buf.read_from(&mut WouldBlockReader).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(buf.size(), 8);
+    }
+
+    #[test]
+    fn fixed_size_never_changes() {
+        let mut buf = AdaptiveReadBuf::fixed(8);
+        buf.read_from(&mut &[0; 100][..]).unwrap();
+        assert_eq!(buf.size(), 8);
+        buf.read_from(&mut &[0; 1][..]).unwrap();
+        buf.read_from(&mut &[0; 1][..]).unwrap();
+        assert_eq!(buf.size(), 8);
+    }
+}
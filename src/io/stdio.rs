@@ -110,5 +110,5 @@ fn unexpected_eof() -> io::Error {
 }
 
 fn into_io_error<E: error::Error + Send + Sync + 'static>(error: E) -> io::Error {
-    io::Error::new(io::ErrorKind::Other, Box::new(error))
+    io::Error::other(Box::new(error))
 }
@@ -182,9 +182,14 @@
 #![warn(missing_docs)]
 
 extern crate futures;
+#[cfg(unix)]
+extern crate libc;
 extern crate mio;
 extern crate nbchan;
+extern crate net2;
 extern crate num_cpus;
+#[cfg(feature = "serde")]
+extern crate serde;
 extern crate splay_tree;
 
 macro_rules! assert_some {
@@ -207,12 +212,29 @@ pub use self::executor::{Executor, InPlaceExecutor, ThreadPoolExecutor};
 #[doc(inline)]
 pub use self::fiber::{BoxSpawn, Spawn};
 
+#[doc(inline)]
+pub use self::runtime::run;
+
+pub mod bench;
+pub mod blocking;
+pub mod bus;
+pub mod cache;
+pub mod cpu;
 pub mod executor;
+#[cfg(feature = "ffi")]
+pub mod ffi;
 pub mod fiber;
 pub mod io;
+pub mod metrics;
 pub mod net;
+pub mod proto;
+pub mod runtime;
 pub mod sync;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 pub mod time;
+pub mod trace;
+pub mod worker;
 
 mod collections;
 mod sync_atomic;
@@ -0,0 +1,257 @@
+// Copyright (c) 2016 DWANGO Co., Ltd. All Rights Reserved.
+// See the LICENSE file at the top-level directory of this distribution.
+
+//! Exporting the metrics of schedulers and pollers.
+//!
+//! The snapshots of the metrics (e.g., `SchedulerSnapshot` and `PollerStats`)
+//! implement `serde::Serialize` if the `serde` feature is enabled.
+//! In addition, this module provides an encoder of the
+//! [Prometheus text format](https://prometheus.io/docs/instrumenting/exposition_formats/).
+//!
+//! The wakeup statistics of individual resources (`WakeStats`) are available via
+//! e.g., `TcpStream::wake_stats`, `sync::mpsc::Receiver::wake_stats` and
+//! the socket registry of pollers (`SocketSnapshot::wake_stats`).
+//!
+//! # Examples
+//!
+//! ```
+//! # extern crate fibers;
+//! # extern crate futures;
+//! use fibers::{Executor, InPlaceExecutor, Spawn};
+//! use fibers::metrics;
+//!
+//! let mut executor = InPlaceExecutor::new().unwrap();
+//! executor.spawn(futures::empty());
+//! executor.run_once().unwrap();
+//!
+//! let mut buf = Vec::new();
+//! metrics::write_prometheus(
+//!     &mut buf,
+//!     &[executor.scheduler_snapshot()],
+//!     &[executor.poller_stats()],
+//! ).unwrap();
+//!
+//! let text = String::from_utf8(buf).unwrap();
+//! assert!(text.contains("fibers_scheduler_fibers{scheduler=\"0\"} 1\n"));
+//! assert!(text.contains("fibers_poller_timers_fired_total{poller=\"0\"} 0\n"));
+//! ```
+use std::io::{self, Write};
+#[cfg(feature = "serde")]
+use std::panic::Location;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::fiber::{SchedulerSnapshot, WaitKind};
+use crate::io::poll::PollerStats;
+
+/// Writes the metrics of `schedulers` and `pollers` in the Prometheus text format.
+///
+/// The schedulers are labeled with their identifiers (`scheduler="ID"`) and
+/// the pollers are labeled with their indices in `pollers` (`poller="INDEX"`).
+///
+/// The wait times of the fibers are included only for the fibers
+/// which have enabled their wait statistics (see `Context::enable_wait_stats`).
+pub fn write_prometheus<W: Write>(
+    mut writer: W,
+    schedulers: &[SchedulerSnapshot],
+    pollers: &[PollerStats],
+) -> io::Result<()> {
+    let scheduler_metrics: [Metric<SchedulerSnapshot>; 5] = [
+        (
+            "fibers_scheduler_fibers",
+            "gauge",
+            "The number of the alive fibers.",
+            |s| s.fibers.len() as u64,
+        ),
+        (
+            "fibers_scheduler_run_queue_length",
+            "gauge",
+            "The number of the runnable fibers waiting to be run.",
+            |s| s.run_queue_len as u64,
+        ),
+        (
+            "fibers_scheduler_fiber_memory_bytes",
+            "gauge",
+            "The number of the bytes charged to the fibers.",
+            |s| s.fibers.iter().map(|f| f.memory_usage as u64).sum(),
+        ),
+        (
+            "fibers_scheduler_first_polls_total",
+            "counter",
+            "The number of the fibers which have been polled at least once.",
+            |s| s.first_poll.fibers,
+        ),
+        (
+            "fibers_scheduler_slow_first_polls_total",
+            "counter",
+            "The number of the fibers whose spawn-to-first-poll latency exceeded the threshold.",
+            |s| s.first_poll.slow_fibers,
+        ),
+    ];
+    for &(name, kind, help, value) in &scheduler_metrics {
+        writeln!(writer, "# HELP {} {}", name, help)?;
+        writeln!(writer, "# TYPE {} {}", name, kind)?;
+        for s in schedulers {
+            writeln!(
+                writer,
+                "{}{{scheduler=\"{}\"}} {}",
+                name,
+                s.scheduler_id,
+                value(s)
+            )?;
+        }
+    }
+
+    let name = "fibers_scheduler_first_poll_seconds_total";
+    writeln!(
+        writer,
+        "# HELP {} The total spawn-to-first-poll latency of the fibers.",
+        name
+    )?;
+    writeln!(writer, "# TYPE {} counter", name)?;
+    for s in schedulers {
+        writeln!(
+            writer,
+            "{}{{scheduler=\"{}\"}} {}",
+            name,
+            s.scheduler_id,
+            s.first_poll.total_latency.as_secs_f64()
+        )?;
+    }
+
+    let name = "fibers_fiber_wait_seconds_total";
+    writeln!(
+        writer,
+        "# HELP {} The total time the fibers (including the exited ones) have spent suspended.",
+        name
+    )?;
+    writeln!(writer, "# TYPE {} counter", name)?;
+    for s in schedulers {
+        for &kind in &WaitKind::ALL {
+            let seconds = s.wait_stats.wait_time(kind).as_secs_f64();
+            writeln!(
+                writer,
+                "{}{{scheduler=\"{}\",kind=\"{}\"}} {}",
+                name,
+                s.scheduler_id,
+                kind.label(),
+                seconds
+            )?;
+        }
+    }
+
+    let poller_metrics: [Metric<PollerStats>; 5] = [
+        (
+            "fibers_poller_polls_total",
+            "counter",
+            "The number of the pollings (i.e., the wakeups of the poller).",
+            |p| p.polls,
+        ),
+        (
+            "fibers_poller_timers_fired_total",
+            "counter",
+            "The number of the timers which have fired.",
+            |p| p.timers_fired,
+        ),
+        (
+            "fibers_poller_timers_coalesced_total",
+            "counter",
+            "The number of the timers which have fired together with other events.",
+            |p| p.timers_coalesced,
+        ),
+        (
+            "fibers_poller_timer_backlog",
+            "gauge",
+            "The number of the expired timers which have not been fired yet.",
+            |p| p.timer_backlog,
+        ),
+        (
+            "fibers_poller_timer_saturations_total",
+            "counter",
+            "The number of the times the poller has become saturated with timers.",
+            |p| p.timer_saturations,
+        ),
+    ];
+    for &(name, kind, help, value) in &poller_metrics {
+        writeln!(writer, "# HELP {} {}", name, help)?;
+        writeln!(writer, "# TYPE {} {}", name, kind)?;
+        for (i, p) in pollers.iter().enumerate() {
+            writeln!(writer, "{}{{poller=\"{}\"}} {}", name, i, value(p))?;
+        }
+    }
+
+    let name = "fibers_poller_longest_wait_seconds";
+    writeln!(
+        writer,
+        "# HELP {} The age of the longest-waiting registration of the poller.",
+        name
+    )?;
+    writeln!(writer, "# TYPE {} gauge", name)?;
+    for (i, p) in pollers.iter().enumerate() {
+        writeln!(
+            writer,
+            "{}{{poller=\"{}\"}} {}",
+            name,
+            i,
+            p.longest_wait.as_secs_f64()
+        )?;
+    }
+    Ok(())
+}
+
+/// Statistics of the wakeups of a resource (e.g., a socket or a channel).
+///
+/// A wakeup is counted when a fiber resumes to retry an operation on the resource
+/// after waiting for it.
+/// If the retried operation makes no progress (e.g., it fails with `WouldBlock` again),
+/// the wakeup is counted as spurious.
+///
+/// A steadily increasing ratio of the spurious wakeups suggests
+/// a thundering herd (many fibers waiting for the same resource)
+/// or a bug in the arming of the interest.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct WakeStats {
+    /// The number of the wakeups.
+    pub wakeups: u64,
+
+    /// The number of the wakeups which resulted in no progress.
+    pub spurious_wakeups: u64,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct WakeCounters {
+    wakeups: AtomicU64,
+    spurious_wakeups: AtomicU64,
+}
+impl WakeCounters {
+    pub fn record(&self, progressed: bool) {
+        self.wakeups.fetch_add(1, Ordering::SeqCst);
+        if !progressed {
+            self.spurious_wakeups.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+    pub fn snapshot(&self) -> WakeStats {
+        WakeStats {
+            wakeups: self.wakeups.load(Ordering::SeqCst),
+            spurious_wakeups: self.spurious_wakeups.load(Ordering::SeqCst),
+        }
+    }
+}
+
+/// The name, type, help text and value getter of a metric.
+type Metric<T> = (&'static str, &'static str, &'static str, fn(&T) -> u64);
+
+/// Serializes a spawn site as a `"FILE:LINE:COLUMN"` string.
+#[cfg(feature = "serde")]
+pub(crate) fn serialize_spawn_site<S>(
+    site: &Option<&'static Location<'static>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    match *site {
+        Some(location) => serializer.collect_str(location),
+        None => serializer.serialize_none(),
+    }
+}
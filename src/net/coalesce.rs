@@ -0,0 +1,285 @@
+// Copyright (c) 2016 DWANGO Co., Ltd. All Rights Reserved.
+// See the LICENSE file at the top-level directory of this distribution.
+
+use futures::{Async, Future, Poll};
+use std::cmp;
+use std::io::{self, Write};
+use std::time::Duration;
+
+use super::tcp::{DetachedTcpStream, FlushAndShutdown};
+use super::TcpStream;
+use crate::time::timer::{self, Timeout};
+
+const DEFAULT_MAX_BUFFER_SIZE: usize = 8 * 1024;
+
+/// The policy to determine when the data buffered in `CoalescingWriter` is flushed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlushPolicy {
+    /// The buffered data is flushed each time `CoalescingWriter::poll_flush_due` is called.
+    ///
+    /// If `poll_flush_due` is called at the end of each poll of the owning fiber,
+    /// all the small writes issued during the poll are coalesced into one write.
+    OnPollEnd,
+
+    /// The buffered data is flushed when the specified time has elapsed since
+    /// the first byte was buffered.
+    Delay(Duration),
+}
+
+/// A writer which coalesces small writes into a buffer and writes them at once.
+///
+/// The writes larger than or equal to the maximum buffer size bypass the buffer
+/// (after the already buffered data is written).
+/// When the buffer is full, the buffered data is written to the inner writer
+/// before accepting new data. If the inner writer would block at that time
+/// (i.e., the connection is congested), the `std::io::ErrorKind::WouldBlock` error is returned
+/// and the caller should retry later as usual.
+///
+/// # Avoiding corked data
+///
+/// The buffered data is written only when the buffer becomes full or when
+/// `flush` or `poll_flush_due` method is called.
+/// So the owning future must call `poll_flush_due` each time it is polled
+/// (typically, just before returning `Async::NotReady`).
+/// If the flush policy is `FlushPolicy::Delay`, a timer is armed when the first byte is buffered,
+/// and it guarantees that the owning fiber is woken up to flush the data after the delay.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate fibers;
+/// use std::io::Write;
+/// use fibers::net::CoalescingWriter;
+///
+/// let mut writer = CoalescingWriter::new(Vec::new());
+/// writer.write_all(b"foo").unwrap();
+/// writer.write_all(b"bar").unwrap();
+/// assert_eq!(writer.buffered_len(), 6);
+/// assert!(writer.get_ref().is_empty());
+///
+/// writer.flush().unwrap();
+/// assert_eq!(writer.buffered_len(), 0);
+/// assert_eq!(writer.get_ref(), b"foobar");
+/// ```
+#[derive(Debug)]
+pub struct CoalescingWriter<W> {
+    inner: W,
+    buf: Vec<u8>,
+    max_buffer_size: usize,
+    flush_policy: FlushPolicy,
+    timer: Option<Timeout>,
+}
+impl<W: Write> CoalescingWriter<W> {
+    /// Makes a new `CoalescingWriter` instance.
+    ///
+    /// The maximum buffer size is 8 KiB and
+    /// the flush policy is `FlushPolicy::OnPollEnd` by default.
+    pub fn new(inner: W) -> Self {
+        CoalescingWriter {
+            inner,
+            buf: Vec::new(),
+            max_buffer_size: DEFAULT_MAX_BUFFER_SIZE,
+            flush_policy: FlushPolicy::OnPollEnd,
+            timer: None,
+        }
+    }
+
+    /// Sets the maximum size of the buffer.
+    pub fn set_max_buffer_size(&mut self, size: usize) {
+        self.max_buffer_size = size;
+    }
+
+    /// Sets the flush policy of this writer.
+    pub fn set_flush_policy(&mut self, policy: FlushPolicy) {
+        self.flush_policy = policy;
+        self.timer = None;
+    }
+
+    /// Returns the flush policy of this writer.
+    pub fn flush_policy(&self) -> FlushPolicy {
+        self.flush_policy
+    }
+
+    /// Returns the number of the bytes buffered but not written yet.
+    pub fn buffered_len(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Returns a reference to the inner writer.
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the inner writer.
+    ///
+    /// Note that writing directly to the inner writer may reorder the data.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Unwraps this writer, returning the inner writer and the data not written yet.
+    pub fn into_inner(self) -> (W, Vec<u8>) {
+        (self.inner, self.buf)
+    }
+
+    /// Writes the buffered data if it is due according to the flush policy.
+    ///
+    /// This returns `Async::Ready(())` if there is no buffered data left.
+    /// Otherwise, it returns `Async::NotReady` and the current fiber will be
+    /// woken up when the data can be written (or the flush delay has expired).
+    pub fn poll_flush_due(&mut self) -> Poll<(), io::Error> {
+        if self.buf.is_empty() {
+            self.timer = None;
+            return Ok(Async::Ready(()));
+        }
+        if let FlushPolicy::Delay(_) = self.flush_policy {
+            let expired = match self.timer.as_mut().map(|t| t.poll()) {
+                None | Some(Ok(Async::Ready(()))) | Some(Err(_)) => true,
+                Some(Ok(Async::NotReady)) => false,
+            };
+            if !expired {
+                return Ok(Async::NotReady);
+            }
+        }
+        match self.write_buf() {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(Async::NotReady),
+            Err(e) => Err(e),
+            Ok(()) => Ok(Async::Ready(())),
+        }
+    }
+
+    fn write_buf(&mut self) -> io::Result<()> {
+        while !self.buf.is_empty() {
+            match self.inner.write(&self.buf) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::WriteZero,
+                        "failed to write the buffered data",
+                    ));
+                }
+                Ok(n) => {
+                    self.buf.drain(..n);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        self.timer = None;
+        Ok(())
+    }
+}
+impl CoalescingWriter<TcpStream> {
+    /// Makes a future which writes the buffered data and then shuts down the write side
+    /// of the inner stream.
+    ///
+    /// See `TcpStream::flush_and_shutdown` for more details.
+    pub fn flush_and_shutdown(self) -> FlushAndShutdown {
+        FlushAndShutdown::new(self.inner, self.buf)
+    }
+
+    /// Detaches the inner stream from the poller which it is registered to.
+    ///
+    /// The buffered data which has not been written yet is carried by the returned stream
+    /// (see `DetachedTcpStream::take_pending_output`).
+    ///
+    /// See `TcpStream::detach` for more details.
+    pub fn detach(self) -> io::Result<DetachedTcpStream> {
+        let stream = self.inner.detach()?;
+        Ok(stream.with_pending_output(self.buf))
+    }
+}
+impl<W: Write> Write for CoalescingWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.buf.len() + data.len() > self.max_buffer_size {
+            self.write_buf()?;
+        }
+        if data.len() >= self.max_buffer_size {
+            return self.inner.write(data);
+        }
+        let size = cmp::min(data.len(), self.max_buffer_size - self.buf.len());
+        if self.buf.is_empty() && size > 0 {
+            if let FlushPolicy::Delay(delay) = self.flush_policy {
+                self.timer = Some(timer::timeout(delay));
+            }
+        }
+        self.buf.extend_from_slice(&data[..size]);
+        Ok(size)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_buf()?;
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Executor, InPlaceExecutor, Spawn};
+
+    /// A writer which accepts at most `capacity` bytes until the capacity is refilled.
+    #[derive(Debug, Default)]
+    struct CongestedWriter {
+        written: Vec<u8>,
+        capacity: usize,
+    }
+    impl Write for CongestedWriter {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            if self.capacity == 0 {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let size = cmp::min(self.capacity, data.len());
+            self.capacity -= size;
+            self.written.extend_from_slice(&data[..size]);
+            Ok(size)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn would_block_in_the_middle_of_buffer_works() {
+        let mut writer = CoalescingWriter::new(CongestedWriter::default());
+        writer.set_max_buffer_size(4);
+        writer.get_mut().capacity = 2;
+        assert_eq!(writer.write(b"abc").unwrap(), 3);
+
+        // The buffer overflows and only a part of the buffered data can be written.
+        let e = writer.write(b"de").unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(writer.get_ref().written, b"ab");
+        assert_eq!(writer.buffered_len(), 1);
+        assert!(writer.poll_flush_due().unwrap().is_not_ready());
+        assert_eq!(writer.buffered_len(), 1);
+
+        // The rest of the data is written in order once the congestion is resolved.
+        writer.get_mut().capacity = 16;
+        assert_eq!(writer.write(b"de").unwrap(), 2);
+        assert_eq!(writer.buffered_len(), 3);
+        assert!(writer.poll_flush_due().unwrap().is_ready());
+        assert_eq!(writer.get_ref().written, b"abcde");
+    }
+
+    #[test]
+    fn delay_timer_flushes_buffer() {
+        let mut writer = CoalescingWriter::new(Vec::new());
+        writer.set_flush_policy(FlushPolicy::Delay(Duration::from_millis(10)));
+        writer.write_all(b"foo").unwrap();
+        assert!(writer.poll_flush_due().unwrap().is_not_ready());
+        assert!(writer.get_ref().is_empty());
+
+        // The fiber is woken up by the timer to flush the buffered data.
+        let mut executor = InPlaceExecutor::new().unwrap();
+        let mut writer = Some(writer);
+        let monitor =
+            executor.spawn_monitor(futures::future::poll_fn(move || -> Poll<_, io::Error> {
+                futures::try_ready!(writer.as_mut().expect("Never fails").poll_flush_due());
+                Ok(Async::Ready(
+                    writer.take().expect("Never fails").into_inner(),
+                ))
+            }));
+        let (written, rest) = executor.run_fiber(monitor).unwrap().unwrap();
+        assert_eq!(written, b"foo");
+        assert!(rest.is_empty());
+    }
+}
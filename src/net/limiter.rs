@@ -0,0 +1,291 @@
+// Copyright (c) 2016 DWANGO Co., Ltd. All Rights Reserved.
+// See the LICENSE file at the top-level directory of this distribution.
+
+use futures::{Async, Future, IntoFuture, Poll};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::hash::Hash;
+use std::io;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::into_io_error;
+use crate::sync::oneshot;
+use crate::time::timer::{self, Timeout};
+
+/// A limiter of the number of concurrent connection attempts to each host.
+///
+/// When the number of in-flight attempts to a host reaches the limit,
+/// subsequent attempts are queued in FIFO order until one of the preceding attempts completes.
+/// If a queue timeout is set, the attempts which have waited longer than it
+/// will fail with the `std::io::ErrorKind::TimedOut` error.
+///
+/// This is useful to prevent connection stampedes to a recovering backend.
+///
+/// The key `K` identifies a host (e.g., `SocketAddr` or a host name to be resolved by the factory).
+///
+/// # Examples
+///
+/// ```
+/// # extern crate fibers;
+/// # extern crate futures;
+/// use fibers::{Executor, InPlaceExecutor, Spawn};
+/// use fibers::net::{ConnectLimiter, TcpListener, TcpStream};
+/// use futures::{Future, Stream};
+///
+/// let mut executor = InPlaceExecutor::new().unwrap();
+/// let bind = executor.spawn_monitor(TcpListener::bind("127.0.0.1:0".parse().unwrap()));
+/// let listener = executor.run_fiber(bind).unwrap().unwrap();
+/// let addr = listener.local_addr().unwrap();
+/// executor.spawn(listener.incoming().for_each(|_| Ok(())).map_err(|e| panic!("{}", e)));
+///
+/// // At most one connection attempt per host is in-flight at a time.
+/// let limiter = ConnectLimiter::new(1);
+/// let c0 = executor.spawn_monitor(limiter.connect(addr, move || TcpStream::connect(addr)));
+/// let c1 = executor.spawn_monitor(limiter.connect(addr, move || TcpStream::connect(addr)));
+/// assert!(executor.run_future(c0.join(c1)).unwrap().is_ok());
+/// assert_eq!(limiter.in_flight(&addr), 0);
+/// ```
+#[derive(Debug)]
+pub struct ConnectLimiter<K> {
+    shared: Arc<Mutex<HashMap<K, HostState>>>,
+    max_concurrency: usize,
+    queue_timeout: Option<Duration>,
+}
+impl<K> ConnectLimiter<K>
+where
+    K: Hash + Eq + Clone + Send + 'static,
+{
+    /// Makes a new limiter which allows `max_concurrency` concurrent attempts per host.
+    ///
+    /// # Panics
+    ///
+    /// If `max_concurrency` is `0`, this function will panic.
+    pub fn new(max_concurrency: usize) -> Self {
+        assert!(max_concurrency > 0);
+        ConnectLimiter {
+            shared: Arc::new(Mutex::new(HashMap::new())),
+            max_concurrency,
+            queue_timeout: None,
+        }
+    }
+
+    /// Makes a new limiter which also limits the time an attempt can wait in the queue.
+    pub fn with_queue_timeout(max_concurrency: usize, queue_timeout: Duration) -> Self {
+        let mut this = Self::new(max_concurrency);
+        this.queue_timeout = Some(queue_timeout);
+        this
+    }
+
+    /// Makes a future which will call `connect` to start a connection attempt to `host`
+    /// once the number of in-flight attempts to the host falls below the limit.
+    ///
+    /// The permit is held until the future returned by `connect` completes.
+    pub fn connect<F, T>(&self, host: K, connect: F) -> LimitedConnect<F, T>
+    where
+        F: FnOnce() -> T,
+        T: IntoFuture<Error = io::Error>,
+    {
+        let mut hosts = self.shared.lock().unwrap_or_else(|e| e.into_inner());
+        let state = hosts.entry(host.clone()).or_insert_with(HostState::new);
+        let inner = if state.in_flight < self.max_concurrency {
+            state.in_flight += 1;
+            let permit = Permit::new(host, Arc::clone(&self.shared));
+            LimitedConnectInner::Acquired(permit, connect)
+        } else {
+            let (tx, rx) = oneshot::channel();
+            state.waitings.push_back(tx);
+            let timeout = self.queue_timeout.map(timer::timeout);
+            LimitedConnectInner::Queued(rx, timeout, connect)
+        };
+        LimitedConnect(inner)
+    }
+
+    /// Returns the number of in-flight attempts to `host`.
+    pub fn in_flight(&self, host: &K) -> usize {
+        let hosts = self.shared.lock().unwrap_or_else(|e| e.into_inner());
+        hosts.get(host).map_or(0, |s| s.in_flight)
+    }
+
+    /// Returns the number of attempts to `host` which are waiting in the queue.
+    ///
+    /// Note that the attempts which have already been abandoned may be included in the result.
+    pub fn queued(&self, host: &K) -> usize {
+        let hosts = self.shared.lock().unwrap_or_else(|e| e.into_inner());
+        hosts.get(host).map_or(0, |s| s.waitings.len())
+    }
+}
+impl<K> Clone for ConnectLimiter<K> {
+    fn clone(&self) -> Self {
+        ConnectLimiter {
+            shared: Arc::clone(&self.shared),
+            max_concurrency: self.max_concurrency,
+            queue_timeout: self.queue_timeout,
+        }
+    }
+}
+
+/// A future which will start a connection attempt once it is permitted by `ConnectLimiter`.
+///
+/// This is created by calling `ConnectLimiter::connect` method.
+pub struct LimitedConnect<F, T: IntoFuture>(LimitedConnectInner<F, T>);
+impl<F, T> Future for LimitedConnect<F, T>
+where
+    F: FnOnce() -> T,
+    T: IntoFuture<Error = io::Error>,
+{
+    type Item = T::Item;
+    type Error = io::Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            match mem::replace(&mut self.0, LimitedConnectInner::Polled) {
+                LimitedConnectInner::Queued(mut rx, mut timeout, connect) => {
+                    if let Async::Ready(permit) = rx.poll().map_err(into_io_error)? {
+                        self.0 = LimitedConnectInner::Acquired(permit, connect);
+                        continue;
+                    }
+                    if let Some(Ok(Async::Ready(()))) = timeout.as_mut().map(|t| t.poll()) {
+                        return Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "Timeout expired while waiting for a connection permit",
+                        ));
+                    }
+                    self.0 = LimitedConnectInner::Queued(rx, timeout, connect);
+                    return Ok(Async::NotReady);
+                }
+                LimitedConnectInner::Acquired(permit, connect) => {
+                    let future = connect().into_future();
+                    self.0 = LimitedConnectInner::Connecting(permit, future);
+                }
+                LimitedConnectInner::Connecting(permit, mut future) => {
+                    if let Async::Ready(item) = future.poll()? {
+                        return Ok(Async::Ready(item));
+                    }
+                    self.0 = LimitedConnectInner::Connecting(permit, future);
+                    return Ok(Async::NotReady);
+                }
+                LimitedConnectInner::Polled => panic!("Cannot poll LimitedConnect twice"),
+            }
+        }
+    }
+}
+impl<F, T: IntoFuture> fmt::Debug for LimitedConnect<F, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            LimitedConnectInner::Queued(..) => write!(f, "LimitedConnect::Queued(_)"),
+            LimitedConnectInner::Acquired(..) => write!(f, "LimitedConnect::Acquired(_)"),
+            LimitedConnectInner::Connecting(..) => write!(f, "LimitedConnect::Connecting(_)"),
+            LimitedConnectInner::Polled => write!(f, "LimitedConnect::Polled"),
+        }
+    }
+}
+
+enum LimitedConnectInner<F, T: IntoFuture> {
+    Queued(oneshot::Receiver<Permit>, Option<Timeout>, F),
+    Acquired(Permit, F),
+    Connecting(Permit, T::Future),
+    Polled,
+}
+
+#[derive(Debug)]
+struct HostState {
+    in_flight: usize,
+    waitings: VecDeque<oneshot::Sender<Permit>>,
+}
+impl HostState {
+    fn new() -> Self {
+        HostState {
+            in_flight: 0,
+            waitings: VecDeque::new(),
+        }
+    }
+}
+
+/// The right to make a connection attempt to a host.
+///
+/// When this is dropped, the right is handed over to the next queued attempt (if any).
+struct Permit(Option<Box<dyn FnOnce() + Send + 'static>>);
+impl Permit {
+    fn new<K>(host: K, shared: Arc<Mutex<HashMap<K, HostState>>>) -> Self
+    where
+        K: Hash + Eq + Clone + Send + 'static,
+    {
+        Permit(Some(Box::new(move || release(host, shared))))
+    }
+}
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some(release) = self.0.take() {
+            release();
+        }
+    }
+}
+
+fn release<K>(host: K, shared: Arc<Mutex<HashMap<K, HostState>>>)
+where
+    K: Hash + Eq + Clone + Send + 'static,
+{
+    let mut hosts = shared.lock().unwrap_or_else(|e| e.into_inner());
+    let state = assert_some!(hosts.get_mut(&host));
+    while let Some(tx) = state.waitings.pop_front() {
+        let permit = Permit::new(host.clone(), Arc::clone(&shared));
+        match tx.send(permit) {
+            Ok(()) => return,
+            Err(e) => {
+                // The waiting attempt has been abandoned.
+                let mut permit = e.0;
+                permit.0 = None;
+            }
+        }
+    }
+    state.in_flight -= 1;
+    if state.in_flight == 0 {
+        hosts.remove(&host);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn connect() -> futures::Finished<(), io::Error> {
+        futures::finished(())
+    }
+
+    #[test]
+    fn abandoned_waiters_are_counted_until_permit_is_released() {
+        let limiter = ConnectLimiter::new(1);
+        let acquired = limiter.connect(0, connect);
+        let abandoned = limiter.connect(0, connect);
+        let mut queued = limiter.connect(0, connect);
+        assert_eq!(limiter.in_flight(&0), 1);
+        assert_eq!(limiter.queued(&0), 2);
+
+        // The abandoned attempt stays in the queue until the permit is handed over.
+        drop(abandoned);
+        assert_eq!(limiter.queued(&0), 2);
+
+        drop(acquired);
+        assert_eq!(limiter.in_flight(&0), 1);
+        assert_eq!(limiter.queued(&0), 0);
+        assert!(queued.poll().unwrap().is_ready());
+
+        drop(queued);
+        assert_eq!(limiter.in_flight(&0), 0);
+    }
+
+    #[test]
+    fn host_state_is_removed_if_all_waiters_are_abandoned() {
+        let limiter = ConnectLimiter::new(1);
+        let acquired = limiter.connect(0, connect);
+        let abandoned = limiter.connect(0, connect);
+        drop(abandoned);
+        assert_eq!(limiter.queued(&0), 1);
+
+        drop(acquired);
+        assert_eq!(limiter.in_flight(&0), 0);
+        assert_eq!(limiter.queued(&0), 0);
+        assert!(limiter.shared.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,287 @@
+// Copyright (c) 2016 DWANGO Co., Ltd. All Rights Reserved.
+// See the LICENSE file at the top-level directory of this distribution.
+
+use futures::{Async, Poll, Stream};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{self, AtomicUsize};
+use std::sync::Arc;
+
+use super::tcp::{Connected, Incoming};
+use super::TcpListener;
+use crate::sync::mpsc;
+
+/// The identifier of a listener in a `ListenerSet`.
+pub type ListenerId = usize;
+
+/// A dynamic set of listeners which merges their accepted connections into one stream.
+///
+/// Listeners can be added to (or removed from) the set at runtime via `ListenerSetHandle`,
+/// so that, for example, a new listen port can be opened by a control fiber
+/// without restarting the accept loop.
+///
+/// The stream yields the accepted connections with the identifier of the listener
+/// which accepted them.
+/// It never ends (new listeners may be added later) and never fails:
+/// if a listener fails, it is removed from the set and the error is reported
+/// via `ListenerSet::errors`.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate fibers;
+/// # extern crate futures;
+/// use fibers::{Executor, InPlaceExecutor, Spawn};
+/// use fibers::net::{ListenerSet, TcpListener, TcpStream};
+/// use futures::{Future, Stream};
+///
+/// let mut executor = InPlaceExecutor::new().unwrap();
+/// let listeners = ListenerSet::new();
+/// let handle = listeners.handle();
+/// let accepted = executor.spawn_monitor(listeners.take(2).collect());
+///
+/// // Adds listeners at runtime.
+/// let mut addrs = Vec::new();
+/// for _ in 0..2 {
+///     let bind = executor.spawn_monitor(TcpListener::bind("127.0.0.1:0".parse().unwrap()));
+///     let listener = executor.run_fiber(bind).unwrap().unwrap();
+///     addrs.push(listener.local_addr().unwrap());
+///     handle.add(listener);
+/// }
+/// for addr in addrs {
+///     executor.spawn(TcpStream::connect(addr).then(|_| Ok(())));
+/// }
+///
+/// let accepted = executor.run_fiber(accepted).unwrap().unwrap();
+/// let mut ids = accepted.iter().map(|&(id, _, _)| id).collect::<Vec<_>>();
+/// ids.sort();
+/// assert_eq!(ids, vec![0, 1]);
+/// ```
+#[derive(Debug)]
+pub struct ListenerSet {
+    listeners: Vec<(ListenerId, Incoming)>,
+    command_rx: mpsc::Receiver<Command>,
+    handle: ListenerSetHandle,
+    error_tx: Option<mpsc::Sender<(ListenerId, io::Error)>>,
+    next_index: usize,
+}
+impl ListenerSet {
+    /// Makes a new empty `ListenerSet` instance.
+    pub fn new() -> Self {
+        let (command_tx, command_rx) = mpsc::channel();
+        ListenerSet {
+            listeners: Vec::new(),
+            command_rx,
+            handle: ListenerSetHandle {
+                command_tx,
+                next_id: Arc::new(AtomicUsize::new(0)),
+            },
+            error_tx: None,
+            next_index: 0,
+        }
+    }
+
+    /// Returns a handle to add or remove listeners.
+    pub fn handle(&self) -> ListenerSetHandle {
+        self.handle.clone()
+    }
+
+    /// Returns the number of the listeners in this set.
+    ///
+    /// Note that the additions and removals requested via handles
+    /// are applied when this stream is polled.
+    pub fn len(&self) -> usize {
+        self.listeners.len()
+    }
+
+    /// Returns `true` if this set has no listener, otherwise `false`.
+    pub fn is_empty(&self) -> bool {
+        self.listeners.is_empty()
+    }
+
+    /// Returns the local addresses of the listeners in this set.
+    ///
+    /// Like `ListenerSet::len`, the additions and removals requested via handles
+    /// are reflected after this stream is polled.
+    pub fn local_addrs(&self) -> Vec<(ListenerId, SocketAddr)> {
+        self.listeners
+            .iter()
+            .filter_map(|&(id, ref l)| l.local_addr().ok().map(|addr| (id, addr)))
+            .collect()
+    }
+
+    /// Returns a stream of the errors which made listeners removed from this set.
+    ///
+    /// If this method is called multiple times, only the latest stream receives errors.
+    pub fn errors(&mut self) -> mpsc::Receiver<(ListenerId, io::Error)> {
+        let (tx, rx) = mpsc::channel();
+        self.error_tx = Some(tx);
+        rx
+    }
+
+    pub(super) fn push(&mut self, incoming: Incoming) -> ListenerId {
+        let id = self.handle.next_id.fetch_add(1, atomic::Ordering::SeqCst);
+        self.listeners.push((id, incoming));
+        id
+    }
+
+    fn handle_commands(&mut self) {
+        while let Ok(Async::Ready(Some(command))) = self.command_rx.poll() {
+            match command {
+                Command::Add(id, incoming) => self.listeners.push((id, *incoming)),
+                Command::Remove(id) => self.listeners.retain(|l| l.0 != id),
+            }
+        }
+    }
+}
+impl Default for ListenerSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Stream for ListenerSet {
+    type Item = (ListenerId, Connected, SocketAddr);
+    type Error = io::Error;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        self.handle_commands();
+        let mut i = 0;
+        while i < self.listeners.len() {
+            let index = (self.next_index + i) % self.listeners.len();
+            let id = self.listeners[index].0;
+            match self.listeners[index].1.poll() {
+                Ok(Async::NotReady) => {
+                    i += 1;
+                }
+                Ok(Async::Ready(Some((connected, addr)))) => {
+                    self.next_index = index + 1;
+                    return Ok(Async::Ready(Some((id, connected, addr))));
+                }
+                Ok(Async::Ready(None)) => {
+                    self.listeners.remove(index);
+                }
+                Err(e) => {
+                    self.listeners.remove(index);
+                    if let Some(ref tx) = self.error_tx {
+                        let _ = tx.send((id, e));
+                    }
+                }
+            }
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+/// A handle to add or remove listeners of a `ListenerSet`.
+///
+/// This is created by calling `ListenerSet::handle` method.
+#[derive(Debug, Clone)]
+pub struct ListenerSetHandle {
+    command_tx: mpsc::Sender<Command>,
+    next_id: Arc<AtomicUsize>,
+}
+impl ListenerSetHandle {
+    /// Adds `listener` to the set and returns its identifier.
+    pub fn add(&self, listener: TcpListener) -> ListenerId {
+        self.add_incoming(listener.incoming())
+    }
+
+    /// Adds `incoming` to the set and returns its identifier.
+    ///
+    /// This is useful to apply per-listener settings (e.g., `Incoming::set_error_policy`).
+    pub fn add_incoming(&self, incoming: Incoming) -> ListenerId {
+        let id = self.next_id.fetch_add(1, atomic::Ordering::SeqCst);
+        let _ = self.command_tx.send(Command::Add(id, Box::new(incoming)));
+        id
+    }
+
+    /// Removes the listener identified by `id` from the set.
+    ///
+    /// The listener is closed when the removal is applied.
+    /// The connections which have been already yielded from the set are not affected.
+    pub fn remove(&self, id: ListenerId) {
+        let _ = self.command_tx.send(Command::Remove(id));
+    }
+}
+
+#[derive(Debug)]
+enum Command {
+    Add(ListenerId, Box<Incoming>),
+    Remove(ListenerId),
+}
+
+#[cfg(test)]
+mod test {
+    use futures::Future;
+    use std::net;
+
+    use super::*;
+    use crate::{Executor, InPlaceExecutor, Spawn};
+
+    fn bind(executor: &mut InPlaceExecutor) -> TcpListener {
+        let bind = executor.spawn_monitor(TcpListener::bind("127.0.0.1:0".parse().unwrap()));
+        executor.run_fiber(bind).unwrap().unwrap()
+    }
+
+    #[test]
+    fn connections_are_accepted_in_round_robin_order() {
+        let mut executor = InPlaceExecutor::new().unwrap();
+        let listeners = ListenerSet::new();
+        let handle = listeners.handle();
+
+        let mut ids = Vec::new();
+        let mut clients = Vec::new();
+        for _ in 0..2 {
+            let listener = bind(&mut executor);
+            let addr = listener.local_addr().unwrap();
+            ids.push(handle.add(listener));
+
+            // Both listeners have pending connections.
+            for _ in 0..2 {
+                clients.push(net::TcpStream::connect(addr).unwrap());
+            }
+        }
+
+        let accepted = executor.spawn_monitor(listeners.take(4).collect());
+        let accepted = executor.run_fiber(accepted).unwrap().unwrap();
+        let accepted_ids = accepted.iter().map(|&(id, _, _)| id).collect::<Vec<_>>();
+        assert_eq!(accepted_ids, vec![ids[0], ids[1], ids[0], ids[1]]);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn failing_listener_is_removed() {
+        use std::os::unix::io::AsRawFd;
+
+        let mut executor = InPlaceExecutor::new().unwrap();
+        let mut listeners = ListenerSet::new();
+        let mut errors = listeners.errors();
+        let handle = listeners.handle();
+
+        // On Linux, `accept` fails with `EINVAL` if the listener has been shut down.
+        let failing = bind(&mut executor);
+        failing.with_inner(|l| unsafe { libc::shutdown(l.as_raw_fd(), libc::SHUT_RDWR) });
+        let failing_id = handle.add(failing);
+
+        let healthy = bind(&mut executor);
+        let _client = net::TcpStream::connect(healthy.local_addr().unwrap()).unwrap();
+        let healthy_id = handle.add(healthy);
+
+        let accept = executor.spawn_monitor(listeners.into_future().map_err(|(e, _)| e));
+        let (accepted, mut listeners) = executor.run_fiber(accept).unwrap().unwrap();
+        assert_eq!(accepted.map(|(id, _, _)| id), Some(healthy_id));
+        assert_eq!(listeners.len(), 1);
+        match errors.poll() {
+            Ok(Async::Ready(Some((id, e)))) => {
+                assert_eq!(id, failing_id);
+                assert_eq!(e.raw_os_error(), Some(libc::EINVAL));
+            }
+            _ => panic!(),
+        }
+
+        // Removing the listener which has already been removed due to the failure is harmless.
+        handle.remove(failing_id);
+        handle.remove(healthy_id);
+        assert!(listeners.poll().unwrap().is_not_ready());
+        assert!(listeners.is_empty());
+    }
+}
@@ -1,7 +1,7 @@
 // Copyright (c) 2016 DWANGO Co., Ltd. All Rights Reserved.
 // See the LICENSE file at the top-level directory of this distribution.
 
-//! Networking primitives for TCP/UDP communication.
+//! Networking primitives for TCP/UDP (and VM socket) communication.
 //!
 //! # Implementation Details
 //!
@@ -25,15 +25,26 @@ use std::mem;
 use std::net::SocketAddr;
 use std::sync::Arc;
 
+pub use self::coalesce::{CoalescingWriter, FlushPolicy};
+pub use self::limiter::ConnectLimiter;
+pub use self::listener_set::{ListenerId, ListenerSet, ListenerSetHandle};
+pub use self::rate_limit::{AcceptRateLimiter, EvictIdleSources};
+pub use self::reassemble::{Fragment, Reassembler};
+pub use self::stream_options::TcpStreamOptions;
+pub use self::tcp::{on_readable, AcceptErrorPolicy, DetachedTcpStream, ShedCounter};
 pub use self::tcp::{TcpListener, TcpStream};
 pub use self::udp::UdpSocket;
 
-use crate::fiber;
-use crate::io::poll::{EventedHandle, Register};
+use crate::fiber::{self, Context};
+use crate::io::poll::{EventedHandle, Register, SocketInfo, SocketKind};
 
 pub mod futures {
     //! Implementations of `futures::Future` trait.
-    pub use super::tcp::{Connect, Connected, TcpListenerBind};
+    pub use super::limiter::LimitedConnect;
+    pub use super::tcp::{
+        AcceptBatch, AttachTcpStream, Connect, Connected, FlushAndShutdown, TcpListenerBind,
+        TcpListenerBindAny, TcpListenerBindDualStack,
+    };
     pub use super::udp::{RecvFrom, SendTo, UdpSocketBind};
 }
 pub mod streams {
@@ -41,9 +52,77 @@ pub mod streams {
     pub use super::tcp::Incoming;
 }
 
+pub mod rudp;
+#[cfg(target_os = "linux")]
+pub mod vsock;
+
+mod coalesce;
+mod limiter;
+mod listener_set;
+mod rate_limit;
+mod reassemble;
+mod stream_options;
 mod tcp;
 mod udp;
 
+/// A mio socket which can be recorded in the socket registry of a poller.
+trait Socket: mio::Evented + Send + 'static {
+    fn kind(&self) -> SocketKind;
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+}
+impl Socket for mio::net::TcpListener {
+    fn kind(&self) -> SocketKind {
+        SocketKind::TcpListener
+    }
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.local_addr()
+    }
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Err(io::ErrorKind::NotConnected.into())
+    }
+}
+impl Socket for mio::net::TcpStream {
+    fn kind(&self) -> SocketKind {
+        SocketKind::TcpStream
+    }
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.local_addr()
+    }
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.peer_addr()
+    }
+}
+impl Socket for mio::net::UdpSocket {
+    fn kind(&self) -> SocketKind {
+        SocketKind::UdpSocket
+    }
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.local_addr()
+    }
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Err(io::ErrorKind::NotConnected.into())
+    }
+}
+
+/// Registers `socket` to the poller of the current fiber.
+///
+/// If the socket registry of the poller is enabled,
+/// the socket is recorded with the current fiber as its owner.
+fn register_socket<T: Socket>(mut c: Context, socket: T) -> Register<T> {
+    let owner = c.context_id();
+    let spawn_site = c.spawn_site();
+    c.poller().register_socket(socket, |s| SocketInfo {
+        kind: s.kind(),
+        local_addr: s.local_addr().ok(),
+        peer_addr: s.peer_addr().ok(),
+        owner: Some(owner),
+        spawn_site,
+        wake: Default::default(),
+    })
+}
+
+#[allow(clippy::enum_variant_names)]
 enum Bind<F, T> {
     Bind(SocketAddr, F),
     Registering(Register<T>),
@@ -52,7 +131,7 @@ enum Bind<F, T> {
 impl<F, T> Future for Bind<F, T>
 where
     F: FnOnce(&SocketAddr) -> io::Result<T>,
-    T: mio::Evented + Send + 'static,
+    T: Socket,
 {
     type Item = Arc<EventedHandle<T>>;
     type Error = io::Error;
@@ -60,9 +139,8 @@ where
         match mem::replace(self, Bind::Polled) {
             Bind::Bind(addr, bind) => {
                 let socket = bind(&addr)?;
-                let register = assert_some!(fiber::with_current_context(|mut c| c
-                    .poller()
-                    .register(socket),));
+                let register =
+                    assert_some!(fiber::with_current_context(|c| register_socket(c, socket)));
                 *self = Bind::Registering(register);
                 self.poll()
             }
@@ -89,5 +167,5 @@ impl<F, T> fmt::Debug for Bind<F, T> {
 }
 
 fn into_io_error<E: error::Error + Send + Sync + 'static>(error: E) -> io::Error {
-    io::Error::new(io::ErrorKind::Other, Box::new(error))
+    io::Error::other(Box::new(error))
 }
@@ -0,0 +1,301 @@
+// Copyright (c) 2016 DWANGO Co., Ltd. All Rights Reserved.
+// See the LICENSE file at the top-level directory of this distribution.
+
+use futures::{Async, Future, Poll, Stream};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::collections::HeapMap;
+use crate::sync::mpsc;
+use crate::time::timer::ExpiryTimer;
+
+/// A limiter of the connection rate of each source IP address.
+///
+/// The limiter holds a token bucket for each source address.
+/// Each accepted connection consumes a token, and the tokens are refilled at the specified rate
+/// up to the burst size.
+/// The connections from a source which has no token are shed (i.e., closed immediately).
+///
+/// The bucket of a source is evicted after it has been idle for a while
+/// (ten seconds by default), so the memory usage is bounded by the number of the active sources.
+/// The idle buckets are evicted lazily by `check` (i.e., at the next accept),
+/// or as soon as they expire if the future returned by `evict_idle_sources` is running.
+///
+/// This is typically used with `Incoming::set_rate_limiter` method,
+/// which sheds the abusive connections before any fiber is spawned for them.
+/// It is also possible to share a limiter among multiple listeners.
+///
+/// # Examples
+///
+/// ```
+/// use fibers::net::AcceptRateLimiter;
+///
+/// // Allows 10 connections per second with bursts of up to 2 connections.
+/// let limiter = AcceptRateLimiter::new(10, 2);
+/// let source = "192.0.2.1".parse().unwrap();
+/// assert!(limiter.check(source));
+/// assert!(limiter.check(source));
+/// assert!(!limiter.check(source));
+/// assert_eq!(limiter.shed_count(), 1);
+///
+/// // Other sources are not affected.
+/// assert!(limiter.check("192.0.2.2".parse().unwrap()));
+/// ```
+#[derive(Debug, Clone)]
+pub struct AcceptRateLimiter {
+    inner: Arc<Mutex<RateLimiterInner>>,
+}
+impl AcceptRateLimiter {
+    /// Makes a new limiter which allows `rate_per_sec` connections per second
+    /// with bursts of up to `burst` connections for each source address.
+    ///
+    /// # Panics
+    ///
+    /// If `rate_per_sec` or `burst` is `0`, this function will panic.
+    pub fn new(rate_per_sec: u32, burst: u32) -> Self {
+        assert!(rate_per_sec > 0);
+        assert!(burst > 0);
+        let inner = RateLimiterInner {
+            rate_per_sec: f64::from(rate_per_sec),
+            burst: f64::from(burst),
+            idle_timeout: Duration::from_secs(10),
+            buckets: HashMap::new(),
+            expiry_queue: HeapMap::new(),
+            shed_count: 0,
+            rearm_tx: None,
+        };
+        AcceptRateLimiter {
+            inner: Arc::new(Mutex::new(inner)),
+        }
+    }
+
+    /// Sets the duration after which the bucket of an idle source is evicted.
+    ///
+    /// Note that an evicted source starts with a full bucket again.
+    /// If the eviction time is not representable (e.g., `timeout` is `Duration::MAX`),
+    /// the buckets are never evicted.
+    pub fn set_idle_timeout(&self, timeout: Duration) {
+        self.lock().idle_timeout = timeout;
+    }
+
+    /// Consumes a token of `source` and returns `true` if the connection is permitted.
+    ///
+    /// If the bucket of `source` is empty, this returns `false` and
+    /// increments the shed count.
+    pub fn check(&self, source: IpAddr) -> bool {
+        self.lock().check(source, Instant::now())
+    }
+
+    /// Returns the number of the connections which have been rejected by this limiter.
+    pub fn shed_count(&self) -> u64 {
+        self.lock().shed_count
+    }
+
+    /// Returns the number of the source addresses currently tracked by this limiter.
+    pub fn tracked_sources(&self) -> usize {
+        let mut inner = self.lock();
+        inner.evict_idle_buckets(Instant::now());
+        inner.buckets.len()
+    }
+
+    /// Makes a future which evicts the buckets of the idle sources as soon as they expire.
+    ///
+    /// The future never completes, so it is typically spawned as a fiber.
+    /// If this method is called multiple times, only the latest future is kept up to date.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate fibers;
+    /// # extern crate futures;
+    /// use std::time::Duration;
+    /// use fibers::{Executor, InPlaceExecutor, Spawn};
+    /// use fibers::net::AcceptRateLimiter;
+    ///
+    /// let mut executor = InPlaceExecutor::new().unwrap();
+    /// let limiter = AcceptRateLimiter::new(10, 2);
+    /// limiter.set_idle_timeout(Duration::from_millis(10));
+    /// executor.spawn(limiter.evict_idle_sources());
+    ///
+    /// assert!(limiter.check("192.0.2.1".parse().unwrap()));
+    /// while limiter.tracked_sources() > 0 {
+    ///     executor.run_once().unwrap();
+    /// }
+    /// ```
+    pub fn evict_idle_sources(&self) -> EvictIdleSources {
+        let (tx, rx) = mpsc::channel();
+        self.lock().rearm_tx = Some(tx);
+        EvictIdleSources {
+            limiter: self.clone(),
+            rearm_rx: rx,
+            timer: ExpiryTimer::default(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, RateLimiterInner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// A future which evicts the buckets of the idle sources of an `AcceptRateLimiter`.
+///
+/// This is created by calling `AcceptRateLimiter::evict_idle_sources` method.
+///
+/// The future never completes.
+pub struct EvictIdleSources {
+    limiter: AcceptRateLimiter,
+    rearm_rx: mpsc::Receiver<()>,
+    timer: ExpiryTimer,
+}
+impl Future for EvictIdleSources {
+    type Item = ();
+    type Error = ();
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        while let Async::Ready(Some(())) = self.rearm_rx.poll()? {}
+        let limiter = &self.limiter;
+        self.timer.poll(|now| {
+            let mut inner = limiter.lock();
+            inner.evict_idle_buckets(now);
+            inner.expiry_queue.peek().map(|(k, _)| k.0)
+        });
+        Ok(Async::NotReady)
+    }
+}
+impl fmt::Debug for EvictIdleSources {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "EvictIdleSources {{ .. }}")
+    }
+}
+
+#[derive(Debug)]
+struct RateLimiterInner {
+    rate_per_sec: f64,
+    burst: f64,
+    idle_timeout: Duration,
+    buckets: HashMap<IpAddr, Bucket>,
+    expiry_queue: HeapMap<(Instant, IpAddr), ()>,
+    shed_count: u64,
+    rearm_tx: Option<mpsc::Sender<()>>,
+}
+impl RateLimiterInner {
+    fn check(&mut self, source: IpAddr, now: Instant) -> bool {
+        self.evict_idle_buckets(now);
+
+        let expiry_time = now.checked_add(self.idle_timeout);
+        let (rate_per_sec, burst) = (self.rate_per_sec, self.burst);
+        let bucket = self.buckets.entry(source).or_insert(Bucket {
+            tokens: burst,
+            updated_at: now,
+            expiry_time,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated_at);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * rate_per_sec).min(burst);
+        bucket.updated_at = now;
+
+        let old_expiry_time = bucket.expiry_time;
+        bucket.expiry_time = expiry_time;
+        let permitted = if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        };
+
+        if let Some(old_expiry_time) = old_expiry_time {
+            self.expiry_queue.remove(&(old_expiry_time, source));
+        }
+        if let Some(expiry_time) = expiry_time {
+            let is_earliest = self
+                .expiry_queue
+                .peek()
+                .is_none_or(|(k, _)| expiry_time < k.0);
+            self.expiry_queue.push_if_absent((expiry_time, source), ());
+            if is_earliest {
+                if let Some(ref tx) = self.rearm_tx {
+                    let _ = tx.send(());
+                }
+            }
+        }
+        if !permitted {
+            self.shed_count += 1;
+        }
+        permitted
+    }
+
+    fn evict_idle_buckets(&mut self, now: Instant) {
+        while let Some(((_, source), ())) = self.expiry_queue.pop_if(|k, _| k.0 <= now) {
+            self.buckets.remove(&source);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+
+    /// `None` means that the bucket is never evicted.
+    expiry_time: Option<Instant>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::executor::{Executor, InPlaceExecutor};
+    use crate::fiber::Spawn;
+
+    #[test]
+    fn tokens_are_refilled_and_idle_buckets_are_evicted() {
+        let limiter = AcceptRateLimiter::new(10, 1);
+        let mut inner = limiter.lock();
+        let source = "192.0.2.1".parse().unwrap();
+        let now = Instant::now();
+
+        assert!(inner.check(source, now));
+        assert!(!inner.check(source, now));
+        assert!(inner.check(source, now + Duration::from_millis(100)));
+        assert_eq!(inner.shed_count, 1);
+
+        inner.evict_idle_buckets(now + Duration::from_secs(5));
+        assert_eq!(inner.buckets.len(), 1);
+        inner.evict_idle_buckets(now + Duration::from_secs(11));
+        assert_eq!(inner.buckets.len(), 0);
+        assert_eq!(inner.expiry_queue.len(), 0);
+    }
+
+    #[test]
+    fn huge_idle_timeout_means_no_eviction() {
+        let limiter = AcceptRateLimiter::new(10, 1);
+        limiter.set_idle_timeout(Duration::MAX);
+        let mut inner = limiter.lock();
+        let source = "192.0.2.1".parse().unwrap();
+        let now = Instant::now();
+
+        assert!(inner.check(source, now));
+        assert!(!inner.check(source, now));
+        assert_eq!(inner.expiry_queue.len(), 0);
+        inner.evict_idle_buckets(now + Duration::from_secs(3600));
+        assert_eq!(inner.buckets.len(), 1);
+    }
+
+    #[test]
+    fn idle_buckets_are_evicted_by_timer() {
+        let mut executor = InPlaceExecutor::new().unwrap();
+        let limiter = AcceptRateLimiter::new(10, 1);
+        limiter.set_idle_timeout(Duration::from_millis(20));
+        executor.spawn(limiter.evict_idle_sources());
+        executor.run_once().unwrap();
+
+        // The evictor is woken up by the first bucket inserted to the empty limiter.
+        assert!(limiter.check("192.0.2.1".parse().unwrap()));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !limiter.lock().buckets.is_empty() {
+            assert!(Instant::now() < deadline);
+            executor.run_once().unwrap();
+        }
+        assert_eq!(limiter.lock().expiry_queue.len(), 0);
+    }
+}
@@ -0,0 +1,349 @@
+// Copyright (c) 2016 DWANGO Co., Ltd. All Rights Reserved.
+// See the LICENSE file at the top-level directory of this distribution.
+
+use futures::{Async, Poll, Stream};
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::mem;
+use std::time::{Duration, Instant};
+
+use crate::collections::HeapMap;
+use crate::time::timer::ExpiryTimer;
+
+const DEFAULT_MAX_FRAGMENTS: usize = 256;
+const DEFAULT_MAX_PENDING: usize = 1024;
+
+/// A fragment of a message to be reassembled by `Reassembler`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fragment<K> {
+    /// The identifier of the message which this fragment belongs to.
+    pub key: K,
+
+    /// The index of this fragment in the message.
+    pub index: usize,
+
+    /// The total number of the fragments of the message.
+    pub count: usize,
+
+    /// The payload of this fragment.
+    pub payload: Vec<u8>,
+}
+
+/// A stream which reassembles messages from the fragments yielded by an inner stream.
+///
+/// The inner stream is typically made from datagrams received by a `UdpSocket`
+/// (decoding the fragment headers is up to the user).
+///
+/// When all the fragments of a message are received,
+/// the concatenated payloads are yielded with the key of the message.
+/// If a message is not completed within the timeout (measured from its first fragment),
+/// its fragments are discarded.
+///
+/// Invalid fragments (i.e., fragments whose `index` is out of range or whose `count` differs
+/// from the one of the preceding fragments) and duplicate fragments are ignored.
+///
+/// Because the fragments usually come from the network, the memory used by a reassembler is bounded:
+/// fragments whose `count` exceeds `max_fragments`, and fragments which would start
+/// a new message while `max_pending` messages are already being reassembled, are rejected
+/// (see `set_max_fragments` and `set_max_pending`).
+///
+/// # Note
+///
+/// The timeout works only if the stream is polled inside a fiber.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate fibers;
+/// # extern crate futures;
+/// use std::time::Duration;
+/// use fibers::{Executor, InPlaceExecutor, Spawn};
+/// use fibers::net::{Fragment, Reassembler};
+/// use futures::{stream, Stream};
+///
+/// let fragment = |key, index, count, payload: &[u8]| Fragment {
+///     key,
+///     index,
+///     count,
+///     payload: payload.to_owned(),
+/// };
+/// let fragments = stream::iter_ok::<_, ()>(vec![
+///     fragment(0, 1, 2, b"world"),
+///     fragment(1, 0, 1, b"foo"),
+///     fragment(0, 0, 2, b"hello "),
+/// ]);
+///
+/// let mut executor = InPlaceExecutor::new().unwrap();
+/// let reassembler = Reassembler::new(fragments, Duration::from_secs(1));
+/// let monitor = executor.spawn_monitor(reassembler.collect());
+/// let messages = executor.run_fiber(monitor).unwrap().unwrap();
+/// assert_eq!(messages, vec![(1, b"foo".to_vec()), (0, b"hello world".to_vec())]);
+/// ```
+pub struct Reassembler<S, K> {
+    inner: S,
+    timeout: Duration,
+    entries: HashMap<K, Entry>,
+    expiry_queue: HeapMap<(Instant, u64), K>,
+    timer: ExpiryTimer,
+    next_seqno: u64,
+    expired_count: u64,
+    max_fragments: usize,
+    max_pending: usize,
+    rejected_count: u64,
+}
+impl<S, K> Reassembler<S, K>
+where
+    S: Stream<Item = Fragment<K>>,
+    K: Hash + Eq + Clone,
+{
+    /// Makes a new `Reassembler` instance which discards the incomplete messages
+    /// after `timeout`.
+    ///
+    /// By default, `max_fragments` is 256 and `max_pending` is 1024.
+    pub fn new(inner: S, timeout: Duration) -> Self {
+        Reassembler {
+            inner,
+            timeout,
+            entries: HashMap::new(),
+            expiry_queue: HeapMap::new(),
+            timer: ExpiryTimer::default(),
+            next_seqno: 0,
+            expired_count: 0,
+            max_fragments: DEFAULT_MAX_FRAGMENTS,
+            max_pending: DEFAULT_MAX_PENDING,
+            rejected_count: 0,
+        }
+    }
+
+    /// Sets the maximum number of the fragments of a message.
+    ///
+    /// Fragments whose `count` exceeds this are rejected.
+    pub fn set_max_fragments(&mut self, max: usize) {
+        self.max_fragments = max;
+    }
+
+    /// Sets the maximum number of the messages being reassembled at the same time.
+    ///
+    /// If the limit is reached, fragments of new messages are rejected
+    /// until some of the pending messages are completed or expire.
+    pub fn set_max_pending(&mut self, max: usize) {
+        self.max_pending = max;
+    }
+
+    /// Returns the number of the messages being reassembled.
+    pub fn pending_len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns the number of the messages discarded due to the timeout.
+    pub fn expired_count(&self) -> u64 {
+        self.expired_count
+    }
+
+    /// Returns the number of the fragments rejected due to `max_fragments` or `max_pending`.
+    pub fn rejected_count(&self) -> u64 {
+        self.rejected_count
+    }
+
+    /// Returns a reference to the inner stream.
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the inner stream.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    fn handle_fragment(&mut self, fragment: Fragment<K>, now: Instant) -> Option<Vec<u8>> {
+        if fragment.count == 0 || fragment.index >= fragment.count {
+            return None;
+        }
+        if !self.entries.contains_key(&fragment.key) {
+            if fragment.count > self.max_fragments || self.entries.len() >= self.max_pending {
+                self.rejected_count += 1;
+                return None;
+            }
+            let expiry_time = now + self.timeout;
+            let seqno = self.next_seqno;
+            self.next_seqno += 1;
+            self.expiry_queue
+                .push_if_absent((expiry_time, seqno), fragment.key.clone());
+            let entry = Entry {
+                fragments: vec![None; fragment.count],
+                received: 0,
+                expiry: (expiry_time, seqno),
+            };
+            self.entries.insert(fragment.key.clone(), entry);
+        }
+
+        let completed = {
+            let entry = self.entries.get_mut(&fragment.key).expect("Never fails");
+            if entry.fragments.len() != fragment.count || entry.fragments[fragment.index].is_some()
+            {
+                return None;
+            }
+            entry.fragments[fragment.index] = Some(fragment.payload);
+            entry.received += 1;
+            entry.received == entry.fragments.len()
+        };
+        if !completed {
+            return None;
+        }
+
+        let entry = self.entries.remove(&fragment.key).expect("Never fails");
+        self.expiry_queue.remove(&entry.expiry);
+        let mut message = Vec::new();
+        for payload in entry.fragments.into_iter().flatten() {
+            message.extend_from_slice(&payload);
+        }
+        Some(message)
+    }
+
+    fn evict_expired_entries(&mut self, now: Instant) {
+        while let Some((_, key)) = self.expiry_queue.pop_if(|k, _| k.0 <= now) {
+            self.entries.remove(&key);
+            self.expired_count += 1;
+        }
+    }
+
+    fn poll_timer(&mut self) {
+        let mut timer = mem::take(&mut self.timer);
+        timer.poll(|now| {
+            self.evict_expired_entries(now);
+            self.expiry_queue
+                .peek()
+                .map(|(&(expiry_time, _), _)| expiry_time)
+        });
+        self.timer = timer;
+    }
+}
+impl<S, K> Stream for Reassembler<S, K>
+where
+    S: Stream<Item = Fragment<K>>,
+    K: Hash + Eq + Clone,
+{
+    type Item = (K, Vec<u8>);
+    type Error = S::Error;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        while let Async::Ready(fragment) = self.inner.poll()? {
+            let fragment = if let Some(fragment) = fragment {
+                fragment
+            } else {
+                // The incomplete messages are discarded.
+                return Ok(Async::Ready(None));
+            };
+            let key = fragment.key.clone();
+            if let Some(message) = self.handle_fragment(fragment, Instant::now()) {
+                return Ok(Async::Ready(Some((key, message))));
+            }
+        }
+        self.poll_timer();
+        Ok(Async::NotReady)
+    }
+}
+impl<S: fmt::Debug, K> fmt::Debug for Reassembler<S, K> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Reassembler {{ inner: {:?}, timeout: {:?}, pending_len: {}, expired_count: {}, rejected_count: {}, .. }}",
+            self.inner,
+            self.timeout,
+            self.entries.len(),
+            self.expired_count,
+            self.rejected_count
+        )
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    fragments: Vec<Option<Vec<u8>>>,
+    received: usize,
+    expiry: (Instant, u64),
+}
+
+#[cfg(test)]
+mod test {
+    use futures::stream;
+
+    use super::*;
+
+    #[test]
+    fn incomplete_messages_expire() {
+        let timeout = Duration::from_secs(1);
+        let mut reassembler = Reassembler::new(stream::empty::<Fragment<u8>, ()>(), timeout);
+        let now = Instant::now();
+        let fragment = |key, index| Fragment {
+            key,
+            index,
+            count: 2,
+            payload: vec![index as u8],
+        };
+
+        assert_eq!(reassembler.handle_fragment(fragment(0, 0), now), None);
+        assert_eq!(reassembler.handle_fragment(fragment(0, 0), now), None);
+        assert_eq!(reassembler.handle_fragment(fragment(1, 1), now), None);
+        assert_eq!(
+            reassembler.handle_fragment(fragment(0, 1), now),
+            Some(vec![0, 1])
+        );
+        assert_eq!(reassembler.pending_len(), 1);
+
+        reassembler.evict_expired_entries(now + timeout);
+        assert_eq!(reassembler.pending_len(), 0);
+        assert_eq!(reassembler.expired_count(), 1);
+        assert_eq!(reassembler.expiry_queue.len(), 0);
+    }
+
+    #[test]
+    fn too_many_fragments_are_rejected() {
+        let mut reassembler =
+            Reassembler::new(stream::empty::<Fragment<u8>, ()>(), Duration::from_secs(1));
+        reassembler.set_max_fragments(2);
+        let now = Instant::now();
+        let fragment = |count| Fragment {
+            key: 0,
+            index: 0,
+            count,
+            payload: vec![0],
+        };
+
+        assert_eq!(reassembler.handle_fragment(fragment(usize::MAX), now), None);
+        assert_eq!(reassembler.handle_fragment(fragment(3), now), None);
+        assert_eq!(reassembler.pending_len(), 0);
+        assert_eq!(reassembler.rejected_count(), 2);
+
+        assert_eq!(reassembler.handle_fragment(fragment(2), now), None);
+        assert_eq!(reassembler.pending_len(), 1);
+    }
+
+    #[test]
+    fn too_many_pending_messages_are_rejected() {
+        let mut reassembler =
+            Reassembler::new(stream::empty::<Fragment<u8>, ()>(), Duration::from_secs(1));
+        reassembler.set_max_pending(2);
+        let now = Instant::now();
+        let fragment = |key, index| Fragment {
+            key,
+            index,
+            count: 2,
+            payload: vec![index as u8],
+        };
+
+        assert_eq!(reassembler.handle_fragment(fragment(0, 0), now), None);
+        assert_eq!(reassembler.handle_fragment(fragment(1, 0), now), None);
+        assert_eq!(reassembler.handle_fragment(fragment(2, 0), now), None);
+        assert_eq!(reassembler.pending_len(), 2);
+        assert_eq!(reassembler.rejected_count(), 1);
+
+        // The pending messages can still be completed
+        assert_eq!(
+            reassembler.handle_fragment(fragment(0, 1), now),
+            Some(vec![0, 1])
+        );
+        assert_eq!(reassembler.handle_fragment(fragment(2, 0), now), None);
+        assert_eq!(reassembler.pending_len(), 2);
+    }
+}
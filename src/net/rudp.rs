@@ -0,0 +1,656 @@
+// Copyright (c) 2016 DWANGO Co., Ltd. All Rights Reserved.
+// See the LICENSE file at the top-level directory of this distribution.
+
+//! A "mostly reliable" delivery helper over UDP.
+//!
+//! `RudpSocket` attaches a sequence number to each outgoing message and
+//! retransmits it until the peer acknowledges it (or the retransmission limit is reached).
+//! The number of unacknowledged messages per peer is limited by the window size.
+//!
+//! Messages are delivered at most once, but not necessarily in order.
+//! Each peer on the sending side is assigned a random session identifier,
+//! so the messages of a restarted (or expired) sender are not confused with the old ones.
+//! A message which is not acknowledged after the maximum number of retransmissions
+//! is given up silently and counted in `RudpStats::lost`.
+//! This is suitable for telemetry protocols and the like,
+//! which prefer timeliness to the strict reliability of TCP.
+//!
+//! # Wire Format
+//!
+//! Each datagram consists of a one byte type tag (`0`: data, `1`: ack),
+//! a four bytes big-endian session identifier, a four bytes big-endian sequence number
+//! and the payload (data only).
+//! An ack carries the session identifier and the sequence number of the acknowledged message.
+//!
+//! # Examples
+//!
+//! ```
+//! # extern crate fibers;
+//! # extern crate futures;
+//! use fibers::{Executor, InPlaceExecutor, Spawn};
+//! use fibers::net::UdpSocket;
+//! use fibers::net::rudp::RudpSocket;
+//! use futures::{future, Future, Stream};
+//!
+//! let mut executor = InPlaceExecutor::new().unwrap();
+//! let mut bind = || {
+//!     let monitor = executor.spawn_monitor(UdpSocket::bind("127.0.0.1:0".parse().unwrap()));
+//!     executor.run_fiber(monitor).unwrap().unwrap()
+//! };
+//! let receiver = RudpSocket::new(bind());
+//! let mut sender = RudpSocket::new(bind());
+//!
+//! let receiver_addr = receiver.local_addr().unwrap();
+//! sender.send_to(b"foo", receiver_addr);
+//! sender.send_to(b"bar", receiver_addr);
+//!
+//! let received = executor.spawn_monitor(receiver.take(2).collect());
+//! let flushed = executor.spawn_monitor(future::poll_fn(move || sender.poll_flush()));
+//! let (messages, ()) = executor.run_future(received.join(flushed)).unwrap().unwrap();
+//! let payloads = messages.into_iter().map(|(_, payload)| payload).collect::<Vec<_>>();
+//! assert_eq!(payloads, vec![b"foo".to_vec(), b"bar".to_vec()]);
+//! ```
+use futures::{Async, Future, Poll, Stream};
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use super::into_io_error;
+use super::UdpSocket;
+use crate::io::poll::Interest;
+use crate::sync::oneshot::Monitor;
+use crate::time::timer::{self, Timeout};
+
+const TYPE_DATA: u8 = 0;
+const TYPE_ACK: u8 = 1;
+const HEADER_SIZE: usize = 9;
+const MAX_DATAGRAM_SIZE: usize = 65_536;
+
+/// Options of `RudpSocket`.
+#[derive(Debug, Clone)]
+pub struct RudpOptions {
+    /// The maximum number of unacknowledged messages per peer.
+    ///
+    /// The messages exceeding this are queued until the preceding messages are acknowledged.
+    ///
+    /// The default value is `32`.
+    pub window_size: usize,
+
+    /// The time to wait for an acknowledgement before retransmitting a message.
+    ///
+    /// The default value is `200` milliseconds.
+    pub retransmit_timeout: Duration,
+
+    /// The maximum number of retransmissions of a message.
+    ///
+    /// The default value is `5`.
+    pub max_retransmits: usize,
+
+    /// The time after which the state of an idle peer is discarded.
+    ///
+    /// A peer is idle if no messages have been exchanged with it and
+    /// it has no messages to be sent (or acknowledged).
+    /// This must be longer than the time a peer takes to give up a message
+    /// (i.e., `retransmit_timeout * (max_retransmits + 1)`),
+    /// otherwise retransmitted messages may be delivered twice.
+    ///
+    /// The default value is `60` seconds.
+    pub peer_idle_timeout: Duration,
+
+    /// The maximum number of peers whose state is kept.
+    ///
+    /// If the limit is reached, the messages from new peers are discarded without
+    /// acknowledgements (and counted in `RudpStats::rejected`) until some peers expire.
+    /// Note that `RudpSocket::send_to` always accepts new peers.
+    ///
+    /// The default value is `1024`.
+    pub max_peers: usize,
+}
+impl Default for RudpOptions {
+    fn default() -> Self {
+        RudpOptions {
+            window_size: 32,
+            retransmit_timeout: Duration::from_millis(200),
+            max_retransmits: 5,
+            peer_idle_timeout: Duration::from_secs(60),
+            max_peers: 1024,
+        }
+    }
+}
+
+/// Statistics of a `RudpSocket`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct RudpStats {
+    /// The number of the messages transmitted for the first time.
+    pub sent: u64,
+
+    /// The number of the retransmissions.
+    pub retransmitted: u64,
+
+    /// The number of the messages acknowledged by the peers.
+    pub acked: u64,
+
+    /// The number of the messages given up after the maximum number of retransmissions.
+    pub lost: u64,
+
+    /// The number of the messages delivered to the user.
+    pub received: u64,
+
+    /// The number of the duplicate messages discarded.
+    pub duplicates: u64,
+
+    /// The number of the messages discarded because the number of peers reached `max_peers`.
+    pub rejected: u64,
+
+    /// The number of the peers discarded because they had been idle.
+    pub expired_peers: u64,
+}
+
+/// A UDP socket which retransmits messages until they are acknowledged.
+///
+/// As a `Stream`, this yields the messages received from peers.
+/// Note that the acknowledgements and retransmissions are processed only while
+/// this is polled (via `Stream::poll` or `RudpSocket::poll_flush`),
+/// so the owning fiber must keep polling it.
+///
+/// See the [module documentation](index.html) for more details.
+#[derive(Debug)]
+pub struct RudpSocket {
+    socket: UdpSocket,
+    options: RudpOptions,
+    peers: HashMap<SocketAddr, Peer>,
+    outbox: VecDeque<(SocketAddr, Vec<u8>)>,
+    inbox: VecDeque<(SocketAddr, Vec<u8>)>,
+    read_monitor: Option<Monitor<(), io::Error>>,
+    write_monitor: Option<Monitor<(), io::Error>>,
+    timer: Option<(Instant, Timeout)>,
+    expiry_timer: Option<Timeout>,
+    recv_buf: Vec<u8>,
+    stats: RudpStats,
+}
+impl RudpSocket {
+    /// Makes a new `RudpSocket` instance with the default options.
+    pub fn new(socket: UdpSocket) -> Self {
+        Self::with_options(socket, RudpOptions::default())
+    }
+
+    /// Makes a new `RudpSocket` instance with the specified options.
+    ///
+    /// # Panics
+    ///
+    /// If `options.window_size` is `0`, this function will panic.
+    pub fn with_options(socket: UdpSocket, options: RudpOptions) -> Self {
+        assert!(options.window_size > 0);
+        RudpSocket {
+            socket,
+            options,
+            peers: HashMap::new(),
+            outbox: VecDeque::new(),
+            inbox: VecDeque::new(),
+            read_monitor: None,
+            write_monitor: None,
+            timer: None,
+            expiry_timer: None,
+            recv_buf: vec![0; MAX_DATAGRAM_SIZE],
+            stats: RudpStats::default(),
+        }
+    }
+
+    /// Returns the local address of the underlying socket.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Returns the statistics of this socket.
+    pub fn stats(&self) -> &RudpStats {
+        &self.stats
+    }
+
+    /// Returns the number of the messages to `peer` which are not acknowledged yet
+    /// (including the ones queued because of the window size).
+    pub fn unacked_len(&self, peer: &SocketAddr) -> usize {
+        self.peers
+            .get(peer)
+            .map_or(0, |p| p.unacked.len() + p.pending.len())
+    }
+
+    /// Queues `payload` to be sent to `target`.
+    ///
+    /// The message is transmitted immediately if the window of the peer has room,
+    /// otherwise when the preceding messages are acknowledged.
+    pub fn send_to(&mut self, payload: &[u8], target: SocketAddr) {
+        let peer = self.peer_mut(target);
+        peer.last_active = Instant::now();
+        peer.pending.push_back(payload.to_vec());
+        self.fill_window(target);
+    }
+
+    /// Processes the incoming packets, retransmissions and pending transmissions.
+    ///
+    /// This returns `Async::Ready(())` if all the sent messages have been
+    /// acknowledged (or given up).
+    /// Received messages are kept and will be yielded by `Stream::poll`.
+    pub fn poll_flush(&mut self) -> Poll<(), io::Error> {
+        self.drive()?;
+        if self
+            .peers
+            .values()
+            .all(|p| p.unacked.is_empty() && p.pending.is_empty())
+        {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+
+    fn drive(&mut self) -> io::Result<()> {
+        loop {
+            self.handle_retransmits()?;
+            self.handle_peer_expiry()?;
+            self.flush_outbox()?;
+            while self.recv_packet()? {}
+            self.flush_outbox()?;
+            if !self.arm_timer()? {
+                return Ok(());
+            }
+        }
+    }
+
+    fn peer_mut(&mut self, addr: SocketAddr) -> &mut Peer {
+        self.peers.entry(addr).or_insert_with(Peer::new)
+    }
+
+    fn fill_window(&mut self, addr: SocketAddr) {
+        let window_size = self.options.window_size;
+        let now = Instant::now();
+        let mut packets = Vec::new();
+        {
+            let peer = self.peer_mut(addr);
+            while peer.unacked.len() < window_size {
+                let payload = match peer.pending.pop_front() {
+                    None => break,
+                    Some(payload) => payload,
+                };
+                let seqno = peer.next_seqno;
+                peer.next_seqno = peer.next_seqno.wrapping_add(1);
+                let packet = encode(TYPE_DATA, peer.session, seqno, &payload);
+                packets.push(packet.clone());
+                peer.unacked.insert(
+                    seqno,
+                    Outgoing {
+                        packet,
+                        sent_at: now,
+                        retransmits: 0,
+                    },
+                );
+            }
+        }
+        for packet in packets {
+            self.stats.sent += 1;
+            self.send_packet(addr, packet);
+        }
+    }
+
+    fn handle_retransmits(&mut self) -> io::Result<()> {
+        if let Some((_, ref mut timeout)) = self.timer {
+            if let Async::NotReady = timeout.poll().map_err(into_io_error)? {
+                return Ok(());
+            }
+        }
+        self.timer = None;
+
+        let now = Instant::now();
+        let rto = self.options.retransmit_timeout;
+        let max_retransmits = self.options.max_retransmits;
+        let mut packets = Vec::new();
+        let mut lost_peers = Vec::new();
+        for (&addr, peer) in &mut self.peers {
+            let mut lost = Vec::new();
+            for (&seqno, outgoing) in &mut peer.unacked {
+                if outgoing.sent_at + rto > now {
+                    continue;
+                }
+                if outgoing.retransmits == max_retransmits {
+                    lost.push(seqno);
+                } else {
+                    outgoing.retransmits += 1;
+                    outgoing.sent_at = now;
+                    packets.push((addr, outgoing.packet.clone()));
+                }
+            }
+            if !lost.is_empty() {
+                for seqno in &lost {
+                    peer.unacked.remove(seqno);
+                }
+                self.stats.lost += lost.len() as u64;
+                lost_peers.push(addr);
+            }
+        }
+        for (addr, packet) in packets {
+            self.stats.retransmitted += 1;
+            self.send_packet(addr, packet);
+        }
+        for addr in lost_peers {
+            self.fill_window(addr);
+        }
+        Ok(())
+    }
+
+    fn handle_peer_expiry(&mut self) -> io::Result<()> {
+        loop {
+            if let Some(mut timeout) = self.expiry_timer.take() {
+                if let Async::NotReady = timeout.poll().map_err(into_io_error)? {
+                    self.expiry_timer = Some(timeout);
+                    return Ok(());
+                }
+                self.expire_idle_peers(Instant::now());
+            }
+            if self.peers.is_empty() {
+                return Ok(());
+            }
+            self.expiry_timer = Some(timer::timeout(self.options.peer_idle_timeout));
+        }
+    }
+
+    fn expire_idle_peers(&mut self, now: Instant) {
+        let idle_timeout = self.options.peer_idle_timeout;
+        let before = self.peers.len();
+        self.peers.retain(|_, p| {
+            !(p.unacked.is_empty() && p.pending.is_empty())
+                || now.saturating_duration_since(p.last_active) < idle_timeout
+        });
+        self.stats.expired_peers += (before - self.peers.len()) as u64;
+    }
+
+    /// Returns `true` if some retransmissions are already due.
+    fn arm_timer(&mut self) -> io::Result<bool> {
+        let rto = self.options.retransmit_timeout;
+        let deadline = self
+            .peers
+            .values()
+            .flat_map(|p| p.unacked.values())
+            .map(|o| o.sent_at + rto)
+            .min();
+        let deadline = match deadline {
+            None => {
+                self.timer = None;
+                return Ok(false);
+            }
+            Some(deadline) => deadline,
+        };
+        if self.timer.as_ref().is_none_or(|t| t.0 != deadline) {
+            let delay = deadline.saturating_duration_since(Instant::now());
+            self.timer = Some((deadline, timer::timeout(delay)));
+        }
+        let timeout = &mut assert_some!(self.timer.as_mut()).1;
+        Ok(timeout.poll().map_err(into_io_error)?.is_ready())
+    }
+
+    fn recv_packet(&mut self) -> io::Result<bool> {
+        loop {
+            if let Some(mut monitor) = self.read_monitor.take() {
+                if let Async::NotReady = monitor.poll().map_err(into_io_error)? {
+                    self.read_monitor = Some(monitor);
+                    return Ok(false);
+                }
+            }
+            let recv_buf = &mut self.recv_buf;
+            let result = self.socket.with_inner(|socket| socket.recv_from(recv_buf));
+            match result {
+                Err(e) => match e.kind() {
+                    io::ErrorKind::WouldBlock => {
+                        self.read_monitor = Some(self.socket.monitor(Interest::Read));
+                    }
+                    // ICMP errors caused by the previous transmissions; retransmission handles them.
+                    io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionReset => {}
+                    _ => return Err(e),
+                },
+                Ok((size, addr)) => {
+                    let packet = self.recv_buf[..size].to_vec();
+                    self.handle_packet(addr, &packet);
+                    return Ok(true);
+                }
+            }
+        }
+    }
+
+    fn handle_packet(&mut self, addr: SocketAddr, packet: &[u8]) {
+        if packet.len() < HEADER_SIZE {
+            return;
+        }
+        let session = u32::from_be_bytes([packet[1], packet[2], packet[3], packet[4]]);
+        let seqno = u32::from_be_bytes([packet[5], packet[6], packet[7], packet[8]]);
+        match packet[0] {
+            TYPE_DATA => {
+                if !self.peers.contains_key(&addr) && self.peers.len() >= self.options.max_peers {
+                    self.stats.rejected += 1;
+                    return;
+                }
+                self.send_packet(addr, encode(TYPE_ACK, session, seqno, &[]));
+                let peer = self.peer_mut(addr);
+                peer.last_active = Instant::now();
+                if peer.mark_received(session, seqno) {
+                    self.stats.received += 1;
+                    self.inbox.push_back((addr, packet[HEADER_SIZE..].to_vec()));
+                } else {
+                    self.stats.duplicates += 1;
+                }
+            }
+            TYPE_ACK => {
+                let acked = self.peers.get_mut(&addr).is_some_and(|p| {
+                    if p.session != session || p.unacked.remove(&seqno).is_none() {
+                        return false;
+                    }
+                    p.last_active = Instant::now();
+                    true
+                });
+                if acked {
+                    self.stats.acked += 1;
+                    self.fill_window(addr);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn send_packet(&mut self, addr: SocketAddr, packet: Vec<u8>) {
+        if self.outbox.is_empty() {
+            let result = self
+                .socket
+                .with_inner(|socket| socket.send_to(&packet, &addr));
+            match result {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                // Other errors are treated as packet losses (the retransmission recovers them).
+                _ => return,
+            }
+        }
+        self.outbox.push_back((addr, packet));
+    }
+
+    fn flush_outbox(&mut self) -> io::Result<()> {
+        loop {
+            if let Some(mut monitor) = self.write_monitor.take() {
+                if let Async::NotReady = monitor.poll().map_err(into_io_error)? {
+                    self.write_monitor = Some(monitor);
+                    return Ok(());
+                }
+            }
+            while let Some((addr, packet)) = self.outbox.pop_front() {
+                let result = self
+                    .socket
+                    .with_inner(|socket| socket.send_to(&packet, &addr));
+                if let Err(ref e) = result {
+                    if e.kind() == io::ErrorKind::WouldBlock {
+                        self.outbox.push_front((addr, packet));
+                        break;
+                    }
+                }
+            }
+            if self.outbox.is_empty() {
+                return Ok(());
+            }
+            self.write_monitor = Some(self.socket.monitor(Interest::Write));
+        }
+    }
+}
+impl Stream for RudpSocket {
+    type Item = (SocketAddr, Vec<u8>);
+    type Error = io::Error;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        self.drive()?;
+        if let Some(message) = self.inbox.pop_front() {
+            Ok(Async::Ready(Some(message)))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Peer {
+    session: u32,
+    next_seqno: u32,
+    pending: VecDeque<Vec<u8>>,
+    unacked: BTreeMap<u32, Outgoing>,
+    received: HashSet<(u32, u32)>,
+    received_order: VecDeque<(u32, u32)>,
+    last_active: Instant,
+}
+impl Peer {
+    fn new() -> Self {
+        Peer {
+            session: new_session_id(),
+            next_seqno: 0,
+            pending: VecDeque::new(),
+            unacked: BTreeMap::new(),
+            received: HashSet::new(),
+            received_order: VecDeque::new(),
+            last_active: Instant::now(),
+        }
+    }
+
+    /// Returns `false` if `seqno` of `session` has been received recently.
+    fn mark_received(&mut self, session: u32, seqno: u32) -> bool {
+        const MAX_HISTORY: usize = 4096;
+        if !self.received.insert((session, seqno)) {
+            return false;
+        }
+        self.received_order.push_back((session, seqno));
+        if self.received_order.len() > MAX_HISTORY {
+            let oldest = assert_some!(self.received_order.pop_front());
+            self.received.remove(&oldest);
+        }
+        true
+    }
+}
+
+#[derive(Debug)]
+struct Outgoing {
+    packet: Vec<u8>,
+    sent_at: Instant,
+    retransmits: usize,
+}
+
+fn encode(kind: u8, session: u32, seqno: u32, payload: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(HEADER_SIZE + payload.len());
+    packet.push(kind);
+    packet.extend_from_slice(&session.to_be_bytes());
+    packet.extend_from_slice(&seqno.to_be_bytes());
+    packet.extend_from_slice(payload);
+    packet
+}
+
+fn new_session_id() -> u32 {
+    // `RandomState` is randomly seeded per thread and its keys are changed on each instantiation.
+    RandomState::new().build_hasher().finish() as u32
+}
+
+#[cfg(test)]
+mod test {
+    use futures::future;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{Executor, InPlaceExecutor, Spawn};
+
+    #[test]
+    fn unacked_messages_are_retransmitted_and_given_up() {
+        let mut executor = InPlaceExecutor::new().unwrap();
+        let mut bind = || {
+            let monitor = executor.spawn_monitor(UdpSocket::bind("127.0.0.1:0".parse().unwrap()));
+            executor.run_fiber(monitor).unwrap().unwrap()
+        };
+        let silent_peer = bind();
+        let options = RudpOptions {
+            window_size: 1,
+            retransmit_timeout: Duration::from_millis(1),
+            max_retransmits: 2,
+            ..RudpOptions::default()
+        };
+        let mut sender = RudpSocket::with_options(bind(), options);
+        let target = silent_peer.local_addr().unwrap();
+        sender.send_to(b"foo", target);
+        sender.send_to(b"bar", target);
+        assert_eq!(sender.unacked_len(&target), 2);
+
+        let flushed = executor.spawn_monitor(future::poll_fn(move || {
+            let ready = sender.poll_flush()?;
+            Ok::<_, io::Error>(ready.map(|()| sender.stats().clone()))
+        }));
+        let stats = executor.run_fiber(flushed).unwrap().unwrap();
+        assert_eq!(stats.sent, 2);
+        assert_eq!(stats.retransmitted, 4);
+        assert_eq!(stats.lost, 2);
+        assert_eq!(stats.acked, 0);
+    }
+
+    #[test]
+    fn restarted_senders_are_not_treated_as_duplicates() {
+        let mut executor = InPlaceExecutor::new().unwrap();
+        let monitor = executor.spawn_monitor(UdpSocket::bind("127.0.0.1:0".parse().unwrap()));
+        let mut receiver = RudpSocket::new(executor.run_fiber(monitor).unwrap().unwrap());
+        let sender = "127.0.0.1:1".parse().unwrap();
+
+        receiver.handle_packet(sender, &encode(TYPE_DATA, 10, 0, b"foo"));
+        receiver.handle_packet(sender, &encode(TYPE_DATA, 10, 0, b"foo"));
+        receiver.handle_packet(sender, &encode(TYPE_DATA, 11, 0, b"bar"));
+        assert_eq!(receiver.stats().received, 2);
+        assert_eq!(receiver.stats().duplicates, 1);
+        let payloads = receiver
+            .inbox
+            .iter()
+            .map(|m| m.1.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(payloads, vec![b"foo".to_vec(), b"bar".to_vec()]);
+    }
+
+    #[test]
+    fn peers_are_bounded_and_expire() {
+        let mut executor = InPlaceExecutor::new().unwrap();
+        let monitor = executor.spawn_monitor(UdpSocket::bind("127.0.0.1:0".parse().unwrap()));
+        let options = RudpOptions {
+            max_peers: 2,
+            ..RudpOptions::default()
+        };
+        let mut receiver =
+            RudpSocket::with_options(executor.run_fiber(monitor).unwrap().unwrap(), options);
+
+        for port in 1..4 {
+            let sender = SocketAddr::from(([127, 0, 0, 1], port));
+            receiver.handle_packet(sender, &encode(TYPE_DATA, 0, 0, b"foo"));
+        }
+        assert_eq!(receiver.peers.len(), 2);
+        assert_eq!(receiver.stats().received, 2);
+        assert_eq!(receiver.stats().rejected, 1);
+
+        receiver.expire_idle_peers(Instant::now());
+        assert_eq!(receiver.peers.len(), 2);
+        receiver.expire_idle_peers(Instant::now() + Duration::from_secs(60));
+        assert_eq!(receiver.peers.len(), 0);
+        assert_eq!(receiver.stats().expired_peers, 2);
+    }
+}
@@ -0,0 +1,147 @@
+// Copyright (c) 2016 DWANGO Co., Ltd. All Rights Reserved.
+// See the LICENSE file at the top-level directory of this distribution.
+
+use mio::net::TcpStream as MioTcpStream;
+use std::io;
+use std::time::Duration;
+
+use super::TcpStream;
+
+/// A profile of the options of TCP streams.
+///
+/// This is typically set to a listener via `TcpListener::set_stream_options` method,
+/// so that the options are applied to every accepted stream before it is yielded.
+///
+/// The options which are not specified are left unchanged (i.e., the system defaults).
+///
+/// # Examples
+///
+/// ```
+/// # extern crate fibers;
+/// # extern crate futures;
+/// use fibers::{Executor, InPlaceExecutor, Spawn};
+/// use fibers::net::{TcpListener, TcpStream, TcpStreamOptions};
+/// use futures::{Future, Stream};
+///
+/// let mut executor = InPlaceExecutor::new().unwrap();
+/// let listener = executor.spawn_monitor(TcpListener::bind("127.0.0.1:0".parse().unwrap()));
+/// let mut listener = executor.run_fiber(listener).unwrap().unwrap();
+/// let addr = listener.local_addr().unwrap();
+/// listener.set_stream_options(TcpStreamOptions::new().nodelay(true));
+///
+/// let server = executor.spawn_monitor(
+///     listener
+///         .incoming()
+///         .into_future()
+///         .map_err(|(e, _)| e)
+///         .and_then(|(client, _)| client.unwrap().0)
+///         .map(|stream| stream.nodelay().unwrap()),
+/// );
+/// executor.spawn(TcpStream::connect(addr).map(|_| ()).map_err(|e| panic!("{}", e)));
+/// assert_eq!(executor.run_fiber(server).unwrap().unwrap(), true);
+/// ```
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TcpStreamOptions {
+    nodelay: Option<bool>,
+    keepalive: Option<Option<Duration>>,
+    send_buffer_size: Option<usize>,
+    recv_buffer_size: Option<usize>,
+    tos: Option<u32>,
+}
+impl TcpStreamOptions {
+    /// Makes a new `TcpStreamOptions` instance which specifies no options.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the value of the `TCP_NODELAY` option.
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = Some(nodelay);
+        self
+    }
+
+    /// Sets the TCP keepalive interval (`None` disables keepalive).
+    pub fn keepalive(mut self, keepalive: Option<Duration>) -> Self {
+        self.keepalive = Some(keepalive);
+        self
+    }
+
+    /// Sets the value of the `SO_SNDBUF` option.
+    pub fn send_buffer_size(mut self, size: usize) -> Self {
+        self.send_buffer_size = Some(size);
+        self
+    }
+
+    /// Sets the value of the `SO_RCVBUF` option.
+    pub fn recv_buffer_size(mut self, size: usize) -> Self {
+        self.recv_buffer_size = Some(size);
+        self
+    }
+
+    /// Sets the type-of-service field of the IP packets
+    /// (i.e., the `IP_TOS` option for IPv4 and the `IPV6_TCLASS` option for IPv6).
+    ///
+    /// This option is supported only on Unix platforms.
+    pub fn tos(mut self, tos: u32) -> Self {
+        self.tos = Some(tos);
+        self
+    }
+
+    /// Applies the options to `stream`.
+    pub fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        stream.with_inner(|s| self.apply_to(s))
+    }
+
+    pub(super) fn apply_to(&self, stream: &MioTcpStream) -> io::Result<()> {
+        if let Some(nodelay) = self.nodelay {
+            stream.set_nodelay(nodelay)?;
+        }
+        if let Some(keepalive) = self.keepalive {
+            stream.set_keepalive(keepalive)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            stream.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            stream.set_recv_buffer_size(size)?;
+        }
+        if let Some(tos) = self.tos {
+            set_tos(stream, tos)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn set_tos(stream: &MioTcpStream, tos: u32) -> io::Result<()> {
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+
+    let (level, name) = if stream.local_addr()?.is_ipv4() {
+        (libc::IPPROTO_IP, libc::IP_TOS)
+    } else {
+        (libc::IPPROTO_IPV6, libc::IPV6_TCLASS)
+    };
+    let value = tos as libc::c_int;
+    let result = unsafe {
+        libc::setsockopt(
+            stream.as_raw_fd(),
+            level,
+            name,
+            &value as *const _ as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(not(unix))]
+fn set_tos(_stream: &MioTcpStream, _tos: u32) -> io::Result<()> {
+    Err(io::Error::other(
+        "The TOS option is unsupported on this platform",
+    ))
+}
@@ -1,18 +1,26 @@
 // Copyright (c) 2016 DWANGO Co., Ltd. All Rights Reserved.
 // See the LICENSE file at the top-level directory of this distribution.
 
-use futures::{Async, Future, Poll, Stream};
+use futures::{Async, Future, IntoFuture, Poll, Stream};
 use mio::net::{TcpListener as MioTcpListener, TcpStream as MioTcpStream};
+use net2::TcpBuilder;
 use std::fmt;
-use std::io;
+use std::fs::File;
+use std::io::{self, Write};
 use std::mem;
-use std::net::SocketAddr;
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr};
+use std::sync::atomic::{self, AtomicUsize};
 use std::sync::Arc;
+use std::time::Duration;
 
-use super::{into_io_error, Bind};
-use crate::fiber::{self, Context};
+use super::TcpStreamOptions;
+use super::{into_io_error, register_socket, AcceptRateLimiter, Bind, ListenerSet};
+use crate::fiber::{self, Context, Spawn};
 use crate::io::poll::{EventedHandle, Interest, Register};
+use crate::metrics::WakeStats;
+use crate::sync::mpsc;
 use crate::sync::oneshot::Monitor;
+use crate::time::timer::{self, Timeout};
 
 /// A structure representing a socket server.
 ///
@@ -62,6 +70,7 @@ use crate::sync::oneshot::Monitor;
 pub struct TcpListener {
     handle: Arc<EventedHandle<MioTcpListener>>,
     monitor: Option<Monitor<(), io::Error>>,
+    stream_options: Option<TcpStreamOptions>,
 }
 impl TcpListener {
     /// Makes a future to create a new `TcpListener` which will be bound to the specified address.
@@ -69,9 +78,105 @@ impl TcpListener {
         TcpListenerBind(Bind::Bind(addr, MioTcpListener::bind))
     }
 
+    /// Makes a future to create a new `TcpListener` with the specified `IPV6_V6ONLY` option.
+    ///
+    /// If `only_v6` is `false`, an IPv6 listener also accepts IPv4 connections
+    /// (as IPv4-mapped IPv6 addresses) on the platforms which support it.
+    /// The option is ignored if `addr` is an IPv4 address.
+    pub fn bind_only_v6(addr: SocketAddr, only_v6: bool) -> TcpListenerBind {
+        let bind: fn(&SocketAddr) -> io::Result<MioTcpListener> = if only_v6 {
+            |addr| listen(addr, Some(true))
+        } else {
+            |addr| listen(addr, Some(false))
+        };
+        TcpListenerBind(Bind::Bind(addr, bind))
+    }
+
+    /// Makes a future to create a new `TcpListener` bound to the first available address
+    /// of `addrs`.
+    ///
+    /// The addresses are tried in order.
+    /// If `TcpListenerBindAny::ephemeral_fallback` is enabled and none of them is available,
+    /// the addresses are tried again with port `0` (i.e., a port assigned by the system).
+    /// The actually bound address can be retrieved by `TcpListener::local_addr`.
+    ///
+    /// If all the attempts fail, the error of the last attempt is returned.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate fibers;
+    /// # extern crate futures;
+    /// use fibers::{Executor, InPlaceExecutor, Spawn};
+    /// use fibers::net::TcpListener;
+    ///
+    /// let mut executor = InPlaceExecutor::new().unwrap();
+    /// let bind = executor.spawn_monitor(TcpListener::bind("127.0.0.1:0".parse().unwrap()));
+    /// let occupied = executor.run_fiber(bind).unwrap().unwrap();
+    /// let occupied_addr = occupied.local_addr().unwrap();
+    ///
+    /// let bind = TcpListener::bind_any(&[occupied_addr]).ephemeral_fallback(true);
+    /// let listener = executor.run_fiber(executor.spawn_monitor(bind)).unwrap().unwrap();
+    /// assert_ne!(listener.local_addr().unwrap(), occupied_addr);
+    /// ```
+    pub fn bind_any(addrs: &[SocketAddr]) -> TcpListenerBindAny {
+        TcpListenerBindAny {
+            addrs: addrs.to_owned(),
+            ephemeral_fallback: false,
+            register: None,
+        }
+    }
+
+    /// Makes a future to create listeners which accept both IPv4 and IPv6 connections on `port`.
+    ///
+    /// If the platform supports dual-stack sockets, a single IPv6 listener with
+    /// `IPV6_V6ONLY` disabled is created.
+    /// Otherwise, a pair of IPv4 and IPv6-only listeners bound to the same port is created
+    /// (or only an IPv4 listener if IPv6 is unavailable).
+    ///
+    /// In either case, the resulting listeners are merged into a `ListenerSet`.
+    /// If `port` is `0`, all the listeners share the port assigned by the system.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate fibers;
+    /// # extern crate futures;
+    /// use fibers::{Executor, InPlaceExecutor, Spawn};
+    /// use fibers::net::{TcpListener, TcpStream};
+    /// use futures::{Future, Stream};
+    ///
+    /// let mut executor = InPlaceExecutor::new().unwrap();
+    /// let bind = executor.spawn_monitor(TcpListener::bind_dual_stack(0));
+    /// let listeners = executor.run_fiber(bind).unwrap().unwrap();
+    /// let port = listeners.local_addrs()[0].1.port();
+    ///
+    /// let accepted = executor.spawn_monitor(listeners.into_future().map_err(|(e, _)| e));
+    /// let addr = format!("127.0.0.1:{}", port).parse().unwrap();
+    /// executor.spawn(TcpStream::connect(addr).then(|_| Ok(())));
+    /// let (connected, _) = executor.run_fiber(accepted).unwrap().unwrap();
+    /// assert!(connected.is_some());
+    /// ```
+    pub fn bind_dual_stack(port: u16) -> TcpListenerBindDualStack {
+        TcpListenerBindDualStack {
+            port,
+            registers: None,
+            handles: Vec::new(),
+        }
+    }
+
     /// Makes a stream of the connections which will be accepted by this listener.
     pub fn incoming(self) -> Incoming {
-        Incoming(self)
+        Incoming {
+            listener: self,
+            error_policy: AcceptErrorPolicy::Terminate,
+            error_tx: None,
+            retry_timer: None,
+            reserved_fd: None,
+            shed_count: Arc::new(AtomicUsize::new(0)),
+            rate_limiter: None,
+            pending_error: None,
+        }
     }
 
     /// Returns the local socket address of this listener.
@@ -88,12 +193,33 @@ impl TcpListener {
         self.handle.inner().take_error()
     }
 
+    /// Returns the statistics of the wakeups of the fibers waiting for this listener.
+    ///
+    /// See `fibers::metrics::WakeStats` for more details.
+    pub fn wake_stats(&self) -> WakeStats {
+        self.handle.wake_stats()
+    }
+
+    /// Sets the options applied to every stream accepted by this listener.
+    ///
+    /// The options are applied before the stream is registered to the poller.
+    /// If it fails, the connection is closed and the error is handled as a connection level
+    /// error (i.e., it is reported via `Incoming::errors` and the stream keeps accepting).
+    pub fn set_stream_options(&mut self, options: TcpStreamOptions) {
+        self.stream_options = Some(options);
+    }
+
+    /// Returns the options applied to every stream accepted by this listener.
+    pub fn stream_options(&self) -> Option<&TcpStreamOptions> {
+        self.stream_options.as_ref()
+    }
+
     /// Calls `f` with the reference to the inner socket.
     pub fn with_inner<F, T>(&self, f: F) -> T
     where
         F: FnOnce(&MioTcpListener) -> T,
     {
-        f(&*self.handle.inner())
+        f(&self.handle.inner())
     }
 }
 impl fmt::Debug for TcpListener {
@@ -124,10 +250,196 @@ impl Future for TcpListenerBind {
         Ok(self.0.poll()?.map(|handle| TcpListener {
             handle,
             monitor: None,
+            stream_options: None,
         }))
     }
 }
 
+/// A future which will create a new `TcpListener` bound to one of the candidate addresses.
+///
+/// This is created by calling `TcpListener::bind_any` function.
+/// It is permitted to move the future across fibers.
+///
+/// # Panics
+///
+/// If the future is polled on the outside of a fiber, it may crash.
+#[derive(Debug)]
+pub struct TcpListenerBindAny {
+    addrs: Vec<SocketAddr>,
+    ephemeral_fallback: bool,
+    register: Option<Register<MioTcpListener>>,
+}
+impl TcpListenerBindAny {
+    /// Sets whether to fall back to ephemeral ports if none of the addresses is available.
+    ///
+    /// The default value is `false`.
+    pub fn ephemeral_fallback(mut self, enabled: bool) -> Self {
+        self.ephemeral_fallback = enabled;
+        self
+    }
+
+    fn bind(&self) -> io::Result<MioTcpListener> {
+        let ephemeral_addrs = self
+            .addrs
+            .iter()
+            .filter(|_| self.ephemeral_fallback)
+            .map(|addr| SocketAddr::new(addr.ip(), 0));
+        let mut last_error = None;
+        for addr in self.addrs.iter().cloned().chain(ephemeral_addrs) {
+            match MioTcpListener::bind(&addr) {
+                Ok(listener) => return Ok(listener),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error
+            .unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No candidate address")))
+    }
+}
+impl Future for TcpListenerBindAny {
+    type Item = TcpListener;
+    type Error = io::Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if self.register.is_none() {
+            let listener = self.bind()?;
+            let register = assert_some!(fiber::with_current_context(|c| register_socket(
+                c, listener
+            )));
+            self.register = Some(register);
+        }
+        let register = self.register.as_mut().expect("Never fails");
+        Ok(register
+            .poll()
+            .map_err(into_io_error)?
+            .map(|handle| TcpListener {
+                handle,
+                monitor: None,
+                stream_options: None,
+            }))
+    }
+}
+
+/// A future which will create listeners accepting both IPv4 and IPv6 connections.
+///
+/// This is created by calling `TcpListener::bind_dual_stack` function.
+/// It is permitted to move the future across fibers.
+///
+/// # Panics
+///
+/// If the future is polled on the outside of a fiber, it may crash.
+#[derive(Debug)]
+pub struct TcpListenerBindDualStack {
+    port: u16,
+    registers: Option<Vec<Register<MioTcpListener>>>,
+    handles: Vec<Arc<EventedHandle<MioTcpListener>>>,
+}
+impl Future for TcpListenerBindDualStack {
+    type Item = ListenerSet;
+    type Error = io::Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if self.registers.is_none() {
+            let listeners = listen_dual_stack(self.port)?;
+            let registers = listeners
+                .into_iter()
+                .map(|l| assert_some!(fiber::with_current_context(|c| register_socket(c, l))))
+                .collect();
+            self.registers = Some(registers);
+        }
+
+        let registers = self.registers.as_mut().expect("Never fails");
+        let mut i = 0;
+        while i < registers.len() {
+            if let Async::Ready(handle) = registers[i].poll().map_err(into_io_error)? {
+                registers.remove(i);
+                self.handles.push(handle);
+            } else {
+                i += 1;
+            }
+        }
+        if !registers.is_empty() {
+            return Ok(Async::NotReady);
+        }
+
+        let mut listeners = ListenerSet::new();
+        self.handles.sort_by_key(|h| h.inner().local_addr().ok());
+        for handle in self.handles.drain(..) {
+            let listener = TcpListener {
+                handle,
+                monitor: None,
+                stream_options: None,
+            };
+            listeners.push(listener.incoming());
+        }
+        Ok(Async::Ready(listeners))
+    }
+}
+
+fn listen(addr: &SocketAddr, only_v6: Option<bool>) -> io::Result<MioTcpListener> {
+    let builder = match *addr {
+        SocketAddr::V4(_) => TcpBuilder::new_v4()?,
+        SocketAddr::V6(_) => TcpBuilder::new_v6()?,
+    };
+    listen_with_builder(&builder, addr, only_v6)
+}
+
+fn listen_with_builder(
+    builder: &TcpBuilder,
+    addr: &SocketAddr,
+    only_v6: Option<bool>,
+) -> io::Result<MioTcpListener> {
+    // The same as `mio::net::TcpListener::bind`, except for the `IPV6_V6ONLY` option.
+    if cfg!(unix) {
+        builder.reuse_address(true)?;
+    }
+    if let (Some(only_v6), SocketAddr::V6(_)) = (only_v6, addr) {
+        builder.only_v6(only_v6)?;
+    }
+    builder.bind(addr)?;
+    let listener = builder.listen(1024)?;
+    MioTcpListener::from_std(listener)
+}
+
+fn listen_dual_stack(port: u16) -> io::Result<Vec<MioTcpListener>> {
+    let v6_addr = SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port);
+    let v6_available = if let Ok(builder) = TcpBuilder::new_v6() {
+        if let Ok(listener) = listen_with_builder(&builder, &v6_addr, Some(false)) {
+            return Ok(vec![listener]);
+        }
+        true
+    } else {
+        false
+    };
+
+    // Dual-stack sockets are unavailable (e.g., the platform forces `IPV6_V6ONLY`).
+    let v4_addr = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port);
+    let v4_listener = listen(&v4_addr, None)?;
+    let port = v4_listener.local_addr()?.port();
+    let mut listeners = vec![v4_listener];
+    if v6_available {
+        let v6_addr = SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port);
+        listeners.push(listen(&v6_addr, Some(true))?);
+    }
+    Ok(listeners)
+}
+
+/// The policy to handle the recoverable errors occurred while accepting connections.
+///
+/// The following errors are regarded as recoverable:
+///
+/// - Errors caused by a connection aborted before being accepted
+///   (e.g., `ECONNABORTED`), which are retried immediately
+/// - Resource exhaustion errors (e.g., `EMFILE`, `ENFILE`, `ENOBUFS` and `ENOMEM`),
+///   which are retried after the delay specified by the policy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcceptErrorPolicy {
+    /// Any error terminates the stream (i.e., the error is returned from `Stream::poll`).
+    ///
+    /// This is the default policy.
+    Terminate,
+
+    /// Recoverable errors are reported via `Incoming::errors` and the accept is retried.
+    RetryAfter(Duration),
+}
+
 /// An infinite stream of the connections which will be accepted by the listener.
 ///
 /// This is created by calling `TcpListener::incoming` method.
@@ -136,39 +448,390 @@ impl Future for TcpListenerBind {
 /// # Panics
 ///
 /// If the stream is polled on the outside of a fiber, it may crash.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate fibers;
+/// # extern crate futures;
+/// use std::time::Duration;
+/// use fibers::{Executor, InPlaceExecutor, Spawn};
+/// use fibers::net::{AcceptErrorPolicy, TcpListener};
+/// use futures::{Future, Stream};
+///
+/// let mut executor = InPlaceExecutor::new().unwrap();
+/// let handle = executor.handle();
+/// executor.spawn(TcpListener::bind("127.0.0.1:0".parse().unwrap())
+///     .and_then(move |listener| {
+///         let mut incoming = listener.incoming();
+///
+///         // Keeps accepting even if the process runs out of file descriptors.
+///         incoming.set_error_policy(AcceptErrorPolicy::RetryAfter(Duration::from_millis(100)));
+///         handle.spawn(incoming.errors().for_each(|e| {
+///             println!("# Accept error: {}", e);
+///             Ok(())
+///         }));
+///         incoming.for_each(|_| Ok(()))
+///     })
+///     .map_err(|e| panic!("{:?}", e)));
+/// executor.run_once().unwrap();
+/// ```
 #[derive(Debug)]
-pub struct Incoming(TcpListener);
+pub struct Incoming {
+    listener: TcpListener,
+    error_policy: AcceptErrorPolicy,
+    error_tx: Option<mpsc::Sender<io::Error>>,
+    retry_timer: Option<Timeout>,
+    reserved_fd: Option<File>,
+    shed_count: Arc<AtomicUsize>,
+    rate_limiter: Option<AcceptRateLimiter>,
+    pending_error: Option<io::Error>,
+}
+impl Incoming {
+    /// Returns the local socket address of the underlying listener.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Enables the shed-accept mode for file descriptor exhaustion.
+    ///
+    /// In this mode, the stream holds a reserved file descriptor.
+    /// If an accept fails with `EMFILE`, the stream releases the reserved descriptor,
+    /// accepts a pending connection with it and closes the connection immediately,
+    /// then reserves a descriptor again.
+    /// This is repeated until the backlog is drained, so that the clients get
+    /// fast failures rather than hanging in the backlog until the exhaustion is resolved.
+    ///
+    /// The number of the shed connections can be retrieved via `ShedCounter`.
+    /// The `EMFILE` errors are also reported via `Incoming::errors`.
+    #[cfg(unix)]
+    pub fn enable_emfile_shedding(&mut self) -> io::Result<()> {
+        if self.reserved_fd.is_none() {
+            self.reserved_fd = Some(File::open("/dev/null")?);
+        }
+        Ok(())
+    }
+
+    /// Returns the counter of the connections shed by the shed-accept mode.
+    pub fn shed_counter(&self) -> ShedCounter {
+        ShedCounter(Arc::clone(&self.shed_count))
+    }
+
+    fn try_shed(&mut self, e: &io::Error) -> Shed {
+        if !is_fd_exhaustion(e) {
+            return Shed::Failed;
+        }
+        if let Some(reserved_fd) = self.reserved_fd.take() {
+            drop(reserved_fd);
+            let result = self.listener.handle.inner().accept();
+            let shed = match result {
+                Ok(_) => {
+                    self.shed_count.fetch_add(1, atomic::Ordering::SeqCst);
+                    Shed::Accepted
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Shed::Drained,
+                Err(_) => Shed::Failed,
+            };
+            drop(result);
+            self.reserved_fd = File::open("/dev/null").ok();
+            shed
+        } else {
+            Shed::Failed
+        }
+    }
+
+    /// Sets the limiter of the connection rate of each source address.
+    ///
+    /// The connections rejected by the limiter are closed immediately
+    /// without being yielded from this stream.
+    pub fn set_rate_limiter(&mut self, limiter: AcceptRateLimiter) {
+        self.rate_limiter = Some(limiter);
+    }
+
+    /// Sets the policy to handle the recoverable accept errors.
+    pub fn set_error_policy(&mut self, policy: AcceptErrorPolicy) {
+        self.error_policy = policy;
+    }
+
+    /// Returns the policy to handle the recoverable accept errors.
+    pub fn error_policy(&self) -> AcceptErrorPolicy {
+        self.error_policy
+    }
+
+    /// Returns a stream of the recoverable errors which were retried by this stream.
+    ///
+    /// Errors are reported only if the policy is `AcceptErrorPolicy::RetryAfter`.
+    /// If this method is called multiple times, only the latest stream receives errors.
+    pub fn errors(&mut self) -> mpsc::Receiver<io::Error> {
+        let (tx, rx) = mpsc::channel();
+        self.error_tx = Some(tx);
+        rx
+    }
+
+    /// Makes a future which accepts a batch of at most `max` connections.
+    ///
+    /// The future waits until at least one connection is accepted,
+    /// then accepts the connections pending in the backlog without waiting further.
+    /// On completion, it returns the accepted connections together with this stream.
+    ///
+    /// On readiness based backends (i.e., all the current ones),
+    /// this is implemented by a loop of non-blocking accepts.
+    /// The API is designed so that completion based backends
+    /// (e.g., multishot accept of io_uring) can fill the batch per completion.
+    ///
+    /// If an error occurs after some connections have been accepted,
+    /// the batch is completed with them and the error is returned from the next polling.
+    ///
+    /// # Panics
+    ///
+    /// If `max` is `0`, this method will panic.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate fibers;
+    /// # extern crate futures;
+    /// use fibers::{Executor, InPlaceExecutor, Spawn};
+    /// use fibers::net::{TcpListener, TcpStream};
+    /// use futures::Future;
+    ///
+    /// let mut executor = InPlaceExecutor::new().unwrap();
+    /// let bind = executor.spawn_monitor(TcpListener::bind("127.0.0.1:0".parse().unwrap()));
+    /// let listener = executor.run_fiber(bind).unwrap().unwrap();
+    /// let addr = listener.local_addr().unwrap();
+    ///
+    /// let connects = (0..3).map(|_| executor.spawn_monitor(TcpStream::connect(addr))).collect::<Vec<_>>();
+    /// for connect in connects {
+    ///     executor.run_fiber(connect).unwrap().unwrap();
+    /// }
+    ///
+    /// let batch = executor.spawn_monitor(listener.incoming().accept_batch(16));
+    /// let (connections, _incoming) = executor.run_fiber(batch).unwrap().unwrap();
+    /// assert_eq!(connections.len(), 3);
+    /// ```
+    pub fn accept_batch(self, max: usize) -> AcceptBatch {
+        assert!(max > 0);
+        AcceptBatch {
+            incoming: Some(self),
+            max,
+        }
+    }
+
+    fn report_error(&mut self, e: io::Error) {
+        if let Some(ref tx) = self.error_tx {
+            let _ = tx.send(e);
+        }
+    }
+}
 impl Stream for Incoming {
     type Item = (Connected, SocketAddr);
     type Error = io::Error;
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if let Some(e) = self.pending_error.take() {
+            return Err(e);
+        }
+        let mut woken = false;
         loop {
-            if let Some(mut monitor) = self.0.monitor.take() {
+            if let Some(mut timer) = self.retry_timer.take() {
+                if let Ok(Async::NotReady) = timer.poll() {
+                    self.retry_timer = Some(timer);
+                    return Ok(Async::NotReady);
+                }
+            } else if let Some(mut monitor) = self.listener.monitor.take() {
                 if let Async::NotReady = monitor.poll().map_err(into_io_error)? {
-                    self.0.monitor = Some(monitor);
+                    self.listener.monitor = Some(monitor);
                     return Ok(Async::NotReady);
                 }
+                woken = true;
             } else {
-                match self.0.handle.inner().accept() {
+                let result = self.listener.handle.inner().accept();
+                if woken {
+                    let would_block =
+                        matches!(result, Err(ref e) if e.kind() == io::ErrorKind::WouldBlock);
+                    self.listener.handle.record_wakeup(!would_block);
+                    woken = false;
+                }
+                match result {
                     Ok((stream, addr)) => {
-                        let register = |mut c: Context| c.poller().register(stream);
+                        if let Some(ref limiter) = self.rate_limiter {
+                            if !limiter.check(addr.ip()) {
+                                drop(stream);
+                                continue;
+                            }
+                        }
+                        if let Some(ref options) = self.listener.stream_options {
+                            if let Err(e) = options.apply_to(&stream) {
+                                drop(stream);
+                                self.report_error(e);
+                                continue;
+                            }
+                        }
+                        let register = |c: Context| register_socket(c, stream);
                         let future = assert_some!(fiber::with_current_context(register));
                         let stream = Connected(Some(future));
                         return Ok(Async::Ready(Some((stream, addr))));
                     }
                     Err(e) => {
                         if e.kind() == io::ErrorKind::WouldBlock {
-                            self.0.monitor = Some(self.0.handle.monitor(Interest::Read));
-                        } else {
+                            self.listener.monitor =
+                                Some(self.listener.handle.monitor(Interest::Read));
+                            continue;
+                        }
+                        match self.try_shed(&e) {
+                            Shed::Accepted => {
+                                self.report_error(e);
+                                continue;
+                            }
+                            Shed::Drained => {
+                                // NOTE: On Linux, `accept` fails with `EMFILE` even if
+                                // the backlog is empty, so we wait for the next connection.
+                                self.listener.monitor =
+                                    Some(self.listener.handle.monitor(Interest::Read));
+                                continue;
+                            }
+                            Shed::Failed => {}
+                        }
+                        let delay = match self.error_policy {
+                            AcceptErrorPolicy::Terminate => return Err(e),
+                            AcceptErrorPolicy::RetryAfter(delay) => delay,
+                        };
+                        match classify_accept_error(&e) {
+                            AcceptErrorClass::Fatal => return Err(e),
+                            AcceptErrorClass::ConnectionLevel => {}
+                            AcceptErrorClass::ResourceExhausted => {
+                                self.retry_timer = Some(timer::timeout(delay));
+                            }
+                        }
+                        self.report_error(e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// A future which accepts a batch of connections.
+///
+/// This is created by calling `Incoming::accept_batch` method.
+#[derive(Debug)]
+pub struct AcceptBatch {
+    incoming: Option<Incoming>,
+    max: usize,
+}
+impl Future for AcceptBatch {
+    type Item = (Vec<(Connected, SocketAddr)>, Incoming);
+    type Error = io::Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let mut batch = Vec::new();
+        {
+            let incoming = self
+                .incoming
+                .as_mut()
+                .expect("Cannot poll AcceptBatch twice");
+            while batch.len() < self.max {
+                match incoming.poll() {
+                    Ok(Async::Ready(Some(connection))) => batch.push(connection),
+                    Ok(Async::Ready(None)) | Ok(Async::NotReady) => break,
+                    Err(e) => {
+                        if batch.is_empty() {
                             return Err(e);
                         }
+                        incoming.pending_error = Some(e);
+                        break;
                     }
                 }
             }
         }
+        if batch.is_empty() {
+            Ok(Async::NotReady)
+        } else {
+            let incoming = self.incoming.take().expect("Never fails");
+            Ok(Async::Ready((batch, incoming)))
+        }
+    }
+}
+
+/// A counter of the connections shed by the shed-accept mode of `Incoming`.
+///
+/// This is created by calling `Incoming::shed_counter` method.
+#[derive(Debug, Clone)]
+pub struct ShedCounter(Arc<AtomicUsize>);
+impl ShedCounter {
+    /// Returns the number of the connections which have been accepted and closed immediately.
+    pub fn get(&self) -> usize {
+        self.0.load(atomic::Ordering::SeqCst)
     }
 }
 
+/// The result of an attempt of the shed-accept mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Shed {
+    /// A pending connection has been accepted and closed.
+    Accepted,
+
+    /// No connection is pending in the backlog.
+    Drained,
+
+    /// The connection could not be shed.
+    Failed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AcceptErrorClass {
+    ConnectionLevel,
+    ResourceExhausted,
+    Fatal,
+}
+
+fn classify_accept_error(e: &io::Error) -> AcceptErrorClass {
+    match e.kind() {
+        io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::Interrupted => return AcceptErrorClass::ConnectionLevel,
+        io::ErrorKind::OutOfMemory => return AcceptErrorClass::ResourceExhausted,
+        _ => {}
+    }
+    classify_os_error(e.raw_os_error())
+}
+
+#[cfg(unix)]
+fn classify_os_error(errno: Option<i32>) -> AcceptErrorClass {
+    match errno {
+        Some(libc::EMFILE) | Some(libc::ENFILE) | Some(libc::ENOBUFS) | Some(libc::ENOMEM) => {
+            AcceptErrorClass::ResourceExhausted
+        }
+        Some(libc::EPROTO) | Some(libc::EPERM) => AcceptErrorClass::ConnectionLevel,
+        _ => AcceptErrorClass::Fatal,
+    }
+}
+
+#[cfg(not(unix))]
+fn classify_os_error(_errno: Option<i32>) -> AcceptErrorClass {
+    AcceptErrorClass::Fatal
+}
+
+#[cfg(unix)]
+fn is_fd_exhaustion(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(libc::EMFILE)
+}
+
+#[cfg(not(unix))]
+fn is_fd_exhaustion(_e: &io::Error) -> bool {
+    false
+}
+
+#[cfg(unix)]
+fn into_std_stream(stream: MioTcpStream) -> std::net::TcpStream {
+    use std::os::unix::io::{FromRawFd, IntoRawFd};
+    unsafe { std::net::TcpStream::from_raw_fd(stream.into_raw_fd()) }
+}
+
+#[cfg(windows)]
+fn into_std_stream(stream: MioTcpStream) -> std::net::TcpStream {
+    use std::os::windows::io::{FromRawSocket, IntoRawSocket};
+    unsafe { std::net::TcpStream::from_raw_socket(stream.into_raw_socket()) }
+}
+
 /// A future which represents a `TcpStream` connected to a `TcpListener`.
 ///
 /// This is produced by `Incoming` stream.
@@ -193,6 +856,85 @@ impl Future for Connected {
     }
 }
 
+/// A TCP stream which is not registered to any poller.
+///
+/// This is created by calling `TcpStream::detach` (or `CoalescingWriter::detach`) method,
+/// and is used to hand off a connection from an executor to another.
+pub struct DetachedTcpStream {
+    stream: MioTcpStream,
+    pending_output: Vec<u8>,
+}
+impl DetachedTcpStream {
+    /// Returns the local socket address of this stream.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.local_addr()
+    }
+
+    /// Returns the socket address of the remote peer of this stream.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    /// Takes the data which was buffered for writing but has not been written yet.
+    ///
+    /// The data should be written to the attached stream before any other data.
+    /// If this stream was detached by `TcpStream::detach` method, the data is always empty.
+    pub fn take_pending_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.pending_output)
+    }
+
+    /// Makes a future which registers this stream to the poller of the fiber polling the future.
+    pub fn attach(self) -> AttachTcpStream {
+        AttachTcpStream {
+            stream: Some(self.stream),
+            connected: None,
+        }
+    }
+
+    pub(super) fn with_pending_output(mut self, pending_output: Vec<u8>) -> Self {
+        self.pending_output = pending_output;
+        self
+    }
+}
+impl fmt::Debug for DetachedTcpStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "DetachedTcpStream {{ pending_output_len: {}, .. }}",
+            self.pending_output.len()
+        )
+    }
+}
+
+/// A future which attaches a `DetachedTcpStream` to the poller of the current fiber.
+///
+/// This is created by calling `DetachedTcpStream::attach` method.
+/// It is permitted to move the future across fibers.
+///
+/// # Panics
+///
+/// If the future is polled on the outside of a fiber, it may crash.
+#[derive(Debug)]
+pub struct AttachTcpStream {
+    stream: Option<MioTcpStream>,
+    connected: Option<Connected>,
+}
+impl Future for AttachTcpStream {
+    type Item = TcpStream;
+    type Error = io::Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if let Some(stream) = self.stream.take() {
+            let register = |c: Context| register_socket(c, stream);
+            let future = assert_some!(fiber::with_current_context(register));
+            self.connected = Some(Connected(Some(future)));
+        }
+        self.connected
+            .as_mut()
+            .expect("Cannot poll AttachTcpStream twice")
+            .poll()
+    }
+}
+
 /// A structure which represents a TCP stream between a local socket and a remote socket.
 ///
 /// The socket will be closed when the value is dropped.
@@ -302,6 +1044,13 @@ impl TcpStream {
         self.handle.inner().take_error()
     }
 
+    /// Returns the statistics of the wakeups of the fibers waiting for this stream.
+    ///
+    /// See `fibers::metrics::WakeStats` for more details.
+    pub fn wake_stats(&self) -> WakeStats {
+        self.handle.wake_stats()
+    }
+
     /// Gets the value of the `TCP_NODELAY` option on this socket.
     pub fn nodelay(&self) -> io::Result<bool> {
         self.handle.inner().nodelay()
@@ -317,7 +1066,153 @@ impl TcpStream {
     where
         F: FnOnce(&MioTcpStream) -> T,
     {
-        f(&*self.handle.inner())
+        f(&self.handle.inner())
+    }
+
+    /// Makes a future which flushes the written data and then shuts down the write side.
+    ///
+    /// The write side is shut down only after all the data accepted by the previous writes
+    /// have been handed to the kernel (retrying on `WouldBlock` errors),
+    /// so the peer is guaranteed to receive them before the end of the stream.
+    /// The future returns the stream, so the remaining data sent by the peer can still be read.
+    ///
+    /// See also `CoalescingWriter::flush_and_shutdown` for the data buffered in a writer.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate fibers;
+    /// # extern crate futures;
+    /// use std::io::Write;
+    /// use fibers::{Executor, InPlaceExecutor, Spawn};
+    /// use fibers::net::{TcpListener, TcpStream};
+    /// use futures::{Future, Stream};
+    ///
+    /// let mut executor = InPlaceExecutor::new().unwrap();
+    /// let bind = executor.spawn_monitor(TcpListener::bind("127.0.0.1:0".parse().unwrap()));
+    /// let listener = executor.run_fiber(bind).unwrap().unwrap();
+    /// let addr = listener.local_addr().unwrap();
+    /// let accept = executor.spawn_monitor(listener.incoming().into_future()
+    ///     .map_err(|(e, _)| e)
+    ///     .and_then(|(client, _)| client.unwrap().0));
+    /// let connect = executor.spawn_monitor(TcpStream::connect(addr));
+    /// let server = executor.run_fiber(accept).unwrap().unwrap();
+    /// let mut client = executor.run_fiber(connect).unwrap().unwrap();
+    ///
+    /// client.write_all(b"foo").unwrap();
+    /// let shutdown = executor.spawn_monitor(client.flush_and_shutdown());
+    /// let _client = executor.run_fiber(shutdown).unwrap().unwrap();
+    ///
+    /// let mut server = server.into_std(false).unwrap();
+    /// let mut buf = Vec::new();
+    /// std::io::Read::read_to_end(&mut server, &mut buf).unwrap();
+    /// assert_eq!(buf, b"foo");
+    /// ```
+    pub fn flush_and_shutdown(self) -> FlushAndShutdown {
+        FlushAndShutdown::new(self, Vec::new())
+    }
+
+    /// Converts this stream into a standard (i.e., blocking capable) TCP stream.
+    ///
+    /// The returned stream refers to the same connection via a duplicated descriptor,
+    /// and this stream is deregistered from the poller and closed.
+    /// So the connection can be handed off to a blocking library or another process.
+    ///
+    /// If `nonblocking` is `false`, the non-blocking mode of the returned stream is cleared.
+    ///
+    /// Note that if there are clones of this stream, the connection remains registered
+    /// to the poller until all of them are dropped.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate fibers;
+    /// # extern crate futures;
+    /// use std::io::{Read, Write};
+    /// use fibers::{Executor, InPlaceExecutor, Spawn};
+    /// use fibers::net::{TcpListener, TcpStream};
+    /// use futures::{Future, Stream};
+    ///
+    /// let mut executor = InPlaceExecutor::new().unwrap();
+    /// let bind = executor.spawn_monitor(TcpListener::bind("127.0.0.1:0".parse().unwrap()));
+    /// let listener = executor.run_fiber(bind).unwrap().unwrap();
+    /// let addr = listener.local_addr().unwrap();
+    /// let accept = executor.spawn_monitor(listener.incoming().into_future()
+    ///     .map_err(|(e, _)| e)
+    ///     .and_then(|(client, _)| client.unwrap().0));
+    /// let connect = executor.spawn_monitor(TcpStream::connect(addr));
+    /// let server = executor.run_fiber(accept).unwrap().unwrap();
+    /// let client = executor.run_fiber(connect).unwrap().unwrap();
+    ///
+    /// let mut server = server.into_std(false).unwrap();
+    /// let mut client = client.into_std(false).unwrap();
+    /// client.write_all(b"foo").unwrap();
+    /// let mut buf = [0; 3];
+    /// server.read_exact(&mut buf).unwrap();
+    /// assert_eq!(&buf, b"foo");
+    /// ```
+    pub fn into_std(self, nonblocking: bool) -> io::Result<std::net::TcpStream> {
+        let stream = into_std_stream(self.handle.inner().try_clone()?);
+        stream.set_nonblocking(nonblocking)?;
+        Ok(stream)
+    }
+
+    /// Detaches this stream from the poller which it is registered to,
+    /// so that the connection can be handed off to another executor.
+    ///
+    /// The returned `DetachedTcpStream` can be moved to a fiber of any executor
+    /// (in the same process), and attached to the poller of the executor
+    /// by calling `DetachedTcpStream::attach` method.
+    ///
+    /// Note that if there are clones of this stream, the connection remains registered
+    /// to the source poller until all of them are dropped.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate fibers;
+    /// # extern crate futures;
+    /// use std::io::{Read, Write};
+    /// use fibers::{Executor, InPlaceExecutor, Spawn};
+    /// use fibers::net::{TcpListener, TcpStream};
+    /// use futures::{Future, Stream};
+    ///
+    /// // The executor which accepts connections.
+    /// let mut acceptor = InPlaceExecutor::new().unwrap();
+    /// let bind = acceptor.spawn_monitor(TcpListener::bind("127.0.0.1:0".parse().unwrap()));
+    /// let listener = acceptor.run_fiber(bind).unwrap().unwrap();
+    /// let addr = listener.local_addr().unwrap();
+    /// let accept = acceptor.spawn_monitor(listener.incoming().into_future()
+    ///     .map_err(|(e, _)| e)
+    ///     .and_then(|(client, _)| client.unwrap().0)
+    ///     .and_then(|stream| stream.detach()));
+    /// let mut client = std::net::TcpStream::connect(addr).unwrap();
+    /// let detached = acceptor.run_fiber(accept).unwrap().unwrap();
+    ///
+    /// // The executor which handles the connection.
+    /// let mut worker = InPlaceExecutor::new().unwrap();
+    /// let attach = worker.spawn_monitor(detached.attach());
+    /// let mut stream = worker.run_fiber(attach).unwrap().unwrap();
+    ///
+    /// client.write_all(b"foo").unwrap();
+    /// let read = worker.spawn_monitor(futures::future::poll_fn(move || {
+    ///     let mut buf = [0; 3];
+    ///     match stream.read(&mut buf) {
+    ///         Ok(n) => Ok(futures::Async::Ready(buf[..n].to_vec())),
+    ///         Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+    ///             Ok(futures::Async::NotReady)
+    ///         }
+    ///         Err(e) => Err(e),
+    ///     }
+    /// }));
+    /// assert_eq!(worker.run_fiber(read).unwrap().unwrap(), b"foo");
+    /// ```
+    pub fn detach(self) -> io::Result<DetachedTcpStream> {
+        let stream = self.handle.inner().try_clone()?;
+        Ok(DetachedTcpStream {
+            stream,
+            pending_output: Vec::new(),
+        })
     }
 
     fn monitor(&mut self, interest: Interest) -> &mut Option<Monitor<(), io::Error>> {
@@ -331,9 +1226,7 @@ impl TcpStream {
         if self.monitor(interest).is_none() {
             *self.monitor(interest) = Some(self.handle.monitor(interest));
             if let Err(e) = self.monitor(interest).poll() {
-                return Err(e.unwrap_or_else(|| {
-                    io::Error::new(io::ErrorKind::Other, "Monitor channel disconnected")
-                }));
+                return Err(e.unwrap_or_else(|| io::Error::other("Monitor channel disconnected")));
             }
             Ok(true)
         } else {
@@ -344,17 +1237,25 @@ impl TcpStream {
     where
         F: FnMut(&mut MioTcpStream) -> io::Result<T>,
     {
+        let mut woken = false;
         loop {
             if let Some(mut monitor) = self.monitor(interest).take() {
                 if let Async::NotReady = monitor.poll().map_err(into_io_error)? {
                     *self.monitor(interest) = Some(monitor);
                     return Err(mio::would_block());
                 }
+                woken = true;
             } else {
-                let result = f(&mut *self.handle.inner());
+                let result = f(&mut self.handle.inner());
+                let would_block =
+                    matches!(result, Err(ref e) if e.kind() == io::ErrorKind::WouldBlock);
+                if woken {
+                    self.handle.record_wakeup(!would_block);
+                    woken = false;
+                }
                 match result {
                     Err(e) => {
-                        if e.kind() == io::ErrorKind::WouldBlock {
+                        if would_block {
                             *self.monitor(interest) = Some(self.handle.monitor(interest));
                         } else {
                             return Err(e);
@@ -366,6 +1267,113 @@ impl TcpStream {
         }
     }
 }
+/// Spawns a fiber to handle `stream` when the first bytes arrive on it.
+///
+/// Until then, no fiber is spawned for the stream: only a small callback is held by the poller.
+/// So servers with huge numbers of idle inbound connections
+/// don't pay the memory cost of a suspended fiber per connection.
+///
+/// The fiber `f(stream)` is spawned by `spawner` when the stream becomes readable,
+/// which includes the cases where the peer has closed the connection or an error has occurred.
+/// Note that the stream is owned by the poller until then.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate fibers;
+/// # extern crate futures;
+/// use std::io::{Read, Write};
+/// use fibers::{Executor, InPlaceExecutor, Spawn};
+/// use fibers::net::{self, TcpListener, TcpStream};
+/// use fibers::sync::oneshot;
+/// use futures::{Future, Stream};
+///
+/// let mut executor = InPlaceExecutor::new().unwrap();
+/// let bind = executor.spawn_monitor(TcpListener::bind("127.0.0.1:0".parse().unwrap()));
+/// let listener = executor.run_fiber(bind).unwrap().unwrap();
+/// let addr = listener.local_addr().unwrap();
+/// let accept = executor.spawn_monitor(listener.incoming().into_future()
+///     .map_err(|(e, _)| e)
+///     .and_then(|(client, _)| client.unwrap().0));
+/// let connect = executor.spawn_monitor(TcpStream::connect(addr));
+/// let server = executor.run_fiber(accept).unwrap().unwrap();
+/// let mut client = executor.run_fiber(connect).unwrap().unwrap();
+///
+/// let (tx, rx) = oneshot::channel();
+/// net::on_readable(executor.handle(), server, move |mut stream| {
+///     let mut buf = [0; 3];
+///     stream.read_exact(&mut buf).unwrap();
+///     let _ = tx.send(buf);
+///     Ok(())
+/// });
+///
+/// client.write_all(b"foo").unwrap();
+/// assert_eq!(executor.run_future(rx).unwrap(), Ok(*b"foo"));
+/// ```
+pub fn on_readable<S, F, T>(spawner: S, stream: TcpStream, f: F)
+where
+    S: Spawn + Send + 'static,
+    F: FnOnce(TcpStream) -> T + Send + 'static,
+    T: IntoFuture<Item = (), Error = ()> + Send + 'static,
+    T::Future: Send,
+{
+    let handle = Arc::clone(&stream.handle);
+    handle.on_ready(Interest::Read, move || {
+        spawner.spawn_fn(move || f(stream));
+    });
+}
+
+/// A future which flushes the written data of a `TcpStream` and then shuts down the write side.
+///
+/// This is created by calling `TcpStream::flush_and_shutdown` or
+/// `CoalescingWriter::flush_and_shutdown` method.
+#[derive(Debug)]
+pub struct FlushAndShutdown {
+    stream: Option<TcpStream>,
+    buf: Vec<u8>,
+    offset: usize,
+}
+impl FlushAndShutdown {
+    pub(super) fn new(stream: TcpStream, buf: Vec<u8>) -> Self {
+        FlushAndShutdown {
+            stream: Some(stream),
+            buf,
+            offset: 0,
+        }
+    }
+}
+impl Future for FlushAndShutdown {
+    type Item = TcpStream;
+    type Error = io::Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        {
+            let stream = self
+                .stream
+                .as_mut()
+                .expect("Cannot poll FlushAndShutdown twice");
+            while self.offset < self.buf.len() {
+                match stream.write(&self.buf[self.offset..]) {
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        return Ok(Async::NotReady);
+                    }
+                    Err(e) => return Err(e),
+                    Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                    Ok(n) => self.offset += n,
+                }
+            }
+            match stream.flush() {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(Async::NotReady);
+                }
+                Err(e) => return Err(e),
+                Ok(()) => {}
+            }
+            stream.with_inner(|inner| inner.shutdown(Shutdown::Write))?;
+        }
+        Ok(Async::Ready(self.stream.take().expect("Never fails")))
+    }
+}
+
 impl io::Read for TcpStream {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         self.operate(Interest::Read, |inner| inner.read(buf))
@@ -425,9 +1433,8 @@ impl Future for ConnectInner {
         match mem::replace(self, ConnectInner::Polled) {
             ConnectInner::Connect(addr) => {
                 let stream = MioTcpStream::connect(&addr)?;
-                let register = assert_some!(fiber::with_current_context(|mut c| c
-                    .poller()
-                    .register(stream),));
+                let register =
+                    assert_some!(fiber::with_current_context(|c| register_socket(c, stream)));
                 *self = ConnectInner::Registering(register);
                 self.poll()
             }
@@ -463,3 +1470,102 @@ impl Future for ConnectInner {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn classify_accept_error_works() {
+        let e = io::Error::from(io::ErrorKind::ConnectionAborted);
+        assert_eq!(classify_accept_error(&e), AcceptErrorClass::ConnectionLevel);
+
+        let e = io::Error::from(io::ErrorKind::InvalidInput);
+        assert_eq!(classify_accept_error(&e), AcceptErrorClass::Fatal);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn classify_fd_exhaustion_works() {
+        let e = io::Error::from_raw_os_error(libc::EMFILE);
+        assert_eq!(
+            classify_accept_error(&e),
+            AcceptErrorClass::ResourceExhausted
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn emfile_shedding_waits_for_connections_if_backlog_is_empty() {
+        use futures::Stream;
+        use std::os::unix::io::AsRawFd;
+        use std::process::Command;
+        use std::sync::mpsc as std_mpsc;
+        use std::thread;
+
+        use crate::executor::{Executor, InPlaceExecutor};
+        use crate::net::TcpListener;
+
+        // NOTE: `RLIMIT_NOFILE` is process-wide, so the test is executed in a child process.
+        const CHILD_ENV: &str = "FIBERS_TEST_EMFILE_SHEDDING";
+        if std::env::var_os(CHILD_ENV).is_none() {
+            let status = Command::new(std::env::current_exe().unwrap())
+                .args([
+                    "--exact",
+                    "net::tcp::test::emfile_shedding_waits_for_connections_if_backlog_is_empty",
+                    "--test-threads=1",
+                ])
+                .env(CHILD_ENV, "1")
+                .status()
+                .unwrap();
+            assert!(status.success());
+            return;
+        }
+
+        let (done_tx, done_rx) = std_mpsc::channel();
+        thread::spawn(move || {
+            let mut executor = InPlaceExecutor::new().unwrap();
+            let bind = executor.spawn_monitor(TcpListener::bind("127.0.0.1:0".parse().unwrap()));
+            let listener = executor.run_fiber(bind).unwrap().unwrap();
+            let addr = listener.local_addr().unwrap();
+
+            let mut incoming = listener.incoming();
+            incoming.set_error_policy(AcceptErrorPolicy::RetryAfter(Duration::from_secs(60)));
+            incoming.enable_emfile_shedding().unwrap();
+            let shed_counter = incoming.shed_counter();
+            let errors = Arc::new(AtomicUsize::new(0));
+            let errors_count = Arc::clone(&errors);
+            executor.spawn(incoming.errors().for_each(move |_| {
+                errors_count.fetch_add(1, atomic::Ordering::SeqCst);
+                Ok(())
+            }));
+            executor.spawn(incoming.for_each(|_| Ok(())).map_err(|e| panic!("{}", e)));
+            executor.run_once().unwrap();
+
+            let _client = std::net::TcpStream::connect(addr).unwrap();
+
+            // Makes the process run out of file descriptors.
+            let lowest_free_fd = File::open("/dev/null").unwrap().as_raw_fd();
+            let mut limit = libc::rlimit {
+                rlim_cur: 0,
+                rlim_max: 0,
+            };
+            assert_eq!(
+                unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) },
+                0
+            );
+            limit.rlim_cur = lowest_free_fd as libc::rlim_t;
+            assert_eq!(unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) }, 0);
+
+            for _ in 0..10 {
+                executor.run_once().unwrap();
+            }
+            assert_eq!(shed_counter.get(), 1);
+            assert_eq!(errors.load(atomic::Ordering::SeqCst), 1);
+            done_tx.send(()).unwrap();
+        });
+        done_rx
+            .recv_timeout(Duration::from_secs(10))
+            .expect("The accepting fiber does not yield");
+    }
+}
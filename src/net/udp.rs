@@ -10,6 +10,7 @@ use std::sync::Arc;
 
 use super::{into_io_error, Bind};
 use crate::io::poll::{EventedHandle, Interest};
+use crate::metrics::WakeStats;
 use crate::sync::oneshot::Monitor;
 
 /// A User Datagram Protocol socket.
@@ -98,12 +99,23 @@ impl UdpSocket {
         self.handle.inner().take_error()
     }
 
+    /// Returns the statistics of the wakeups of the fibers waiting for this socket.
+    ///
+    /// See `fibers::metrics::WakeStats` for more details.
+    pub fn wake_stats(&self) -> WakeStats {
+        self.handle.wake_stats()
+    }
+
     /// Calls `f` with the reference to the inner socket.
     pub fn with_inner<F, T>(&self, f: F) -> T
     where
         F: FnOnce(&MioUdpSocket) -> T,
     {
-        f(&*self.handle.inner())
+        f(&self.handle.inner())
+    }
+
+    pub(super) fn monitor(&self, interest: Interest) -> Monitor<(), io::Error> {
+        self.handle.monitor(interest)
     }
 }
 impl fmt::Debug for UdpSocket {
@@ -150,6 +162,7 @@ impl<B: AsRef<[u8]>> Future for SendTo<B> {
     type Error = (UdpSocket, B, io::Error);
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         let mut state = self.0.take().expect("Cannot poll SendTo twice");
+        let mut woken = false;
         loop {
             if let Some(mut monitor) = state.monitor.take() {
                 match monitor.poll() {
@@ -159,7 +172,7 @@ impl<B: AsRef<[u8]>> Future for SendTo<B> {
                         self.0 = Some(state);
                         return Ok(Async::NotReady);
                     }
-                    Ok(Async::Ready(())) => {}
+                    Ok(Async::Ready(())) => woken = true,
                 }
             } else {
                 let result = state
@@ -167,9 +180,15 @@ impl<B: AsRef<[u8]>> Future for SendTo<B> {
                     .handle
                     .inner()
                     .send_to(state.buf.as_ref(), &state.target);
+                let would_block =
+                    matches!(result, Err(ref e) if e.kind() == io::ErrorKind::WouldBlock);
+                if woken {
+                    state.socket.handle.record_wakeup(!would_block);
+                    woken = false;
+                }
                 match result {
                     Err(e) => {
-                        if e.kind() == io::ErrorKind::WouldBlock {
+                        if would_block {
                             state.monitor = Some(state.socket.handle.monitor(Interest::Write));
                         } else {
                             return Err((state.socket, state.buf, e));
@@ -205,6 +224,7 @@ impl<B: AsMut<[u8]>> Future for RecvFrom<B> {
     type Error = (UdpSocket, B, io::Error);
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         let mut state = self.0.take().expect("Cannot poll RecvFrom twice");
+        let mut woken = false;
         loop {
             if let Some(mut monitor) = state.monitor.take() {
                 match monitor.poll() {
@@ -214,15 +234,21 @@ impl<B: AsMut<[u8]>> Future for RecvFrom<B> {
                         self.0 = Some(state);
                         return Ok(Async::NotReady);
                     }
-                    Ok(Async::Ready(())) => {}
+                    Ok(Async::Ready(())) => woken = true,
                 }
             } else {
                 let mut buf = state.buf;
                 let result = state.socket.handle.inner().recv_from(buf.as_mut());
                 state.buf = buf;
+                let would_block =
+                    matches!(result, Err(ref e) if e.kind() == io::ErrorKind::WouldBlock);
+                if woken {
+                    state.socket.handle.record_wakeup(!would_block);
+                    woken = false;
+                }
                 match result {
                     Err(e) => {
-                        if e.kind() == io::ErrorKind::WouldBlock {
+                        if would_block {
                             state.monitor = Some(state.socket.handle.monitor(Interest::Read));
                         } else {
                             return Err((state.socket, state.buf, e));
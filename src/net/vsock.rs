@@ -0,0 +1,639 @@
+// Copyright (c) 2016 DWANGO Co., Ltd. All Rights Reserved.
+// See the LICENSE file at the top-level directory of this distribution.
+
+//! VM sockets (`AF_VSOCK`).
+//!
+//! VM sockets enable the communication between virtual machines (or enclaves) and their host
+//! without any network stack.
+//! A peer is identified by a context identifier (CID) and a port number (see `VsockAddr`).
+//!
+//! This module is only available on Linux.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! # extern crate fibers;
+//! # extern crate futures;
+//! use fibers::{Executor, InPlaceExecutor, Spawn};
+//! use fibers::net::vsock::{VsockAddr, VsockListener, VsockStream};
+//! use futures::{Future, Stream};
+//!
+//! let mut executor = InPlaceExecutor::new().unwrap();
+//! let addr = VsockAddr::new(VsockAddr::CID_LOCAL, 5000);
+//!
+//! // Spawns a server
+//! executor.spawn(VsockListener::bind(VsockAddr::new(VsockAddr::CID_ANY, 5000))
+//!     .and_then(|listener| {
+//!         listener.incoming().for_each(|(_client, addr)| {
+//!             println!("# Accepted: {}", addr);
+//!             Ok(())
+//!         })
+//!     })
+//!     .map_err(|e| panic!("{:?}", e)));
+//!
+//! // Connects to the server
+//! let monitor = executor.spawn_monitor(VsockStream::connect(addr));
+//! let stream = executor.run_fiber(monitor).unwrap().unwrap();
+//! assert_eq!(stream.peer_addr().unwrap(), addr);
+//! ```
+use futures::{Async, Future, Poll, Stream};
+use mio::unix::EventedFd;
+use mio::{Evented, PollOpt, Ready, Token};
+use std::fmt;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Arc;
+
+use super::into_io_error;
+use crate::fiber::{self, Context};
+use crate::io::poll::{EventedHandle, Interest, Register};
+use crate::metrics::WakeStats;
+use crate::sync::oneshot::Monitor;
+
+/// An address of a VM socket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct VsockAddr {
+    cid: u32,
+    port: u32,
+}
+impl VsockAddr {
+    /// The wildcard CID which is used to bind to any CID of the local machine.
+    pub const CID_ANY: u32 = libc::VMADDR_CID_ANY;
+
+    /// The CID of the hypervisor.
+    pub const CID_HYPERVISOR: u32 = libc::VMADDR_CID_HYPERVISOR;
+
+    /// The CID of the local machine (loopback).
+    pub const CID_LOCAL: u32 = libc::VMADDR_CID_LOCAL;
+
+    /// The CID of the host.
+    pub const CID_HOST: u32 = libc::VMADDR_CID_HOST;
+
+    /// The wildcard port which is used to bind to an arbitrary free port.
+    pub const PORT_ANY: u32 = libc::VMADDR_PORT_ANY;
+
+    /// Makes a new `VsockAddr` instance.
+    pub fn new(cid: u32, port: u32) -> Self {
+        VsockAddr { cid, port }
+    }
+
+    /// Returns the context identifier of this address.
+    pub fn cid(&self) -> u32 {
+        self.cid
+    }
+
+    /// Returns the port number of this address.
+    pub fn port(&self) -> u32 {
+        self.port
+    }
+
+    fn to_raw(self) -> libc::sockaddr_vm {
+        let mut raw: libc::sockaddr_vm = unsafe { mem::zeroed() };
+        raw.svm_family = libc::AF_VSOCK as libc::sa_family_t;
+        raw.svm_cid = self.cid;
+        raw.svm_port = self.port;
+        raw
+    }
+
+    fn from_raw(raw: &libc::sockaddr_vm) -> Self {
+        VsockAddr::new(raw.svm_cid, raw.svm_port)
+    }
+}
+impl fmt::Display for VsockAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "vsock:{}:{}", self.cid, self.port)
+    }
+}
+
+/// A structure representing a VM socket server.
+///
+/// See the [module documentation](index.html) for an example.
+pub struct VsockListener {
+    handle: Arc<EventedHandle<VsockSocket>>,
+    monitor: Option<Monitor<(), io::Error>>,
+}
+impl VsockListener {
+    /// Makes a future to create a new `VsockListener` which will be bound to the specified address.
+    pub fn bind(addr: VsockAddr) -> VsockListenerBind {
+        VsockListenerBind(Open::Start(Box::new(move || {
+            let socket = VsockSocket::new()?;
+            socket.bind(addr)?;
+            socket.listen(1024)?;
+            Ok(socket)
+        })))
+    }
+
+    /// Makes a stream of the connections which will be accepted by this listener.
+    pub fn incoming(self) -> Incoming {
+        Incoming(self)
+    }
+
+    /// Returns the local address of this listener.
+    pub fn local_addr(&self) -> io::Result<VsockAddr> {
+        self.handle.inner().local_addr()
+    }
+
+    /// Returns the statistics of the wakeups of the fibers waiting for this listener.
+    ///
+    /// See `fibers::metrics::WakeStats` for more details.
+    pub fn wake_stats(&self) -> WakeStats {
+        self.handle.wake_stats()
+    }
+}
+impl fmt::Debug for VsockListener {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "VsockListener {{ ")?;
+        if let Ok(addr) = self.local_addr() {
+            write!(f, "local_addr:{}, ", addr)?;
+        }
+        write!(f, ".. }}")?;
+        Ok(())
+    }
+}
+
+/// A future which will create a new `VsockListener`.
+///
+/// This is created by calling `VsockListener::bind` function.
+/// It is permitted to move the future across fibers.
+///
+/// # Panics
+///
+/// If the future is polled on the outside of a fiber, it may crash.
+#[derive(Debug)]
+pub struct VsockListenerBind(Open);
+impl Future for VsockListenerBind {
+    type Item = VsockListener;
+    type Error = io::Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        Ok(self.0.poll()?.map(|handle| VsockListener {
+            handle,
+            monitor: None,
+        }))
+    }
+}
+
+/// A stream which produces the connections accepted by a `VsockListener`.
+///
+/// This is created by calling `VsockListener::incoming` method.
+/// It is permitted to move the stream across fibers.
+///
+/// # Panics
+///
+/// If the stream is polled on the outside of a fiber, it may crash.
+#[derive(Debug)]
+pub struct Incoming(VsockListener);
+impl Stream for Incoming {
+    type Item = (Connected, VsockAddr);
+    type Error = io::Error;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let mut woken = false;
+        loop {
+            if let Some(mut monitor) = self.0.monitor.take() {
+                if let Async::NotReady = monitor.poll().map_err(into_io_error)? {
+                    self.0.monitor = Some(monitor);
+                    return Ok(Async::NotReady);
+                }
+                woken = true;
+            } else {
+                let result = self.0.handle.inner().accept();
+                if woken {
+                    let would_block =
+                        matches!(result, Err(ref e) if e.kind() == io::ErrorKind::WouldBlock);
+                    self.0.handle.record_wakeup(!would_block);
+                    woken = false;
+                }
+                match result {
+                    Ok((socket, addr)) => {
+                        let register = |mut c: Context| c.poller().register(socket);
+                        let future = assert_some!(fiber::with_current_context(register));
+                        let stream = Connected(Open::Registering(future));
+                        return Ok(Async::Ready(Some((stream, addr))));
+                    }
+                    Err(e) => {
+                        if e.kind() == io::ErrorKind::WouldBlock {
+                            self.0.monitor = Some(self.0.handle.monitor(Interest::Read));
+                        } else {
+                            return Err(e);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// A future which represents a `VsockStream` connected to a `VsockListener`.
+///
+/// This is produced by `Incoming` stream.
+/// It is permitted to move the future across fibers.
+///
+/// # Panics
+///
+/// If the future is polled on the outside of a fiber, it may crash.
+#[derive(Debug)]
+pub struct Connected(Open);
+impl Future for Connected {
+    type Item = VsockStream;
+    type Error = io::Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        Ok(self.0.poll()?.map(VsockStream::new))
+    }
+}
+
+/// A VM socket stream between a local and a remote socket.
+///
+/// Like `TcpStream`, a cloned stream shares the same underlying socket.
+///
+/// See the [module documentation](index.html) for an example.
+pub struct VsockStream {
+    handle: Arc<EventedHandle<VsockSocket>>,
+    read_monitor: Option<Monitor<(), io::Error>>,
+    write_monitor: Option<Monitor<(), io::Error>>,
+}
+impl Clone for VsockStream {
+    fn clone(&self) -> Self {
+        VsockStream::new(self.handle.clone())
+    }
+}
+impl VsockStream {
+    fn new(handle: Arc<EventedHandle<VsockSocket>>) -> Self {
+        VsockStream {
+            handle,
+            read_monitor: None,
+            write_monitor: None,
+        }
+    }
+
+    /// Makes a future to open a connection to a remote VM socket.
+    pub fn connect(addr: VsockAddr) -> Connect {
+        let start = Box::new(move || {
+            let socket = VsockSocket::new()?;
+            socket.connect(addr)?;
+            Ok(socket)
+        });
+        Connect {
+            open: Open::Start(start),
+            stream: None,
+        }
+    }
+
+    /// Returns the local address of this stream.
+    pub fn local_addr(&self) -> io::Result<VsockAddr> {
+        self.handle.inner().local_addr()
+    }
+
+    /// Returns the address of the remote peer of this stream.
+    pub fn peer_addr(&self) -> io::Result<VsockAddr> {
+        self.handle.inner().peer_addr()
+    }
+
+    /// Get the value of the `SO_ERROR` option on this socket.
+    ///
+    /// This will retrieve the stored error in the underlying socket,
+    /// clearing the field in the process.
+    pub fn take_error(&self) -> io::Result<Option<io::Error>> {
+        self.handle.inner().take_error()
+    }
+
+    /// Shuts down the read, write, or both halves of this connection.
+    pub fn shutdown(&self, how: std::net::Shutdown) -> io::Result<()> {
+        self.handle.inner().shutdown(how)
+    }
+
+    /// Returns the statistics of the wakeups of the fibers waiting for this stream.
+    ///
+    /// See `fibers::metrics::WakeStats` for more details.
+    pub fn wake_stats(&self) -> WakeStats {
+        self.handle.wake_stats()
+    }
+
+    fn monitor(&mut self, interest: Interest) -> &mut Option<Monitor<(), io::Error>> {
+        if interest == Interest::Read {
+            &mut self.read_monitor
+        } else {
+            &mut self.write_monitor
+        }
+    }
+    fn operate<F, T>(&mut self, interest: Interest, mut f: F) -> io::Result<T>
+    where
+        F: FnMut(&mut VsockSocket) -> io::Result<T>,
+    {
+        let mut woken = false;
+        loop {
+            if let Some(mut monitor) = self.monitor(interest).take() {
+                if let Async::NotReady = monitor.poll().map_err(into_io_error)? {
+                    *self.monitor(interest) = Some(monitor);
+                    return Err(mio::would_block());
+                }
+                woken = true;
+            } else {
+                let result = f(&mut self.handle.inner());
+                let would_block =
+                    matches!(result, Err(ref e) if e.kind() == io::ErrorKind::WouldBlock);
+                if woken {
+                    self.handle.record_wakeup(!would_block);
+                    woken = false;
+                }
+                match result {
+                    Err(e) => {
+                        if would_block {
+                            *self.monitor(interest) = Some(self.handle.monitor(interest));
+                        } else {
+                            return Err(e);
+                        }
+                    }
+                    Ok(v) => return Ok(v),
+                }
+            }
+        }
+    }
+}
+impl io::Read for VsockStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.operate(Interest::Read, |inner| inner.read(buf))
+    }
+}
+impl io::Write for VsockStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.operate(Interest::Write, |inner| inner.write(buf))
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+impl fmt::Debug for VsockStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "VsockStream {{ ")?;
+        if let Ok(addr) = self.local_addr() {
+            write!(f, "local_addr:{}, ", addr)?;
+        }
+        if let Ok(addr) = self.peer_addr() {
+            write!(f, "peer_addr:{}, ", addr)?;
+        }
+        write!(f, ".. }}")?;
+        Ok(())
+    }
+}
+
+/// A future which will open a connection to a remote VM socket.
+///
+/// This is created by calling `VsockStream::connect` function.
+/// It is permitted to move the future across fibers.
+///
+/// # Panics
+///
+/// If the future is polled on the outside of a fiber, it may crash.
+#[derive(Debug)]
+pub struct Connect {
+    open: Open,
+    stream: Option<VsockStream>,
+}
+impl Future for Connect {
+    type Item = VsockStream;
+    type Error = io::Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            if let Some(mut stream) = self.stream.take() {
+                if let Some(mut monitor) = stream.write_monitor.take() {
+                    if let Async::NotReady = monitor.poll().map_err(into_io_error)? {
+                        stream.write_monitor = Some(monitor);
+                        self.stream = Some(stream);
+                        return Ok(Async::NotReady);
+                    }
+                }
+                if let Some(e) = stream.take_error()? {
+                    return Err(e);
+                }
+                match stream.peer_addr() {
+                    Ok(_) => return Ok(Async::Ready(stream)),
+                    Err(ref e) if e.kind() == io::ErrorKind::NotConnected => {
+                        stream.write_monitor = Some(stream.handle.monitor(Interest::Write));
+                        self.stream = Some(stream);
+                    }
+                    Err(e) => return Err(e),
+                }
+            } else if let Async::Ready(handle) = self.open.poll()? {
+                self.stream = Some(VsockStream::new(handle));
+            } else {
+                return Ok(Async::NotReady);
+            }
+        }
+    }
+}
+
+/// The state of a future which creates a socket and registers it to the poller of the current fiber.
+enum Open {
+    Start(Box<dyn FnOnce() -> io::Result<VsockSocket> + Send>),
+    Registering(Register<VsockSocket>),
+    Polled,
+}
+impl Future for Open {
+    type Item = Arc<EventedHandle<VsockSocket>>;
+    type Error = io::Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match mem::replace(self, Open::Polled) {
+            Open::Start(start) => {
+                let socket = start()?;
+                let register = |mut c: Context| c.poller().register(socket);
+                *self = Open::Registering(assert_some!(fiber::with_current_context(register)));
+                self.poll()
+            }
+            Open::Registering(mut future) => {
+                if let Async::Ready(handle) = future.poll().map_err(into_io_error)? {
+                    Ok(Async::Ready(handle))
+                } else {
+                    *self = Open::Registering(future);
+                    Ok(Async::NotReady)
+                }
+            }
+            Open::Polled => panic!("Cannot poll a VM socket future twice"),
+        }
+    }
+}
+impl fmt::Debug for Open {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Open::Start(_) => write!(f, "Open::Start(_)"),
+            Open::Registering(_) => write!(f, "Open::Registering(_)"),
+            Open::Polled => write!(f, "Open::Polled"),
+        }
+    }
+}
+
+/// A non-blocking `AF_VSOCK` stream socket.
+#[derive(Debug)]
+struct VsockSocket(RawFd);
+impl VsockSocket {
+    fn new() -> io::Result<Self> {
+        let flags = libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC;
+        let fd = cvt(unsafe { libc::socket(libc::AF_VSOCK, flags, 0) })?;
+        Ok(VsockSocket(fd))
+    }
+
+    fn bind(&self, addr: VsockAddr) -> io::Result<()> {
+        let raw = addr.to_raw();
+        cvt(unsafe {
+            libc::bind(
+                self.0,
+                &raw as *const _ as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t,
+            )
+        })?;
+        Ok(())
+    }
+
+    fn listen(&self, backlog: i32) -> io::Result<()> {
+        cvt(unsafe { libc::listen(self.0, backlog) })?;
+        Ok(())
+    }
+
+    fn connect(&self, addr: VsockAddr) -> io::Result<()> {
+        let raw = addr.to_raw();
+        let result = cvt(unsafe {
+            libc::connect(
+                self.0,
+                &raw as *const _ as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t,
+            )
+        });
+        match result {
+            Err(ref e) if e.raw_os_error() == Some(libc::EINPROGRESS) => Ok(()),
+            Err(e) => Err(e),
+            Ok(_) => Ok(()),
+        }
+    }
+
+    fn accept(&self) -> io::Result<(VsockSocket, VsockAddr)> {
+        let mut raw: libc::sockaddr_vm = unsafe { mem::zeroed() };
+        let mut len = mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t;
+        let fd = cvt(unsafe {
+            libc::accept4(
+                self.0,
+                &mut raw as *mut _ as *mut libc::sockaddr,
+                &mut len,
+                libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            )
+        })?;
+        Ok((VsockSocket(fd), VsockAddr::from_raw(&raw)))
+    }
+
+    fn local_addr(&self) -> io::Result<VsockAddr> {
+        self.addr(libc::getsockname)
+    }
+
+    fn peer_addr(&self) -> io::Result<VsockAddr> {
+        self.addr(libc::getpeername)
+    }
+
+    fn addr(
+        &self,
+        f: unsafe extern "C" fn(
+            libc::c_int,
+            *mut libc::sockaddr,
+            *mut libc::socklen_t,
+        ) -> libc::c_int,
+    ) -> io::Result<VsockAddr> {
+        let mut raw: libc::sockaddr_vm = unsafe { mem::zeroed() };
+        let mut len = mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t;
+        cvt(unsafe { f(self.0, &mut raw as *mut _ as *mut libc::sockaddr, &mut len) })?;
+        Ok(VsockAddr::from_raw(&raw))
+    }
+
+    fn take_error(&self) -> io::Result<Option<io::Error>> {
+        let mut error: libc::c_int = 0;
+        let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        cvt(unsafe {
+            libc::getsockopt(
+                self.0,
+                libc::SOL_SOCKET,
+                libc::SO_ERROR,
+                &mut error as *mut _ as *mut libc::c_void,
+                &mut len,
+            )
+        })?;
+        if error == 0 {
+            Ok(None)
+        } else {
+            Ok(Some(io::Error::from_raw_os_error(error)))
+        }
+    }
+
+    fn shutdown(&self, how: std::net::Shutdown) -> io::Result<()> {
+        let how = match how {
+            std::net::Shutdown::Read => libc::SHUT_RD,
+            std::net::Shutdown::Write => libc::SHUT_WR,
+            std::net::Shutdown::Both => libc::SHUT_RDWR,
+        };
+        cvt(unsafe { libc::shutdown(self.0, how) })?;
+        Ok(())
+    }
+
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = unsafe { libc::read(self.0, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+        cvt_size(n)
+    }
+
+    fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        let n = unsafe {
+            libc::send(
+                self.0,
+                buf.as_ptr() as *const libc::c_void,
+                buf.len(),
+                libc::MSG_NOSIGNAL,
+            )
+        };
+        cvt_size(n)
+    }
+}
+impl AsRawFd for VsockSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+impl Evented for VsockSocket {
+    fn register(
+        &self,
+        poll: &mio::Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.0).register(poll, token, interest, opts)
+    }
+    fn reregister(
+        &self,
+        poll: &mio::Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.0).reregister(poll, token, interest, opts)
+    }
+    fn deregister(&self, poll: &mio::Poll) -> io::Result<()> {
+        EventedFd(&self.0).deregister(poll)
+    }
+}
+impl Drop for VsockSocket {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.0);
+        }
+    }
+}
+
+fn cvt(result: libc::c_int) -> io::Result<libc::c_int> {
+    if result == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+fn cvt_size(result: libc::ssize_t) -> io::Result<usize> {
+    if result == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result as usize)
+    }
+}
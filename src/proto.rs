@@ -0,0 +1,395 @@
+// Copyright (c) 2016 DWANGO Co., Ltd. All Rights Reserved.
+// See the LICENSE file at the top-level directory of this distribution.
+
+//! Helpers to write protocols as I/O-free state machines.
+//!
+//! A protocol is implemented as a synchronous `StateMachine`:
+//! it is fed with `Event`s (received bytes, end of the stream and timer expirations)
+//! and tells what to do next via `Actions` (bytes to send and the timer to set).
+//! Thus, the protocol logic can be unit-tested without any I/O,
+//! and then be run over a stream (e.g., `TcpStream`) by the `Drive` future.
+//!
+//! # Examples
+//!
+//! ```
+//! # extern crate fibers;
+//! # extern crate futures;
+//! use std::io;
+//! use std::time::Duration;
+//! use fibers::{Executor, InPlaceExecutor, Spawn};
+//! use fibers::net::{TcpListener, TcpStream};
+//! use fibers::proto::{self, Actions, Event, StateMachine};
+//! use futures::{Future, Stream};
+//!
+//! // Sends `request` and waits for a reply line (at most one second).
+//! struct LineClient {
+//!     request: &'static [u8],
+//!     buf: Vec<u8>,
+//! }
+//! impl StateMachine for LineClient {
+//!     type Item = Vec<u8>;
+//!     type Error = io::Error;
+//!     fn start(&mut self, actions: &mut Actions) -> io::Result<()> {
+//!         actions.send(self.request);
+//!         actions.set_timer(Duration::from_secs(1));
+//!         Ok(())
+//!     }
+//!     fn handle(&mut self, event: Event, _actions: &mut Actions) -> io::Result<Option<Vec<u8>>> {
+//!         match event {
+//!             Event::Bytes(bytes) => self.buf.extend_from_slice(bytes),
+//!             Event::Eof => return Err(io::ErrorKind::UnexpectedEof.into()),
+//!             Event::Timeout => return Err(io::ErrorKind::TimedOut.into()),
+//!         }
+//!         Ok(self.buf.iter().position(|&b| b == b'\n').map(|i| self.buf[..i].to_vec()))
+//!     }
+//! }
+//!
+//! // Unit test without any I/O
+//! let mut client = LineClient { request: b"hello\n", buf: Vec::new() };
+//! let mut actions = Actions::new();
+//! client.start(&mut actions).unwrap();
+//! assert_eq!(actions.take_output(), b"hello\n");
+//! assert_eq!(client.handle(Event::Bytes(b"wor"), &mut actions).unwrap(), None);
+//! assert_eq!(client.handle(Event::Bytes(b"ld\n"), &mut actions).unwrap(), Some(b"world".to_vec()));
+//! assert!(client.handle(Event::Timeout, &mut actions).is_err());
+//!
+//! // Runs over TCP (the server is also a `LineClient` which sends the reply first)
+//! let mut executor = InPlaceExecutor::new().unwrap();
+//! let bind = executor.spawn_monitor(TcpListener::bind("127.0.0.1:0".parse().unwrap()));
+//! let listener = executor.run_fiber(bind).unwrap().unwrap();
+//! let addr = listener.local_addr().unwrap();
+//! executor.spawn(listener.incoming().into_future()
+//!     .map_err(|(e, _)| e)
+//!     .and_then(|(client, _)| client.expect("Never fails").0)
+//!     .and_then(|stream| proto::drive(LineClient { request: b"world\n", buf: Vec::new() }, stream))
+//!     .map(|_| ())
+//!     .map_err(|e| panic!("{}", e)));
+//!
+//! let client = LineClient { request: b"hello\n", buf: Vec::new() };
+//! let monitor = executor.spawn_monitor(
+//!     TcpStream::connect(addr).and_then(move |stream| proto::drive(client, stream)),
+//! );
+//! let (reply, _stream) = executor.run_fiber(monitor).unwrap().unwrap();
+//! assert_eq!(reply, b"world");
+//! ```
+use futures::{Async, Future, Poll};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::time::Duration;
+
+use crate::io::AdaptiveReadBuf;
+use crate::time::timer::{self, Timeout};
+
+/// A synchronous (I/O-free) state machine of a protocol.
+///
+/// See the [module documentation](index.html) for an example.
+pub trait StateMachine {
+    /// The result of the state machine.
+    type Item;
+
+    /// The error of the state machine.
+    ///
+    /// The I/O errors occurred in `Drive` are converted to this type.
+    type Error: From<io::Error>;
+
+    /// Starts the state machine.
+    ///
+    /// This is called once before any events are handled.
+    /// The default implementation does nothing.
+    fn start(&mut self, actions: &mut Actions) -> Result<(), Self::Error> {
+        let _ = actions;
+        Ok(())
+    }
+
+    /// Handles `event`.
+    ///
+    /// If this returns `Ok(Some(item))`, the state machine finishes with `item`
+    /// after the output in `actions` is written.
+    fn handle(
+        &mut self,
+        event: Event,
+        actions: &mut Actions,
+    ) -> Result<Option<Self::Item>, Self::Error>;
+}
+
+/// An event fed to a `StateMachine`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event<'a> {
+    /// Some bytes have been received.
+    Bytes(&'a [u8]),
+
+    /// The peer has closed the stream.
+    ///
+    /// If the state machine does not finish on this event,
+    /// `Drive` fails with an `UnexpectedEof` error.
+    Eof,
+
+    /// The timer set via `Actions::set_timer` has expired.
+    Timeout,
+}
+
+/// The command on the timer of a state machine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerAction {
+    /// (Re)sets the timer to expire after the duration.
+    Set(Duration),
+
+    /// Cancels the timer.
+    Cancel,
+}
+
+/// The actions requested by a `StateMachine`.
+#[derive(Debug, Default)]
+pub struct Actions {
+    output: Vec<u8>,
+    timer: Option<TimerAction>,
+}
+impl Actions {
+    /// Makes a new `Actions` instance which has no actions.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends `bytes` to the output to be sent to the peer.
+    pub fn send(&mut self, bytes: &[u8]) {
+        self.output.extend_from_slice(bytes);
+    }
+
+    /// Sets the timer to expire after `duration`.
+    ///
+    /// The previously set timer (if any) is replaced.
+    pub fn set_timer(&mut self, duration: Duration) {
+        self.timer = Some(TimerAction::Set(duration));
+    }
+
+    /// Cancels the timer.
+    pub fn cancel_timer(&mut self) {
+        self.timer = Some(TimerAction::Cancel);
+    }
+
+    /// Returns the output which has not been sent yet.
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    /// Takes the output which has not been sent yet.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        mem::take(&mut self.output)
+    }
+
+    /// Takes the last requested command on the timer.
+    pub fn take_timer_action(&mut self) -> Option<TimerAction> {
+        self.timer.take()
+    }
+}
+
+/// Makes a future which runs `machine` over `stream`.
+///
+/// `stream` is expected to be non-blocking one provided by fibers (e.g., `TcpStream`).
+/// The future results in the item of the state machine and the stream.
+pub fn drive<S, T>(machine: S, stream: T) -> Drive<S, T>
+where
+    S: StateMachine,
+    T: Read + Write,
+{
+    Drive {
+        machine,
+        stream: Some(stream),
+        actions: Actions::new(),
+        read_buf: AdaptiveReadBuf::new(),
+        timer: None,
+        started: false,
+        eof: false,
+        item: None,
+    }
+}
+
+/// A future which runs a `StateMachine` over a stream.
+///
+/// This is created by calling `drive` function.
+///
+/// # Panics
+///
+/// If the future is polled on the outside of a fiber, it may crash.
+pub struct Drive<S: StateMachine, T> {
+    machine: S,
+    stream: Option<T>,
+    actions: Actions,
+    read_buf: AdaptiveReadBuf,
+    timer: Option<Timeout>,
+    started: bool,
+    eof: bool,
+    item: Option<S::Item>,
+}
+impl<S: StateMachine, T> Drive<S, T> {
+    /// Returns a reference to the state machine.
+    pub fn machine(&self) -> &S {
+        &self.machine
+    }
+
+    /// Sets the read buffer.
+    ///
+    /// The default value is `AdaptiveReadBuf::new()`,
+    /// so the buffers of idle or low-traffic streams are kept small.
+    /// Use `AdaptiveReadBuf::fixed` to disable the adaptation.
+    pub fn read_buffer(mut self, buf: AdaptiveReadBuf) -> Self {
+        self.read_buf = buf;
+        self
+    }
+
+    fn apply_timer_action(&mut self) {
+        match self.actions.take_timer_action() {
+            Some(TimerAction::Set(duration)) => self.timer = Some(timer::timeout(duration)),
+            Some(TimerAction::Cancel) => self.timer = None,
+            None => {}
+        }
+    }
+    fn handled(
+        &mut self,
+        result: Result<Option<S::Item>, S::Error>,
+        eof: bool,
+    ) -> Result<(), S::Error> {
+        let item = result?;
+        self.apply_timer_action();
+        if item.is_some() {
+            self.item = item;
+        } else if eof {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        Ok(())
+    }
+}
+impl<S: StateMachine, T: Read + Write> Future for Drive<S, T> {
+    type Item = (S::Item, T);
+    type Error = S::Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if !self.started {
+            self.started = true;
+            self.machine.start(&mut self.actions)?;
+            self.apply_timer_action();
+        }
+        loop {
+            let stream = self.stream.as_mut().expect("Cannot poll Drive twice");
+
+            // Output
+            let mut write_blocked = false;
+            while !self.actions.output.is_empty() {
+                match stream.write(&self.actions.output) {
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        write_blocked = true;
+                        break;
+                    }
+                    Err(e) => return Err(e.into()),
+                    Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero).into()),
+                    Ok(n) => {
+                        self.actions.output.drain(..n);
+                    }
+                }
+            }
+            if self.item.is_some() {
+                if write_blocked {
+                    return Ok(Async::NotReady);
+                }
+                let item = self.item.take().expect("Never fails");
+                let stream = self.stream.take().expect("Never fails");
+                return Ok(Async::Ready((item, stream)));
+            }
+
+            // Timer
+            if let Some(mut timer) = self.timer.take() {
+                if let Async::Ready(()) = timer.poll().map_err(io::Error::other)? {
+                    let result = self.machine.handle(Event::Timeout, &mut self.actions);
+                    self.handled(result, false)?;
+                    continue;
+                }
+                self.timer = Some(timer);
+            }
+
+            // Input
+            if !self.eof {
+                match self.read_buf.read_from(stream) {
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(e) => return Err(e.into()),
+                    Ok(bytes) => {
+                        let eof = bytes.is_empty();
+                        let event = if eof { Event::Eof } else { Event::Bytes(bytes) };
+                        let result = self.machine.handle(event, &mut self.actions);
+                        self.eof = eof;
+                        self.handled(result, eof)?;
+                        continue;
+                    }
+                }
+            }
+            return Ok(Async::NotReady);
+        }
+    }
+}
+impl<S: StateMachine, T> fmt::Debug for Drive<S, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Drive {{ pending_output_len: {}, eof: {}, .. }}",
+            self.actions.output.len(),
+            self.eof
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{Executor, InPlaceExecutor, Spawn};
+
+    /// A stream which never becomes readable and accepts all output.
+    struct Silent(Vec<u8>);
+    impl Read for Silent {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+    impl Write for Silent {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Sends a ping at each expiration of the timer.
+    struct Pinger(usize);
+    impl StateMachine for Pinger {
+        type Item = usize;
+        type Error = io::Error;
+        fn start(&mut self, actions: &mut Actions) -> io::Result<()> {
+            actions.set_timer(Duration::from_millis(1));
+            Ok(())
+        }
+        fn handle(&mut self, event: Event, actions: &mut Actions) -> io::Result<Option<usize>> {
+            assert_eq!(event, Event::Timeout);
+            self.0 += 1;
+            actions.send(b"ping");
+            if self.0 == 3 {
+                actions.cancel_timer();
+                Ok(Some(self.0))
+            } else {
+                actions.set_timer(Duration::from_millis(1));
+                Ok(None)
+            }
+        }
+    }
+
+    #[test]
+    fn timers_work() {
+        let mut executor = InPlaceExecutor::new().unwrap();
+        let monitor = executor.spawn_monitor(drive(Pinger(0), Silent(Vec::new())));
+        let (pings, stream) = executor.run_fiber(monitor).unwrap().unwrap();
+        assert_eq!(pings, 3);
+        assert_eq!(stream.0, b"pingpingping");
+    }
+}
@@ -0,0 +1,516 @@
+// Copyright (c) 2016 DWANGO Co., Ltd. All Rights Reserved.
+// See the LICENSE file at the top-level directory of this distribution.
+
+//! The one-call entry point of programs and the information of the runtime.
+//!
+//! # Examples
+//!
+//! ```
+//! # extern crate fibers;
+//! # extern crate futures;
+//! use futures::Future;
+//!
+//! let result = fibers::run(futures::lazy(|| {
+//!     // Spawns other fibers, starts servers, etc.
+//!     Ok::<_, ()>("done")
+//! }));
+//! assert_eq!(result.unwrap(), Ok("done"));
+//! ```
+use futures::{Async, Future, IntoFuture};
+use std::fmt;
+use std::io;
+use std::sync::atomic::{self, AtomicUsize};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::executor::{Executor, ThreadPoolExecutor, ThreadPoolExecutorHandle};
+use crate::fiber::Spawn;
+use crate::sync::oneshot::{Monitor, MonitorError};
+
+static RECEIVED_SIGNAL: AtomicUsize = AtomicUsize::new(0);
+
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Runs `main` as a fiber on a default `ThreadPoolExecutor` and returns its result.
+///
+/// This function does the following:
+///
+/// 1. Builds a `ThreadPoolExecutor` (i.e., `ThreadPoolExecutor::new()`)
+/// 2. Installs the handlers of `SIGINT` and `SIGTERM` (on Unix platforms)
+/// 3. Runs `main` until it completes or one of the signals is received
+/// 4. Shuts down the executor (i.e., stops the scheduler and poller threads
+///    and drops the remaining fibers) and restores the previous signal handlers
+///
+/// If a signal is received before `main` completes,
+/// an error of the kind `io::ErrorKind::Interrupted` is returned.
+///
+/// Note that the signal handlers are process-wide,
+/// so this function should be called at most once at a time.
+pub fn run<F>(main: F) -> io::Result<Result<F::Item, F::Error>>
+where
+    F: Future + Send + 'static,
+    F::Item: Send + 'static,
+    F::Error: Send + 'static,
+{
+    run_with(|_| main)
+}
+
+/// Runs the future made by `f` as a fiber on a default `ThreadPoolExecutor`
+/// and returns its result.
+///
+/// This is the same as `run` function except that `f` is given a `Handle` of the runtime,
+/// which can be used to register shutdown hooks (see `Handle::on_shutdown`).
+///
+/// The shutdown hooks are executed after the main fiber completes (successfully or not)
+/// or one of the signals is received, and before the executor is shut down.
+/// If another signal is received or the shutdown timeout expires (see `Handle::set_shutdown_timeout`)
+/// while the hooks are being executed, the remaining hooks are abandoned.
+/// In any case, the result of the main fiber (or the error of the signal) is returned.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate fibers;
+/// # extern crate futures;
+/// use std::sync::{Arc, Mutex};
+/// use fibers::runtime;
+///
+/// let log = Arc::new(Mutex::new(Vec::new()));
+/// let result = runtime::run_with(|handle| {
+///     for &(priority, name) in &[(20, "persist cache"), (10, "drain connections")] {
+///         let log = Arc::clone(&log);
+///         handle.on_shutdown(priority, move || {
+///             log.lock().unwrap().push(name);
+///             Ok(())
+///         });
+///     }
+///     futures::finished::<_, ()>("done")
+/// });
+/// assert_eq!(result.unwrap(), Ok("done"));
+/// assert_eq!(*log.lock().unwrap(), ["drain connections", "persist cache"]);
+/// ```
+pub fn run_with<F, M>(f: F) -> io::Result<Result<M::Item, M::Error>>
+where
+    F: FnOnce(Handle) -> M,
+    M: Future + Send + 'static,
+    M::Item: Send + 'static,
+    M::Error: Send + 'static,
+{
+    let mut executor = ThreadPoolExecutor::new()?;
+    let _guard = SignalGuard::install();
+    let handle = Handle {
+        executor: executor.handle(),
+        hooks: Arc::default(),
+        shutdown_timeout: Arc::new(Mutex::new(DEFAULT_SHUTDOWN_TIMEOUT)),
+    };
+    let mut monitor = executor.spawn_monitor(f(handle.clone()));
+    let result = loop {
+        let signal = RECEIVED_SIGNAL.swap(0, atomic::Ordering::SeqCst);
+        if signal != 0 {
+            break Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                format!("Received signal {}", signal),
+            ));
+        }
+        match monitor.poll() {
+            Ok(Async::NotReady) => {}
+            Ok(Async::Ready(item)) => break Ok(Ok(item)),
+            Err(MonitorError::Failed(e)) => break Ok(Err(e)),
+            Err(_) => break Err(io::Error::other("The main fiber has been aborted")),
+        }
+        executor.run_once()?;
+    };
+
+    // NOTE: The errors of the executor during the shutdown are ignored,
+    // so that the result of the main fiber is not lost.
+    let _ = handle.run_shutdown_hooks(&mut executor);
+    result
+}
+
+/// A handle of the runtime started by `run_with` function.
+///
+/// Fibers can be spawned via the handle.
+#[derive(Clone)]
+pub struct Handle {
+    executor: ThreadPoolExecutorHandle,
+    hooks: Arc<Mutex<Vec<ShutdownHook>>>,
+    shutdown_timeout: Arc<Mutex<Duration>>,
+}
+impl Handle {
+    /// Registers a shutdown hook.
+    ///
+    /// At the shutdown of the runtime, a fiber executing the future made by `f` is spawned.
+    /// The hooks are executed in the ascending order of their `priority`:
+    /// the hooks with the same priority are executed concurrently,
+    /// and the next ones are started after all of them complete.
+    ///
+    /// The hooks registered during the shutdown are ignored.
+    pub fn on_shutdown<F, T>(&self, priority: u32, f: F)
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: IntoFuture<Item = (), Error = ()>,
+        T::Future: Send + 'static,
+    {
+        let hook = ShutdownHook {
+            priority,
+            start: Box::new(move |executor| executor.spawn_monitor(f().into_future())),
+        };
+        self.hooks
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(hook);
+    }
+
+    /// Sets the time limit of the execution of the shutdown hooks.
+    ///
+    /// If the hooks do not complete within `timeout` (measured from the start of the shutdown),
+    /// the remaining hooks are abandoned and the executor is shut down.
+    ///
+    /// The default value is 30 seconds.
+    pub fn set_shutdown_timeout(&self, timeout: Duration) {
+        *self
+            .shutdown_timeout
+            .lock()
+            .unwrap_or_else(|e| e.into_inner()) = timeout;
+    }
+
+    fn run_shutdown_hooks(&self, executor: &mut ThreadPoolExecutor) -> io::Result<()> {
+        let mut hooks = {
+            let mut hooks = self.hooks.lock().unwrap_or_else(|e| e.into_inner());
+            std::mem::take(&mut *hooks)
+        };
+        let timeout = *self
+            .shutdown_timeout
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let deadline = Instant::now().checked_add(timeout);
+
+        // The sort is stable, so hooks with the same priority start in registration order.
+        hooks.sort_by_key(|h| h.priority);
+        let mut hooks = hooks.into_iter().peekable();
+        while let Some(first) = hooks.next() {
+            let priority = first.priority;
+            let mut monitors = vec![(first.start)(&self.executor)];
+            while let Some(hook) = hooks.next_if(|h| h.priority == priority) {
+                monitors.push((hook.start)(&self.executor));
+            }
+            while !monitors.is_empty() {
+                if RECEIVED_SIGNAL.swap(0, atomic::Ordering::SeqCst) != 0 {
+                    return Ok(());
+                }
+                if deadline.is_some_and(|d| Instant::now() >= d) {
+                    return Ok(());
+                }
+                monitors.retain_mut(|m| matches!(m.poll(), Ok(Async::NotReady)));
+                executor.run_once()?;
+            }
+        }
+        Ok(())
+    }
+}
+impl Spawn for Handle {
+    fn spawn_boxed(&self, fiber: Box<dyn Future<Item = (), Error = ()> + Send>) {
+        self.executor.spawn_boxed(fiber)
+    }
+}
+impl fmt::Debug for Handle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let hooks = self.hooks.lock().unwrap_or_else(|e| e.into_inner()).len();
+        let timeout = *self
+            .shutdown_timeout
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        write!(
+            f,
+            "Handle {{ shutdown_hooks: {}, shutdown_timeout: {:?}, .. }}",
+            hooks, timeout
+        )
+    }
+}
+
+type StartHook = Box<dyn FnOnce(&ThreadPoolExecutorHandle) -> Monitor<(), ()> + Send>;
+
+struct ShutdownHook {
+    priority: u32,
+    start: StartHook,
+}
+
+/// Returns the capabilities of the runtime on the current platform.
+///
+/// The optional OS features are probed at each call (e.g., by setting a socket option
+/// on a temporary socket), so the result reflects the kernel and its configuration
+/// the process is actually running on.
+///
+/// # Examples
+///
+/// ```
+/// use fibers::runtime::{self, Backend};
+///
+/// let capabilities = runtime::capabilities();
+/// if cfg!(target_os = "linux") {
+///     assert_eq!(capabilities.backend, Backend::Epoll);
+/// }
+/// println!("{:?}", capabilities);
+/// ```
+pub fn capabilities() -> Capabilities {
+    Capabilities {
+        backend: Backend::current(),
+        timer_resolution: Duration::from_millis(1),
+        tcp_fast_open: probe::tcp_fast_open(),
+        udp_gso: probe::udp_gso(),
+        reuse_port: probe::reuse_port(),
+        pidfd: probe::pidfd(),
+        vsock: probe::vsock(),
+    }
+}
+
+/// The capabilities of the runtime.
+///
+/// This is created by calling `capabilities` function.
+///
+/// Note that the optional features (e.g., `tcp_fast_open`) indicate the support of the OS:
+/// fibers itself does not enable them, but applications can do so via
+/// e.g., `TcpListener::with_inner`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Capabilities {
+    /// The I/O polling backend of the pollers.
+    pub backend: Backend,
+
+    /// The resolution of the timers (e.g., `fibers::time::timer::timeout`).
+    ///
+    /// The timers never fire earlier than their deadlines,
+    /// but may fire later by at most this duration (plus the slack of the timers).
+    pub timer_resolution: Duration,
+
+    /// Whether TCP Fast Open is enabled in the OS.
+    pub tcp_fast_open: bool,
+
+    /// Whether UDP generic segmentation offload (`UDP_SEGMENT`) is supported by the OS.
+    pub udp_gso: bool,
+
+    /// Whether the `SO_REUSEPORT` socket option is supported by the OS.
+    pub reuse_port: bool,
+
+    /// Whether process file descriptors (`pidfd_open`) are supported by the OS.
+    pub pidfd: bool,
+
+    /// Whether VM sockets (see `fibers::net::vsock`) are available.
+    pub vsock: bool,
+}
+
+/// The I/O polling backend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum Backend {
+    /// `epoll` (Linux and Android).
+    Epoll,
+
+    /// `kqueue` (macOS, iOS and BSDs).
+    Kqueue,
+
+    /// I/O completion ports (Windows).
+    Iocp,
+
+    /// Other backends.
+    ///
+    /// Note that `io_uring` is not supported as a backend.
+    Other,
+}
+impl Backend {
+    fn current() -> Self {
+        if cfg!(any(target_os = "linux", target_os = "android")) {
+            Backend::Epoll
+        } else if cfg!(any(
+            target_os = "macos",
+            target_os = "ios",
+            target_os = "freebsd",
+            target_os = "netbsd",
+            target_os = "openbsd",
+            target_os = "dragonfly"
+        )) {
+            Backend::Kqueue
+        } else if cfg!(windows) {
+            Backend::Iocp
+        } else {
+            Backend::Other
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod probe {
+    use std::fs;
+    use std::mem;
+
+    /// See `linux/udp.h`.
+    const UDP_SEGMENT: libc::c_int = 103;
+
+    pub fn tcp_fast_open() -> bool {
+        // Bit 0 enables the client side, and bit 1 enables the server side.
+        fs::read_to_string("/proc/sys/net/ipv4/tcp_fastopen")
+            .ok()
+            .and_then(|s| s.trim().parse::<u32>().ok())
+            .is_some_and(|flags| flags & 0b11 != 0)
+    }
+
+    pub fn udp_gso() -> bool {
+        set_option(libc::SOCK_DGRAM, libc::SOL_UDP, UDP_SEGMENT, 1200)
+    }
+
+    pub fn reuse_port() -> bool {
+        set_option(libc::SOCK_STREAM, libc::SOL_SOCKET, libc::SO_REUSEPORT, 1)
+    }
+
+    pub fn pidfd() -> bool {
+        let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, libc::getpid(), 0) };
+        if fd < 0 {
+            return false;
+        }
+        unsafe {
+            libc::close(fd as libc::c_int);
+        }
+        true
+    }
+
+    pub fn vsock() -> bool {
+        let fd = unsafe { libc::socket(libc::AF_VSOCK, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+        if fd < 0 {
+            return false;
+        }
+        unsafe {
+            libc::close(fd);
+        }
+        true
+    }
+
+    fn set_option(
+        kind: libc::c_int,
+        level: libc::c_int,
+        name: libc::c_int,
+        value: libc::c_int,
+    ) -> bool {
+        let fd = unsafe { libc::socket(libc::AF_INET, kind | libc::SOCK_CLOEXEC, 0) };
+        if fd < 0 {
+            return false;
+        }
+        let result = unsafe {
+            libc::setsockopt(
+                fd,
+                level,
+                name,
+                &value as *const _ as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        unsafe {
+            libc::close(fd);
+        }
+        result == 0
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod probe {
+    pub fn tcp_fast_open() -> bool {
+        false
+    }
+
+    pub fn udp_gso() -> bool {
+        false
+    }
+
+    #[cfg(unix)]
+    pub fn reuse_port() -> bool {
+        use std::mem;
+
+        let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_STREAM, 0) };
+        if fd < 0 {
+            return false;
+        }
+        let value: libc::c_int = 1;
+        let result = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_REUSEPORT,
+                &value as *const _ as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        unsafe {
+            libc::close(fd);
+        }
+        result == 0
+    }
+
+    #[cfg(not(unix))]
+    pub fn reuse_port() -> bool {
+        false
+    }
+
+    pub fn pidfd() -> bool {
+        false
+    }
+
+    pub fn vsock() -> bool {
+        false
+    }
+}
+
+#[cfg(unix)]
+struct SignalGuard {
+    previous: Vec<(libc::c_int, libc::sighandler_t)>,
+}
+#[cfg(unix)]
+impl SignalGuard {
+    fn install() -> Self {
+        extern "C" fn handle_signal(signal: libc::c_int) {
+            RECEIVED_SIGNAL.store(signal as usize, atomic::Ordering::SeqCst);
+        }
+
+        RECEIVED_SIGNAL.store(0, atomic::Ordering::SeqCst);
+        let handler = handle_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        let previous = [libc::SIGINT, libc::SIGTERM]
+            .iter()
+            .map(|&signal| (signal, unsafe { libc::signal(signal, handler) }))
+            .collect();
+        SignalGuard { previous }
+    }
+}
+#[cfg(unix)]
+impl Drop for SignalGuard {
+    fn drop(&mut self) {
+        for &(signal, handler) in &self.previous {
+            unsafe {
+                libc::signal(signal, handler);
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+struct SignalGuard;
+#[cfg(not(unix))]
+impl SignalGuard {
+    fn install() -> Self {
+        SignalGuard
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn shutdown_hooks_are_abandoned_after_timeout() {
+        let started = Instant::now();
+        let result = run_with(|handle| {
+            handle.set_shutdown_timeout(Duration::from_millis(50));
+            handle.on_shutdown(0, futures::empty);
+            futures::failed::<(), _>("error")
+        });
+        assert_eq!(result.unwrap(), Err("error"));
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+}
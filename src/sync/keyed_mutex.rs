@@ -0,0 +1,302 @@
+// Copyright (c) 2016 DWANGO Co., Ltd. All Rights Reserved.
+// See the LICENSE file at the top-level directory of this distribution.
+
+use futures::{Async, Future, Poll};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::hash::Hash;
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+use super::oneshot;
+
+type Table<K> = Arc<Mutex<HashMap<K, KeyState<K>>>>;
+
+/// A set of asynchronous mutual exclusion locks, each of which is identified by a key.
+///
+/// This is useful to serialize the operations on the same resource (e.g., per user or per file)
+/// across many fibers, without blocking the operations on the other resources.
+///
+/// The lock of a key is acquired in FIFO order.
+/// The entry of a key is created on demand and removed automatically
+/// when the key is unlocked and no fiber is waiting for it.
+/// So the memory usage of a `KeyedMutex` is proportional to the number of the keys in use.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate fibers;
+/// # extern crate futures;
+/// use fibers::{Executor, InPlaceExecutor, Spawn};
+/// use fibers::sync::KeyedMutex;
+/// use futures::Future;
+///
+/// let mut executor = InPlaceExecutor::new().unwrap();
+/// let mutex = KeyedMutex::new();
+///
+/// let guard = executor.run_future(mutex.lock("foo")).unwrap().unwrap();
+/// assert!(mutex.is_locked(&"foo"));
+///
+/// // Other keys can be locked concurrently.
+/// let other = executor.run_future(mutex.lock("bar")).unwrap().unwrap();
+/// drop(other);
+///
+/// // The same key can not be locked until the guard is released.
+/// let waiting = executor.spawn_monitor(mutex.lock("foo").map(|_| ()));
+/// executor.run_once().unwrap();
+/// assert_eq!(mutex.len(), 1);
+///
+/// drop(guard);
+/// executor.run_fiber(waiting).unwrap().unwrap();
+/// assert!(mutex.is_empty());
+/// ```
+#[derive(Debug)]
+pub struct KeyedMutex<K: Hash + Eq + Clone> {
+    table: Table<K>,
+}
+impl<K: Hash + Eq + Clone> KeyedMutex<K> {
+    /// Makes a new `KeyedMutex` instance.
+    pub fn new() -> Self {
+        KeyedMutex {
+            table: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Makes a future which will acquire the lock of `key`.
+    ///
+    /// If the returned future is dropped before acquisition, the attempt is abandoned.
+    pub fn lock(&self, key: K) -> KeyedLock<K> {
+        let mut table = self.table.lock().unwrap_or_else(|e| e.into_inner());
+        let inner = match table.get_mut(&key) {
+            None => {
+                table.insert(key.clone(), KeyState::new());
+                KeyedLockInner::Acquired(KeyedMutexGuard::new(key, Arc::clone(&self.table)))
+            }
+            Some(state) => {
+                let (tx, rx) = oneshot::channel();
+                state.waitings.push_back(tx);
+                KeyedLockInner::Waiting(rx)
+            }
+        };
+        KeyedLock(inner)
+    }
+
+    /// Tries to acquire the lock of `key` immediately.
+    ///
+    /// If the key is already locked, this will return `None`.
+    pub fn try_lock(&self, key: K) -> Option<KeyedMutexGuard<K>> {
+        let mut table = self.table.lock().unwrap_or_else(|e| e.into_inner());
+        if table.contains_key(&key) {
+            None
+        } else {
+            table.insert(key.clone(), KeyState::new());
+            Some(KeyedMutexGuard::new(key, Arc::clone(&self.table)))
+        }
+    }
+
+    /// Returns `true` if `key` is locked, otherwise `false`.
+    pub fn is_locked(&self, key: &K) -> bool {
+        let table = self.table.lock().unwrap_or_else(|e| e.into_inner());
+        table.contains_key(key)
+    }
+
+    /// Returns the number of the keys which are locked.
+    pub fn len(&self) -> usize {
+        let table = self.table.lock().unwrap_or_else(|e| e.into_inner());
+        table.len()
+    }
+
+    /// Returns `true` if no key is locked, otherwise `false`.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+impl<K: Hash + Eq + Clone> Default for KeyedMutex<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl<K: Hash + Eq + Clone> Clone for KeyedMutex<K> {
+    fn clone(&self) -> Self {
+        KeyedMutex {
+            table: Arc::clone(&self.table),
+        }
+    }
+}
+
+/// A future which will acquire the lock of a key.
+///
+/// This is created by calling `KeyedMutex::lock` method.
+pub struct KeyedLock<K: Hash + Eq + Clone>(KeyedLockInner<K>);
+impl<K: Hash + Eq + Clone> Future for KeyedLock<K> {
+    type Item = KeyedMutexGuard<K>;
+    type Error = ();
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match mem::replace(&mut self.0, KeyedLockInner::Polled) {
+            KeyedLockInner::Acquired(guard) => Ok(Async::Ready(guard)),
+            KeyedLockInner::Waiting(mut rx) => {
+                // NOTE: The sender is never dropped without sending a guard
+                // as long as the `KeyedMutex` (or a guard) is alive.
+                if let Async::Ready(guard) = rx.poll().map_err(|_| ())? {
+                    Ok(Async::Ready(guard))
+                } else {
+                    self.0 = KeyedLockInner::Waiting(rx);
+                    Ok(Async::NotReady)
+                }
+            }
+            KeyedLockInner::Polled => panic!("Cannot poll KeyedLock twice"),
+        }
+    }
+}
+impl<K: Hash + Eq + Clone> fmt::Debug for KeyedLock<K> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            KeyedLockInner::Acquired(_) => write!(f, "KeyedLock::Acquired(_)"),
+            KeyedLockInner::Waiting(_) => write!(f, "KeyedLock::Waiting(_)"),
+            KeyedLockInner::Polled => write!(f, "KeyedLock::Polled"),
+        }
+    }
+}
+
+enum KeyedLockInner<K: Hash + Eq + Clone> {
+    Acquired(KeyedMutexGuard<K>),
+    Waiting(oneshot::Receiver<KeyedMutexGuard<K>>),
+    Polled,
+}
+
+/// A guard of the lock of a key.
+///
+/// When this is dropped, the lock is handed over to the next waiting fiber (if any).
+pub struct KeyedMutexGuard<K: Hash + Eq + Clone> {
+    key: K,
+    table: Option<Table<K>>,
+}
+impl<K: Hash + Eq + Clone> KeyedMutexGuard<K> {
+    fn new(key: K, table: Table<K>) -> Self {
+        KeyedMutexGuard {
+            key,
+            table: Some(table),
+        }
+    }
+
+    /// Returns a reference to the locked key.
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+}
+impl<K: Hash + Eq + Clone> Drop for KeyedMutexGuard<K> {
+    fn drop(&mut self) {
+        if let Some(table) = self.table.take() {
+            release(&self.key, table);
+        }
+    }
+}
+impl<K: Hash + Eq + Clone + fmt::Debug> fmt::Debug for KeyedMutexGuard<K> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "KeyedMutexGuard {{ key: {:?}, .. }}", self.key)
+    }
+}
+
+struct KeyState<K: Hash + Eq + Clone> {
+    waitings: VecDeque<oneshot::Sender<KeyedMutexGuard<K>>>,
+}
+impl<K: Hash + Eq + Clone> KeyState<K> {
+    fn new() -> Self {
+        KeyState {
+            waitings: VecDeque::new(),
+        }
+    }
+}
+impl<K: Hash + Eq + Clone> fmt::Debug for KeyState<K> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "KeyState {{ waitings: {} }}", self.waitings.len())
+    }
+}
+
+fn release<K: Hash + Eq + Clone>(key: &K, table: Table<K>) {
+    let mut entries = table.lock().unwrap_or_else(|e| e.into_inner());
+    let state = assert_some!(entries.get_mut(key));
+    while let Some(tx) = state.waitings.pop_front() {
+        let guard = KeyedMutexGuard::new(key.clone(), Arc::clone(&table));
+        match tx.send(guard) {
+            Ok(()) => return,
+            Err(e) => {
+                // The waiting fiber has abandoned the lock.
+                let mut guard = e.0;
+                guard.table = None;
+            }
+        }
+    }
+    entries.remove(key);
+}
+
+#[cfg(test)]
+mod test {
+    use futures::{Async, Future};
+
+    use super::*;
+
+    #[test]
+    fn lock_is_handed_over_in_fifo_order() {
+        let mutex = KeyedMutex::new();
+        let guard = assert_some!(mutex.try_lock(0));
+        let mut first = mutex.lock(0);
+        let mut second = mutex.lock(0);
+        assert!(matches!(first.poll(), Ok(Async::NotReady)));
+        assert!(matches!(second.poll(), Ok(Async::NotReady)));
+
+        drop(guard);
+        let guard = match first.poll() {
+            Ok(Async::Ready(guard)) => guard,
+            _ => panic!(),
+        };
+        assert!(matches!(second.poll(), Ok(Async::NotReady)));
+
+        drop(guard);
+        assert!(matches!(second.poll(), Ok(Async::Ready(_))));
+    }
+
+    #[test]
+    fn abandoned_waiters_are_skipped() {
+        let mutex = KeyedMutex::new();
+        let guard = assert_some!(mutex.try_lock(0));
+        let abandoned = mutex.lock(0);
+        let mut waiting = mutex.lock(0);
+
+        // The waiter abandons the lock before it is released.
+        drop(abandoned);
+        drop(guard);
+        assert!(matches!(waiting.poll(), Ok(Async::Ready(_))));
+    }
+
+    #[test]
+    fn guard_is_handed_over_if_waiter_is_dropped_after_release() {
+        let mutex = KeyedMutex::new();
+        let guard = assert_some!(mutex.try_lock(0));
+        let abandoned = mutex.lock(0);
+        let mut waiting = mutex.lock(0);
+
+        // The guard sent to the first waiter is dropped together with it.
+        drop(guard);
+        drop(abandoned);
+        assert!(matches!(waiting.poll(), Ok(Async::Ready(_))));
+    }
+
+    #[test]
+    fn entry_is_removed_when_last_guard_is_dropped() {
+        let mutex = KeyedMutex::new();
+        let guard = assert_some!(mutex.try_lock(0));
+        let other = assert_some!(mutex.try_lock(1));
+        let abandoned = mutex.lock(0);
+        assert_eq!(mutex.len(), 2);
+
+        drop(other);
+        assert_eq!(mutex.len(), 1);
+        assert!(!mutex.is_locked(&1));
+
+        drop(abandoned);
+        drop(guard);
+        assert!(mutex.is_empty());
+        assert!(mutex.try_lock(0).is_some());
+    }
+}
@@ -4,20 +4,29 @@
 //! Synchronization primitives.
 use std::sync::Arc;
 
-use crate::fiber;
+use crate::fiber::{self, WaitKind};
 use crate::sync_atomic::AtomicCell;
 
+pub use self::keyed_mutex::{KeyedLock, KeyedMutex, KeyedMutexGuard};
+
 pub mod mpsc;
 pub mod oneshot;
 
+mod keyed_mutex;
+
 #[derive(Debug, Clone)]
-struct Notifier {
+pub(crate) struct Notifier {
     unpark: Arc<AtomicCell<Option<fiber::Unpark>>>,
+    kind: WaitKind,
 }
 impl Notifier {
     pub fn new() -> Self {
+        Self::with_kind(WaitKind::Channel)
+    }
+    pub fn with_kind(kind: WaitKind) -> Self {
         Notifier {
             unpark: Arc::new(AtomicCell::new(None)),
+            kind,
         }
     }
     pub fn await_notification(&mut self) {
@@ -25,7 +34,8 @@ impl Notifier {
             if let Some(mut unpark) = self.unpark.try_borrow_mut() {
                 let context_id = fiber::with_current_context(|c| c.context_id());
                 if unpark.as_ref().map(|u| u.context_id()) != context_id {
-                    *unpark = fiber::with_current_context(|mut c| c.park());
+                    let kind = self.kind;
+                    *unpark = fiber::with_current_context(|mut c| c.park_for(kind));
                 }
                 return;
             }
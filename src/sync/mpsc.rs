@@ -63,8 +63,10 @@ use futures::{Async, AsyncSink, Poll, Sink, StartSend, Stream};
 use nbchan::mpsc as nb_mpsc;
 use std::fmt;
 use std::sync::mpsc::{SendError, TryRecvError, TrySendError};
+use std::sync::Arc;
 
 use super::Notifier;
+use crate::metrics::{WakeCounters, WakeStats};
 
 /// Creates a new asynchronous channel, returning the sender/receiver halves.
 ///
@@ -114,14 +116,123 @@ pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
         Sender {
             inner: tx,
             notifier: notifier.clone(),
+            hooks: None,
         },
         Receiver {
             inner: rx,
             notifier,
+            hooks: None,
+            waiting: false,
+            wake: WakeCounters::default(),
         },
     )
 }
 
+/// Creates a new asynchronous channel whose events are reported to `hooks`.
+///
+/// This is the same as `channel` function except for the instrumentation hooks,
+/// which are useful to investigate message losses (e.g., during shutdown).
+///
+/// # Examples
+///
+/// ```
+/// use std::sync::Arc;
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+/// use fibers::sync::mpsc::{self, ChannelHooks};
+///
+/// let dropped = Arc::new(AtomicUsize::new(0));
+/// let hooks = {
+///     let dropped = dropped.clone();
+///     ChannelHooks::new().on_drop(move |_: &u32| {
+///         dropped.fetch_add(1, Ordering::SeqCst);
+///     })
+/// };
+/// let (tx, rx) = mpsc::observable_channel(hooks);
+/// tx.send(1).unwrap();
+/// tx.send(2).unwrap();
+///
+/// // The receiver is dropped without consuming the messages.
+/// std::mem::drop(rx);
+/// assert_eq!(dropped.load(Ordering::SeqCst), 2);
+/// ```
+pub fn observable_channel<T>(hooks: ChannelHooks<T>) -> (Sender<T>, Receiver<T>) {
+    let (mut tx, mut rx) = channel();
+    let hooks = Arc::new(hooks);
+    tx.hooks = Some(Arc::clone(&hooks));
+    rx.hooks = Some(hooks);
+    (tx, rx)
+}
+
+type Hook<T> = Box<dyn Fn(&T) + Send + Sync + 'static>;
+
+/// Instrumentation hooks of a channel created by `observable_channel` function.
+///
+/// The hooks are called synchronously on the thread which caused the event,
+/// so they should be cheap.
+pub struct ChannelHooks<T> {
+    on_enqueue: Option<Hook<T>>,
+    on_dequeue: Option<Hook<T>>,
+    on_drop: Option<Hook<T>>,
+}
+impl<T> ChannelHooks<T> {
+    /// Makes a new `ChannelHooks` instance which has no hooks.
+    pub fn new() -> Self {
+        ChannelHooks {
+            on_enqueue: None,
+            on_dequeue: None,
+            on_drop: None,
+        }
+    }
+
+    /// Sets the hook called when a message is enqueued by a sender.
+    ///
+    /// If the receiver is dropped concurrently, the hook may be called
+    /// even though the sending fails (and the message is returned to the sender).
+    pub fn on_enqueue<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&T) + Send + Sync + 'static,
+    {
+        self.on_enqueue = Some(Box::new(f));
+        self
+    }
+
+    /// Sets the hook called when a message is dequeued by the receiver.
+    pub fn on_dequeue<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&T) + Send + Sync + 'static,
+    {
+        self.on_dequeue = Some(Box::new(f));
+        self
+    }
+
+    /// Sets the hook called for each message remaining in the channel
+    /// when the receiver is dropped.
+    ///
+    /// Note that the channel cannot be closed before the remaining messages are drained.
+    /// So if a sender sends a message concurrently with the drop of the receiver,
+    /// the message may be enqueued after the draining and then be freed without
+    /// calling this hook (while the sending succeeds).
+    /// The messages sent after the receiver has been dropped are returned to the senders
+    /// as usual.
+    pub fn on_drop<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&T) + Send + Sync + 'static,
+    {
+        self.on_drop = Some(Box::new(f));
+        self
+    }
+}
+impl<T> Default for ChannelHooks<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl<T> fmt::Debug for ChannelHooks<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ChannelHooks {{ .. }}")
+    }
+}
+
 /// Creates a new synchronous, bounded channel.
 #[deprecated]
 pub fn sync_channel<T>(bound: usize) -> (SyncSender<T>, Receiver<T>) {
@@ -135,6 +246,9 @@ pub fn sync_channel<T>(bound: usize) -> (SyncSender<T>, Receiver<T>) {
         Receiver {
             inner: rx,
             notifier,
+            hooks: None,
+            waiting: false,
+            wake: WakeCounters::default(),
         },
     )
 }
@@ -147,6 +261,35 @@ pub fn sync_channel<T>(bound: usize) -> (SyncSender<T>, Receiver<T>) {
 pub struct Receiver<T> {
     inner: nb_mpsc::Receiver<T>,
     notifier: Notifier,
+    hooks: Option<Arc<ChannelHooks<T>>>,
+    waiting: bool,
+    wake: WakeCounters,
+}
+impl<T> Receiver<T> {
+    /// Returns the statistics of the wakeups of the fiber waiting on this receiver.
+    ///
+    /// A wakeup is counted when the receiver is polled again after it returned `NotReady`,
+    /// and it is spurious if no message (nor disconnection) is available at that time.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use fibers::sync::mpsc;
+    /// use futures::Stream;
+    ///
+    /// let (tx, mut rx) = mpsc::channel();
+    /// assert!(rx.poll().unwrap().is_not_ready());
+    /// assert!(rx.poll().unwrap().is_not_ready());
+    /// tx.send(1).unwrap();
+    /// assert!(rx.poll().unwrap().is_ready());
+    ///
+    /// let stats = rx.wake_stats();
+    /// assert_eq!(stats.wakeups, 2);
+    /// assert_eq!(stats.spurious_wakeups, 1);
+    /// ```
+    pub fn wake_stats(&self) -> WakeStats {
+        self.wake.snapshot()
+    }
 }
 impl<T> Stream for Receiver<T> {
     /// # Note
@@ -160,15 +303,33 @@ impl<T> Stream for Receiver<T> {
             self.notifier.await_notification();
             result = self.inner.try_recv();
         }
+        let is_empty = matches!(result, Err(TryRecvError::Empty));
+        if self.waiting {
+            self.wake.record(!is_empty);
+        }
+        self.waiting = is_empty;
         match result {
             Err(TryRecvError::Empty) => Ok(Async::NotReady),
             Err(TryRecvError::Disconnected) => Ok(Async::Ready(None)),
-            Ok(t) => Ok(Async::Ready(Some(t))),
+            Ok(t) => {
+                if let Some(f) = self.hooks.as_ref().and_then(|h| h.on_dequeue.as_ref()) {
+                    f(&t);
+                }
+                Ok(Async::Ready(Some(t)))
+            }
         }
     }
 }
 impl<T> Drop for Receiver<T> {
     fn drop(&mut self) {
+        if let Some(f) = self.hooks.as_ref().and_then(|h| h.on_drop.as_ref()) {
+            // NOTE: `nbchan` provides no way to close the channel before the draining,
+            // so the messages sent during the draining may escape the hook
+            // (see the documentation of `ChannelHooks::on_drop`).
+            while let Ok(t) = self.inner.try_recv() {
+                f(&t);
+            }
+        }
         self.notifier.notify();
     }
 }
@@ -184,12 +345,19 @@ impl<T> fmt::Debug for Receiver<T> {
 pub struct Sender<T> {
     inner: nb_mpsc::Sender<T>,
     notifier: Notifier,
+    hooks: Option<Arc<ChannelHooks<T>>>,
 }
 impl<T> Sender<T> {
     /// Sends a value on this asynchronous channel.
     ///
     /// This method will never block the current thread.
     pub fn send(&self, t: T) -> Result<(), SendError<T>> {
+        if let Some(f) = self.hooks.as_ref().and_then(|h| h.on_enqueue.as_ref()) {
+            if self.inner.is_disconnected() {
+                return Err(SendError(t));
+            }
+            f(&t);
+        }
         self.inner.send(t)?;
         self.notifier.notify();
         Ok(())
@@ -206,6 +374,7 @@ impl<T> Clone for Sender<T> {
         Sender {
             inner: self.inner.clone(),
             notifier: self.notifier.clone(),
+            hooks: self.hooks.clone(),
         }
     }
 }
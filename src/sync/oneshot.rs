@@ -18,9 +18,11 @@
 use futures::{Async, Future, Poll};
 use std::error;
 use std::fmt;
+use std::mem;
 use std::sync::mpsc::{RecvError, SendError};
 
 use super::Notifier;
+use crate::fiber::WaitKind;
 
 /// Creates a new asynchronous oneshot channel, returning the sender/receiver halves.
 ///
@@ -65,7 +67,12 @@ use super::Notifier;
 /// # }
 /// ```
 pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
-    let notifier = Notifier::new();
+    channel_for(WaitKind::Channel)
+}
+
+/// Creates a oneshot channel whose waiting receiver is regarded as waiting on `kind` resource.
+pub(crate) fn channel_for<T>(kind: WaitKind) -> (Sender<T>, Receiver<T>) {
+    let notifier = Notifier::with_kind(kind);
     let (tx, rx) = nbchan::oneshot::channel();
     (
         Sender {
@@ -225,19 +232,30 @@ impl<T> fmt::Debug for Receiver<T> {
 ///
 /// Internally, this channel is almost the same as the one created by `channel` function.
 pub fn monitor<T, E>() -> (Monitored<T, E>, Monitor<T, E>) {
-    let (tx, rx) = channel();
-    (Monitored(tx), Monitor(rx))
+    monitor_for(WaitKind::Channel)
+}
+
+/// Creates a monitor channel whose waiting monitor is regarded as waiting on `kind` resource.
+pub(crate) fn monitor_for<T, E>(kind: WaitKind) -> (Monitored<T, E>, Monitor<T, E>) {
+    let (tx, rx) = channel_for(kind);
+    (Monitored(tx), Monitor(rx, None))
 }
 
 /// The monitored-half of a monitor channel.
 ///
 /// This is created by calling `monitor` function.
 #[derive(Debug)]
-pub struct Monitored<T, E>(Sender<Result<T, E>>);
+pub struct Monitored<T, E>(Sender<Result<T, MonitorError<E>>>);
 impl<T, E> Monitored<T, E> {
     /// Notifies the monitoring peer that the monitored target has exited intentionally.
     pub fn exit(self, result: Result<T, E>) {
-        let _ = self.0.send(result);
+        let _ = self.0.send(result.map_err(MonitorError::Failed));
+    }
+
+    /// Notifies the monitoring peer that the monitored target has been cancelled
+    /// because its deadline expired.
+    pub(crate) fn exit_deadline_exceeded(self) {
+        let _ = self.0.send(Err(MonitorError::DeadlineExceeded));
     }
 }
 
@@ -245,22 +263,291 @@ impl<T, E> Monitored<T, E> {
 ///
 /// This is created by calling `monitor` function.
 #[derive(Debug)]
-pub struct Monitor<T, E>(Receiver<Result<T, E>>);
+pub struct Monitor<T, E>(Receiver<Result<T, MonitorError<E>>>, Option<Sender<()>>);
 impl<T, E> Future for Monitor<T, E> {
     type Item = T;
     type Error = MonitorError<E>;
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         if let Async::Ready(r) = self.0.poll().or(Err(MonitorError::Aborted))? {
-            match r {
-                Err(e) => Err(MonitorError::Failed(e)),
-                Ok(v) => Ok(Async::Ready(v)),
+            r.map(Async::Ready)
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+impl<T, E> Monitor<T, E> {
+    /// Requests the monitored fiber to abort.
+    ///
+    /// The fiber is dropped at the next time it is resumed,
+    /// and this monitor results in `MonitorError::Aborted` (unless the fiber has already completed).
+    ///
+    /// Only the fibers spawned by `Spawn::spawn_abortable` can be aborted.
+    /// For the other monitors (e.g., the ones returned by `Spawn::spawn_monitor` or
+    /// created by `monitor` function), this does nothing.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate fibers;
+    /// # extern crate futures;
+    /// use fibers::{Executor, InPlaceExecutor, Spawn};
+    /// use fibers::sync::oneshot::MonitorError;
+    ///
+    /// let mut executor = InPlaceExecutor::new().unwrap();
+    /// let mut monitor = executor.spawn_abortable(futures::empty::<(), ()>());
+    /// monitor.abort();
+    /// assert_eq!(executor.run_fiber(monitor).unwrap(), Err(MonitorError::Aborted));
+    /// ```
+    pub fn abort(&mut self) {
+        if let Some(abort_tx) = self.1.take() {
+            let _ = abort_tx.send(());
+        }
+    }
+
+    /// Sets the sender of the abort request to the monitored fiber (see `fiber::Abortable`).
+    pub(crate) fn set_abort_handle(&mut self, abort_tx: Sender<()>) {
+        self.1 = Some(abort_tx);
+    }
+
+    /// Makes a future which waits until all of `monitors` complete.
+    ///
+    /// This is equivalent to `oneshot::join_all(monitors)`.
+    pub fn join_all<I>(monitors: I) -> JoinAll<Monitor<T, E>>
+    where
+        I: IntoIterator<Item = Monitor<T, E>>,
+    {
+        join_all(monitors)
+    }
+
+    /// Makes a future which waits until both `self` and `other` complete.
+    ///
+    /// This is equivalent to `oneshot::try_join(self, other)`.
+    pub fn try_join<F>(self, other: F) -> TryJoin<Self, F>
+    where
+        F: Future<Error = MonitorError<E>> + Abort,
+    {
+        try_join(self, other)
+    }
+}
+
+/// Requests the computation which a future is waiting for to abort.
+///
+/// This is used by `join_all` and `try_join` to cancel the remaining children on the first error.
+pub trait Abort {
+    /// Requests the computation to abort.
+    fn abort(&mut self);
+}
+impl<T, E> Abort for Monitor<T, E> {
+    fn abort(&mut self) {
+        Monitor::abort(self);
+    }
+}
+impl<T0, E0, T1, E1> Abort for Link<T0, E0, T1, E1> {
+    /// Does nothing.
+    ///
+    /// `join_all` and `try_join` drop the aborted children,
+    /// and dropping a `Link` created by `Spawn::spawn_link` terminates the linked fiber.
+    fn abort(&mut self) {}
+}
+
+/// Makes a future which waits until all of `children` complete.
+///
+/// `children` are `Monitor`s or `Link`s of spawned fibers.
+/// The resulting future yields the items of them in the same order as `children`.
+///
+/// If any of the children fails, the future immediately results in the error and
+/// the remaining children are aborted (see `Monitor::abort`) and dropped.
+/// Dropping a `Link` created by `Spawn::spawn_link` terminates the linked fiber,
+/// so the children spawned by `Spawn::spawn_abortable` or `Spawn::spawn_link`
+/// are cancelled on the first error.
+/// The fibers of the other monitors (e.g., the ones returned by `Spawn::spawn_monitor`)
+/// keep running.
+///
+/// The future panics if it is polled after it has completed or failed.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate fibers;
+/// # extern crate futures;
+/// use fibers::{Executor, InPlaceExecutor, Spawn};
+/// use fibers::sync::oneshot::{self, MonitorError};
+/// use futures::{self, Future};
+///
+/// let mut executor = InPlaceExecutor::new().unwrap();
+/// let (tx, rx) = oneshot::channel::<()>();
+///
+/// let children = vec![
+///     // This child never completes unless it is cancelled.
+///     executor.spawn_link(futures::empty().and_then(move |()| tx.send(()).map_err(|_| ()))),
+///     executor.spawn_link(futures::failed::<(), ()>(())),
+/// ];
+/// let result = executor.run_future(oneshot::join_all(children)).unwrap();
+/// assert_eq!(result, Err(MonitorError::Failed(())));
+///
+/// // The first child has been cancelled (i.e., `tx` has been dropped).
+/// assert!(executor.run_future(rx).unwrap().is_err());
+/// ```
+pub fn join_all<I, F, E>(children: I) -> JoinAll<F>
+where
+    I: IntoIterator<Item = F>,
+    F: Future<Error = MonitorError<E>> + Abort,
+{
+    let children = children.into_iter().map(JoinState::Pending).collect();
+    JoinAll {
+        children: Some(children),
+    }
+}
+
+/// Makes a future which waits until both `a` and `b` complete.
+///
+/// If either of them fails, the future immediately results in the error and
+/// the other is aborted (see `Monitor::abort`) and dropped.
+/// See the documentation of `join_all` for the cancellation semantics.
+///
+/// Note that `Abort::abort` does nothing for a `Link`.
+/// A `Link` child is cancelled only because it is dropped after the failure
+/// (i.e., the linked fiber terminates when it notices the disconnection).
+pub fn try_join<A, B, E>(a: A, b: B) -> TryJoin<A, B>
+where
+    A: Future<Error = MonitorError<E>> + Abort,
+    B: Future<Error = MonitorError<E>> + Abort,
+{
+    TryJoin {
+        a: JoinState::Pending(a),
+        b: JoinState::Pending(b),
+    }
+}
+
+/// A future which waits until all of the children complete.
+///
+/// This is created by calling `join_all` function.
+pub struct JoinAll<F: Future> {
+    children: Option<Vec<JoinState<F>>>,
+}
+impl<F, E> Future for JoinAll<F>
+where
+    F: Future<Error = MonitorError<E>> + Abort,
+{
+    type Item = Vec<F::Item>;
+    type Error = MonitorError<E>;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let mut all_done = true;
+        let children = self
+            .children
+            .as_mut()
+            .expect("Cannot poll JoinAll after completion");
+        for child in children.iter_mut() {
+            match child.poll() {
+                Err(e) => {
+                    let mut children = self.children.take().expect("Never fails");
+                    for child in &mut children {
+                        child.abort();
+                    }
+                    return Err(e);
+                }
+                Ok(done) => all_done &= done,
             }
+        }
+        if all_done {
+            let children = self.children.take().expect("Never fails");
+            let items = children.into_iter().map(|c| c.into_item()).collect();
+            Ok(Async::Ready(items))
         } else {
             Ok(Async::NotReady)
         }
     }
 }
 
+impl<F: Future> fmt::Debug for JoinAll<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "JoinAll {{ .. }}")
+    }
+}
+
+/// A future which waits until both of the two children complete.
+///
+/// This is created by calling `try_join` function.
+pub struct TryJoin<A: Future, B: Future> {
+    a: JoinState<A>,
+    b: JoinState<B>,
+}
+impl<A, B, E> Future for TryJoin<A, B>
+where
+    A: Future<Error = MonitorError<E>> + Abort,
+    B: Future<Error = MonitorError<E>> + Abort,
+{
+    type Item = (A::Item, B::Item);
+    type Error = MonitorError<E>;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let a_done = match self.a.poll() {
+            Err(e) => {
+                self.b.abort();
+                self.b = JoinState::Taken;
+                return Err(e);
+            }
+            Ok(done) => done,
+        };
+        let b_done = match self.b.poll() {
+            Err(e) => {
+                self.a.abort();
+                self.a = JoinState::Taken;
+                return Err(e);
+            }
+            Ok(done) => done,
+        };
+        if a_done && b_done {
+            let a = mem::replace(&mut self.a, JoinState::Taken).into_item();
+            let b = mem::replace(&mut self.b, JoinState::Taken).into_item();
+            Ok(Async::Ready((a, b)))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+impl<A: Future, B: Future> fmt::Debug for TryJoin<A, B> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TryJoin {{ .. }}")
+    }
+}
+
+enum JoinState<F: Future> {
+    Pending(F),
+    Done(F::Item),
+    Taken,
+}
+impl<F: Future> JoinState<F> {
+    fn poll(&mut self) -> Result<bool, F::Error> {
+        let item = match *self {
+            JoinState::Pending(ref mut f) => match f.poll()? {
+                Async::Ready(item) => item,
+                Async::NotReady => return Ok(false),
+            },
+            JoinState::Done(_) => return Ok(true),
+            JoinState::Taken => panic!("Cannot poll a joined future twice"),
+        };
+        *self = JoinState::Done(item);
+        Ok(true)
+    }
+    fn abort(&mut self)
+    where
+        F: Abort,
+    {
+        if let JoinState::Pending(ref mut f) = *self {
+            f.abort();
+        }
+    }
+    fn into_item(self) -> F::Item {
+        if let JoinState::Done(item) = self {
+            item
+        } else {
+            panic!("Not completed yet")
+        }
+    }
+}
+
 /// The reason that a monitored peer has not completed successfully.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum MonitorError<E> {
@@ -271,6 +558,11 @@ pub enum MonitorError<E> {
     ///
     /// i.e., `Monitored::exit(self, Err(E))` was called
     Failed(E),
+
+    /// The monitored peer has been cancelled because its deadline expired.
+    ///
+    /// See `Spawn::spawn_monitor_with_deadline`.
+    DeadlineExceeded,
 }
 impl<E> MonitorError<E> {
     /// Maps an `MonitorError<E>` to `MonitorError<T>` by applying a function to a contained error.
@@ -293,12 +585,14 @@ impl<E> MonitorError<E> {
         match self {
             MonitorError::Aborted => MonitorError::Aborted,
             MonitorError::Failed(e) => MonitorError::Failed(f(e)),
+            MonitorError::DeadlineExceeded => MonitorError::DeadlineExceeded,
         }
     }
 
     /// Unwraps `MonitorError` and returns the internal error `E`.
     ///
-    /// If `self` is `MonitorError::Aborted`, `or_error` will be returned.
+    /// If `self` is `MonitorError::Aborted` or `MonitorError::DeadlineExceeded`,
+    /// `or_error` will be returned.
     ///
     /// # Examples
     ///
@@ -317,13 +611,14 @@ impl<E> MonitorError<E> {
 
     /// Unwraps `MonitorError` and returns the internal error `E`.
     ///
-    /// If `self` is `MonitorError::Aborted`, the result of `f()` will be returned.
+    /// If `self` is `MonitorError::Aborted` or `MonitorError::DeadlineExceeded`,
+    /// the result of `f()` will be returned.
     pub fn unwrap_or_else<F>(self, f: F) -> E
     where
         F: FnOnce() -> E,
     {
         match self {
-            MonitorError::Aborted => f(),
+            MonitorError::Aborted | MonitorError::DeadlineExceeded => f(),
             MonitorError::Failed(e) => e,
         }
     }
@@ -333,11 +628,12 @@ impl<E: error::Error> error::Error for MonitorError<E> {
         match *self {
             MonitorError::Aborted => "Monitor target aborted",
             MonitorError::Failed(_) => "Monitor target failed: {}",
+            MonitorError::DeadlineExceeded => "Monitor target exceeded its deadline",
         }
     }
     fn cause(&self) -> Option<&dyn error::Error> {
         match *self {
-            MonitorError::Aborted => None,
+            MonitorError::Aborted | MonitorError::DeadlineExceeded => None,
             MonitorError::Failed(ref e) => Some(e),
         }
     }
@@ -347,6 +643,7 @@ impl<E: fmt::Display> fmt::Display for MonitorError<E> {
         match *self {
             MonitorError::Aborted => write!(f, "Monitor target aborted"),
             MonitorError::Failed(ref e) => write!(f, "Monitor target failed: {}", e),
+            MonitorError::DeadlineExceeded => write!(f, "Monitor target exceeded its deadline"),
         }
     }
 }
@@ -382,3 +679,78 @@ impl<T0, E0, T1, E1> Future for Link<T0, E0, T1, E1> {
         self.rx.poll()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use futures::{self, Future};
+
+    use super::*;
+    use crate::executor::{Executor, InPlaceExecutor};
+    use crate::fiber::Spawn;
+
+    #[test]
+    fn join_all_aborts_remaining_monitors() {
+        let mut executor = InPlaceExecutor::new().unwrap();
+        let (tx, rx) = channel::<()>();
+        let (failure_tx, failure_rx) = channel::<()>();
+
+        // The sibling never completes unless it is aborted.
+        let sibling = executor.spawn_abortable(
+            futures::empty::<(), ()>().and_then(move |()| tx.send(()).map_err(|_| ())),
+        );
+        let failure = executor.spawn_monitor(failure_rx.then(|_| Err::<(), ()>(())));
+        failure_tx.send(()).unwrap();
+
+        let result = executor.run_future(Monitor::join_all(vec![sibling, failure]));
+        assert_eq!(result.unwrap(), Err(MonitorError::Failed(())));
+
+        // The sibling has been stopped (i.e., `tx` has been dropped).
+        assert!(executor.run_future(rx).unwrap().is_err());
+        assert!(executor.scheduler_snapshot().fibers.is_empty());
+    }
+
+    #[test]
+    fn try_join_aborts_the_other_monitor() {
+        let mut executor = InPlaceExecutor::new().unwrap();
+        let (tx, rx) = channel::<()>();
+        let sibling = executor.spawn_abortable(
+            futures::empty::<(), ()>().and_then(move |()| tx.send(()).map_err(|_| ())),
+        );
+        let failure = executor.spawn_monitor(futures::failed::<(), ()>(()));
+
+        let result = executor.run_future(failure.try_join(sibling));
+        assert_eq!(result.unwrap(), Err(MonitorError::Failed(())));
+        assert!(executor.run_future(rx).unwrap().is_err());
+    }
+
+    #[test]
+    fn try_join_drops_the_other_link() {
+        let mut executor = InPlaceExecutor::new().unwrap();
+        let (tx, rx) = channel::<()>();
+        let sibling = executor
+            .spawn_link(futures::empty::<(), ()>().and_then(move |()| tx.send(()).map_err(|_| ())));
+        let failure = executor.spawn_link(futures::failed::<(), ()>(()));
+
+        let result = executor.run_future(try_join(failure, sibling));
+        assert_eq!(result.unwrap(), Err(MonitorError::Failed(())));
+        assert!(executor.run_future(rx).unwrap().is_err());
+    }
+
+    #[test]
+    fn spawn_monitor_is_not_abortable() {
+        let mut executor = InPlaceExecutor::new().unwrap();
+        let mut monitor = executor.spawn_monitor(futures::finished::<(), ()>(()));
+        monitor.abort();
+        assert_eq!(executor.run_fiber(monitor).unwrap(), Ok(()));
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot poll JoinAll after completion")]
+    fn join_all_panics_if_polled_after_failure() {
+        let (monitored, monitor) = monitor::<(), ()>();
+        monitored.exit(Err(()));
+        let mut future = join_all(vec![monitor]);
+        assert_eq!(future.poll(), Err(MonitorError::Failed(())));
+        let _ = future.poll();
+    }
+}
@@ -17,7 +17,7 @@ impl<T> AtomicCell<T> {
             inner: AtomicPtr::new(Box::into_raw(boxed)),
         }
     }
-    pub fn try_borrow_mut(&self) -> Option<AtomicBorrowMut<T>> {
+    pub fn try_borrow_mut(&self) -> Option<AtomicBorrowMut<'_, T>> {
         let old = self.inner.swap(ptr::null_mut(), atomic::Ordering::SeqCst);
         if old.is_null() {
             None
@@ -26,7 +26,7 @@ impl<T> AtomicCell<T> {
             Some(AtomicBorrowMut::new(self, inner))
         }
     }
-    pub fn try_borrow(&self) -> Option<AtomicBorrowRef<T>> {
+    pub fn try_borrow(&self) -> Option<AtomicBorrowRef<'_, T>> {
         self.try_borrow_mut().map(AtomicBorrowRef)
     }
 }
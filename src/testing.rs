@@ -0,0 +1,230 @@
+// Copyright (c) 2016 DWANGO Co., Ltd. All Rights Reserved.
+// See the LICENSE file at the top-level directory of this distribution.
+
+//! Utilities for testing the behavior of fibers under faults.
+//!
+//! This module is available only if the `testing` feature is enabled.
+use futures::{Async, Future};
+use std::io;
+use std::time::{Duration, Instant};
+
+use crate::executor::{Executor, ThreadPoolExecutor, ThreadPoolExecutorHandle};
+use crate::fiber::Spawn;
+use crate::sync::oneshot::{Monitor, MonitorError};
+
+/// A test harness which runs fibers on a `ThreadPoolExecutor` and injects faults into it.
+///
+/// The following faults can be injected:
+///
+/// - Killing a scheduler thread (`Harness::kill_scheduler`):
+///   the fibers on the scheduler are dropped and their monitors result in `MonitorError::Aborted`
+/// - Delaying a poller (`Harness::delay_poller`):
+///   the poller sleeps for the specified duration before each polling
+/// - Dropping wakeups (`Harness::drop_wakeups`):
+///   the scheduler ignores the specified number of subsequent wakeup requests,
+///   so the fibers waiting for them may never be resumed
+///
+/// # Examples
+///
+/// ```
+/// # extern crate fibers;
+/// # extern crate futures;
+/// use fibers::sync::oneshot::MonitorError;
+/// use fibers::testing::Harness;
+///
+/// let mut harness = Harness::new(2).unwrap();
+/// let results = harness
+///     .run(|h| {
+///         let pending = h.spawn_on(0, futures::empty::<usize, ()>());
+///         let finished = h.spawn_on(1, futures::finished::<usize, ()>(1));
+///         h.kill_scheduler(0);
+///         vec![pending, finished]
+///     })
+///     .unwrap();
+/// assert_eq!(results, vec![Err(MonitorError::Aborted), Ok(1)]);
+/// ```
+#[derive(Debug)]
+pub struct Harness {
+    executor: ThreadPoolExecutor,
+    deadline: Duration,
+}
+impl Harness {
+    /// Makes a new harness which has `thread_count` scheduler (and poller) threads.
+    ///
+    /// The deadline of `Harness::run` is ten seconds by default.
+    pub fn new(thread_count: usize) -> io::Result<Self> {
+        let executor = ThreadPoolExecutor::with_thread_count(thread_count)?;
+        Ok(Harness {
+            executor,
+            deadline: Duration::from_secs(10),
+        })
+    }
+
+    /// Sets the deadline of `Harness::run`.
+    pub fn set_deadline(&mut self, deadline: Duration) {
+        self.deadline = deadline;
+    }
+
+    /// Returns the number of the scheduler threads.
+    pub fn thread_count(&self) -> usize {
+        self.executor.scheduler_handles().len()
+    }
+
+    /// Returns a handle of the underlying executor.
+    ///
+    /// The fibers spawned via the handle are distributed to the schedulers in round-robin order.
+    pub fn handle(&self) -> ThreadPoolExecutorHandle {
+        self.executor.handle()
+    }
+
+    /// Spawns a fiber on the `index`-th scheduler and returns its monitor.
+    ///
+    /// # Panics
+    ///
+    /// If `index` is not less than `self.thread_count()`, this will panic.
+    pub fn spawn_on<F>(&self, index: usize, future: F) -> Monitor<F::Item, F::Error>
+    where
+        F: Future + Send + 'static,
+        F::Item: Send,
+        F::Error: Send,
+    {
+        self.executor.scheduler_handles()[index].spawn_monitor(future)
+    }
+
+    /// Kills the `index`-th scheduler thread.
+    ///
+    /// The fibers which have been spawned on the scheduler before calling this are aborted.
+    /// Note that the executor may subsequently assign new fibers to the dead scheduler,
+    /// and those are also aborted.
+    pub fn kill_scheduler(&self, index: usize) {
+        self.executor.scheduler_handles()[index].abort();
+    }
+
+    /// Makes the `index`-th poller thread sleep `delay` before each polling.
+    ///
+    /// As a result, I/O events and timers handled by the poller are delayed.
+    pub fn delay_poller(&self, index: usize, delay: Duration) {
+        self.executor.poller_handles()[index].set_poll_delay(Some(delay));
+    }
+
+    /// Cancels the delay set by `Harness::delay_poller`.
+    pub fn clear_poller_delay(&self, index: usize) {
+        self.executor.poller_handles()[index].set_poll_delay(None);
+    }
+
+    /// Makes the `index`-th scheduler ignore the next `count` wakeup requests.
+    pub fn drop_wakeups(&self, index: usize, count: usize) {
+        self.executor.scheduler_handles()[index].drop_wakeups(count);
+    }
+
+    /// Sets up a topology of fibers by calling `topology` and runs the executor
+    /// until all of the returned monitors exit.
+    ///
+    /// The results are returned in the same order as the monitors.
+    ///
+    /// The errors of the executor (e.g., the abortion of a scheduler thread) are ignored,
+    /// since those may be caused by the injected faults.
+    /// If the monitors do not exit within the deadline,
+    /// this will return an error of which kind is `std::io::ErrorKind::TimedOut`.
+    pub fn run<F, T, E>(&mut self, topology: F) -> io::Result<Vec<Result<T, MonitorError<E>>>>
+    where
+        F: FnOnce(&Self) -> Vec<Monitor<T, E>>,
+    {
+        let mut monitors = topology(self)
+            .into_iter()
+            .map(|m| (m, None))
+            .collect::<Vec<_>>();
+        let start = Instant::now();
+        loop {
+            let mut pending = 0;
+            for &mut (ref mut monitor, ref mut result) in &mut monitors {
+                if result.is_some() {
+                    continue;
+                }
+                match monitor.poll() {
+                    Ok(Async::NotReady) => pending += 1,
+                    Ok(Async::Ready(v)) => *result = Some(Ok(v)),
+                    Err(e) => *result = Some(Err(e)),
+                }
+            }
+            if pending == 0 {
+                break;
+            }
+            if start.elapsed() >= self.deadline {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("{} fibers have not exited within the deadline", pending),
+                ));
+            }
+            let _ = self.executor.run_once();
+        }
+        Ok(monitors
+            .into_iter()
+            .map(|(_, r)| r.expect("Never fails"))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::Future;
+    use std::sync::mpsc;
+
+    use super::*;
+    use crate::sync::oneshot;
+    use crate::time::timer;
+
+    #[test]
+    fn drop_wakeups_works() {
+        let mut harness = Harness::new(2).unwrap();
+        harness.set_deadline(Duration::from_millis(200));
+        let (tx0, rx0) = oneshot::channel();
+        let (tx1, rx1) = oneshot::channel();
+        let (resumed_tx, resumed_rx) = mpsc::channel();
+
+        let e = harness
+            .run(|h| {
+                let stuck = h.spawn_on(0, rx0.map_err(|_| ()));
+                let resumed = h.spawn_on(
+                    0,
+                    rx1.map(move |v| resumed_tx.send(v).unwrap())
+                        .map_err(|_| ()),
+                );
+                h.drop_wakeups(0, 1);
+
+                // Wakes up the two fibers after both of them are suspended.
+                h.spawn_on(
+                    1,
+                    timer::timeout(Duration::from_millis(50)).then(move |_| {
+                        let _ = tx0.send(());
+                        let _ = tx1.send(1);
+                        Ok::<_, ()>(())
+                    }),
+                );
+                vec![stuck, resumed]
+            })
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+
+        // Only the first wakeup has been dropped.
+        assert_eq!(resumed_rx.try_recv(), Ok(1));
+    }
+
+    #[test]
+    fn delay_poller_works() {
+        let mut harness = Harness::new(1).unwrap();
+        harness.delay_poller(0, Duration::from_millis(100));
+        let start = Instant::now();
+        let results = harness
+            .run(|h| vec![h.spawn_on(0, timer::timeout(Duration::from_millis(1)))])
+            .unwrap();
+        assert!(results[0].is_ok());
+        assert!(start.elapsed() >= Duration::from_millis(100));
+
+        harness.clear_poller_delay(0);
+        let results = harness
+            .run(|h| vec![h.spawn_on(0, timer::timeout(Duration::from_millis(1)))])
+            .unwrap();
+        assert!(results[0].is_ok());
+    }
+}
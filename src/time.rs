@@ -54,14 +54,47 @@ pub mod timer {
     pub struct Timeout {
         start: time::Instant,
         duration: time::Duration,
+        slack: time::Duration,
         inner: Option<poll::poller::Timeout>,
     }
 
     /// Makes a future which will expire after `delay_from_now`.
     pub fn timeout(delay_from_now: time::Duration) -> Timeout {
+        timeout_with_slack(delay_from_now, time::Duration::from_secs(0))
+    }
+
+    /// Makes a future which will expire after `delay_from_now`,
+    /// allowing the expiration to be delayed by at most `slack`.
+    ///
+    /// The poller does not wake up just for the timer until the end of the slack.
+    /// If the poller wakes up for other events within the slack, the timer is fired
+    /// together with them (i.e., it is coalesced).
+    /// Thus, non-critical timers can reduce wakeups by specifying a large slack.
+    ///
+    /// The number of coalesced timers can be retrieved via `PollerStats`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate fibers;
+    /// # extern crate futures;
+    /// use std::time::Duration;
+    /// use fibers::{Executor, InPlaceExecutor, Spawn};
+    /// use fibers::time::timer;
+    ///
+    /// let mut executor = InPlaceExecutor::new().unwrap();
+    /// let monitor = executor.spawn_monitor(timer::timeout_with_slack(
+    ///     Duration::from_millis(1),
+    ///     Duration::from_millis(10),
+    /// ));
+    /// executor.run_fiber(monitor).unwrap().unwrap();
+    /// assert_eq!(executor.poller_stats().timers_fired, 1);
+    /// ```
+    pub fn timeout_with_slack(delay_from_now: time::Duration, slack: time::Duration) -> Timeout {
         Timeout {
             start: time::Instant::now(),
             duration: delay_from_now,
+            slack,
             inner: None,
         }
     }
@@ -73,6 +106,7 @@ pub mod timer {
                 inner.poll()
             } else {
                 let duration = self.duration;
+                let slack = self.slack;
                 let elapsed = self.start.elapsed();
                 if elapsed >= duration {
                     return Ok(Async::Ready(()));
@@ -80,7 +114,7 @@ pub mod timer {
 
                 let set_timeout = |mut c: Context| {
                     let rest = duration - elapsed;
-                    poll::poller::set_timeout(c.poller(), rest)
+                    poll::poller::set_timeout(c.poller(), rest, slack)
                 };
                 if let Some(inner) = fiber::with_current_context(set_timeout) {
                     self.inner = Some(inner);
@@ -92,6 +126,48 @@ pub mod timer {
         }
     }
 
+    /// A timer which drives the expiration of entries ordered by their expiry times
+    /// (e.g., the expiry queue of a cache).
+    #[derive(Debug, Default)]
+    pub(crate) struct ExpiryTimer {
+        timer: Option<(time::Instant, Timeout)>,
+    }
+    impl ExpiryTimer {
+        /// Evicts the expired entries and (re)arms the timer for the earliest remaining one.
+        ///
+        /// `evict_expired` evicts the entries expired at the given time and
+        /// returns the expiry time of the earliest remaining entry.
+        ///
+        /// The current fiber is notified when the timer expires.
+        pub fn poll<F>(&mut self, mut evict_expired: F)
+        where
+            F: FnMut(time::Instant) -> Option<time::Instant>,
+        {
+            loop {
+                let now = time::Instant::now();
+                let earliest = if let Some(earliest) = evict_expired(now) {
+                    earliest
+                } else {
+                    self.timer = None;
+                    return;
+                };
+                if self.timer.as_ref().is_none_or(|t| t.0 != earliest) {
+                    let timeout = timeout(earliest.saturating_duration_since(now));
+                    self.timer = Some((earliest, timeout));
+                }
+                let timeout = &mut self.timer.as_mut().expect("Never fails").1;
+                match timeout.poll() {
+                    Ok(Async::NotReady) => return,
+                    Ok(Async::Ready(())) => self.timer = None,
+                    Err(_) => {
+                        // The poller is down.
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
     #[cfg(test)]
     mod test {
         use super::*;
@@ -115,5 +191,23 @@ pub mod timer {
             let mut future = futures::failed::<(), ()>(()).timeout_after(Duration::from_secs(1));
             assert_eq!(future.poll(), Err(Some(())));
         }
+
+        #[test]
+        fn expiry_timer_works() {
+            let mut timer = ExpiryTimer::default();
+            let expiry_time = time::Instant::now();
+            let mut queue = vec![expiry_time + Duration::from_secs(60), expiry_time];
+            timer.poll(|now| {
+                queue.retain(|&t| t > now);
+                queue.first().cloned()
+            });
+            assert_eq!(queue.len(), 1);
+
+            // The `Timeout` for the remaining entry is created, but outside of fibers
+            // it is never registered with a poller.
+            assert!(timer.timer.is_some());
+            timer.poll(|_| None);
+            assert!(timer.timer.is_none());
+        }
     }
 }
@@ -0,0 +1,238 @@
+// Copyright (c) 2016 DWANGO Co., Ltd. All Rights Reserved.
+// See the LICENSE file at the top-level directory of this distribution.
+
+//! In-memory tracing of recent scheduling events for post-mortem analysis.
+//!
+//! A `TraceRing` keeps a fixed number of the most recent events
+//! recorded by schedulers and pollers.
+//! It can be dumped on demand (`TraceRing::events`, `TraceRing::write_to`)
+//! or automatically on panic (`TraceRing::dump_on_panic`).
+//!
+//! # Examples
+//!
+//! ```
+//! # extern crate fibers;
+//! # extern crate futures;
+//! use fibers::{Executor, InPlaceExecutor, Spawn};
+//! use fibers::trace::TraceEventKind;
+//!
+//! let mut executor = InPlaceExecutor::new().unwrap();
+//! let ring = executor.enable_tracing(128);
+//!
+//! let monitor = executor.spawn_monitor(futures::finished::<(), ()>(()));
+//! executor.run_fiber(monitor).unwrap().unwrap();
+//!
+//! let events = ring.events();
+//! assert!(events.iter().any(|e| match e.kind {
+//!     TraceEventKind::PollEnd { finished, .. } => finished,
+//!     _ => false,
+//! }));
+//!
+//! let mut dump = Vec::new();
+//! ring.write_to(&mut dump).unwrap();
+//! assert!(!dump.is_empty());
+//! ```
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::panic;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::fiber::{FiberId, SchedulerId};
+
+/// A ring buffer of recent scheduling events.
+///
+/// The ring is shared by the schedulers and pollers which it is installed to,
+/// and is cheaply cloneable.
+#[derive(Debug, Clone)]
+pub struct TraceRing {
+    inner: Arc<Mutex<VecDeque<TraceEvent>>>,
+    capacity: usize,
+}
+impl TraceRing {
+    /// Makes a new ring which keeps at most `capacity` events.
+    ///
+    /// # Panics
+    ///
+    /// If `capacity` is `0`, this function will panic.
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0);
+        TraceRing {
+            inner: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+        }
+    }
+
+    /// Returns the maximum number of the events kept by this ring.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Returns the recorded events in chronological order.
+    pub fn events(&self) -> Vec<TraceEvent> {
+        let events = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        events.iter().cloned().collect()
+    }
+
+    /// Removes all the recorded events.
+    pub fn clear(&self) {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner()).clear();
+    }
+
+    /// Writes the recorded events to `writer` in a human readable format.
+    ///
+    /// Each line contains the elapsed time from the oldest event,
+    /// the source of the event and the event itself.
+    pub fn write_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let events = self.events();
+        let base = match events.first() {
+            None => return Ok(()),
+            Some(e) => e.time,
+        };
+        for e in &events {
+            let offset = e.time.duration_since(base);
+            write!(writer, "+{:>10}us ", offset.as_micros())?;
+            match e.scheduler_id {
+                Some(id) => write!(writer, "scheduler#{} ", id)?,
+                None => write!(writer, "poller ")?,
+            }
+            writeln!(writer, "{:?}", e.kind)?;
+        }
+        Ok(())
+    }
+
+    /// Installs a panic hook which writes the recorded events to the standard error.
+    ///
+    /// The previously installed hook is called after the dump.
+    pub fn dump_on_panic(&self) {
+        let ring = self.clone();
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            let stderr = io::stderr();
+            let mut stderr = stderr.lock();
+            let _ = writeln!(stderr, "--- Recent scheduling events ---");
+            let _ = ring.write_to(&mut stderr);
+            let _ = writeln!(stderr, "--------------------------------");
+            previous(info);
+        }));
+    }
+
+    pub(crate) fn record(&self, scheduler_id: Option<SchedulerId>, kind: TraceEventKind) {
+        let event = TraceEvent {
+            time: Instant::now(),
+            scheduler_id,
+            kind,
+        };
+        let mut events = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        if events.len() == self.capacity {
+            events.pop_front();
+        }
+        events.push_back(event);
+    }
+}
+
+/// A scheduling event recorded in a `TraceRing`.
+#[derive(Debug, Clone)]
+pub struct TraceEvent {
+    /// The time when the event occurred.
+    pub time: Instant,
+
+    /// The identifier of the scheduler which recorded the event.
+    ///
+    /// This is `None` if the event was recorded by a poller.
+    pub scheduler_id: Option<SchedulerId>,
+
+    /// The kind of the event.
+    pub kind: TraceEventKind,
+}
+
+/// The kind of a scheduling event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceEventKind {
+    /// A fiber was spawned.
+    Spawn {
+        /// The identifier of the fiber.
+        fiber_id: FiberId,
+    },
+
+    /// The scheduler started polling a fiber.
+    PollStart {
+        /// The identifier of the fiber.
+        fiber_id: FiberId,
+    },
+
+    /// The scheduler finished polling a fiber.
+    PollEnd {
+        /// The identifier of the fiber.
+        fiber_id: FiberId,
+
+        /// Whether the fiber has exited.
+        finished: bool,
+    },
+
+    /// A fiber was woken up.
+    WakeUp {
+        /// The identifier of the fiber.
+        fiber_id: FiberId,
+    },
+
+    /// A timer fired.
+    TimerFired {
+        /// The identifier of the timer (unique within the poller).
+        timeout_id: usize,
+    },
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn oldest_events_are_overwritten() {
+        let ring = TraceRing::new(2);
+        for fiber_id in 0..3 {
+            ring.record(Some(0), TraceEventKind::Spawn { fiber_id });
+        }
+        let kinds = ring
+            .events()
+            .into_iter()
+            .map(|e| e.kind)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            vec![
+                TraceEventKind::Spawn { fiber_id: 1 },
+                TraceEventKind::Spawn { fiber_id: 2 },
+            ]
+        );
+
+        ring.clear();
+        assert!(ring.events().is_empty());
+        assert_eq!(ring.capacity(), 2);
+    }
+
+    #[test]
+    fn write_to_works() {
+        let ring = TraceRing::new(4);
+        let mut dump = Vec::new();
+        ring.write_to(&mut dump).unwrap();
+        assert!(dump.is_empty());
+
+        ring.record(Some(3), TraceEventKind::PollStart { fiber_id: 7 });
+        ring.record(None, TraceEventKind::TimerFired { timeout_id: 5 });
+        ring.write_to(&mut dump).unwrap();
+
+        let dump = String::from_utf8(dump).unwrap();
+        let lines = dump.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("+         0us "));
+        assert!(lines[0].ends_with("scheduler#3 PollStart { fiber_id: 7 }"));
+        assert!(lines[1].ends_with("poller TimerFired { timeout_id: 5 }"));
+    }
+
+    #[test]
+    #[should_panic]
+    fn zero_capacity_is_rejected() {
+        TraceRing::new(0);
+    }
+}
@@ -0,0 +1,171 @@
+// Copyright (c) 2016 DWANGO Co., Ltd. All Rights Reserved.
+// See the LICENSE file at the top-level directory of this distribution.
+
+//! Storage which lives per scheduler (worker) thread.
+//!
+//! A `WorkerLocal` is declared by the `worker_local!` macro.
+//! Fibers access the value of their current worker thread without any locks,
+//! and the values of all the workers can be aggregated (or flushed) centrally
+//! via `WorkerLocal::collect` method.
+//!
+//! # Examples
+//!
+//! ```
+//! #[macro_use]
+//! extern crate fibers;
+//! # extern crate futures;
+//! use fibers::{Executor, Spawn, ThreadPoolExecutor};
+//!
+//! worker_local! {
+//!     static REQUESTS: u64 = 0;
+//! }
+//!
+//! # fn main() {
+//! let mut executor = ThreadPoolExecutor::with_thread_count(2).unwrap();
+//! for _ in 0..10 {
+//!     let monitor = executor.spawn_monitor_fn(|| {
+//!         REQUESTS.with(|n| *n += 1);
+//!         Ok::<_, ()>(())
+//!     });
+//!     executor.run_fiber(monitor).unwrap().unwrap();
+//! }
+//!
+//! // Takes the per-worker counts (resetting them) and sums them up.
+//! let counts = REQUESTS.collect(executor.scheduler_handles(), |n| std::mem::replace(n, 0));
+//! let counts = executor.run_future(counts).unwrap().unwrap();
+//! assert_eq!(counts.len(), 2);
+//! assert_eq!(counts.iter().sum::<u64>(), 10);
+//! # }
+//! ```
+use futures::{self, Future};
+use std::cell::RefCell;
+use std::fmt;
+use std::sync::Arc;
+use std::thread::LocalKey;
+
+use crate::fiber::{SchedulerHandle, Spawn};
+use crate::sync::oneshot::{self, JoinAll, Monitor};
+
+/// Declares a `WorkerLocal` static value.
+///
+/// The syntax is the same as the `thread_local!` macro of the standard library,
+/// but only one value can be declared per invocation.
+///
+/// See the [worker module](worker/index.html) for an example.
+#[macro_export]
+macro_rules! worker_local {
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty = $init:expr;) => {
+        $(#[$attr])* $vis static $name: $crate::worker::WorkerLocal<$t> = {
+            ::std::thread_local! {
+                static INNER: ::std::cell::RefCell<$t> = ::std::cell::RefCell::new($init);
+            }
+            $crate::worker::WorkerLocal::new(&INNER)
+        };
+    };
+}
+
+/// A value which lives per scheduler (worker) thread.
+///
+/// This is declared by the `worker_local!` macro.
+pub struct WorkerLocal<T: 'static> {
+    key: &'static LocalKey<RefCell<T>>,
+}
+impl<T: 'static> WorkerLocal<T> {
+    #[doc(hidden)]
+    pub const fn new(key: &'static LocalKey<RefCell<T>>) -> Self {
+        WorkerLocal { key }
+    }
+
+    /// Calls `f` with the value of the current thread.
+    ///
+    /// # Panics
+    ///
+    /// If this is called recursively, it will panic.
+    pub fn with<F, R>(&'static self, f: F) -> R
+    where
+        F: FnOnce(&mut T) -> R,
+    {
+        self.key.with(|value| f(&mut value.borrow_mut()))
+    }
+
+    /// Makes a future which calls `f` with the value of each worker thread
+    /// of `schedulers` and collects the results.
+    ///
+    /// `f` is executed on the worker threads (as fibers), so it can
+    /// read, reset or flush the values without races.
+    /// The results are ordered in the same order as `schedulers`.
+    ///
+    /// If some schedulers are dead, the future will fail.
+    pub fn collect<F, R>(&'static self, schedulers: &[SchedulerHandle], f: F) -> Collect<R>
+    where
+        F: Fn(&mut T) -> R + Send + Sync + 'static,
+        R: Send + 'static,
+    {
+        let f = Arc::new(f);
+        let monitors = schedulers
+            .iter()
+            .map(|scheduler| {
+                let f = Arc::clone(&f);
+                scheduler.spawn_monitor(futures::lazy(move || Ok(self.with(|v| f(v)))))
+            })
+            .collect::<Vec<_>>();
+        Collect(Monitor::join_all(monitors))
+    }
+}
+impl<T: 'static> fmt::Debug for WorkerLocal<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "WorkerLocal {{ .. }}")
+    }
+}
+
+/// A future which collects the results of a function called on each worker thread.
+///
+/// This is created by calling `WorkerLocal::collect` method.
+#[derive(Debug)]
+pub struct Collect<R>(JoinAll<Monitor<R, ()>>);
+impl<R> Future for Collect<R> {
+    type Item = Vec<R>;
+    type Error = oneshot::MonitorError<()>;
+    fn poll(&mut self) -> futures::Poll<Self::Item, Self::Error> {
+        self.0.poll()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+
+    use super::*;
+    use crate::{Executor, ThreadPoolExecutor};
+
+    worker_local! {
+        static COUNTER: u64 = 0;
+    }
+
+    #[test]
+    fn values_are_thread_local() {
+        COUNTER.with(|n| *n = 5);
+        assert_eq!(thread::spawn(|| COUNTER.with(|n| *n)).join().unwrap(), 0);
+        assert_eq!(COUNTER.with(|n| *n), 5);
+    }
+
+    #[test]
+    fn collect_works() {
+        let mut executor = ThreadPoolExecutor::with_thread_count(2).unwrap();
+        let schedulers = executor.scheduler_handles().to_vec();
+        for (scheduler, delta) in schedulers.iter().zip([1, 10]) {
+            let monitor = scheduler.spawn_monitor_fn(move || {
+                COUNTER.with(|n| *n += delta);
+                Ok::<_, ()>(())
+            });
+            executor.run_fiber(monitor).unwrap().unwrap();
+        }
+
+        // The results are ordered in the same order as the schedulers.
+        let counts = COUNTER.collect(&schedulers, |n| std::mem::replace(n, 0));
+        assert_eq!(executor.run_future(counts).unwrap(), Ok(vec![1, 10]));
+
+        let counts = COUNTER.collect(&schedulers, |n| *n);
+        assert_eq!(executor.run_future(counts).unwrap(), Ok(vec![0, 0]));
+    }
+}